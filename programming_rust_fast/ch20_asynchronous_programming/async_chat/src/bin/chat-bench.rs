@@ -0,0 +1,249 @@
+use async_chat::utils::{self, ChatResult};
+use async_chat::{FromClient, FromServer, PROTOCOL_VERSION};
+use async_std::future;
+use async_std::io::BufReader;
+use async_std::net::TcpStream;
+use async_std::prelude::*;
+use async_std::sync::Barrier;
+use async_std::task;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// 压测的参数
+struct Options {
+    address: String,
+    clients: usize,
+    groups: usize,
+    /// 每个客户端每秒发布的消息数
+    rate: f64,
+    duration: Duration,
+}
+
+/// 解析命令行参数：
+/// chat-bench ADDRESS [--clients N] [--groups M] [--rate MSGS_PER_SEC] [--duration SECS]
+fn parse_options() -> Options {
+    const USAGE: &str = "用法: chat-bench address [--clients N] [--groups M] \
+                         [--rate MSGS_PER_SEC] [--duration SECS]";
+    let mut options = Options {
+        address: String::new(),
+        clients: 10,
+        groups: 1,
+        rate: 10.0,
+        duration: Duration::from_secs(10),
+    };
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().expect(USAGE);
+        match arg.as_str() {
+            "--clients" => options.clients = value().parse().expect(USAGE),
+            "--groups" => options.groups = value().parse().expect(USAGE),
+            "--rate" => options.rate = value().parse().expect(USAGE),
+            "--duration" => options.duration = Duration::from_secs_f64(
+                value().parse().expect(USAGE)),
+            _ if options.address.is_empty() => options.address = arg,
+            _ => panic!("{}", USAGE),
+        }
+    }
+    if options.address.is_empty() || options.clients == 0 || options.groups == 0
+        || options.rate <= 0.0 {
+        panic!("{}", USAGE);
+    }
+    options
+}
+
+/// 单个客户端收集到的统计数据
+#[derive(Default)]
+struct Stats {
+    posted: u64,
+    received: u64,
+    /// 端到端延迟，单位为微秒
+    latencies: Vec<u64>,
+    /// 服务端报告的因为接收太慢而被丢弃的消息数
+    lagged: u64,
+    errors: u64,
+}
+
+impl Stats {
+    fn merge(&mut self, other: Stats) {
+        self.posted += other.posted;
+        self.received += other.received;
+        self.latencies.extend(other.latencies);
+        self.lagged += other.lagged;
+        self.errors += other.errors;
+    }
+}
+
+/// 当前时间，单位为微秒，嵌入在消息中用来计算端到端延迟
+fn now_micros() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_micros() as u64
+}
+
+fn group_name(group: usize) -> Arc<String> {
+    Arc::new(format!("bench-{}", group))
+}
+
+/// 模拟一个客户端：加入所有群组，等所有客户端就绪后按照固定速率发布消息，
+/// 同时接收消息直到发布结束后再等待 drain 时间
+async fn simulate_client(client: usize, options: Arc<Options>, ready: Arc<Barrier>)
+    -> ChatResult<Stats> {
+    let connected = connect_and_join(&options).await;
+
+    // 等待所有客户端都加入群组之后再开始发布，避免后加入的客户端错过消息，
+    // 连接失败的客户端也要等待，否则其他客户端会一直阻塞在这里
+    ready.wait().await;
+    let socket = connected?;
+    let mut to_server = socket.clone();
+    task::sleep(Duration::from_millis(200)).await;
+
+    let drain = Duration::from_secs(2);
+    let deadline = Instant::now() + options.duration + drain;
+    let receiver = task::spawn(receive_messages(socket, deadline));
+
+    let interval = Duration::from_secs_f64(1.0 / options.rate);
+    let start = Instant::now();
+    let mut posted = 0;
+    while start.elapsed() < options.duration {
+        let post = FromClient::Post {
+            group_name: group_name((client + posted as usize) % options.groups),
+            message: Arc::new(format!("{} {} {}", client, posted, now_micros())),
+        };
+        utils::send_as_json(&mut to_server, &post).await?;
+        to_server.flush().await?;
+        posted += 1;
+
+        let next = start + interval * posted as u32;
+        if let Some(delay) = next.checked_duration_since(Instant::now()) {
+            task::sleep(delay).await;
+        }
+    }
+
+    let mut stats = receiver.await;
+    stats.posted = posted;
+    Ok(stats)
+}
+
+async fn connect_and_join(options: &Options) -> ChatResult<TcpStream> {
+    let socket = TcpStream::connect(&options.address).await?;
+    socket.set_nodelay(true)?;
+    let mut to_server = socket.clone();
+
+    let hello = FromClient::Hello {
+        protocol_version: PROTOCOL_VERSION,
+        features: Vec::new(),
+    };
+    utils::send_as_json(&mut to_server, &hello).await?;
+    for group in 0..options.groups {
+        let join = FromClient::Join { group_name: group_name(group) };
+        utils::send_as_json(&mut to_server, &join).await?;
+    }
+    to_server.flush().await?;
+    Ok(socket)
+}
+
+/// 接收消息并记录延迟，到达 deadline 后返回
+async fn receive_messages(socket: TcpStream, deadline: Instant) -> Stats {
+    let mut stats = Stats::default();
+    let mut from_server = utils::receive_as_json(BufReader::new(socket));
+
+    while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+        let reply = match future::timeout(remaining, from_server.next()).await {
+            Ok(Some(reply)) => reply,
+            Ok(None) | Err(_) => break,
+        };
+        match reply {
+            Ok(FromServer::Message { message, .. }) => {
+                stats.received += 1;
+                let sent = message.rsplit(' ').next().and_then(|sent| sent.parse::<u64>().ok());
+                if let Some(sent) = sent {
+                    stats.latencies.push(now_micros().saturating_sub(sent));
+                }
+            }
+            Ok(FromServer::Error(message)) => {
+                // 订阅者落后太多时服务端会报告 "Dropped N messages from GROUP."
+                let dropped = message.strip_prefix("Dropped ")
+                    .and_then(|rest| rest.split(' ').next())
+                    .and_then(|n| n.parse::<u64>().ok());
+                match dropped {
+                    Some(n) => stats.lagged += n,
+                    None => stats.errors += 1,
+                }
+            }
+            Ok(_) => {}
+            Err(error) => {
+                stats.errors += 1;
+                if utils::as_unknown_packet(&error).is_none() {
+                    break;
+                }
+            }
+        }
+    }
+    stats
+}
+
+/// 已排序的延迟中第 p 百分位的值
+fn percentile(sorted: &[u64], p: f64) -> u64 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    let rank = (p / 100.0 * (sorted.len() - 1) as f64).round() as usize;
+    sorted[rank]
+}
+
+fn report(options: &Options, mut stats: Stats, failed_clients: usize) {
+    let seconds = options.duration.as_secs_f64();
+    // 每个客户端都加入了所有群组，所以每条消息应该被投递给所有成功的客户端
+    let receivers = (options.clients - failed_clients) as u64;
+    let expected = stats.posted * receivers;
+    let missing = expected.saturating_sub(stats.received + stats.lagged);
+
+    stats.latencies.sort_unstable();
+    let millis = |micros: u64| micros as f64 / 1000.0;
+
+    println!("clients: {} ({} failed), groups: {}, duration: {:.1}s",
+             options.clients, failed_clients, options.groups, seconds);
+    println!("posted: {} ({:.1} msgs/s)", stats.posted, stats.posted as f64 / seconds);
+    println!("delivered: {} of {} expected ({:.1} msgs/s)",
+             stats.received, expected, stats.received as f64 / seconds);
+    println!("latency ms: p50 {:.2}, p90 {:.2}, p99 {:.2}, max {:.2}",
+             millis(percentile(&stats.latencies, 50.0)),
+             millis(percentile(&stats.latencies, 90.0)),
+             millis(percentile(&stats.latencies, 99.0)),
+             millis(stats.latencies.last().cloned().unwrap_or(0)));
+    println!("lagged (dropped by server): {}, missing: {}, errors: {}",
+             stats.lagged, missing, stats.errors);
+}
+
+fn main() -> ChatResult<()> {
+    let options = Arc::new(parse_options());
+    let ready = Arc::new(Barrier::new(options.clients));
+
+    task::block_on(async {
+        let clients: Vec<_> = (0..options.clients)
+            .map(|client| task::spawn(simulate_client(client, options.clone(), ready.clone())))
+            .collect();
+
+        let mut total = Stats::default();
+        let mut failed_clients = 0;
+        for client in clients {
+            match client.await {
+                Ok(stats) => total.merge(stats),
+                Err(error) => {
+                    eprintln!("client failed: {}", error);
+                    failed_clients += 1;
+                }
+            }
+        }
+
+        report(&options, total, failed_clients);
+        Ok(())
+    })
+}
+
+#[test]
+fn test_percentile() {
+    let sorted: Vec<u64> = (1..=100).collect();
+    assert_eq!(percentile(&sorted, 50.0), 51);
+    assert_eq!(percentile(&sorted, 99.0), 99);
+    assert_eq!(percentile(&[], 50.0), 0);
+}
@@ -0,0 +1,249 @@
+use async_chat::e2e::{self, GroupKey};
+use async_chat::utils::ChatResult;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::path::PathBuf;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// 客户端本地保存的密钥：
+/// 一个长期使用的 X25519 身份密钥，每个端到端加密群组的群组密钥，
+/// 以及用户确认过的群组成员公钥指纹
+pub struct Keystore {
+    path: PathBuf,
+    identity: StaticSecret,
+    groups: HashMap<String, GroupKey>,
+    /// 只和指纹被用户确认过的成员交换群组密钥，
+    /// 否则服务端可以伪造公钥或者群组密钥
+    trusted: HashMap<String, HashSet<String>>,
+    /// 还没有确认指纹的成员公布的公钥，按群组名和指纹保存，只保存在内存中
+    announced: HashMap<(String, String), PublicKey>,
+    /// 还没有确认指纹的成员发来的群组密钥，保存对方公钥和加密后的群组密钥
+    offered: HashMap<(String, String), (PublicKey, String)>,
+}
+
+/// 密钥文件的 json 格式，密钥都以十六进制保存
+#[derive(Deserialize, Serialize)]
+struct KeyFile {
+    identity: String,
+    groups: HashMap<String, String>,
+    #[serde(default)]
+    trusted: HashMap<String, Vec<String>>,
+}
+
+impl Keystore {
+    /// 读取密钥文件，文件不存在时生成新的身份密钥并保存
+    pub fn load(path: impl Into<PathBuf>) -> ChatResult<Keystore> {
+        let path = path.into();
+        let json = match fs::read_to_string(&path) {
+            Ok(json) => json,
+            Err(error) if error.kind() == ErrorKind::NotFound => {
+                let keystore = Keystore {
+                    path,
+                    identity: e2e::generate_identity(),
+                    groups: HashMap::new(),
+                    trusted: HashMap::new(),
+                    announced: HashMap::new(),
+                    offered: HashMap::new(),
+                };
+                keystore.save()?;
+                return Ok(keystore);
+            }
+            Err(error) => return Err(error.into()),
+        };
+
+        // 以前保存的密钥文件可能允许其他用户读取
+        #[cfg(unix)]
+        fs::set_permissions(&path, std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+        let file: KeyFile = serde_json::from_str(&json)?;
+        let mut groups = HashMap::new();
+        for (group_name, key) in file.groups {
+            groups.insert(group_name, decode_key(&key)?);
+        }
+        Ok(Keystore {
+            path,
+            identity: StaticSecret::from(decode_key(&file.identity)?),
+            groups,
+            trusted: file.trusted.into_iter()
+                .map(|(group_name, fingerprints)| (group_name, fingerprints.into_iter().collect()))
+                .collect(),
+            announced: HashMap::new(),
+            offered: HashMap::new(),
+        })
+    }
+
+    pub fn identity(&self) -> &StaticSecret {
+        &self.identity
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey::from(&self.identity)
+    }
+
+    pub fn group_key(&self, group_name: &str) -> Option<&GroupKey> {
+        self.groups.get(group_name)
+    }
+
+    /// 保存群组密钥并写回密钥文件
+    pub fn set_group_key(&mut self, group_name: &str, key: GroupKey) -> ChatResult<()> {
+        self.groups.insert(group_name.to_string(), key);
+        self.save()
+    }
+
+    /// 用户通过其他渠道比对之后，确认群组中指纹为 fingerprint 的成员，并写回密钥文件
+    pub fn trust(&mut self, group_name: &str, fingerprint: &str) -> ChatResult<()> {
+        self.trusted.entry(group_name.to_string()).or_default()
+            .insert(fingerprint.to_ascii_lowercase());
+        self.save()
+    }
+
+    pub fn is_trusted(&self, group_name: &str, peer: &PublicKey) -> bool {
+        self.trusted.get(group_name)
+            .is_some_and(|fingerprints| fingerprints.contains(&e2e::fingerprint(peer)))
+    }
+
+    /// 记下未确认的成员公布的公钥，确认指纹后再把群组密钥发给对方
+    pub fn remember_announced(&mut self, group_name: &str, peer: PublicKey) {
+        let key = (group_name.to_string(), e2e::fingerprint(&peer));
+        self.announced.insert(key, peer);
+    }
+
+    pub fn take_announced(&mut self, group_name: &str, fingerprint: &str) -> Option<PublicKey> {
+        self.announced.remove(&(group_name.to_string(), fingerprint.to_ascii_lowercase()))
+    }
+
+    /// 记下未确认的成员发来的群组密钥，确认指纹后再接受
+    pub fn remember_offered(&mut self, group_name: &str, peer: PublicKey, wrapped_key: &str) {
+        let key = (group_name.to_string(), e2e::fingerprint(&peer));
+        self.offered.insert(key, (peer, wrapped_key.to_string()));
+    }
+
+    pub fn take_offered(&mut self, group_name: &str, fingerprint: &str)
+        -> Option<(PublicKey, String)> {
+        self.offered.remove(&(group_name.to_string(), fingerprint.to_ascii_lowercase()))
+    }
+
+    fn save(&self) -> ChatResult<()> {
+        let file = KeyFile {
+            identity: hex::encode(self.identity.to_bytes()),
+            groups: self.groups.iter()
+                .map(|(group_name, key)| (group_name.clone(), hex::encode(key)))
+                .collect(),
+            trusted: self.trusted.iter()
+                .map(|(group_name, fingerprints)| {
+                    let mut fingerprints: Vec<String> = fingerprints.iter().cloned().collect();
+                    fingerprints.sort();
+                    (group_name.clone(), fingerprints)
+                })
+                .collect(),
+        };
+        let json = serde_json::to_string_pretty(&file)?;
+
+        // 先写到同一目录下只允许当前用户读写的临时文件，写入磁盘之后再替换密钥文件，
+        // 写到一半崩溃时原来的密钥文件不受影响
+        let temp_path = self.temp_path();
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut temp = options.open(&temp_path)?;
+        #[cfg(unix)]
+        temp.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+        temp.write_all(json.as_bytes())?;
+        temp.sync_all()?;
+        fs::rename(&temp_path, &self.path)?;
+        Ok(())
+    }
+
+    fn temp_path(&self) -> PathBuf {
+        let mut name = self.path.file_name().unwrap_or_default().to_os_string();
+        name.push(".tmp");
+        self.path.with_file_name(name)
+    }
+}
+
+fn decode_key(key: &str) -> ChatResult<GroupKey> {
+    let key = hex::decode(key)?.try_into()
+        .map_err(|_| "key in keystore has wrong length")?;
+    Ok(key)
+}
+
+#[test]
+fn test_trusted_fingerprints_survive_reload() {
+    let path = std::env::temp_dir()
+        .join(format!("async_chat_keystore_test_{}.json", std::process::id()));
+    let _ignored = fs::remove_file(&path);
+
+    let peer = PublicKey::from(&e2e::generate_identity());
+    let fingerprint = e2e::fingerprint(&peer);
+    {
+        let mut keystore = Keystore::load(&path).unwrap();
+        assert!(!keystore.is_trusted("students", &peer));
+        keystore.set_group_key("students", e2e::generate_group_key()).unwrap();
+        keystore.trust("students", &fingerprint.to_ascii_uppercase()).unwrap();
+        assert!(keystore.is_trusted("students", &peer));
+        // 信任只对确认过的群组有效
+        assert!(!keystore.is_trusted("teachers", &peer));
+
+        keystore.remember_announced("students", peer);
+        keystore.remember_offered("students", peer, "e2e:wrapped");
+    }
+
+    let mut keystore = Keystore::load(&path).unwrap();
+    assert!(keystore.is_trusted("students", &peer));
+    assert!(keystore.group_key("students").is_some());
+    // 未确认的公钥和群组密钥不会写入密钥文件
+    assert!(keystore.take_announced("students", &fingerprint).is_none());
+    assert!(keystore.take_offered("students", &fingerprint).is_none());
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_pending_keys_are_taken_once() {
+    let path = std::env::temp_dir()
+        .join(format!("async_chat_keystore_pending_{}.json", std::process::id()));
+    let _ignored = fs::remove_file(&path);
+    let mut keystore = Keystore::load(&path).unwrap();
+
+    let peer = PublicKey::from(&e2e::generate_identity());
+    let fingerprint = e2e::fingerprint(&peer);
+    keystore.remember_announced("students", peer);
+    keystore.remember_offered("students", peer, "e2e:wrapped");
+    assert!(keystore.take_announced("teachers", &fingerprint).is_none());
+    assert_eq!(keystore.take_announced("students", &fingerprint), Some(peer));
+    assert!(keystore.take_announced("students", &fingerprint).is_none());
+    assert_eq!(keystore.take_offered("students", &fingerprint),
+               Some((peer, "e2e:wrapped".to_string())));
+    assert!(keystore.take_offered("students", &fingerprint).is_none());
+    fs::remove_file(&path).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_keystore_is_private_and_replaced_whole() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let path = std::env::temp_dir()
+        .join(format!("async_chat_keystore_private_{}.json", std::process::id()));
+    let _ignored = fs::remove_file(&path);
+    let mode = |path: &PathBuf| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+
+    let mut keystore = Keystore::load(&path).unwrap();
+    assert_eq!(mode(&path), 0o600);
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+    keystore.set_group_key("students", e2e::generate_group_key()).unwrap();
+    assert_eq!(mode(&path), 0o600);
+    assert!(!keystore.temp_path().exists());
+
+    // 上次保存时留下的临时文件不影响读取，下次保存时被替换
+    fs::write(keystore.temp_path(), "{").unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+    let mut keystore = Keystore::load(&path).unwrap();
+    assert_eq!(mode(&path), 0o600);
+    assert!(keystore.group_key("students").is_some());
+    keystore.trust("students", "0123456789abcdef").unwrap();
+    assert!(!keystore.temp_path().exists());
+    assert_eq!(mode(&path), 0o600);
+    fs::remove_file(&path).unwrap();
+}
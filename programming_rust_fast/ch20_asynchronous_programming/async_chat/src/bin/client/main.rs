@@ -0,0 +1,814 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use async_std::prelude::*;
+use async_chat::utils::{self, ChatResult};
+use async_std::fs;
+use async_std::io;
+use async_std::net;
+use async_std::os::unix::net::UnixStream;
+use async_std::path::{Path, PathBuf};
+use async_std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use sha2::{Digest, Sha256};
+use x25519_dalek::PublicKey;
+
+mod keystore;
+
+use keystore::Keystore;
+
+/// 客户端的密钥，由发送命令和处理回复的两个任务共享
+type SharedKeystore = Arc<std::sync::Mutex<Keystore>>;
+
+/// 从命令行读取客户端的请求发送到服务端
+async fn send_commands<S>(to_server: Arc<Mutex<S>>, keystore: SharedKeystore)
+    -> ChatResult<()>
+where S: io::Write + Unpin
+{
+    println!("Commands:\n\
+                login USER PASSWORD\n\
+                join GROUP\n\
+                post GROUP MESSAGE...\n\
+                dm USER MESSAGE...\n\
+                edit GROUP ID MESSAGE...\n\
+                delete GROUP ID\n\
+                react GROUP ID EMOJI\n\
+                search GROUP WORDS...\n\
+                upload GROUP PATH\n\
+                download ATTACHMENT_ID\n\
+                e2e-new GROUP (create a key and encrypt messages in GROUP)\n\
+                e2e GROUP (request the key of GROUP from its members)\n\
+                trust GROUP FINGERPRINT (exchange the key of GROUP with the member \
+                whose key has FINGERPRINT, compare it with them first)\n\
+                Type Control-D (on Unix) or Control-Z(on Windows) \
+                to close the connection.");
+
+    let mut command_lines = io::BufReader::new(io::stdin()).lines();
+    let mut next_upload_id = 1;
+    while let Some(command_result) = command_lines.next().await {
+        let command = command_result?;
+
+        if let Some((group, path)) = parse_upload(&command) {
+            let upload_id = next_upload_id;
+            next_upload_id += 1;
+            if let Err(error) = upload_file(&to_server, upload_id, group, path).await {
+                eprintln!("upload {} failed: {}", path, error);
+            }
+            continue;
+        }
+
+        if let Some((group, fingerprint)) = parse_trust(&command) {
+            let share = trust_member(&mut keystore.lock().unwrap(), group, fingerprint);
+            match share {
+                Ok(Some(request)) => send_request(&to_server, &request).await?,
+                Ok(None) => {}
+                Err(error) => eprintln!("trust {} failed: {}", group, error),
+            }
+            continue;
+        }
+
+        if let Some((group, new_key)) = parse_e2e(&command) {
+            let announce = enable_e2e(&mut keystore.lock().unwrap(), group, new_key);
+            match announce {
+                Ok(request) => send_request(&to_server, &request).await?,
+                Err(error) => eprintln!("e2e {} failed: {}", group, error),
+            }
+            continue;
+        }
+
+        let request = match parse_command(&command) {
+            Some(request) => seal_request(request, &keystore.lock().unwrap()),
+            None => continue
+        };
+        send_request(&to_server, &request).await?;
+    }
+    Ok(())
+}
+
+/// 发送一个请求，连接的写入端由发送命令和处理回复的两个任务共享
+async fn send_request<S>(to_server: &Mutex<S>, request: &FromClient) -> ChatResult<()>
+where S: io::Write + Unpin
+{
+    let mut to_server = to_server.lock().await;
+    utils::send_as_json(&mut *to_server, request).await?;
+    to_server.flush().await?;
+    Ok(())
+}
+
+/// search 命令最多显示的消息数量
+const SEARCH_LIMIT: usize = 20;
+
+/// 将标准输入的命令内容解析为请求
+fn parse_command(line: &str) -> Option<FromClient> {
+    let (command, rest) = get_next_token(line)?;
+
+    if command == "post" {
+        let (group, rest) = get_next_token(rest)?;
+        let message = rest.trim_start().to_string();
+        Some(FromClient::Post {
+            group_name: Arc::new(group.to_string()),
+            message: Arc::new(message),
+        })
+    } else if command == "dm" {
+        let (recipient, rest) = get_next_token(rest)?;
+        let message = rest.trim_start().to_string();
+        Some(FromClient::Direct {
+            recipient: Arc::new(recipient.to_string()),
+            message: Arc::new(message),
+        })
+    } else if command == "login" {
+        let (user_name, rest) = get_next_token(rest)?;
+        let (password, rest) = get_next_token(rest)?;
+        if !rest.trim_start().is_empty() {
+            return None;
+        }
+        Some(FromClient::Login {
+            user_name: Arc::new(user_name.to_string()),
+            password: password.to_string(),
+        })
+    } else if command == "join" {
+        let (group, rest) = get_next_token(rest)?;
+        if !rest.trim_start().is_empty() {
+            return None;
+        }
+        Some(FromClient::Join {
+            group_name: Arc::new(group.to_string()),
+        })
+    } else if command == "edit" {
+        let (group, rest) = get_next_token(rest)?;
+        let (id, rest) = get_next_id(rest)?;
+        let message = rest.trim_start().to_string();
+        Some(FromClient::Edit {
+            group_name: Arc::new(group.to_string()),
+            id,
+            message: Arc::new(message),
+        })
+    } else if command == "delete" {
+        let (group, rest) = get_next_token(rest)?;
+        let (id, rest) = get_next_id(rest)?;
+        if !rest.trim_start().is_empty() {
+            return None;
+        }
+        Some(FromClient::Delete {
+            group_name: Arc::new(group.to_string()),
+            id,
+        })
+    } else if command == "react" {
+        let (group, rest) = get_next_token(rest)?;
+        let (id, rest) = get_next_id(rest)?;
+        let (emoji, rest) = get_next_token(rest)?;
+        if !rest.trim_start().is_empty() {
+            return None;
+        }
+        Some(FromClient::React {
+            group_name: Arc::new(group.to_string()),
+            id,
+            emoji: Arc::new(emoji.to_string()),
+        })
+    } else if command == "search" {
+        let (group, rest) = get_next_token(rest)?;
+        let query = rest.trim().to_string();
+        if query.is_empty() {
+            return None;
+        }
+        Some(FromClient::Search {
+            group_name: Arc::new(group.to_string()),
+            query: Arc::new(query),
+            limit: SEARCH_LIMIT,
+        })
+    } else if command == "download" {
+        let (attachment_id, rest) = get_next_token(rest)?;
+        if !rest.trim_start().is_empty() {
+            return None;
+        }
+        Some(FromClient::Download {
+            attachment_id: attachment_id.to_string(),
+        })
+    } else {
+        eprintln!("Unrecongnized command {:?}", line);
+        None
+    }
+}
+
+/// 解析 e2e GROUP 和 e2e-new GROUP 命令，返回群组名以及是否需要生成新的群组密钥
+fn parse_e2e(line: &str) -> Option<(&str, bool)> {
+    let (command, rest) = get_next_token(line)?;
+    let new_key = match command {
+        "e2e" => false,
+        "e2e-new" => true,
+        _ => return None,
+    };
+    let (group, rest) = get_next_token(rest)?;
+    if !rest.trim_start().is_empty() {
+        return None;
+    }
+    Some((group, new_key))
+}
+
+/// 解析 trust GROUP FINGERPRINT 命令，指纹是 e2e 命令显示的 16 位十六进制数
+fn parse_trust(line: &str) -> Option<(&str, &str)> {
+    let (command, rest) = get_next_token(line)?;
+    if command != "trust" {
+        return None;
+    }
+    let (group, rest) = get_next_token(rest)?;
+    let (fingerprint, rest) = get_next_token(rest)?;
+    if !rest.trim_start().is_empty()
+        || fingerprint.len() != 16 || !fingerprint.chars().all(|c| c.is_ascii_hexdigit()) {
+        eprintln!("Usage: trust GROUP FINGERPRINT");
+        return None;
+    }
+    Some((group, fingerprint))
+}
+
+/// 为群组开启端到端加密，需要时生成新的群组密钥，
+/// 返回向群组成员公布自己公钥的请求，
+/// 持有群组密钥的成员确认自己的指纹之后会把密钥发过来
+fn enable_e2e(keystore: &mut Keystore, group: &str, new_key: bool) -> ChatResult<FromClient> {
+    if new_key {
+        keystore.set_group_key(group, e2e::generate_group_key())?;
+    }
+    println!("your key fingerprint: {}", e2e::fingerprint(&keystore.public_key()));
+
+    Ok(FromClient::AnnounceKey {
+        group_name: Arc::new(group.to_string()),
+        public_key: e2e::encode_public_key(&keystore.public_key()),
+    })
+}
+
+/// 持有群组密钥时，在发送之前加密消息内容
+fn seal_request(request: FromClient, keystore: &Keystore) -> FromClient {
+    match request {
+        FromClient::Post { group_name, message } => {
+            let message = seal_message(keystore, &group_name, message);
+            FromClient::Post { group_name, message }
+        }
+        FromClient::Edit { group_name, id, message } => {
+            let message = seal_message(keystore, &group_name, message);
+            FromClient::Edit { group_name, id, message }
+        }
+        request => request,
+    }
+}
+
+fn seal_message(keystore: &Keystore, group_name: &str, message: Arc<String>) -> Arc<String> {
+    match keystore.group_key(group_name) {
+        Some(key) => Arc::new(e2e::seal(key, group_name, &message)),
+        None => message,
+    }
+}
+
+/// 解密收到的消息，没有群组密钥或者解密失败时显示提示
+fn open_message(keystore: &Keystore, group_name: &str, message: Arc<String>) -> Arc<String> {
+    if !e2e::is_sealed(&message) {
+        return message;
+    }
+    let opened = match keystore.group_key(group_name) {
+        Some(key) => e2e::open(key, group_name, &message)
+            .unwrap_or_else(|_| "[message could not be decrypted]".to_string()),
+        None => "[encrypted message, run e2e GROUP to request the key]".to_string(),
+    };
+    Arc::new(opened)
+}
+
+/// 其他成员公布公钥时，如果持有群组密钥并且用户确认过对方的指纹，就把密钥加密后发给对方。
+/// 公钥由服务端转发，没有确认过的指纹可能是服务端伪造的，这时只记下公钥，等用户确认
+fn answer_key_request(keystore: &mut Keystore, group_name: Arc<String>, sender: &str,
+                      public_key: &str) -> ChatResult<Option<FromClient>> {
+    let peer = e2e::decode_public_key(public_key)?;
+    if peer == keystore.public_key() {
+        return Ok(None);
+    }
+    let fingerprint = e2e::fingerprint(&peer);
+    println!("[{}] {} announced key fingerprint {}", group_name, sender, fingerprint);
+
+    if keystore.group_key(&group_name).is_none() {
+        return Ok(None);
+    }
+    if !keystore.is_trusted(&group_name, &peer) {
+        println!("[{}] compare the fingerprint with {}, then run: trust {} {}",
+                 group_name, sender, group_name, fingerprint);
+        keystore.remember_announced(&group_name, peer);
+        return Ok(None);
+    }
+    Ok(share_key(keystore, group_name, &peer))
+}
+
+/// 把群组密钥加密后发给 peer 的请求，没有群组密钥时返回 None
+fn share_key(keystore: &Keystore, group_name: Arc<String>, peer: &PublicKey)
+    -> Option<FromClient> {
+    let group_key = keystore.group_key(&group_name)?;
+    Some(FromClient::ShareKey {
+        wrapped_key: e2e::wrap_key(keystore.identity(), peer, &group_name, group_key),
+        group_name,
+        sender_key: e2e::encode_public_key(&keystore.public_key()),
+        recipient_key: e2e::encode_public_key(peer),
+    })
+}
+
+/// 保存其他成员发给自己的群组密钥，已经持有群组密钥时忽略。
+/// 只接受指纹被用户确认过的成员发来的密钥，否则服务端可以让客户端使用它选择的密钥，
+/// 未确认的密钥先记下来，用户确认指纹之后再保存
+fn accept_shared_key(keystore: &mut Keystore, group_name: &str, sender_key: &str,
+                     recipient_key: &str, wrapped_key: &str) -> ChatResult<()> {
+    if recipient_key != e2e::encode_public_key(&keystore.public_key())
+        || keystore.group_key(group_name).is_some() {
+        return Ok(());
+    }
+    let peer = e2e::decode_public_key(sender_key)?;
+    if !keystore.is_trusted(group_name, &peer) {
+        let fingerprint = e2e::fingerprint(&peer);
+        println!("[{}] received group key from unconfirmed fingerprint {}, \
+                  compare it with the sender, then run: trust {} {}",
+                 group_name, fingerprint, group_name, fingerprint);
+        keystore.remember_offered(group_name, peer, wrapped_key);
+        return Ok(());
+    }
+    save_shared_key(keystore, group_name, &peer, wrapped_key)
+}
+
+fn save_shared_key(keystore: &mut Keystore, group_name: &str, peer: &PublicKey,
+                   wrapped_key: &str) -> ChatResult<()> {
+    let group_key = e2e::unwrap_key(keystore.identity(), peer, group_name, wrapped_key)?;
+    keystore.set_group_key(group_name, group_key)?;
+    println!("[{}] received group key from fingerprint {}",
+             group_name, e2e::fingerprint(peer));
+    Ok(())
+}
+
+/// 确认群组中指纹为 fingerprint 的成员：
+/// 保存对方之前发来的群组密钥，或者在持有群组密钥时发给之前公布过公钥的对方
+fn trust_member(keystore: &mut Keystore, group: &str, fingerprint: &str)
+    -> ChatResult<Option<FromClient>> {
+    keystore.trust(group, fingerprint)?;
+    println!("[{}] trusted key fingerprint {}", group, fingerprint);
+
+    if let Some((peer, wrapped_key)) = keystore.take_offered(group, fingerprint) {
+        if keystore.group_key(group).is_none() {
+            save_shared_key(keystore, group, &peer, &wrapped_key)?;
+        }
+    }
+    Ok(keystore.take_announced(group, fingerprint)
+        .and_then(|peer| share_key(keystore, Arc::new(group.to_string()), &peer)))
+}
+
+/// 解析 upload GROUP PATH 命令，路径可以包含空白字符
+fn parse_upload(line: &str) -> Option<(&str, &str)> {
+    let (command, rest) = get_next_token(line)?;
+    if command != "upload" {
+        return None;
+    }
+    let (group, rest) = get_next_token(rest)?;
+    let path = rest.trim();
+    if path.is_empty() {
+        return None;
+    }
+    Some((group, path))
+}
+
+/// 读取本地文件，计算摘要之后分块上传到服务端
+async fn upload_file<S>(to_server: &Mutex<S>, upload_id: UploadId,
+                        group: &str, path: &str) -> ChatResult<()>
+where S: io::Write + Unpin
+{
+    let content = fs::read(path).await?;
+    // 上传过程中一直持有写入端，避免分块之间插入其他请求
+    let mut to_server = to_server.lock().await;
+    let to_server = &mut *to_server;
+    let file_name = Path::new(path).file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| path.to_string());
+
+    utils::send_as_json(to_server, &FromClient::UploadStart {
+        upload_id,
+        group_name: Arc::new(group.to_string()),
+        file_name: Arc::new(file_name),
+        size: content.len() as u64,
+        sha256: hex::encode(Sha256::digest(&content)),
+    }).await?;
+
+    let mut offset = 0;
+    for chunk in content.chunks(ATTACHMENT_CHUNK_SIZE) {
+        utils::send_as_json(to_server, &FromClient::UploadChunk {
+            upload_id,
+            offset,
+            data: utils::encode_chunk(chunk),
+        }).await?;
+        offset += chunk.len() as u64;
+    }
+
+    utils::send_as_json(to_server, &FromClient::UploadFinish { upload_id }).await?;
+    to_server.flush().await?;
+    Ok(())
+}
+
+/// 将输入 input 拆分成两部分，
+/// token 是 input 中第一个不为空白字符的字符，
+/// rest 是剩余部分的内容，
+/// 如果字符串没有非空白字符则返回 None
+fn get_next_token(mut input: &str) -> Option<(&str, &str)> {
+    input = input.trim_start();
+
+    if input.is_empty() {
+        return None
+    }
+
+    match input.find(char::is_whitespace) {
+        Some(space) => Some((&input[0..space], &input[space..])),
+        None => Some((input, "")),
+    }
+}
+
+/// 读取下一个 token 并解析为消息 id
+fn get_next_id(input: &str) -> Option<(MessageId, &str)> {
+    let (id, rest) = get_next_token(input)?;
+    match id.parse() {
+        Ok(id) => Some((id, rest)),
+        Err(_) => {
+            eprintln!("Invalid message id {:?}", id);
+            None
+        }
+    }
+}
+
+/// 正在下载的附件
+struct Download {
+    path: PathBuf,
+    file: fs::File,
+    hasher: Sha256,
+}
+
+/// 将下载的附件分块写入下载目录下的同名文件，
+/// 收到最后一个分块时校验摘要是否与附件 id 一致
+async fn save_chunk(downloads: &mut HashMap<AttachmentId, Download>,
+                    file_names: &HashMap<AttachmentId, Arc<String>>, download_dir: &Path,
+                    attachment_id: AttachmentId, offset: u64,
+                    data: &str, last: bool) -> ChatResult<()> {
+    if offset == 0 {
+        // 只使用文件名部分，避免服务端传来的名字写到下载目录之外
+        let file_name = file_names.get(&attachment_id)
+            .and_then(|name| Path::new(name.as_str()).file_name().map(PathBuf::from))
+            .unwrap_or_else(|| PathBuf::from(&attachment_id));
+        let (path, file) = create_download(download_dir, &file_name).await?;
+        downloads.insert(attachment_id.clone(), Download {
+            path,
+            file,
+            hasher: Sha256::new(),
+        });
+    }
+
+    let download = match downloads.get_mut(&attachment_id) {
+        Some(download) => download,
+        None => return Err(format!("unexpected chunk of attachment {}", attachment_id).into()),
+    };
+    let bytes = utils::decode_chunk(data)?;
+    download.file.write_all(&bytes).await?;
+    download.hasher.update(&bytes);
+
+    if last {
+        let mut download = downloads.remove(&attachment_id).unwrap();
+        download.file.flush().await?;
+        if hex::encode(download.hasher.finalize()) != attachment_id {
+            let _ignored = fs::remove_file(&download.path).await;
+            return Err(format!("checksum mismatch for {}", download.path.display()).into());
+        }
+        println!("attachment {} saved to {}", attachment_id, download.path.display());
+    }
+    Ok(())
+}
+
+/// 同名文件最多尝试添加的序号
+const MAX_DOWNLOAD_SUFFIX: usize = 1000;
+
+/// 在下载目录中新建文件，已经有同名文件时在文件名后加上序号，从不覆盖已有的文件
+async fn create_download(dir: &Path, file_name: &Path) -> io::Result<(PathBuf, fs::File)> {
+    fs::create_dir_all(dir).await?;
+    let stem = file_name.file_stem().unwrap_or(file_name.as_os_str()).to_string_lossy();
+    let extension = file_name.extension()
+        .map(|extension| format!(".{}", extension.to_string_lossy()))
+        .unwrap_or_default();
+    for suffix in 0..MAX_DOWNLOAD_SUFFIX {
+        let path = match suffix {
+            0 => dir.join(file_name),
+            _ => dir.join(format!("{} ({}){}", stem, suffix, extension)),
+        };
+        let created = fs::OpenOptions::new().write(true).create_new(true).open(&path).await;
+        match created {
+            Ok(file) => return Ok((path, file)),
+            Err(error) if error.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(error) => return Err(error),
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                       format!("too many files named {} in {}",
+                               file_name.display(), dir.display())))
+}
+
+/// 不在线时收到、登录后补发的消息加上标记
+fn offline_marker(offline: bool) -> &'static str {
+    if offline { "(while you were away) " } else { "" }
+}
+
+/// @ 提到自己的消息使用终端的粗体黄色高亮显示
+fn highlight_mentions(message: &str, me: Option<&str>) -> String {
+    match me {
+        Some(me) if utils::mentions(message).any(|name| name == me) => {
+            format!("\x1b[1;33m{}\x1b[0m", message)
+        }
+        _ => message.to_string(),
+    }
+}
+
+/// 将消息的发布时间显示为距离现在多久
+fn format_age(posted_at: u64) -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let age = now.saturating_sub(posted_at);
+    match age {
+        0..=59 => "just now".to_string(),
+        60..=3599 => format!("{} min ago", age / 60),
+        3600..=86399 => format!("{} h ago", age / 3600),
+        _ => format!("{} days ago", age / 86400),
+    }
+}
+
+/// 处理从 server 返回的数据
+async fn handle_replies<R, W>(from_server: R, to_server: Arc<Mutex<W>>,
+                             keystore: SharedKeystore, download_dir: &Path) -> ChatResult<()>
+where R: io::Read + Unpin,
+      W: io::Write + Unpin
+{
+    let buffered = io::BufReader::new(from_server);
+    let mut reply_stream =
+        utils::receive_as_json(buffered);
+    // 已经显示过的消息，用于展示修改、删除和回应针对的是哪条消息
+    let mut displayed: HashMap<(Arc<String>, MessageId), Arc<String>> = HashMap::new();
+    // 已经显示过的附件的文件名，下载时用作保存的文件名
+    let mut file_names: HashMap<AttachmentId, Arc<String>> = HashMap::new();
+    let mut downloads: HashMap<AttachmentId, Download> = HashMap::new();
+    // 登录之后的用户名，用于高亮提到自己的消息
+    let mut me: Option<Arc<String>> = None;
+
+    while let Some(reply) = reply_stream.next().await {
+        let reply = match reply {
+            Ok(reply) => reply,
+            Err(error) => match utils::as_unknown_packet(&error) {
+                Some(unknown) => {
+                    eprintln!("ignored reply from a newer server: {}", unknown.line);
+                    continue;
+                }
+                None => return Err(error),
+            }
+        };
+
+        match reply {
+            FromServer::Hello { protocol_version, features } => {
+                if protocol_version != PROTOCOL_VERSION {
+                    println!("server speaks protocol version {}, features: {}",
+                             protocol_version, features.join(", "));
+                }
+            }
+            FromServer::LoggedIn { user_name } => {
+                println!("logged in as {}", user_name);
+                me = Some(user_name);
+            }
+            FromServer::Message { group_name, id, sender, message, offline } => {
+                let message = open_message(&keystore.lock().unwrap(), &group_name, message);
+                println!("{}[{}#{}] {}: {}", offline_marker(offline), group_name, id, sender,
+                         highlight_mentions(&message, me.as_ref().map(|me| me.as_str())));
+                displayed.insert((group_name, id), message);
+            }
+            FromServer::Direct { sender, message, offline } => {
+                println!("{}[dm] {}: {}", offline_marker(offline), sender, message);
+            }
+            FromServer::Edited { group_name, id, message } => {
+                let message = open_message(&keystore.lock().unwrap(), &group_name, message);
+                let original = displayed.insert((group_name.clone(), id), message.clone());
+                match original {
+                    Some(original) => println!("[{}#{}] edited: {} => {}",
+                                               group_name, id, original, message),
+                    None => println!("[{}#{}] edited: {}", group_name, id, message),
+                }
+            }
+            FromServer::Deleted { group_name, id } => {
+                match displayed.remove(&(group_name.clone(), id)) {
+                    Some(original) => println!("[{}#{}] deleted: {}", group_name, id, original),
+                    None => println!("[{}#{}] deleted", group_name, id),
+                }
+            }
+            FromServer::Reacted { group_name, id, sender, emoji } => {
+                match displayed.get(&(group_name.clone(), id)) {
+                    Some(original) => println!("[{}#{}] {} reacted {} to: {}",
+                                               group_name, id, sender, emoji, original),
+                    None => println!("[{}#{}] {} reacted {}", group_name, id, sender, emoji),
+                }
+            }
+            FromServer::SearchResults { group_name, query, results } => {
+                println!("[{}] {} messages match {:?}", group_name, results.len(), query);
+                for result in results {
+                    println!("  [{}#{}] {} ({}): {}", group_name, result.id, result.sender,
+                             format_age(result.posted_at), result.message);
+                }
+            }
+            FromServer::Attachment { group_name, sender, attachment_id, file_name, size } => {
+                println!("[{}] {} shared {} ({} bytes), download {}",
+                         group_name, sender, file_name, size, attachment_id);
+                file_names.insert(attachment_id, file_name);
+            }
+            FromServer::AttachmentChunk { attachment_id, offset, data, last } => {
+                let result = save_chunk(&mut downloads, &file_names, download_dir,
+                                        attachment_id.clone(), offset, &data, last).await;
+                if let Err(error) = result {
+                    downloads.remove(&attachment_id);
+                    eprintln!("download {} failed: {}", attachment_id, error);
+                }
+            }
+            FromServer::KeyAnnounced { group_name, sender, public_key } => {
+                let share = answer_key_request(&mut keystore.lock().unwrap(),
+                                               group_name, &sender, &public_key);
+                match share {
+                    Ok(Some(request)) => send_request(&to_server, &request).await?,
+                    Ok(None) => {}
+                    Err(error) => eprintln!("invalid key from {}: {}", sender, error),
+                }
+            }
+            FromServer::KeyShared { group_name, sender_key, recipient_key, wrapped_key } => {
+                let result = accept_shared_key(&mut keystore.lock().unwrap(), &group_name,
+                                               &sender_key, &recipient_key, &wrapped_key);
+                if let Err(error) = result {
+                    eprintln!("failed to accept key for {}: {}", group_name, error);
+                }
+            }
+            FromServer::Announcement { message } => {
+                println!("[announcement] {}", message);
+            }
+            FromServer::Error(message) => {
+                println!("error from server: {}", message);
+            }
+        }
+    }
+
+    Ok(())
+
+}
+
+use async_std::task;
+use async_chat::e2e;
+use async_chat::{AttachmentId, FromClient, FromServer, MessageId, UploadId,
+                 ATTACHMENT_CHUNK_SIZE, FEATURES, PROTOCOL_VERSION};
+
+/// 客户端的命令行参数
+struct Options {
+    address: Option<String>,
+    unix_path: Option<String>,
+    keystore: String,
+    downloads: String,
+}
+
+/// 解析命令行参数：client ADDRESS | client --unix PATH，可以用 --keystore PATH 指定密钥文件，
+/// 用 --downloads DIR 指定保存下载附件的目录
+fn parse_options() -> Options {
+    const USAGE: &str = "用法： client Address:port | client --unix PATH \
+                         [--keystore PATH] [--downloads DIR]";
+    let mut address = None;
+    let mut unix_path = None;
+    let mut keystore = "chat_keys.json".to_string();
+    let mut downloads = "downloads".to_string();
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--unix" => unix_path = Some(args.next().expect(USAGE)),
+            "--keystore" => keystore = args.next().expect(USAGE),
+            "--downloads" => downloads = args.next().expect(USAGE),
+            _ if address.is_none() => address = Some(arg),
+            _ => panic!("{}", USAGE),
+        }
+    }
+    if address.is_none() == unix_path.is_none() {
+        panic!("{}", USAGE);
+    }
+
+    Options { address, unix_path, keystore, downloads }
+}
+
+fn main() -> ChatResult<()> {
+    let Options { address, unix_path, keystore, downloads } = parse_options();
+    let downloads = PathBuf::from(downloads);
+    let keystore = Arc::new(std::sync::Mutex::new(Keystore::load(keystore)?));
+
+    task::block_on(async {
+        if let Some(path) = unix_path {
+            let socket = UnixStream::connect(path).await?;
+            return run(socket, keystore, &downloads).await;
+        }
+
+        let socket = net::TcpStream::connect(address.unwrap()).await?;
+        socket.set_nodelay(true)?;
+        run(socket, keystore, &downloads).await
+    })
+}
+
+/// 连接建立后发送的第一个请求
+fn hello() -> FromClient {
+    FromClient::Hello {
+        protocol_version: PROTOCOL_VERSION,
+        features: FEATURES.iter().map(|feature| feature.to_string()).collect(),
+    }
+}
+
+/// 同时从命令行发送请求和处理服务端返回的数据，任意一方结束时断开连接
+async fn run<S>(socket: S, keystore: SharedKeystore, download_dir: &Path) -> ChatResult<()>
+where S: io::Read + io::Write + Clone + Unpin
+{
+    let writer = Arc::new(Mutex::new(socket.clone()));
+    send_request(&writer, &hello()).await?;
+    let to_server = send_commands(writer.clone(), keystore.clone());
+    let from_server = handle_replies(socket, writer, keystore, download_dir);
+
+    from_server.race(to_server).await?;
+
+    Ok(())
+}
+
+#[test]
+fn test_group_key_is_only_exchanged_with_trusted_members() {
+    let path = |name: &str| {
+        std::env::temp_dir().join(format!("async_chat_client_{}_{}.json", name, std::process::id()))
+    };
+    let keystore = |name: &str| {
+        let _ignored = std::fs::remove_file(path(name));
+        Keystore::load(path(name)).unwrap()
+    };
+    let mut alice = keystore("alice");
+    let mut bob = keystore("bob");
+    let mut server = keystore("server");
+    let students = Arc::new("students".to_string());
+    alice.set_group_key(&students, e2e::generate_group_key()).unwrap();
+    let group_key = *alice.group_key(&students).unwrap();
+    let alice_fingerprint = e2e::fingerprint(&alice.public_key());
+    let bob_fingerprint = e2e::fingerprint(&bob.public_key());
+    let bob_key = e2e::encode_public_key(&bob.public_key());
+
+    // 公布公钥不会让 alice 自动发出群组密钥，无论公钥是 bob 的还是服务端伪造的
+    let forged = e2e::encode_public_key(&server.public_key());
+    assert!(answer_key_request(&mut alice, students.clone(), "bob", &forged).unwrap().is_none());
+    assert!(answer_key_request(&mut alice, students.clone(), "bob", &bob_key).unwrap().is_none());
+
+    // 服务端选择的密钥不会被接受
+    server.set_group_key(&students, e2e::generate_group_key()).unwrap();
+    let forged_share = share_key(&server, students.clone(), &bob.public_key()).unwrap();
+    let FromClient::ShareKey { sender_key, recipient_key, wrapped_key, .. } = forged_share else {
+        unreachable!()
+    };
+    accept_shared_key(&mut bob, &students, &sender_key, &recipient_key, &wrapped_key).unwrap();
+    assert!(bob.group_key(&students).is_none());
+
+    // alice 确认 bob 的指纹之后才把密钥发给之前公布的公钥
+    let share = trust_member(&mut alice, &students, &bob_fingerprint).unwrap().unwrap();
+    let FromClient::ShareKey { sender_key, recipient_key, wrapped_key, .. } = share else {
+        unreachable!()
+    };
+    assert_eq!(recipient_key, bob_key);
+    accept_shared_key(&mut bob, &students, &sender_key, &recipient_key, &wrapped_key).unwrap();
+    assert!(bob.group_key(&students).is_none());
+
+    // bob 确认 alice 的指纹之后保存她发来的密钥
+    assert!(trust_member(&mut bob, &students, &alice_fingerprint).unwrap().is_none());
+    assert_eq!(bob.group_key(&students), Some(&group_key));
+
+    assert_eq!(parse_trust(&format!("trust students {}", bob_fingerprint)),
+               Some(("students", bob_fingerprint.as_str())));
+    assert!(parse_trust("trust students not-a-fingerprint").is_none());
+    assert!(parse_trust("trust students").is_none());
+    for name in ["alice", "bob", "server"] {
+        std::fs::remove_file(path(name)).unwrap();
+    }
+}
+
+#[test]
+fn test_downloads_never_overwrite_files() {
+    task::block_on(async {
+        let dir = std::env::temp_dir()
+            .join(format!("async_chat_downloads_{}", std::process::id()));
+        let dir = Path::new(&dir);
+        let _ignored = std::fs::remove_dir_all(dir);
+        std::fs::create_dir_all(dir).unwrap();
+        std::fs::write(dir.join(".bashrc"), "keep me").unwrap();
+
+        let content = b"good good study";
+        let attachment_id = hex::encode(Sha256::digest(content));
+        let mut file_names = HashMap::new();
+        file_names.insert(attachment_id.clone(), Arc::new("../../.bashrc".to_string()));
+        let mut downloads = HashMap::new();
+        save_chunk(&mut downloads, &file_names, dir, attachment_id.clone(), 0,
+                   &utils::encode_chunk(content), true).await.unwrap();
+
+        assert_eq!(std::fs::read_to_string(dir.join(".bashrc")).unwrap(), "keep me");
+        assert_eq!(std::fs::read(dir.join(".bashrc (1)")).unwrap(), content);
+
+        let (path, _file) = create_download(dir, Path::new("notes.txt")).await.unwrap();
+        assert_eq!(path, dir.join("notes.txt"));
+        let (path, _file) = create_download(dir, Path::new("notes.txt")).await.unwrap();
+        assert_eq!(path, dir.join("notes (1).txt"));
+        std::fs::remove_dir_all(dir).unwrap();
+    });
+}
@@ -0,0 +1,77 @@
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use chacha20poly1305::aead::OsRng;
+
+use crate::storage::{storage_error, Account, Storage};
+
+/// 未登录的连接使用的用户名前缀，账号不能使用这个前缀
+pub const GUEST_PREFIX: &str = "guest-";
+
+pub fn is_guest(user_name: &str) -> bool {
+    user_name.starts_with(GUEST_PREFIX)
+}
+
+/// 使用用户名和密码登录，账号不存在时使用这个密码注册新账号
+pub fn login(storage: &dyn Storage, user_name: &str, password: &str) -> Result<(), String> {
+    if user_name.is_empty() || is_guest(user_name) || user_name.contains(char::is_whitespace) {
+        return Err(format!("Invalid user name {:?}", user_name));
+    }
+
+    if let Some(account) = storage.account(user_name).map_err(storage_error)? {
+        return verify(&account, password);
+    }
+
+    let salt = SaltString::generate(&mut OsRng);
+    let password_hash = Argon2::default().hash_password(password.as_bytes(), &salt)
+        .map_err(|error| error.to_string())?
+        .to_string();
+    let created = storage.create_account(&Account {
+        user_name: user_name.to_string(),
+        password_hash,
+    });
+    if let Err(error) = created {
+        // 另一个连接同时用这个名字注册了账号，改为验证对方设置的密码
+        return match storage.account(user_name).map_err(storage_error)? {
+            Some(account) => verify(&account, password),
+            None => Err(storage_error(error)),
+        };
+    }
+    Ok(())
+}
+
+fn verify(account: &Account, password: &str) -> Result<(), String> {
+    let hash = PasswordHash::new(&account.password_hash)
+        .map_err(|error| error.to_string())?;
+    Argon2::default().verify_password(password.as_bytes(), &hash)
+        .map_err(|_| format!("Wrong password for {}", account.user_name))
+}
+
+#[test]
+fn test_concurrent_first_logins() {
+    use crate::storage::MemoryStorage;
+    use std::sync::Arc;
+
+    let storage = Arc::new(MemoryStorage::new());
+    let handles: Vec<_> = ["first", "second", "third"].into_iter()
+        .map(|password| {
+            let storage = storage.clone();
+            std::thread::spawn(move || (password, login(&*storage, "alice", password)))
+        })
+        .collect();
+    let results: Vec<_> = handles.into_iter().map(|handle| handle.join().unwrap()).collect();
+
+    // 只有一个密码注册成功，其他的登录按照错误的密码处理
+    let accepted: Vec<_> = results.iter()
+        .filter(|(_, result)| result.is_ok())
+        .map(|(password, _)| *password)
+        .collect();
+    assert_eq!(accepted.len(), 1, "{:?}", results);
+    assert!(login(&*storage, "alice", accepted[0]).is_ok());
+    assert!(storage.create_account(&Account {
+        user_name: "alice".to_string(),
+        password_hash: String::new(),
+    }).is_err());
+
+    assert!(login(&*storage, "guest-1", "password").is_err());
+    assert!(login(&*storage, "al ice", "password").is_err());
+}
@@ -0,0 +1,205 @@
+//! 本地管理接口：服务端在 Unix 域套接字上接受管理员的文本命令，
+//! 套接字文件只有服务端的运行用户可以访问。
+//! 每行一条命令，每条命令的回复以 "ok" 或者 "error: ..." 一行结束，
+//! 可以使用 `socat - UNIX-CONNECT:PATH` 或者 `nc -U PATH` 连接。
+
+use async_chat::utils::ChatResult;
+use async_chat::FromServer;
+use async_std::io::{self, BufReader};
+use async_std::os::unix::net::{UnixListener, UnixStream};
+use async_std::prelude::*;
+use std::sync::Arc;
+
+use crate::connection_table::ConnectionTable;
+use crate::group_table::GroupTable;
+use crate::log::{self, log, Level};
+
+const HELP: &str = "\
+connections                 list connections with peer addresses
+disconnect ID               close a connection
+delete-group GROUP          delete a group and its history
+announce MESSAGE...         send an announcement to every connection
+log-level [LEVEL]           show or change the log level (error, warn, info, debug)";
+
+pub async fn serve_admin(socket: UnixStream, groups: Arc<GroupTable>,
+                         connections: Arc<ConnectionTable>) -> ChatResult<()> {
+    let mut to_admin = socket.clone();
+    let mut lines = BufReader::new(socket).lines();
+    while let Some(line) = lines.next().await {
+        let line = line?;
+        let mut output = String::new();
+        let result = run_command(line.trim(), &groups, &connections, &mut output).await;
+        match result {
+            Ok(()) => output.push_str("ok\n"),
+            Err(error) => output.push_str(&format!("error: {}\n", error)),
+        }
+        to_admin.write_all(output.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+/// 执行一条管理命令，需要显示的内容写到 output 中
+async fn run_command(line: &str, groups: &GroupTable, connections: &ConnectionTable,
+                     output: &mut String) -> Result<(), String> {
+    let (command, argument) = match line.split_once(char::is_whitespace) {
+        Some((command, argument)) => (command, argument.trim()),
+        None => (line, ""),
+    };
+
+    match command {
+        "" => Ok(()),
+        "help" => {
+            output.push_str(HELP);
+            output.push('\n');
+            Ok(())
+        }
+        "connections" => {
+            for connection in connections.list() {
+                output.push_str(&format!("{}\t{}\t{}\n", connection.id,
+                                         connection.peer_address, connection.user_name));
+            }
+            Ok(())
+        }
+        "disconnect" => {
+            let id = argument.parse()
+                .map_err(|_| format!("Invalid connection id {:?}", argument))?;
+            connections.disconnect(id)?;
+            log!(Level::Info, "admin disconnected connection {}", id);
+            Ok(())
+        }
+        "delete-group" if !argument.is_empty() => {
+            groups.delete(&argument.to_string())?;
+            log!(Level::Info, "admin deleted group {}", argument);
+            Ok(())
+        }
+        "announce" if !argument.is_empty() => {
+            let sent = connections.broadcast(FromServer::Announcement {
+                message: Arc::new(argument.to_string()),
+            }).await;
+            output.push_str(&format!("sent to {} connections\n", sent));
+            Ok(())
+        }
+        "log-level" if argument.is_empty() => {
+            output.push_str(&format!("{}\n", log::level()));
+            Ok(())
+        }
+        "log-level" => {
+            log::set_level(argument.parse()?);
+            Ok(())
+        }
+        _ => Err(format!("Unrecognized command {:?}, try help", line)),
+    }
+}
+
+/// 绑定管理接口的套接字，并且只允许服务端的运行用户连接。
+/// 套接字先在只有运行用户可以访问的临时目录中创建并修改权限，再移动到 path，
+/// 其他用户在修改权限之前没有机会连接
+pub async fn bind(path: &str) -> io::Result<UnixListener> {
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+    crate::remove_stale_socket(path)?;
+    let private_dir = format!("{}.{}.tmp", path, std::process::id());
+    std::fs::DirBuilder::new().mode(0o700).create(&private_dir)?;
+    let private_path = format!("{}/admin.sock", private_dir);
+    let bound = async {
+        let listener = UnixListener::bind(&private_path).await?;
+        async_std::fs::set_permissions(&private_path,
+                                       std::fs::Permissions::from_mode(0o600)).await?;
+        async_std::fs::rename(&private_path, path).await?;
+        Ok(listener)
+    }.await;
+    let _ignored = std::fs::remove_file(&private_path);
+    std::fs::remove_dir(&private_dir)?;
+    bound
+}
+
+#[test]
+fn test_admin_commands() {
+    use crate::storage::MemoryStorage;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    async_std::task::block_on(async {
+        let groups = GroupTable::load(Arc::new(MemoryStorage::new())).unwrap();
+        groups.get_or_create(Arc::new("students".to_string())).unwrap();
+        let connections = Arc::new(ConnectionTable::new());
+        let (client, server) = UnixStream::pair().unwrap();
+        let closed = Arc::new(AtomicBool::new(false));
+        let shutdown = {
+            let closed = closed.clone();
+            Box::new(move || {
+                closed.store(true, Ordering::SeqCst);
+                Ok(())
+            })
+        };
+        let _registered = connections.register(
+            7, "unix".to_string(), Arc::new("alice".to_string()),
+            Arc::new(crate::connection::Outbound::new(server)), shutdown);
+
+        let run = |line: &'static str| {
+            let (groups, connections) = (&groups, &connections);
+            async move {
+                let mut output = String::new();
+                let result = run_command(line, groups, connections, &mut output).await;
+                (result, output)
+            }
+        };
+
+        assert_eq!(run("").await, (Ok(()), String::new()));
+        assert_eq!(run("help").await, (Ok(()), format!("{}\n", HELP)));
+        assert_eq!(run("connections").await, (Ok(()), "7\tunix\talice\n".to_string()));
+
+        assert!(run("disconnect x").await.0.is_err());
+        assert!(run("disconnect 8").await.0.is_err());
+        assert!(!closed.load(Ordering::SeqCst));
+        assert_eq!(run("disconnect 7").await.0, Ok(()));
+        assert!(closed.load(Ordering::SeqCst));
+
+        assert_eq!(run("announce  server restarts soon").await,
+                   (Ok(()), "sent to 1 connections\n".to_string()));
+        let mut from_server = async_chat::utils::receive_as_json(BufReader::new(client));
+        let announcement: FromServer = from_server.next().await.unwrap().unwrap();
+        assert_eq!(announcement, FromServer::Announcement {
+            message: Arc::new("server restarts soon".to_string()),
+        });
+
+        assert!(run("announce").await.0.is_err());
+        assert!(run("delete-group").await.0.is_err());
+        assert_eq!(run("delete-group students").await.0, Ok(()));
+        assert!(groups.get(&"students".to_string()).is_none());
+        assert!(run("delete-group students").await.0.is_err());
+
+        assert!(run("log-level verbose").await.0.is_err());
+        assert_eq!(run("log-level").await, (Ok(()), format!("{}\n", log::level())));
+
+        let (result, _) = run("frobnicate now").await;
+        assert_eq!(result, Err("Unrecognized command \"frobnicate now\", try help".to_string()));
+    });
+}
+
+#[test]
+fn test_admin_socket_is_private() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = std::env::temp_dir().join(format!("async_chat_admin_{}", std::process::id()));
+    let _ignored = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("admin.sock").to_str().unwrap().to_string();
+
+    async_std::task::block_on(async {
+        let listener = bind(&path).await.unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        UnixStream::connect(&path).await.unwrap();
+        drop(listener);
+
+        // 重新启动时替换上次留下的套接字，但不会删除其他文件
+        drop(bind(&path).await.unwrap());
+        let other = dir.join("chat.db").to_str().unwrap().to_string();
+        std::fs::write(&other, "data").unwrap();
+        assert!(bind(&other).await.is_err());
+        assert_eq!(std::fs::read_to_string(&other).unwrap(), "data");
+    });
+    // 临时目录已经删除
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
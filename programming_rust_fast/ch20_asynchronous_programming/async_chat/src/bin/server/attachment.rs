@@ -0,0 +1,427 @@
+use async_chat::{AttachmentId, FromServer, ATTACHMENT_CHUNK_SIZE};
+use async_chat::utils::{self, ChatResult};
+use async_std::fs;
+use async_std::io;
+use async_std::path::{Path, PathBuf};
+use async_std::prelude::*;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use crate::connection::Outbound;
+
+/// 单个附件的大小上限
+const MAX_FILE_SIZE: u64 = 8 * 1024 * 1024;
+/// 每个用户可以上传的附件总大小上限
+const USER_QUOTA: u64 = 64 * 1024 * 1024;
+
+/// 保存在服务端数据目录下的附件，
+/// 附件以内容的 SHA-256 摘要命名，未完成的上传先写到 tmp 子目录。
+/// owners 子目录中按用户记录上传过的附件，重启时用来恢复每个用户的用量
+pub struct AttachmentStore {
+    dir: PathBuf,
+    /// 每个用户可以上传的附件总大小
+    quota: u64,
+    usage: UsageTable,
+}
+
+type UsageTable = Arc<Mutex<HashMap<Arc<String>, Usage>>>;
+
+/// 用户已经上传的附件，同样内容的附件只计算一次用量
+#[derive(Default)]
+struct Usage {
+    bytes: u64,
+    /// 正在进行的上传预留的大小
+    reserved: u64,
+    digests: HashSet<String>,
+}
+
+/// 一次上传开始时预留的用量，上传完成或者被放弃时释放
+struct Reservation {
+    usage: UsageTable,
+    user_name: Arc<String>,
+    bytes: u64,
+}
+
+/// 一次正在进行中的上传
+pub struct Upload {
+    pub group_name: Arc<String>,
+    file_name: Arc<String>,
+    size: u64,
+    sha256: String,
+    received: u64,
+    hasher: Sha256,
+    file: fs::File,
+    temp_path: PathBuf,
+    reservation: Reservation,
+}
+
+/// 上传完成的附件
+pub struct Attachment {
+    pub id: AttachmentId,
+    pub file_name: Arc<String>,
+    pub size: u64,
+}
+
+impl AttachmentStore {
+    pub async fn open(dir: impl AsRef<Path>) -> ChatResult<AttachmentStore> {
+        let dir = dir.as_ref().join("attachments");
+        fs::create_dir_all(dir.join("tmp")).await?;
+        fs::create_dir_all(dir.join("owners")).await?;
+        let usage = load_usage(&dir).await?;
+        Ok(AttachmentStore {
+            dir,
+            quota: USER_QUOTA,
+            usage: Arc::new(Mutex::new(usage)),
+        })
+    }
+
+    /// 检查大小限额，为上传预留用量并创建临时文件
+    pub async fn start(&self, user_name: &Arc<String>, temp_name: String,
+                       group_name: Arc<String>, file_name: Arc<String>,
+                       size: u64, sha256: String) -> Result<Upload, String> {
+        if size > MAX_FILE_SIZE {
+            return Err(format!("Attachment {} is larger than {} bytes",
+                               file_name, MAX_FILE_SIZE));
+        }
+        if !utils::is_attachment_id(&sha256) {
+            return Err(format!("Invalid checksum for attachment {}", file_name));
+        }
+        let reservation = self.reserve(user_name, &sha256, size)?;
+
+        let temp_path = self.dir.join("tmp").join(temp_name);
+        let file = fs::File::create(&temp_path).await
+            .map_err(|error| error.to_string())?;
+        Ok(Upload {
+            group_name,
+            file_name,
+            size,
+            sha256,
+            received: 0,
+            hasher: Sha256::new(),
+            file,
+            temp_path,
+            reservation,
+        })
+    }
+
+    /// 写入一个分块，分块必须按顺序发送
+    pub async fn write_chunk(&self, upload: &mut Upload, offset: u64, data: &str)
+        -> Result<(), String> {
+        if offset != upload.received {
+            return Err(format!("Expected chunk at offset {} of {}, got {}",
+                               upload.received, upload.file_name, offset));
+        }
+        let bytes = utils::decode_chunk(data).map_err(|error| error.to_string())?;
+        if upload.received + bytes.len() as u64 > upload.size {
+            return Err(format!("Attachment {} is larger than announced", upload.file_name));
+        }
+
+        upload.file.write_all(&bytes).await.map_err(|error| error.to_string())?;
+        upload.hasher.update(&bytes);
+        upload.received += bytes.len() as u64;
+        Ok(())
+    }
+
+    /// 校验文件大小和摘要，把预留的用量计入用户的用量并把临时文件移动到附件目录。
+    /// 用户之前上传过同样内容的附件时不再计入用量
+    pub async fn finish(&self, mut upload: Upload) -> Result<Attachment, String> {
+        if upload.received != upload.size {
+            return Err(format!("Attachment {} is incomplete: received {} of {} bytes",
+                               upload.file_name, upload.received, upload.size));
+        }
+        let digest = hex::encode(upload.hasher.finalize_reset());
+        if digest != upload.sha256 {
+            return Err(format!("Checksum mismatch for attachment {}", upload.file_name));
+        }
+        let user_name = upload.reservation.user_name.clone();
+        let charged = self.charge(&mut upload.reservation, &digest);
+
+        let saved = match upload.file.flush().await {
+            Ok(()) => fs::rename(&upload.temp_path, self.dir.join(&digest)).await,
+            Err(error) => Err(error),
+        };
+        let saved = match saved {
+            Ok(()) if charged => self.record_owner(&user_name, &digest).await,
+            saved => saved,
+        };
+        if let Err(error) = saved {
+            if charged {
+                self.refund(&user_name, &digest, upload.size);
+            }
+            return Err(error.to_string());
+        }
+
+        Ok(Attachment {
+            id: digest,
+            file_name: upload.file_name.clone(),
+            size: upload.size,
+        })
+    }
+
+    /// 将附件分块发送给客户端
+    pub async fn send(&self, attachment_id: AttachmentId, outbound: Arc<Outbound>)
+        -> ChatResult<()> {
+        if !utils::is_attachment_id(&attachment_id) {
+            let report = FromServer::Error(format!("Invalid attachment id {}", attachment_id));
+            return outbound.send(report).await;
+        }
+        let mut file = match fs::File::open(self.dir.join(&attachment_id)).await {
+            Ok(file) => file,
+            Err(_) => {
+                let report = FromServer::Error(
+                    format!("Attachment {} does not exist", attachment_id));
+                return outbound.send(report).await;
+            }
+        };
+
+        let mut buffer = vec![0; ATTACHMENT_CHUNK_SIZE];
+        let mut offset = 0;
+        loop {
+            let len = read_full(&mut file, &mut buffer).await?;
+            let last = len < buffer.len();
+            outbound.send(FromServer::AttachmentChunk {
+                attachment_id: attachment_id.clone(),
+                offset,
+                data: utils::encode_chunk(&buffer[..len]),
+                last,
+            }).await?;
+            if last {
+                return Ok(());
+            }
+            offset += len as u64;
+        }
+    }
+
+    /// 开始上传时检查限额并预留用量，已经上传和正在上传的附件都计算在内，
+    /// 同时进行的上传不会一起超出限额。用户已经上传过同样的内容时不需要预留
+    fn reserve(&self, user_name: &Arc<String>, sha256: &str, size: u64)
+        -> Result<Reservation, String> {
+        let mut usage = self.usage.lock().unwrap();
+        let usage = usage.entry(user_name.clone()).or_default();
+        let bytes = if usage.digests.contains(sha256) { 0 } else { size };
+        if usage.bytes + usage.reserved + bytes > self.quota {
+            return Err(format!("Attachment quota of {} bytes exceeded", self.quota));
+        }
+        usage.reserved += bytes;
+        Ok(Reservation {
+            usage: self.usage.clone(),
+            user_name: user_name.clone(),
+            bytes,
+        })
+    }
+
+    /// 把预留的用量转为已经使用的用量。
+    /// 返回是否计入了用量，用户已经上传过同样的内容时不再计入
+    fn charge(&self, reservation: &mut Reservation, digest: &str) -> bool {
+        let mut usage = self.usage.lock().unwrap();
+        let usage = usage.entry(reservation.user_name.clone()).or_default();
+        let bytes = std::mem::take(&mut reservation.bytes);
+        usage.reserved -= bytes;
+        if bytes == 0 || !usage.digests.insert(digest.to_string()) {
+            return false;
+        }
+        usage.bytes += bytes;
+        true
+    }
+
+    /// 在 owners 子目录中记录用户上传过这个附件
+    async fn record_owner(&self, user_name: &str, digest: &str) -> io::Result<()> {
+        let dir = self.dir.join("owners").join(hex::encode(user_name));
+        fs::create_dir_all(&dir).await?;
+        fs::File::create(dir.join(digest)).await?;
+        Ok(())
+    }
+
+    fn refund(&self, user_name: &Arc<String>, digest: &str, size: u64) {
+        if let Some(usage) = self.usage.lock().unwrap().get_mut(user_name) {
+            usage.bytes -= size;
+            usage.digests.remove(digest);
+        }
+    }
+}
+
+/// 上传没有完成时删除临时文件
+impl Drop for Upload {
+    fn drop(&mut self) {
+        let _ignored = std::fs::remove_file(&self.temp_path);
+    }
+}
+
+/// 上传没有完成时释放预留的用量
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        if self.bytes > 0 {
+            if let Some(usage) = self.usage.lock().unwrap().get_mut(&self.user_name) {
+                usage.reserved -= self.bytes;
+            }
+        }
+    }
+}
+
+/// 根据 owners 子目录恢复每个用户已经使用的用量，
+/// 只计算附件目录中确实存在的附件
+async fn load_usage(dir: &Path) -> io::Result<HashMap<Arc<String>, Usage>> {
+    let mut table = HashMap::new();
+    let mut owners = fs::read_dir(dir.join("owners")).await?;
+    while let Some(owner) = owners.next().await {
+        let owner = owner?;
+        let user_name = hex::decode(owner.file_name().to_string_lossy().as_bytes()).ok()
+            .and_then(|name| String::from_utf8(name).ok());
+        let Some(user_name) = user_name else { continue };
+        let mut usage = Usage::default();
+        let mut digests = fs::read_dir(owner.path()).await?;
+        while let Some(digest) = digests.next().await {
+            let digest = digest?.file_name().to_string_lossy().into_owned();
+            if !utils::is_attachment_id(&digest) {
+                continue;
+            }
+            if let Ok(metadata) = fs::metadata(dir.join(&digest)).await {
+                usage.bytes += metadata.len();
+                usage.digests.insert(digest);
+            }
+        }
+        table.insert(Arc::new(user_name), usage);
+    }
+    Ok(table)
+}
+
+/// 尽量读满 buffer，只有到达文件末尾时返回的长度才会小于 buffer 的长度
+async fn read_full(file: &mut fs::File, buffer: &mut [u8]) -> ChatResult<usize> {
+    let mut len = 0;
+    while len < buffer.len() {
+        match file.read(&mut buffer[len..]).await? {
+            0 => break,
+            n => len += n,
+        }
+    }
+    Ok(len)
+}
+
+#[cfg(test)]
+async fn test_store(name: &str, quota: u64) -> AttachmentStore {
+    let dir = std::env::temp_dir()
+        .join(format!("async_chat_attachments_{}_{}", name, std::process::id()));
+    let _ignored = std::fs::remove_dir_all(&dir);
+    let mut store = AttachmentStore::open(dir).await.unwrap();
+    store.quota = quota;
+    store
+}
+
+/// 完整上传 content，分块按顺序写入
+#[cfg(test)]
+async fn test_upload(store: &AttachmentStore, user_name: &Arc<String>, temp_name: &str,
+                     content: &[u8]) -> Result<Attachment, String> {
+    let mut upload = store.start(user_name, temp_name.to_string(),
+                                 Arc::new("students".to_string()),
+                                 Arc::new("notes.txt".to_string()),
+                                 content.len() as u64, hex::encode(Sha256::digest(content)))
+        .await?;
+    store.write_chunk(&mut upload, 0, &utils::encode_chunk(content)).await?;
+    store.finish(upload).await
+}
+
+#[test]
+fn test_attachment_quota() {
+    async_std::task::block_on(async {
+        let store = test_store("quota", 10).await;
+        let alice = Arc::new("alice".to_string());
+
+        let attachment = test_upload(&store, &alice, "1", b"123456").await.unwrap();
+        assert_eq!(attachment.id, hex::encode(Sha256::digest(b"123456")));
+        assert!(store.dir.join(&attachment.id).exists().await);
+        // 同样的内容不会再次计入用量
+        test_upload(&store, &alice, "2", b"123456").await.unwrap();
+        let error = test_upload(&store, &alice, "3", b"abcdef").await.err().unwrap();
+        assert!(error.contains("quota"), "{}", error);
+        // 限额按用户计算
+        test_upload(&store, &Arc::new("bob".to_string()), "4", b"abcdef").await.unwrap();
+
+        // 正在进行的上传在开始时就预留用量，同时开始的上传不能一起超出限额
+        let start = |temp_name: &str, content: &[u8]| {
+            store.start(&alice, temp_name.to_string(), Arc::new("students".to_string()),
+                        Arc::new("a.txt".to_string()), content.len() as u64,
+                        hex::encode(Sha256::digest(content)))
+        };
+        let first = start("5", b"aaaa").await.unwrap();
+        let error = start("6", b"bbbb").await.err().unwrap();
+        assert!(error.contains("quota"), "{}", error);
+        // 放弃的上传释放预留的用量
+        drop(first);
+        let mut second = start("7", b"bbbb").await.unwrap();
+        store.write_chunk(&mut second, 0, &utils::encode_chunk(b"bbbb")).await.unwrap();
+        store.finish(second).await.unwrap();
+        let usage = &store.usage.lock().unwrap()[&alice];
+        assert_eq!((usage.bytes, usage.reserved), (10, 0));
+        std::fs::remove_dir_all(store.dir.parent().unwrap()).unwrap();
+    });
+}
+
+#[test]
+fn test_attachment_upload_errors() {
+    async_std::task::block_on(async {
+        let store = test_store("errors", USER_QUOTA).await;
+        let alice = Arc::new("alice".to_string());
+        let students = Arc::new("students".to_string());
+        let file_name = Arc::new("notes.txt".to_string());
+        let sha256 = hex::encode(Sha256::digest(b"good good study"));
+
+        assert!(store.start(&alice, "big".to_string(), students.clone(), file_name.clone(),
+                            MAX_FILE_SIZE + 1, sha256.clone()).await.is_err());
+        assert!(store.start(&alice, "bad".to_string(), students.clone(), file_name.clone(),
+                            15, "not a digest".to_string()).await.is_err());
+
+        // 内容比声明的大小更长
+        let mut upload = store.start(&alice, "long".to_string(), students.clone(),
+                                     file_name.clone(), 4, sha256.clone()).await.unwrap();
+        let error = store.write_chunk(&mut upload, 0, &utils::encode_chunk(b"good good study"))
+            .await.unwrap_err();
+        assert!(error.contains("larger than announced"), "{}", error);
+        drop(upload);
+
+        // 分块不连续
+        let mut upload = store.start(&alice, "gap".to_string(), students.clone(),
+                                     file_name.clone(), 15, sha256.clone()).await.unwrap();
+        assert!(store.write_chunk(&mut upload, 4, &utils::encode_chunk(b"good")).await.is_err());
+        drop(upload);
+
+        // 没有收到全部内容
+        let mut upload = store.start(&alice, "short".to_string(), students.clone(),
+                                     file_name.clone(), 15, sha256.clone()).await.unwrap();
+        store.write_chunk(&mut upload, 0, &utils::encode_chunk(b"good")).await.unwrap();
+        let error = store.finish(upload).await.err().unwrap();
+        assert!(error.contains("incomplete"), "{}", error);
+
+        // 内容与摘要不一致
+        let mut upload = store.start(&alice, "forged".to_string(), students.clone(),
+                                     file_name.clone(), 15, sha256.clone()).await.unwrap();
+        store.write_chunk(&mut upload, 0, &utils::encode_chunk(b"day day up, yes")).await
+            .unwrap();
+        let error = store.finish(upload).await.err().unwrap();
+        assert!(error.contains("Checksum mismatch"), "{}", error);
+
+        // 失败的上传不计入用量，也不留下临时文件
+        assert!(store.usage.lock().unwrap().get(&alice).is_none_or(|usage| usage.bytes == 0));
+        assert!(std::fs::read_dir(store.dir.join("tmp")).unwrap().next().is_none());
+        std::fs::remove_dir_all(store.dir.parent().unwrap()).unwrap();
+    });
+}
+
+#[test]
+fn test_attachment_usage_survives_reopen() {
+    async_std::task::block_on(async {
+        let store = test_store("reopen", 10).await;
+        let alice = Arc::new("alice".to_string());
+        test_upload(&store, &alice, "1", b"123456").await.unwrap();
+        test_upload(&store, &alice, "2", b"123456").await.unwrap();
+
+        let mut store = AttachmentStore::open(store.dir.parent().unwrap()).await.unwrap();
+        store.quota = 10;
+        assert_eq!(store.usage.lock().unwrap()[&alice].bytes, 6);
+        let error = test_upload(&store, &alice, "3", b"abcdef").await.err().unwrap();
+        assert!(error.contains("quota"), "{}", error);
+        // 已经上传过的内容仍然不会再次计入
+        test_upload(&store, &alice, "4", b"123456").await.unwrap();
+        std::fs::remove_dir_all(store.dir.parent().unwrap()).unwrap();
+    });
+}
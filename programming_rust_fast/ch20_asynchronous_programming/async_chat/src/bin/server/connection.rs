@@ -1,55 +1,411 @@
-use async_chat::{FromServer, FromClient};
+use async_chat::{FromServer, FromClient, MessageId, UploadId};
 use async_chat::utils::{self, ChatResult};
 use async_std::prelude::*;
-use async_std::io::BufReader;
-use async_std::net::TcpStream;
+use async_std::io::{self, BufReader};
+use async_std::net;
+use async_std::os::unix::net::UnixStream;
 use async_std::sync::{Arc, Mutex};
+use async_std::task;
+use std::collections::{HashMap, HashSet};
+use std::net::Shutdown;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Weak;
 
+use crate::accounts;
+use crate::attachment::{AttachmentStore, Upload};
+use crate::connection_table::ConnectionTable;
+use crate::group::Group;
 use crate::group_table::GroupTable;
+use crate::log::{log, Level};
+use crate::presence::{Presence, PresenceGuard};
+use crate::storage::storage_error;
 
-pub async fn serve(socket: TcpStream, groups: Arc<GroupTable>)
+/// 为每个连接分配编号，用来生成连接的默认用户名
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+/// 每个连接可以同时进行的上传数量
+const MAX_UPLOADS: usize = 4;
+
+/// serve 可以处理的连接类型，TCP 连接和 Unix 域套接字连接
+pub trait ChatStream: io::Read + io::Write + Clone + Unpin + Send + Sync + 'static {
+    /// 对端的地址，在管理接口中显示
+    fn peer_address(&self) -> String;
+    /// 关闭连接的读写两端，正在读取这个连接的任务会读到连接结束
+    fn shutdown(&self) -> io::Result<()>;
+}
+
+impl ChatStream for net::TcpStream {
+    fn peer_address(&self) -> String {
+        match self.peer_addr() {
+            Ok(address) => address.to_string(),
+            Err(error) => format!("unknown ({})", error),
+        }
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        net::TcpStream::shutdown(self, Shutdown::Both)
+    }
+}
+
+impl ChatStream for UnixStream {
+    fn peer_address(&self) -> String {
+        // 客户端的 Unix 域套接字一般没有绑定路径
+        let path = self.peer_addr()
+            .ok()
+            .and_then(|address| address.as_pathname().map(|path| path.display().to_string()));
+        match path {
+            Some(path) => format!("unix:{}", path),
+            None => "unix".to_string(),
+        }
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        UnixStream::shutdown(self, Shutdown::Both)
+    }
+}
+
+pub async fn serve<S: ChatStream>(socket: S, groups: Arc<GroupTable>,
+                                  attachments: Arc<AttachmentStore>,
+                                  presence: Arc<Presence>,
+                                  connections: Arc<ConnectionTable>)
     -> ChatResult<()> {
     let outbound = Arc::new(Outbound::new(socket.clone()));
+    let connection_id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+    let mut user_name = Arc::new(format!("{}{}", accounts::GUEST_PREFIX, connection_id));
+    let peer_address = socket.peer_address();
+    log!(Level::Info, "connection {} from {}", connection_id, peer_address);
+    let closer = socket.clone();
+    let _registered = connections.register(connection_id, peer_address, user_name.clone(),
+                                           outbound.clone(),
+                                           Box::new(move || closer.shutdown()));
+    // 当前连接上正在进行的上传，连接断开时未完成的上传会被丢弃
+    let mut uploads: HashMap<UploadId, Upload> = HashMap::new();
+    // 登录之后在线状态一直保持到这个变量被 drop，也就是 serve 返回时
+    let mut _online: Option<PresenceGuard> = None;
+    let mut subscriptions = Subscriptions::default();
 
     let buffered = BufReader::new(socket);
     let mut from_client = utils::receive_as_json(buffered);
     while let Some(request_result) = from_client.next().await {
-        let request = request_result?;
+        let request = match request_result {
+            Ok(request) => request,
+            Err(error) => match utils::as_unknown_packet(&error) {
+                // 新版本客户端发来的请求不应该断开连接，告诉客户端不支持即可
+                Some(unknown) => {
+                    let report = FromServer::Error(
+                        format!("Unsupported request: {}", unknown.error));
+                    outbound.send(report).await?;
+                    continue;
+                }
+                None => return Err(error),
+            }
+        };
 
+        // 登录请求中有密码，不写到日志中
+        if !matches!(request, FromClient::Login { .. }) {
+            log!(Level::Debug, "connection {}: {:?}", connection_id, request);
+        }
         let result = match request {
+            FromClient::Hello { protocol_version, features } => {
+                match async_chat::negotiate_version(protocol_version) {
+                    Ok(protocol_version) => {
+                        let hello = FromServer::Hello {
+                            protocol_version,
+                            features: async_chat::common_features(&features),
+                        };
+                        outbound.send(hello).await?;
+                        Ok(())
+                    }
+                    Err(error) => Err(error),
+                }
+            }
+
+            FromClient::Login { user_name: name, password } => {
+                if !accounts::is_guest(&user_name) {
+                    Err(format!("Already logged in as {}", user_name))
+                } else {
+                    match login(&groups, &outbound, &mut subscriptions, &name, password).await {
+                        Ok(()) => {
+                            user_name = name.clone();
+                            connections.set_user_name(connection_id, name.clone());
+                            log!(Level::Info, "connection {} logged in as {}",
+                                 connection_id, name);
+                            _online = Some(presence.connect(name.clone(), connection_id,
+                                                            outbound.clone()));
+                            outbound.send(FromServer::LoggedIn { user_name: name }).await?;
+                            deliver_offline(&groups, &outbound, &user_name).await?;
+                            Ok(())
+                        }
+                        Err(error) => Err(error),
+                    }
+                }
+            }
+
             FromClient::Join { group_name } => {
-                let group = groups.get_or_create(group_name);
-                group.join(outbound.clone());
-                Ok(())
+                groups.get_or_create(group_name.clone()).and_then(|group| {
+                    // 访客的用户名只在这次连接中有效，不需要记录成员关系
+                    if !accounts::is_guest(&user_name) {
+                        groups.storage().add_member(&group_name, &user_name)
+                            .map_err(storage_error)?;
+                    }
+                    subscriptions.join(&group_name, &group, &outbound);
+                    Ok(())
+                })
             }
 
             FromClient::Post { group_name, message} => {
                 match groups.get(&group_name) {
                     Some(group) => {
-                        group.post(message);
-                        Ok(())
+                        match group.post(user_name.clone(), message.clone()).await {
+                            Ok(id) => queue_mentions(&groups, &presence, group_name, id,
+                                                     &user_name, message),
+                            Err(error) => Err(error),
+                        }
                     }
                     None => {
                         Err(format!("Group {} does not exist", group_name))
                     }
                 }
             }
+
+            FromClient::Direct { recipient, message } => {
+                let packet = |offline| FromServer::Direct {
+                    sender: user_name.clone(),
+                    message: message.clone(),
+                    offline,
+                };
+                if presence.send(&recipient, packet(false)).await {
+                    Ok(())
+                } else {
+                    queue_direct(&groups, &recipient, packet(true))
+                }
+            }
+
+            FromClient::Edit { group_name, id, message } => {
+                match existing_group(&groups, &group_name) {
+                    Ok(group) => group.edit(&user_name, id, message).await,
+                    Err(error) => Err(error),
+                }
+            }
+
+            FromClient::Delete { group_name, id } => {
+                match existing_group(&groups, &group_name) {
+                    Ok(group) => group.delete(&user_name, id).await,
+                    Err(error) => Err(error),
+                }
+            }
+
+            FromClient::React { group_name, id, emoji } => {
+                match existing_group(&groups, &group_name) {
+                    Ok(group) => group.react(user_name.clone(), id, emoji).await,
+                    Err(error) => Err(error),
+                }
+            }
+
+            FromClient::Search { group_name, query, limit } => {
+                match existing_group(&groups, &group_name) {
+                    Ok(group) => {
+                        let results = group.search(&query, limit);
+                        outbound.send(FromServer::SearchResults {
+                            group_name,
+                            query,
+                            results,
+                        }).await?;
+                        Ok(())
+                    }
+                    Err(error) => Err(error),
+                }
+            }
+
+            FromClient::UploadStart { upload_id, group_name, file_name, size, sha256 } => {
+                // 访客每次连接都有新的用户名，不能让访客绕过用量限额
+                match existing_group(&groups, &group_name) {
+                    Ok(_) if accounts::is_guest(&user_name) => {
+                        Err("Log in to upload attachments".to_string())
+                    }
+                    Ok(_) if uploads.contains_key(&upload_id) => {
+                        Err(format!("Upload {} is already in progress", upload_id))
+                    }
+                    Ok(_) if uploads.len() >= MAX_UPLOADS => {
+                        Err(format!("At most {} uploads can be in progress", MAX_UPLOADS))
+                    }
+                    Ok(_) => {
+                        let temp_name = format!("{}-{}", connection_id, upload_id);
+                        attachments.start(&user_name, temp_name, group_name,
+                                          file_name, size, sha256).await
+                            .map(|upload| { uploads.insert(upload_id, upload); })
+                    }
+                    Err(error) => Err(error),
+                }
+            }
+
+            FromClient::UploadChunk { upload_id, offset, data } => {
+                match uploads.get_mut(&upload_id) {
+                    Some(upload) => {
+                        let result = attachments.write_chunk(upload, offset, &data).await;
+                        if result.is_err() {
+                            uploads.remove(&upload_id);
+                        }
+                        result
+                    }
+                    None => Err(format!("Upload {} does not exist", upload_id)),
+                }
+            }
+
+            FromClient::UploadFinish { upload_id } => {
+                match uploads.remove(&upload_id) {
+                    Some(upload) => {
+                        let group_name = upload.group_name.clone();
+                        let attachment = attachments.finish(upload).await;
+                        attachment.and_then(|attachment| {
+                            existing_group(&groups, &group_name)
+                                .map(|group| group.attach(user_name.clone(), attachment))
+                        })
+                    }
+                    None => Err(format!("Upload {} does not exist", upload_id)),
+                }
+            }
+
+            FromClient::AnnounceKey { group_name, public_key } => {
+                existing_group(&groups, &group_name).map(|group| {
+                    group.relay(FromServer::KeyAnnounced {
+                        group_name,
+                        sender: user_name.clone(),
+                        public_key,
+                    })
+                })
+            }
+
+            FromClient::ShareKey { group_name, sender_key, recipient_key, wrapped_key } => {
+                existing_group(&groups, &group_name).map(|group| {
+                    group.relay(FromServer::KeyShared {
+                        group_name,
+                        sender_key,
+                        recipient_key,
+                        wrapped_key,
+                    })
+                })
+            }
+
+            FromClient::Download { attachment_id } => {
+                let attachments = attachments.clone();
+                let outbound = outbound.clone();
+                task::spawn(async move {
+                    let _ignored = attachments.send(attachment_id, outbound).await;
+                });
+                Ok(())
+            }
         };
 
         if let Err(message) = result {
+            log!(Level::Debug, "connection {}: {}", connection_id, message);
             let report = FromServer::Error(message);
             outbound.send(report).await?
         }
     }
 
+    log!(Level::Info, "connection {} closed", connection_id);
+    Ok(())
+}
+
+/// 验证账号密码，成功后重新加入这个账号以前加入过的群组
+async fn login(groups: &GroupTable, outbound: &Arc<Outbound>,
+               subscriptions: &mut Subscriptions, user_name: &Arc<String>,
+               password: String) -> Result<(), String> {
+    // 密码哈希的计算量很大，放到专门的线程中执行，避免阻塞其他连接
+    let storage = groups.storage().clone();
+    let name = user_name.clone();
+    task::spawn_blocking(move || accounts::login(&*storage, &name, &password)).await?;
+
+    let memberships = groups.storage().memberships(user_name).map_err(storage_error)?;
+    for group_name in memberships {
+        if let Some(group) = groups.get(&group_name) {
+            subscriptions.join(&group_name, &group, outbound);
+        }
+    }
+    Ok(())
+}
+
+/// 连接已经订阅的群组。同一个群组只订阅一次，否则每条消息都会收到多份，
+/// 比如以访客身份加入群组之后再登录。
+/// 只保存弱引用，群组被删除后可以重新加入新建的同名群组
+#[derive(Default)]
+struct Subscriptions(HashMap<Arc<String>, Weak<Group>>);
+
+impl Subscriptions {
+    fn join(&mut self, group_name: &Arc<String>, group: &Arc<Group>, outbound: &Arc<Outbound>) {
+        let joined = self.0.get(group_name)
+            .and_then(Weak::upgrade)
+            .is_some_and(|joined| Arc::ptr_eq(&joined, group));
+        if !joined {
+            group.join(outbound.clone());
+            self.0.insert(group_name.clone(), Arc::downgrade(group));
+        }
+    }
+}
+
+/// 把用户不在线时收到的消息发给刚登录的连接
+async fn deliver_offline(groups: &GroupTable, outbound: &Outbound, user_name: &str)
+    -> ChatResult<()> {
+    let packets = match groups.storage().take_offline(user_name) {
+        Ok(packets) => packets,
+        Err(error) => {
+            return outbound.send(FromServer::Error(storage_error(error))).await;
+        }
+    };
+    for packet in packets {
+        outbound.send(packet).await?;
+    }
+    Ok(())
+}
+
+/// 消息中 @ 提到的群组成员不在线时，保存消息等对方下次登录时补发
+fn queue_mentions(groups: &GroupTable, presence: &Presence, group_name: Arc<String>,
+                  id: MessageId, sender: &Arc<String>, message: Arc<String>)
+    -> Result<(), String> {
+    let storage = groups.storage();
+    let mut queued = HashSet::new();
+    for mentioned in utils::mentions(&message) {
+        if mentioned == sender.as_str() || presence.is_online(mentioned)
+            || !queued.insert(mentioned) {
+            continue;
+        }
+        let memberships = storage.memberships(mentioned).map_err(storage_error)?;
+        if !memberships.contains(&group_name) {
+            continue;
+        }
+        let packet = FromServer::Message {
+            group_name: group_name.clone(),
+            id,
+            sender: sender.clone(),
+            message: message.clone(),
+            offline: true,
+        };
+        storage.enqueue_offline(mentioned, &packet).map_err(storage_error)?;
+    }
     Ok(())
 }
 
-pub struct Outbound(Mutex<TcpStream>);
+/// 私信的接收者不在线时，保存私信等对方下次登录时补发
+fn queue_direct(groups: &GroupTable, recipient: &str, packet: FromServer)
+    -> Result<(), String> {
+    let storage = groups.storage();
+    if storage.account(recipient).map_err(storage_error)?.is_none() {
+        return Err(format!("User {} does not exist", recipient));
+    }
+    storage.enqueue_offline(recipient, &packet).map_err(storage_error)
+}
+
+fn existing_group(groups: &GroupTable, group_name: &String)
+    -> Result<Arc<Group>, String> {
+    groups.get(group_name)
+        .ok_or_else(|| format!("Group {} does not exist", group_name))
+}
+
+pub struct Outbound(Mutex<Box<dyn io::Write + Send + Sync + Unpin>>);
 
 impl Outbound {
-    pub fn new(to_client: TcpStream) -> Outbound {
-        Outbound(Mutex::new(to_client))
+    pub fn new<S: ChatStream>(to_client: S) -> Outbound {
+        Outbound(Mutex::new(Box::new(to_client)))
     }
 
     pub async fn send(&self, packet: FromServer) -> ChatResult<()> {
@@ -58,4 +414,227 @@ impl Outbound {
         guard.flush().await?;
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+/// 测试用的服务端状态，每个 TestClient 通过一对 Unix 域套接字连接到 serve
+#[cfg(test)]
+pub struct TestServer {
+    dir: std::path::PathBuf,
+    groups: Arc<GroupTable>,
+    attachments: Arc<AttachmentStore>,
+    presence: Arc<Presence>,
+    connections: Arc<ConnectionTable>,
+}
+
+#[cfg(test)]
+pub struct TestClient {
+    to_server: UnixStream,
+    from_server: std::pin::Pin<Box<dyn Stream<Item = ChatResult<FromServer>> + Send>>,
+}
+
+#[cfg(test)]
+impl TestServer {
+    pub async fn new(name: &str) -> TestServer {
+        let storage = Arc::new(crate::storage::MemoryStorage::new());
+        let dir = std::env::temp_dir()
+            .join(format!("async_chat_server_{}_{}", name, std::process::id()));
+        TestServer {
+            groups: Arc::new(GroupTable::load(storage).unwrap()),
+            attachments: Arc::new(AttachmentStore::open(&dir).await.unwrap()),
+            dir,
+            presence: Arc::new(Presence::new()),
+            connections: Arc::new(ConnectionTable::new()),
+        }
+    }
+
+    pub fn connect(&self) -> TestClient {
+        let (client, server) = UnixStream::pair().unwrap();
+        task::spawn(serve(server, self.groups.clone(), self.attachments.clone(),
+                          self.presence.clone(), self.connections.clone()));
+        TestClient {
+            to_server: client.clone(),
+            from_server: Box::pin(utils::receive_as_json(BufReader::new(client))),
+        }
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ignored = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+#[cfg(test)]
+impl TestClient {
+    pub async fn send(&mut self, request: FromClient) {
+        utils::send_as_json(&mut self.to_server, &request).await.unwrap();
+        self.to_server.flush().await.unwrap();
+    }
+
+    /// 下一个收到的数据包，超过几秒没有收到时测试失败
+    pub async fn receive(&mut self) -> FromServer {
+        let next = io::timeout(std::time::Duration::from_secs(10), async {
+            Ok(self.from_server.next().await)
+        });
+        next.await.expect("no reply from server").unwrap().unwrap()
+    }
+
+    /// 确认一段时间内没有收到任何数据包
+    pub async fn receive_nothing(&mut self) {
+        let next = io::timeout(std::time::Duration::from_millis(300), async {
+            Ok(self.from_server.next().await)
+        });
+        if let Ok(packet) = next.await {
+            panic!("unexpected packet {:?}", packet.map(|packet| packet.unwrap()));
+        }
+    }
+
+    pub async fn login(&mut self, user_name: &str, password: &str) {
+        self.send(FromClient::Login {
+            user_name: Arc::new(user_name.to_string()),
+            password: password.to_string(),
+        }).await;
+        assert_eq!(self.receive().await,
+                   FromServer::LoggedIn { user_name: Arc::new(user_name.to_string()) });
+    }
+
+    pub async fn join(&mut self, group_name: &str) {
+        self.send(FromClient::Join { group_name: Arc::new(group_name.to_string()) }).await;
+    }
+
+    pub async fn post(&mut self, group_name: &str, message: &str) {
+        self.send(FromClient::Post {
+            group_name: Arc::new(group_name.to_string()),
+            message: Arc::new(message.to_string()),
+        }).await;
+    }
+}
+
+/// 收到的群组消息的内容
+#[cfg(test)]
+fn message_text(packet: FromServer) -> String {
+    match packet {
+        FromServer::Message { message, .. } => message.to_string(),
+        packet => panic!("expected a message, got {:?}", packet),
+    }
+}
+
+#[test]
+fn test_login_does_not_join_groups_twice() {
+    task::block_on(async {
+        let server = TestServer::new("join_twice").await;
+        let mut alice = server.connect();
+        alice.login("alice", "secret").await;
+        alice.join("students").await;
+
+        // 以访客身份加入群组之后登录，群组已经在 alice 的成员关系中
+        let mut second = server.connect();
+        second.join("students").await;
+        second.join("students").await;
+        second.login("alice", "secret").await;
+
+        alice.post("students", "good good study").await;
+        alice.post("students", "day day up").await;
+        assert_eq!(message_text(second.receive().await), "good good study");
+        assert_eq!(message_text(second.receive().await), "day day up");
+        second.receive_nothing().await;
+
+        second.send(FromClient::Login {
+            user_name: Arc::new("bob".to_string()),
+            password: "secret".to_string(),
+        }).await;
+        assert_eq!(second.receive().await,
+                   FromServer::Error("Already logged in as alice".to_string()));
+    });
+}
+
+#[test]
+fn test_offline_messages_are_delivered_once() {
+    task::block_on(async {
+        let server = TestServer::new("offline").await;
+        let mut bob = server.connect();
+        bob.login("bob", "secret").await;
+        bob.join("students").await;
+        drop(bob);
+        // 等待服务端处理完 bob 的连接断开
+        while server.presence.is_online("bob") {
+            task::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        let mut alice = server.connect();
+        alice.login("alice", "secret").await;
+        alice.join("students").await;
+        alice.post("students", "see you tomorrow @bob").await;
+        alice.post("students", "no mention here").await;
+        alice.send(FromClient::Direct {
+            recipient: Arc::new("bob".to_string()),
+            message: Arc::new("psst".to_string()),
+        }).await;
+        assert_eq!(message_text(alice.receive().await), "see you tomorrow @bob");
+        assert_eq!(message_text(alice.receive().await), "no mention here");
+        alice.receive_nothing().await;
+
+        let mut bob = server.connect();
+        bob.login("bob", "secret").await;
+        assert_eq!(bob.receive().await, FromServer::Message {
+            group_name: Arc::new("students".to_string()),
+            id: 1,
+            sender: Arc::new("alice".to_string()),
+            message: Arc::new("see you tomorrow @bob".to_string()),
+            offline: true,
+        });
+        assert_eq!(bob.receive().await, FromServer::Direct {
+            sender: Arc::new("alice".to_string()),
+            message: Arc::new("psst".to_string()),
+            offline: true,
+        });
+        bob.receive_nothing().await;
+
+        // 补发过的消息不会在下次登录时再次发送
+        let mut again = server.connect();
+        again.login("bob", "secret").await;
+        again.receive_nothing().await;
+
+        // 在线时提到 bob 的消息直接发送，不会保存下来
+        alice.post("students", "welcome back @bob").await;
+        assert_eq!(message_text(bob.receive().await), "welcome back @bob");
+        drop((bob, again));
+        while server.presence.is_online("bob") {
+            task::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        let mut bob = server.connect();
+        bob.login("bob", "secret").await;
+        bob.receive_nothing().await;
+    });
+}
+
+#[test]
+fn test_uploads_need_an_account_and_are_limited() {
+    task::block_on(async {
+        let server = TestServer::new("uploads").await;
+        let mut client = server.connect();
+        client.join("students").await;
+        let upload_start = |upload_id| FromClient::UploadStart {
+            upload_id,
+            group_name: Arc::new("students".to_string()),
+            file_name: Arc::new("notes.txt".to_string()),
+            size: 4,
+            sha256: hex::encode(<sha2::Sha256 as sha2::Digest>::digest(b"note")),
+        };
+
+        client.send(upload_start(0)).await;
+        assert_eq!(client.receive().await,
+                   FromServer::Error("Log in to upload attachments".to_string()));
+
+        client.login("alice", "secret").await;
+        for upload_id in 1..=MAX_UPLOADS as UploadId {
+            client.send(upload_start(upload_id)).await;
+        }
+        client.send(upload_start(0)).await;
+        assert_eq!(client.receive().await,
+                   FromServer::Error(format!("At most {} uploads can be in progress",
+                                             MAX_UPLOADS)));
+        client.receive_nothing().await;
+    });
+}
@@ -0,0 +1,149 @@
+use async_chat::FromServer;
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use crate::connection::Outbound;
+
+/// 关闭连接的函数，调用后连接的读取端会读到连接结束
+pub type Shutdown = Box<dyn Fn() -> io::Result<()> + Send + Sync>;
+
+/// 服务端当前所有的连接，供管理接口查看和断开连接
+pub struct ConnectionTable(Mutex<BTreeMap<u64, Connection>>);
+
+struct Connection {
+    peer_address: String,
+    user_name: Arc<String>,
+    outbound: Arc<Outbound>,
+    shutdown: Shutdown,
+}
+
+/// 管理接口显示的一个连接
+pub struct ConnectionSummary {
+    pub id: u64,
+    pub peer_address: String,
+    pub user_name: Arc<String>,
+}
+
+impl ConnectionTable {
+    pub fn new() -> ConnectionTable {
+        ConnectionTable(Mutex::new(BTreeMap::new()))
+    }
+
+    /// 记录新的连接，返回的 guard 被 drop 时移除连接
+    pub fn register(self: &Arc<Self>, id: u64, peer_address: String, user_name: Arc<String>,
+                    outbound: Arc<Outbound>, shutdown: Shutdown) -> ConnectionGuard {
+        self.0.lock().unwrap().insert(id, Connection {
+            peer_address,
+            user_name,
+            outbound,
+            shutdown,
+        });
+        ConnectionGuard {
+            connections: self.clone(),
+            id,
+        }
+    }
+
+    /// 连接登录之后更新显示的用户名
+    pub fn set_user_name(&self, id: u64, user_name: Arc<String>) {
+        if let Some(connection) = self.0.lock().unwrap().get_mut(&id) {
+            connection.user_name = user_name;
+        }
+    }
+
+    pub fn list(&self) -> Vec<ConnectionSummary> {
+        self.0.lock()
+            .unwrap()
+            .iter()
+            .map(|(id, connection)| ConnectionSummary {
+                id: *id,
+                peer_address: connection.peer_address.clone(),
+                user_name: connection.user_name.clone(),
+            })
+            .collect()
+    }
+
+    /// 强制断开连接，连接的任务发现连接结束后自行清理
+    pub fn disconnect(&self, id: u64) -> Result<(), String> {
+        let connections = self.0.lock().unwrap();
+        let connection = connections.get(&id)
+            .ok_or_else(|| format!("Connection {} does not exist", id))?;
+        (connection.shutdown)().map_err(|error| error.to_string())
+    }
+
+    /// 发送给所有连接，返回发送的连接数量
+    pub async fn broadcast(&self, packet: FromServer) -> usize {
+        let outbounds: Vec<Arc<Outbound>> = self.0.lock()
+            .unwrap()
+            .values()
+            .map(|connection| connection.outbound.clone())
+            .collect();
+        let mut sent = 0;
+        for outbound in outbounds {
+            if outbound.send(packet.clone()).await.is_ok() {
+                sent += 1;
+            }
+        }
+        sent
+    }
+}
+
+/// 连接结束时将连接从连接表中移除
+pub struct ConnectionGuard {
+    connections: Arc<ConnectionTable>,
+    id: u64,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.connections.0.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[test]
+fn test_register_and_disconnect() {
+    use async_std::os::unix::net::UnixStream;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    async_std::task::block_on(async {
+        let connections = Arc::new(ConnectionTable::new());
+        let shutdowns = Arc::new(AtomicUsize::new(0));
+        let register = |id: u64, user_name: &str| {
+            let (_client, server) = UnixStream::pair().unwrap();
+            let shutdowns = shutdowns.clone();
+            connections.register(id, format!("peer-{}", id), Arc::new(user_name.to_string()),
+                                 Arc::new(Outbound::new(server)),
+                                 Box::new(move || {
+                                     shutdowns.fetch_add(1, Ordering::SeqCst);
+                                     Ok(())
+                                 }))
+        };
+        let first = register(1, "guest-1");
+        let second = register(2, "guest-2");
+        connections.set_user_name(1, Arc::new("alice".to_string()));
+        connections.set_user_name(3, Arc::new("bob".to_string()));
+
+        let listed: Vec<_> = connections.list().into_iter()
+            .map(|summary| (summary.id, summary.peer_address, summary.user_name.to_string()))
+            .collect();
+        assert_eq!(listed, vec![(1, "peer-1".to_string(), "alice".to_string()),
+                                (2, "peer-2".to_string(), "guest-2".to_string())]);
+
+        connections.disconnect(2).unwrap();
+        assert_eq!(shutdowns.load(Ordering::SeqCst), 1);
+        assert!(connections.disconnect(3).is_err());
+
+        // 客户端一端已经关闭，发送失败的连接不计入
+        let sent = connections.broadcast(FromServer::Announcement {
+            message: Arc::new("hello".to_string()),
+        }).await;
+        assert_eq!(sent, 0);
+
+        drop(second);
+        assert_eq!(connections.list().len(), 1);
+        drop(first);
+        assert!(connections.list().is_empty());
+        assert!(connections.disconnect(1).is_err());
+    });
+}
@@ -1,17 +1,49 @@
 use async_std::task;
+use crate::accounts;
+use crate::attachment::Attachment;
 use crate::connection::Outbound;
-use std::sync::Arc;
+use crate::search::{SearchIndex, MAX_SEARCH_RESULTS};
+use crate::storage::{storage_error, History, Storage, StoredMessage};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::sync::broadcast;
+use async_chat::utils::ChatResult;
+use async_chat::{FromServer, MessageId, SearchResult};
 
 pub struct Group {
     name: Arc<String>,
-    sender: broadcast::Sender<Arc<String>>
+    sender: broadcast::Sender<FromServer>,
+    /// 只在修改内存中的历史记录时短暂持有，写入存储时不持有
+    history: Mutex<History>,
+    /// 让修改群组的操作依次执行，写入存储期间等待的任务不会占用执行器线程
+    writes: async_std::sync::Mutex<()>,
+    /// 消息内容的倒排索引，总是在持有 history 锁的时候修改
+    index: Mutex<SearchIndex>,
+    storage: Arc<dyn Storage>,
+    /// 服务端这次启动之后发布的第一条消息的 id
+    first_live_id: MessageId,
 }
 
 impl Group {
-    pub fn new(name: Arc<String>) -> Group {
+    /// 创建新的群组并保存到存储中
+    pub fn create(name: Arc<String>, storage: Arc<dyn Storage>) -> ChatResult<Group> {
+        storage.create_group(&name)?;
+        Group::load(name, storage)
+    }
+
+    /// 从存储中恢复群组的消息历史
+    pub fn load(name: Arc<String>, storage: Arc<dyn Storage>) -> ChatResult<Group> {
         let (sender, _receiver) = broadcast::channel(1000);
-        Group {name, sender}
+        let history = storage.history(&name)?;
+        Ok(Group {
+            name,
+            sender,
+            first_live_id: history.next_id,
+            index: Mutex::new(SearchIndex::build(&history)),
+            history: Mutex::new(history),
+            writes: async_std::sync::Mutex::new(()),
+            storage,
+        })
     }
 
     pub fn join(&self, outbound: Arc<Outbound>) {
@@ -20,23 +52,173 @@ impl Group {
         task::spawn(handle_subscriber(self.name.clone(), receiver, outbound));
     }
 
-    pub fn post(&self, message: Arc<String>) {
-        let _ignored = self.sender.send(message);
+    /// 发布消息，返回服务端为这条消息分配的 id
+    pub async fn post(&self, sender: Arc<String>, message: Arc<String>)
+        -> Result<MessageId, String> {
+        let _writing = self.writes.lock().await;
+        let id = self.history.lock().unwrap().next_id;
+        let stored = StoredMessage {
+            sender: sender.clone(),
+            message: message.clone(),
+            posted_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+            reactions: Vec::new(),
+        };
+        let row = stored.clone();
+        self.write(move |storage, name| storage.insert_message(name, id, &row)).await?;
+
+        let mut history = self.history.lock().unwrap();
+        history.next_id += 1;
+        history.messages.insert(id, stored);
+        self.index.lock().unwrap().insert(id, &message);
+
+        // 持有 writes 锁的时候广播，保证订阅者收到的顺序与 id 顺序一致
+        let _ignored = self.sender.send(FromServer::Message {
+            group_name: self.name.clone(),
+            id,
+            sender,
+            message,
+            offline: false,
+        });
+        Ok(id)
+    }
+
+    /// 修改消息内容，只有消息的作者可以修改
+    pub async fn edit(&self, editor: &str, id: MessageId, message: Arc<String>)
+        -> Result<(), String> {
+        let _writing = self.writes.lock().await;
+        self.authored_by(&mut self.history.lock().unwrap(), editor, id)?;
+        let text = message.clone();
+        self.write(move |storage, name| storage.update_message(name, id, &text)).await?;
+
+        let mut history = self.history.lock().unwrap();
+        let stored = history.messages.get_mut(&id)
+            .ok_or_else(|| self.no_such_message(id))?;
+        let mut index = self.index.lock().unwrap();
+        index.remove(id, &stored.message);
+        index.insert(id, &message);
+        stored.message = message.clone();
+
+        let _ignored = self.sender.send(FromServer::Edited {
+            group_name: self.name.clone(),
+            id,
+            message,
+        });
+        Ok(())
+    }
+
+    /// 删除消息，只有消息的作者可以删除
+    pub async fn delete(&self, editor: &str, id: MessageId) -> Result<(), String> {
+        let _writing = self.writes.lock().await;
+        self.authored_by(&mut self.history.lock().unwrap(), editor, id)?;
+        self.write(move |storage, name| storage.delete_message(name, id)).await?;
+
+        if let Some(stored) = self.history.lock().unwrap().messages.remove(&id) {
+            self.index.lock().unwrap().remove(id, &stored.message);
+        }
+
+        let _ignored = self.sender.send(FromServer::Deleted {
+            group_name: self.name.clone(),
+            id,
+        });
+        Ok(())
+    }
+
+    /// 对消息添加表情回应，同一个用户对同一条消息的相同表情只记录一次
+    pub async fn react(&self, sender: Arc<String>, id: MessageId, emoji: Arc<String>)
+        -> Result<(), String> {
+        let _writing = self.writes.lock().await;
+        let reaction = (sender.clone(), emoji.clone());
+        {
+            let history = self.history.lock().unwrap();
+            let stored = history.messages.get(&id)
+                .ok_or_else(|| self.no_such_message(id))?;
+            if stored.reactions.contains(&reaction) {
+                return Ok(());
+            }
+        }
+        let (user_name, text) = reaction.clone();
+        self.write(move |storage, name| storage.add_reaction(name, id, &user_name, &text))
+            .await?;
+
+        if let Some(stored) = self.history.lock().unwrap().messages.get_mut(&id) {
+            stored.reactions.push(reaction);
+        }
+
+        let _ignored = self.sender.send(FromServer::Reacted {
+            group_name: self.name.clone(),
+            id,
+            sender,
+            emoji,
+        });
+        Ok(())
+    }
+
+    /// 搜索包含 query 中所有词的历史消息，按照从新到旧的顺序返回
+    pub fn search(&self, query: &str, limit: usize) -> Vec<SearchResult> {
+        let history = self.history.lock().unwrap();
+        let ids = self.index.lock().unwrap().search(query, limit.min(MAX_SEARCH_RESULTS));
+        ids.into_iter()
+            .filter_map(|id| {
+                history.messages.get(&id).map(|stored| SearchResult {
+                    id,
+                    sender: stored.sender.clone(),
+                    message: stored.message.clone(),
+                    posted_at: stored.posted_at,
+                })
+            })
+            .collect()
+    }
+
+    /// 通知群组成员有新的附件
+    pub fn attach(&self, sender: Arc<String>, attachment: Attachment) {
+        let _ignored = self.sender.send(FromServer::Attachment {
+            group_name: self.name.clone(),
+            sender,
+            attachment_id: attachment.id,
+            file_name: attachment.file_name,
+            size: attachment.size,
+        });
+    }
+
+    /// 将端到端加密的密钥交换消息原样转发给群组成员
+    pub fn relay(&self, packet: FromServer) {
+        let _ignored = self.sender.send(packet);
+    }
+
+    /// 在专门的线程中执行存储操作，磁盘写入很慢时也不会阻塞其他连接
+    async fn write<F>(&self, write: F) -> Result<(), String>
+    where F: FnOnce(&dyn Storage, &str) -> ChatResult<()> + Send + 'static
+    {
+        let storage = self.storage.clone();
+        let name = self.name.clone();
+        task::spawn_blocking(move || write(&*storage, &name)).await.map_err(storage_error)
+    }
+
+    fn authored_by<'h>(&self, history: &'h mut History, editor: &str, id: MessageId)
+        -> Result<&'h mut StoredMessage, String> {
+        let stored = history.messages.get_mut(&id)
+            .ok_or_else(|| self.no_such_message(id))?;
+        // 访客的用户名在服务端重启后会被重新分配，只能修改这次启动之后发布的消息
+        if stored.sender.as_str() != editor
+            || (accounts::is_guest(editor) && id < self.first_live_id) {
+            return Err(format!("Message {} in {} was not posted by you", id, self.name));
+        }
+        Ok(stored)
+    }
+
+    fn no_such_message(&self, id: MessageId) -> String {
+        format!("Message {} does not exist in {}", id, self.name)
     }
 }
 
-use async_chat::FromServer;
 use tokio::sync::broadcast::error::RecvError;
 
 async fn handle_subscriber(group_name: Arc<String>,
-                            mut receiver: broadcast::Receiver<Arc<String>>,
+                            mut receiver: broadcast::Receiver<FromServer>,
                             outbound: Arc<Outbound>) {
     loop {
         let packet = match receiver.recv().await {
-            Ok(message) => FromServer::Message {
-                group_name: group_name.clone(),
-                message: message.clone(),
-            },
+            Ok(packet) => packet,
             Err(RecvError::Lagged(n)) => FromServer::Error(
                 format!("Dropped {} messages from {}.", n, group_name)
             ),
@@ -48,3 +230,51 @@ async fn handle_subscriber(group_name: Arc<String>,
         }
     }
 }
+
+#[test]
+fn test_only_author_can_edit_or_delete() {
+    task::block_on(async {
+        let storage = Arc::new(crate::storage::MemoryStorage::new());
+        let group = Group::create(Arc::new("students".to_string()), storage).unwrap();
+        let alice = Arc::new("alice".to_string());
+        let id = group.post(alice.clone(), Arc::new("good good stdy".to_string())).await
+            .unwrap();
+
+        assert!(group.edit("bob", id, Arc::new("spam".to_string())).await.is_err());
+        assert!(group.delete("bob", id).await.is_err());
+        let bob = Arc::new("bob".to_string());
+        assert!(group.react(bob, id, Arc::new("👍".to_string())).await.is_ok());
+
+        group.edit("alice", id, Arc::new("good good study".to_string())).await.unwrap();
+        assert_eq!(group.history.lock().unwrap().messages[&id].message.as_str(),
+                   "good good study");
+
+        group.delete("alice", id).await.unwrap();
+        assert!(group.react(alice, id, Arc::new("👍".to_string())).await.is_err());
+    });
+}
+
+#[test]
+fn test_search_follows_edits() {
+    task::block_on(async {
+        let storage = Arc::new(crate::storage::MemoryStorage::new());
+        let group = Group::create(Arc::new("students".to_string()), storage.clone()).unwrap();
+        let alice = Arc::new("alice".to_string());
+        let first = group.post(alice.clone(), Arc::new("good good study".to_string())).await
+            .unwrap();
+        let second = group.post(alice.clone(), Arc::new("study hard".to_string())).await
+            .unwrap();
+
+        group.edit("alice", first, Arc::new("day day up".to_string())).await.unwrap();
+        let results = group.search("study", 10);
+        assert_eq!(results.iter().map(|result| result.id).collect::<Vec<_>>(), vec![second]);
+        assert_eq!(group.search("day", 10)[0].sender, alice);
+
+        group.delete("alice", second).await.unwrap();
+        assert!(group.search("study", 10).is_empty());
+
+        // 重新加载群组时从存储的历史中重建索引
+        let group = Group::load(Arc::new("students".to_string()), storage).unwrap();
+        assert_eq!(group.search("up", 10).len(), 1);
+    });
+}
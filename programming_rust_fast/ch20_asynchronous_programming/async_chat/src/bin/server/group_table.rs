@@ -1,26 +1,66 @@
 use crate::group::Group;
+use crate::storage::{storage_error, Storage};
+use async_chat::utils::ChatResult;
+use async_chat::FromServer;
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 
-pub struct GroupTable(Mutex<HashMap<Arc<String>, Arc<Group>>>);
+pub struct GroupTable {
+    groups: Mutex<HashMap<Arc<String>, Arc<Group>>>,
+    storage: Arc<dyn Storage>,
+}
 
 impl GroupTable {
-    pub fn new() -> GroupTable {
-        GroupTable(Mutex::new(HashMap::new()))
+    /// 从存储中恢复所有的群组
+    pub fn load(storage: Arc<dyn Storage>) -> ChatResult<GroupTable> {
+        let mut groups = HashMap::new();
+        for name in storage.groups()? {
+            let group = Group::load(name.clone(), storage.clone())?;
+            groups.insert(name, Arc::new(group));
+        }
+        Ok(GroupTable {
+            groups: Mutex::new(groups),
+            storage,
+        })
+    }
+
+    pub fn storage(&self) -> &Arc<dyn Storage> {
+        &self.storage
     }
 
     pub fn get(&self, name: &String) -> Option<Arc<Group>> {
-        self.0.lock()
+        self.groups.lock()
             .unwrap()
             .get(name)
             .cloned()
     }
 
-    pub fn get_or_create(&self, name: Arc<String>) -> Arc<Group> {
-        self.0.lock()
-            .unwrap()
-            .entry(name.clone())
-            .or_insert_with(|| Arc::new(Group::new(name.clone())))
-            .clone()
+    pub fn get_or_create(&self, name: Arc<String>) -> Result<Arc<Group>, String> {
+        let mut groups = self.groups.lock().unwrap();
+        if let Some(group) = groups.get(&name) {
+            return Ok(group.clone());
+        }
+
+        let group = Group::create(name.clone(), self.storage.clone())
+            .map_err(storage_error)?;
+        let group = Arc::new(group);
+        groups.insert(name, group.clone());
+        Ok(group)
+    }
+
+    /// 从存储中删除群组，群组的订阅者收到通知之后不再收到这个群组的消息
+    pub fn delete(&self, name: &String) -> Result<(), String> {
+        let mut groups = self.groups.lock().unwrap();
+        if !groups.contains_key(name) {
+            return Err(format!("Group {} does not exist", name));
+        }
+        self.storage.delete_group(name).map_err(storage_error)?;
+        if let Some(group) = groups.remove(name) {
+            // 最后一个引用被 drop 时广播通道关闭，订阅者的任务随之结束
+            group.relay(FromServer::Announcement {
+                message: Arc::new(format!("Group {} was deleted", name)),
+            });
+        }
+        Ok(())
     }
 }
\ No newline at end of file
@@ -0,0 +1,76 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// 日志级别，级别越高输出的内容越多
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+const LEVELS: [Level; 4] = [Level::Error, Level::Warn, Level::Info, Level::Debug];
+
+/// 当前的日志级别，可以通过管理接口在运行时修改
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+pub fn level() -> Level {
+    LEVELS[LEVEL.load(Ordering::Relaxed) as usize]
+}
+
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn enabled(level: Level) -> bool {
+    level <= self::level()
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Level, String> {
+        LEVELS.iter()
+            .find(|level| level.to_string().eq_ignore_ascii_case(name))
+            .copied()
+            .ok_or_else(|| format!("Unknown log level {:?}, expected error, warn, info or debug",
+                                   name))
+    }
+}
+
+/// 当前日志级别允许时输出到标准错误：log!(Level::Info, "...", ...)
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => {
+        if $crate::log::enabled($level) {
+            eprintln!("[{}] {}", $level, format_args!($($arg)*));
+        }
+    };
+}
+
+pub(crate) use log;
+
+#[test]
+fn test_parse_level() {
+    assert_eq!("warn".parse(), Ok(Level::Warn));
+    assert_eq!("DEBUG".parse(), Ok(Level::Debug));
+    assert!("verbose".parse::<Level>().is_err());
+    assert!("".parse::<Level>().is_err());
+    for level in LEVELS {
+        assert_eq!(level.to_string().parse(), Ok(level));
+    }
+    assert!(Level::Error < Level::Warn && Level::Info < Level::Debug);
+}
@@ -5,37 +5,190 @@ use async_chat::utils::ChatResult;
 use async_std::prelude::*;
 use std::sync::Arc;
 
+mod accounts;
+mod admin;
+mod attachment;
 mod connection;
+mod connection_table;
 mod group_table;
 mod group;
+mod log;
+mod presence;
+mod search;
+mod sqlite_storage;
+mod storage;
 
-use connection::serve;
+use attachment::AttachmentStore;
+use connection::{serve, ChatStream};
+use connection_table::ConnectionTable;
+use group_table::GroupTable;
+use log::{log, Level};
+use presence::Presence;
+use sqlite_storage::SqliteStorage;
+use storage::{MemoryStorage, Storage};
+
+/// 服务端的命令行参数
+struct Options {
+    address: String,
+    data_dir: String,
+    unix_path: Option<String>,
+    /// SQLite 数据库文件，不指定时所有状态只保存在内存中
+    database: Option<String>,
+    /// 本地管理接口的 Unix 域套接字路径
+    admin_path: Option<String>,
+}
+
+/// 解析命令行参数：server ADDRESS [--data-dir DIR] [--unix PATH] [--db PATH] [--admin PATH]
+fn parse_options() -> Options {
+    const USAGE: &str =
+        "用法: server address [--data-dir DIR] [--unix PATH] [--db PATH] [--admin PATH]";
+    let mut address = None;
+    let mut data_dir = "chat_data".to_string();
+    let mut unix_path = None;
+    let mut database = None;
+    let mut admin_path = None;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--data-dir" => data_dir = args.next().expect(USAGE),
+            "--unix" => unix_path = Some(args.next().expect(USAGE)),
+            "--db" => database = Some(args.next().expect(USAGE)),
+            "--admin" => admin_path = Some(args.next().expect(USAGE)),
+            _ if address.is_none() => address = Some(arg),
+            _ => panic!("{}", USAGE),
+        }
+    }
+
+    Options {
+        address: address.expect(USAGE),
+        data_dir,
+        unix_path,
+        database,
+        admin_path,
+    }
+}
 
 fn main() -> ChatResult<()> {
-    let address = std::env::args().nth(1).expect("用法: server address");
+    let Options { address, data_dir, unix_path, database, admin_path } = parse_options();
 
-    let chat_group_table = Arc::new(group_table::GroupTable::new());
+    let storage: Arc<dyn Storage> = match database {
+        Some(path) => Arc::new(SqliteStorage::open(path)?),
+        None => Arc::new(MemoryStorage::new()),
+    };
+    let chat_group_table = Arc::new(GroupTable::load(storage)?);
 
     async_std::task::block_on(
         async {
             use async_std::{net, task};
+            use async_std::os::unix;
+
+            let attachments = Arc::new(AttachmentStore::open(data_dir).await?);
+            let presence = Arc::new(Presence::new());
+            let connections = Arc::new(ConnectionTable::new());
+
+            if let Some(path) = admin_path {
+                let listener = admin::bind(&path).await?;
+                let groups = chat_group_table.clone();
+                let connections = connections.clone();
+                task::spawn(async move {
+                    let mut new_connections = listener.incoming();
+                    while let Some(socket_result) = new_connections.next().await {
+                        match socket_result {
+                            Ok(socket) => {
+                                let admin = admin::serve_admin(socket, groups.clone(),
+                                                               connections.clone());
+                                task::spawn(async { log_error(admin.await) });
+                            }
+                            Err(error) => log!(Level::Error, "{}", error),
+                        }
+                    }
+                });
+            }
+
+            if let Some(path) = unix_path {
+                remove_stale_socket(&path)?;
+                let listener = unix::net::UnixListener::bind(path).await?;
+                let groups = chat_group_table.clone();
+                let attachments = attachments.clone();
+                let presence = presence.clone();
+                let connections = connections.clone();
+                task::spawn(async move {
+                    let mut new_connections = listener.incoming();
+                    while let Some(socket_result) = new_connections.next().await {
+                        match socket_result {
+                            Ok(socket) => {
+                                spawn_connection(socket, &groups, &attachments, &presence,
+                                                 &connections)
+                            }
+                            Err(error) => log!(Level::Error, "{}", error),
+                        }
+                    }
+                });
+            }
 
             let listener = net::TcpListener::bind(address).await?;
 
             let mut new_connections = listener.incoming();
             while let Some(socket_result) = new_connections.next().await {
                 let socket = socket_result?;
-                let groups = chat_group_table.clone();
-                task::spawn(async {
-                    log_error(serve(socket, groups).await);
-                });
+                spawn_connection(socket, &chat_group_table, &attachments, &presence,
+                                 &connections);
             }
             Ok(())
         })
 }
 
+/// 在新的任务中处理一个连接
+fn spawn_connection<S: ChatStream>(socket: S, groups: &Arc<GroupTable>,
+                                   attachments: &Arc<AttachmentStore>,
+                                   presence: &Arc<Presence>,
+                                   connections: &Arc<ConnectionTable>) {
+    let groups = groups.clone();
+    let attachments = attachments.clone();
+    let presence = presence.clone();
+    let connections = connections.clone();
+    async_std::task::spawn(async {
+        log_error(serve(socket, groups, attachments, presence, connections).await);
+    });
+}
+
+/// 删除上次运行留下的套接字文件，否则 bind 会失败。
+/// 路径上已经有其他类型的文件时返回错误，不会删除它
+fn remove_stale_socket(path: &str) -> std::io::Result<()> {
+    use std::io::{Error, ErrorKind};
+    use std::os::unix::fs::FileTypeExt;
+
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path),
+        Ok(_) => Err(Error::new(ErrorKind::AlreadyExists,
+                                format!("{} already exists and is not a socket", path))),
+        Err(error) if error.kind() == ErrorKind::NotFound => Ok(()),
+        Err(error) => Err(error),
+    }
+}
+
 fn log_error(result: ChatResult<()>) {
     if let Err(error) = result {
-        eprintln!("Error: {}", error);
+        log!(Level::Error, "{}", error);
     }
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_remove_stale_socket() {
+    let dir = std::env::temp_dir().join(format!("async_chat_socket_{}", std::process::id()));
+    let _ignored = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+
+    remove_stale_socket(&path("missing")).unwrap();
+
+    std::fs::write(path("chat.db"), "data").unwrap();
+    assert!(remove_stale_socket(&path("chat.db")).is_err());
+    assert_eq!(std::fs::read_to_string(path("chat.db")).unwrap(), "data");
+
+    drop(std::os::unix::net::UnixListener::bind(path("chat.sock")).unwrap());
+    remove_stale_socket(&path("chat.sock")).unwrap();
+    assert!(!dir.join("chat.sock").exists());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,68 @@
+use crate::connection::Outbound;
+use async_chat::FromServer;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// 一个用户在线的连接，以及连接的编号
+type Connections = Vec<(u64, Arc<Outbound>)>;
+
+/// 已登录用户的在线连接，一个用户可以同时有多个连接
+pub struct Presence(Mutex<HashMap<String, Connections>>);
+
+impl Presence {
+    pub fn new() -> Presence {
+        Presence(Mutex::new(HashMap::new()))
+    }
+
+    /// 记录用户的一个连接上线，返回的 guard 被 drop 时连接下线
+    pub fn connect(self: &Arc<Self>, user_name: Arc<String>, connection_id: u64,
+                   outbound: Arc<Outbound>) -> PresenceGuard {
+        self.0.lock()
+            .unwrap()
+            .entry(user_name.to_string())
+            .or_default()
+            .push((connection_id, outbound));
+        PresenceGuard {
+            presence: self.clone(),
+            user_name,
+            connection_id,
+        }
+    }
+
+    pub fn is_online(&self, user_name: &str) -> bool {
+        self.0.lock().unwrap().contains_key(user_name)
+    }
+
+    /// 发送给用户所有在线的连接，用户不在线时返回 false
+    pub async fn send(&self, user_name: &str, packet: FromServer) -> bool {
+        let outbounds: Vec<Arc<Outbound>> = match self.0.lock().unwrap().get(user_name) {
+            Some(connections) => connections.iter()
+                .map(|(_, outbound)| outbound.clone())
+                .collect(),
+            None => return false,
+        };
+        for outbound in outbounds {
+            let _ignored = outbound.send(packet.clone()).await;
+        }
+        true
+    }
+}
+
+/// 连接结束时将连接从在线列表中移除
+pub struct PresenceGuard {
+    presence: Arc<Presence>,
+    user_name: Arc<String>,
+    connection_id: u64,
+}
+
+impl Drop for PresenceGuard {
+    fn drop(&mut self) {
+        let mut users = self.presence.0.lock().unwrap();
+        if let Some(connections) = users.get_mut(self.user_name.as_str()) {
+            connections.retain(|(id, _)| *id != self.connection_id);
+            if connections.is_empty() {
+                users.remove(self.user_name.as_str());
+            }
+        }
+    }
+}
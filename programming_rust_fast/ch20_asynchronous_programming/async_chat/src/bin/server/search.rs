@@ -0,0 +1,120 @@
+use async_chat::e2e;
+use async_chat::MessageId;
+use std::collections::{BTreeSet, HashMap};
+
+use crate::storage::History;
+
+/// 一次搜索最多返回的消息数量
+pub const MAX_SEARCH_RESULTS: usize = 100;
+
+/// 群组消息的倒排索引，记录每个词出现在哪些消息中
+pub struct SearchIndex {
+    postings: HashMap<String, BTreeSet<MessageId>>,
+}
+
+impl SearchIndex {
+    /// 为消息历史中已有的消息建立索引
+    pub fn build(history: &History) -> SearchIndex {
+        let mut index = SearchIndex {
+            postings: HashMap::new(),
+        };
+        for (id, stored) in &history.messages {
+            index.insert(*id, &stored.message);
+        }
+        index
+    }
+
+    pub fn insert(&mut self, id: MessageId, message: &str) {
+        // 端到端加密的消息服务端无法读取内容，不建立索引
+        if e2e::is_sealed(message) {
+            return;
+        }
+        for word in words(message) {
+            self.postings.entry(word).or_default().insert(id);
+        }
+    }
+
+    pub fn remove(&mut self, id: MessageId, message: &str) {
+        for word in words(message) {
+            if let Some(ids) = self.postings.get_mut(&word) {
+                ids.remove(&id);
+                if ids.is_empty() {
+                    self.postings.remove(&word);
+                }
+            }
+        }
+    }
+
+    /// 查找包含查询中所有词的消息，按照从新到旧的顺序返回最多 limit 条
+    pub fn search(&self, query: &str, limit: usize) -> Vec<MessageId> {
+        let mut postings = Vec::new();
+        for word in words(query) {
+            match self.postings.get(&word) {
+                Some(ids) => postings.push(ids),
+                None => return Vec::new(),
+            }
+        }
+        // 从最短的列表开始遍历，其余的列表只需要查找
+        postings.sort_by_key(|ids| ids.len());
+        let (shortest, rest) = match postings.split_first() {
+            Some(split) => split,
+            None => return Vec::new(),
+        };
+        shortest.iter()
+            .rev()
+            .filter(|id| rest.iter().all(|ids| ids.contains(id)))
+            .take(limit)
+            .copied()
+            .collect()
+    }
+}
+
+/// 将文本拆分为小写的词，中日韩文字没有空格分隔，每个字单独作为一个词
+pub fn words(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .flat_map(|word| {
+            let mut pieces = Vec::new();
+            let mut start = 0;
+            for (i, c) in word.char_indices() {
+                if is_cjk(c) {
+                    if start < i {
+                        pieces.push(&word[start..i]);
+                    }
+                    pieces.push(&word[i..i + c.len_utf8()]);
+                    start = i + c.len_utf8();
+                }
+            }
+            if start < word.len() {
+                pieces.push(&word[start..]);
+            }
+            pieces
+        })
+        .map(str::to_lowercase)
+}
+
+fn is_cjk(c: char) -> bool {
+    matches!(c, '\u{3040}'..='\u{30ff}' | '\u{3400}'..='\u{4dbf}'
+              | '\u{4e00}'..='\u{9fff}' | '\u{ac00}'..='\u{d7af}')
+}
+
+#[test]
+fn test_search_index() {
+    let mut index = SearchIndex {
+        postings: HashMap::new(),
+    };
+    index.insert(1, "Good good study");
+    index.insert(2, "day day up, study hard");
+    index.insert(3, "好好学习，天天向上");
+    index.insert(4, &e2e::seal(&e2e::generate_group_key(), "students", "study"));
+
+    assert_eq!(index.search("STUDY", 10), vec![2, 1]);
+    assert_eq!(index.search("study", 1), vec![2]);
+    assert_eq!(index.search("study good", 10), vec![1]);
+    assert_eq!(index.search("学习", 10), vec![3]);
+    assert!(index.search("study missing", 10).is_empty());
+    assert!(index.search("  ", 10).is_empty());
+
+    index.remove(1, "Good good study");
+    assert_eq!(index.search("study", 10), vec![2]);
+    assert!(index.search("good", 10).is_empty());
+}
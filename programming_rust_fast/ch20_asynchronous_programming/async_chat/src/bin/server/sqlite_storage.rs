@@ -0,0 +1,283 @@
+use async_chat::utils::ChatResult;
+use async_chat::{FromServer, MessageId};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::storage::{Account, History, Storage, StoredMessage};
+
+/// 保存在 SQLite 数据库中的存储，服务端重启后可以恢复所有状态
+pub struct SqliteStorage(Mutex<Connection>);
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS accounts (
+        user_name TEXT PRIMARY KEY,
+        password_hash TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS groups (
+        name TEXT PRIMARY KEY,
+        next_message_id INTEGER NOT NULL DEFAULT 1
+    );
+    CREATE TABLE IF NOT EXISTS memberships (
+        group_name TEXT NOT NULL,
+        user_name TEXT NOT NULL,
+        PRIMARY KEY (group_name, user_name)
+    );
+    CREATE TABLE IF NOT EXISTS messages (
+        group_name TEXT NOT NULL,
+        id INTEGER NOT NULL,
+        sender TEXT NOT NULL,
+        message TEXT NOT NULL,
+        posted_at INTEGER NOT NULL,
+        PRIMARY KEY (group_name, id)
+    );
+    CREATE TABLE IF NOT EXISTS reactions (
+        group_name TEXT NOT NULL,
+        message_id INTEGER NOT NULL,
+        user_name TEXT NOT NULL,
+        emoji TEXT NOT NULL,
+        PRIMARY KEY (group_name, message_id, user_name, emoji)
+    );
+    CREATE TABLE IF NOT EXISTS offline_messages (
+        user_name TEXT NOT NULL,
+        packet TEXT NOT NULL
+    );
+";
+
+impl SqliteStorage {
+    /// 打开数据库文件，第一次使用时创建表
+    pub fn open(path: impl AsRef<Path>) -> ChatResult<SqliteStorage> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(SCHEMA)?;
+        Ok(SqliteStorage(Mutex::new(connection)))
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn account(&self, user_name: &str) -> ChatResult<Option<Account>> {
+        let connection = self.0.lock().unwrap();
+        let password_hash = connection.query_row(
+            "SELECT password_hash FROM accounts WHERE user_name = ?1",
+            params![user_name],
+            |row| row.get(0))
+            .optional()?;
+        Ok(password_hash.map(|password_hash| Account {
+            user_name: user_name.to_string(),
+            password_hash,
+        }))
+    }
+
+    fn create_account(&self, account: &Account) -> ChatResult<()> {
+        let connection = self.0.lock().unwrap();
+        connection.execute(
+            "INSERT INTO accounts (user_name, password_hash) VALUES (?1, ?2)",
+            params![account.user_name, account.password_hash])?;
+        Ok(())
+    }
+
+    fn groups(&self) -> ChatResult<Vec<Arc<String>>> {
+        let connection = self.0.lock().unwrap();
+        let mut statement = connection.prepare("SELECT name FROM groups ORDER BY name")?;
+        let names = statement.query_map([], |row| row.get::<_, String>(0))?
+            .map(|name| name.map(Arc::new))
+            .collect::<Result<_, _>>()?;
+        Ok(names)
+    }
+
+    fn create_group(&self, group_name: &str) -> ChatResult<()> {
+        let connection = self.0.lock().unwrap();
+        connection.execute("INSERT OR IGNORE INTO groups (name) VALUES (?1)",
+                           params![group_name])?;
+        Ok(())
+    }
+
+    fn add_member(&self, group_name: &str, user_name: &str) -> ChatResult<()> {
+        let connection = self.0.lock().unwrap();
+        connection.execute(
+            "INSERT OR IGNORE INTO memberships (group_name, user_name) VALUES (?1, ?2)",
+            params![group_name, user_name])?;
+        Ok(())
+    }
+
+    fn delete_group(&self, group_name: &str) -> ChatResult<()> {
+        let mut connection = self.0.lock().unwrap();
+        let transaction = connection.transaction()?;
+        for statement in ["DELETE FROM reactions WHERE group_name = ?1",
+                          "DELETE FROM messages WHERE group_name = ?1",
+                          "DELETE FROM memberships WHERE group_name = ?1",
+                          // 还没有补发的群组消息
+                          "DELETE FROM offline_messages \
+                           WHERE json_extract(packet, '$.Message.group_name') = ?1",
+                          "DELETE FROM groups WHERE name = ?1"] {
+            transaction.execute(statement, params![group_name])?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn memberships(&self, user_name: &str) -> ChatResult<Vec<Arc<String>>> {
+        let connection = self.0.lock().unwrap();
+        let mut statement = connection.prepare(
+            "SELECT group_name FROM memberships WHERE user_name = ?1 ORDER BY group_name")?;
+        let names = statement.query_map(params![user_name], |row| row.get::<_, String>(0))?
+            .map(|name| name.map(Arc::new))
+            .collect::<Result<_, _>>()?;
+        Ok(names)
+    }
+
+    fn history(&self, group_name: &str) -> ChatResult<History> {
+        let connection = self.0.lock().unwrap();
+        let next_id: i64 = connection.query_row(
+            "SELECT next_message_id FROM groups WHERE name = ?1",
+            params![group_name],
+            |row| row.get(0))?;
+        let mut history = History {
+            next_id: next_id as MessageId,
+            messages: Default::default(),
+        };
+
+        let mut statement = connection.prepare(
+            "SELECT id, sender, message, posted_at FROM messages WHERE group_name = ?1")?;
+        let mut rows = statement.query(params![group_name])?;
+        while let Some(row) = rows.next()? {
+            history.messages.insert(row.get::<_, i64>(0)? as MessageId, StoredMessage {
+                sender: Arc::new(row.get(1)?),
+                message: Arc::new(row.get(2)?),
+                posted_at: row.get::<_, i64>(3)? as u64,
+                reactions: Vec::new(),
+            });
+        }
+
+        let mut statement = connection.prepare(
+            "SELECT message_id, user_name, emoji FROM reactions WHERE group_name = ?1 \
+             ORDER BY rowid")?;
+        let mut rows = statement.query(params![group_name])?;
+        while let Some(row) = rows.next()? {
+            let id = row.get::<_, i64>(0)? as MessageId;
+            if let Some(stored) = history.messages.get_mut(&id) {
+                stored.reactions.push((Arc::new(row.get(1)?), Arc::new(row.get(2)?)));
+            }
+        }
+        Ok(history)
+    }
+
+    fn insert_message(&self, group_name: &str, id: MessageId, message: &StoredMessage)
+        -> ChatResult<()> {
+        let mut connection = self.0.lock().unwrap();
+        let transaction = connection.transaction()?;
+        transaction.execute(
+            "INSERT INTO messages (group_name, id, sender, message, posted_at) \
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![group_name, id as i64, message.sender.as_str(),
+                    message.message.as_str(), message.posted_at as i64])?;
+        transaction.execute(
+            "UPDATE groups SET next_message_id = MAX(next_message_id, ?2) WHERE name = ?1",
+            params![group_name, id as i64 + 1])?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn update_message(&self, group_name: &str, id: MessageId, message: &str) -> ChatResult<()> {
+        let connection = self.0.lock().unwrap();
+        connection.execute(
+            "UPDATE messages SET message = ?3 WHERE group_name = ?1 AND id = ?2",
+            params![group_name, id as i64, message])?;
+        Ok(())
+    }
+
+    fn delete_message(&self, group_name: &str, id: MessageId) -> ChatResult<()> {
+        let mut connection = self.0.lock().unwrap();
+        let transaction = connection.transaction()?;
+        transaction.execute("DELETE FROM messages WHERE group_name = ?1 AND id = ?2",
+                            params![group_name, id as i64])?;
+        transaction.execute("DELETE FROM reactions WHERE group_name = ?1 AND message_id = ?2",
+                            params![group_name, id as i64])?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn add_reaction(&self, group_name: &str, id: MessageId, user_name: &str, emoji: &str)
+        -> ChatResult<()> {
+        let connection = self.0.lock().unwrap();
+        connection.execute(
+            "INSERT OR IGNORE INTO reactions (group_name, message_id, user_name, emoji) \
+             VALUES (?1, ?2, ?3, ?4)",
+            params![group_name, id as i64, user_name, emoji])?;
+        Ok(())
+    }
+
+    fn enqueue_offline(&self, user_name: &str, packet: &FromServer) -> ChatResult<()> {
+        let connection = self.0.lock().unwrap();
+        connection.execute(
+            "INSERT INTO offline_messages (user_name, packet) VALUES (?1, ?2)",
+            params![user_name, serde_json::to_string(packet)?])?;
+        Ok(())
+    }
+
+    fn take_offline(&self, user_name: &str) -> ChatResult<Vec<FromServer>> {
+        let mut connection = self.0.lock().unwrap();
+        let transaction = connection.transaction()?;
+        let packets = {
+            let mut statement = transaction.prepare(
+                "SELECT packet FROM offline_messages WHERE user_name = ?1 ORDER BY rowid")?;
+            let mut rows = statement.query(params![user_name])?;
+            let mut packets = Vec::new();
+            while let Some(row) = rows.next()? {
+                packets.push(serde_json::from_str(&row.get::<_, String>(0)?)?);
+            }
+            packets
+        };
+        transaction.execute("DELETE FROM offline_messages WHERE user_name = ?1",
+                            params![user_name])?;
+        transaction.commit()?;
+        Ok(packets)
+    }
+}
+
+#[test]
+fn test_history_survives_reopen() {
+    let path = std::env::temp_dir().join(format!("async_chat_test_{}.db", std::process::id()));
+    let _ignored = std::fs::remove_file(&path);
+
+    {
+        let storage = SqliteStorage::open(&path).unwrap();
+        storage.create_group("students").unwrap();
+        storage.add_member("students", "alice").unwrap();
+        let message = StoredMessage {
+            sender: Arc::new("alice".to_string()),
+            message: Arc::new("good good study".to_string()),
+            posted_at: 1,
+            reactions: Vec::new(),
+        };
+        storage.insert_message("students", 1, &message).unwrap();
+        storage.insert_message("students", 2, &message).unwrap();
+        storage.add_reaction("students", 1, "bob", "👍").unwrap();
+        storage.delete_message("students", 2).unwrap();
+        storage.create_group("teachers").unwrap();
+        storage.add_member("teachers", "alice").unwrap();
+        let mention = |group_name: &str| FromServer::Message {
+            group_name: Arc::new(group_name.to_string()),
+            id: 1,
+            sender: Arc::new("alice".to_string()),
+            message: Arc::new("@bob".to_string()),
+            offline: true,
+        };
+        storage.enqueue_offline("bob", &mention("teachers")).unwrap();
+        storage.enqueue_offline("bob", &mention("students")).unwrap();
+        storage.delete_group("teachers").unwrap();
+    }
+
+    let storage = SqliteStorage::open(&path).unwrap();
+    assert_eq!(storage.groups().unwrap(), vec![Arc::new("students".to_string())]);
+    assert_eq!(storage.memberships("alice").unwrap().len(), 1);
+    let history = storage.history("students").unwrap();
+    // 被删除的消息 id 不会被重新使用
+    assert_eq!(history.next_id, 3);
+    assert_eq!(history.messages.len(), 1);
+    assert_eq!(history.messages[&1].reactions.len(), 1);
+    // 被删除的群组中的消息不再补发
+    let offline = storage.take_offline("bob").unwrap();
+    assert!(matches!(&offline[..],
+                     [FromServer::Message { group_name, .. }] if group_name.as_str() == "students"));
+    std::fs::remove_file(&path).unwrap();
+}
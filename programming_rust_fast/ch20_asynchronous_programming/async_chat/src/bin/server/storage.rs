@@ -0,0 +1,200 @@
+use async_chat::utils::{ChatError, ChatResult};
+use async_chat::{FromServer, MessageId};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+
+/// 存储出错时返回给客户端的错误信息
+pub fn storage_error(error: ChatError) -> String {
+    format!("Storage error: {}", error)
+}
+
+/// 群组中保存的一条历史消息
+#[derive(Clone)]
+pub struct StoredMessage {
+    pub sender: Arc<String>,
+    pub message: Arc<String>,
+    /// 发布时间，UNIX 时间戳，单位为秒
+    pub posted_at: u64,
+    pub reactions: Vec<(Arc<String>, Arc<String>)>,
+}
+
+/// 群组的消息历史，按照消息 id 排序
+#[derive(Clone)]
+pub struct History {
+    pub next_id: MessageId,
+    pub messages: BTreeMap<MessageId, StoredMessage>,
+}
+
+impl History {
+    pub fn new() -> History {
+        History {
+            next_id: 1,
+            messages: BTreeMap::new(),
+        }
+    }
+}
+
+/// 用户账号，密码只保存 argon2 哈希
+pub struct Account {
+    pub user_name: String,
+    pub password_hash: String,
+}
+
+/// 服务端状态的存储后端：账号、群组、群组成员以及消息历史，
+/// 服务端重启时从存储中恢复群组和历史
+pub trait Storage: Send + Sync {
+    fn account(&self, user_name: &str) -> ChatResult<Option<Account>>;
+    /// 创建账号，同名账号已经存在时返回错误
+    fn create_account(&self, account: &Account) -> ChatResult<()>;
+
+    fn groups(&self) -> ChatResult<Vec<Arc<String>>>;
+    fn create_group(&self, group_name: &str) -> ChatResult<()>;
+    fn add_member(&self, group_name: &str, user_name: &str) -> ChatResult<()>;
+    /// 删除群组以及群组的成员关系和消息历史
+    fn delete_group(&self, group_name: &str) -> ChatResult<()>;
+    /// 用户加入过的所有群组
+    fn memberships(&self, user_name: &str) -> ChatResult<Vec<Arc<String>>>;
+
+    fn history(&self, group_name: &str) -> ChatResult<History>;
+    fn insert_message(&self, group_name: &str, id: MessageId, message: &StoredMessage)
+        -> ChatResult<()>;
+    fn update_message(&self, group_name: &str, id: MessageId, message: &str) -> ChatResult<()>;
+    fn delete_message(&self, group_name: &str, id: MessageId) -> ChatResult<()>;
+    fn add_reaction(&self, group_name: &str, id: MessageId, user_name: &str, emoji: &str)
+        -> ChatResult<()>;
+
+    /// 保存用户不在线时需要补发的消息
+    fn enqueue_offline(&self, user_name: &str, packet: &FromServer) -> ChatResult<()>;
+    /// 取出并删除用户所有待补发的消息，按照保存的顺序返回
+    fn take_offline(&self, user_name: &str) -> ChatResult<Vec<FromServer>>;
+}
+
+/// 只保存在内存中的存储，服务端重启后所有状态都会丢失
+pub struct MemoryStorage(Mutex<MemoryData>);
+
+#[derive(Default)]
+struct MemoryData {
+    accounts: HashMap<String, String>,
+    groups: BTreeMap<String, History>,
+    memberships: BTreeSet<(String, String)>,
+    offline: HashMap<String, Vec<FromServer>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> MemoryStorage {
+        MemoryStorage(Mutex::new(MemoryData::default()))
+    }
+
+    /// 对群组的历史进行修改，群组不存在时返回错误
+    fn with_history<T>(&self, group_name: &str, f: impl FnOnce(&mut History) -> T)
+        -> ChatResult<T> {
+        let mut data = self.0.lock().unwrap();
+        let history = data.groups.get_mut(group_name)
+            .ok_or_else(|| format!("group {} is not stored", group_name))?;
+        Ok(f(history))
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn account(&self, user_name: &str) -> ChatResult<Option<Account>> {
+        let data = self.0.lock().unwrap();
+        Ok(data.accounts.get(user_name).map(|password_hash| Account {
+            user_name: user_name.to_string(),
+            password_hash: password_hash.clone(),
+        }))
+    }
+
+    fn create_account(&self, account: &Account) -> ChatResult<()> {
+        let mut data = self.0.lock().unwrap();
+        if data.accounts.contains_key(&account.user_name) {
+            return Err(format!("account {} already exists", account.user_name).into());
+        }
+        data.accounts.insert(account.user_name.clone(), account.password_hash.clone());
+        Ok(())
+    }
+
+    fn groups(&self) -> ChatResult<Vec<Arc<String>>> {
+        let data = self.0.lock().unwrap();
+        Ok(data.groups.keys().map(|name| Arc::new(name.clone())).collect())
+    }
+
+    fn create_group(&self, group_name: &str) -> ChatResult<()> {
+        let mut data = self.0.lock().unwrap();
+        data.groups.entry(group_name.to_string()).or_insert_with(History::new);
+        Ok(())
+    }
+
+    fn add_member(&self, group_name: &str, user_name: &str) -> ChatResult<()> {
+        let mut data = self.0.lock().unwrap();
+        data.memberships.insert((user_name.to_string(), group_name.to_string()));
+        Ok(())
+    }
+
+    fn delete_group(&self, group_name: &str) -> ChatResult<()> {
+        let mut data = self.0.lock().unwrap();
+        data.groups.remove(group_name);
+        data.memberships.retain(|(_, member_of)| member_of != group_name);
+        for packets in data.offline.values_mut() {
+            packets.retain(|packet| match packet {
+                FromServer::Message { group_name: queued, .. } => queued.as_str() != group_name,
+                _ => true,
+            });
+        }
+        Ok(())
+    }
+
+    fn memberships(&self, user_name: &str) -> ChatResult<Vec<Arc<String>>> {
+        let data = self.0.lock().unwrap();
+        Ok(data.memberships.iter()
+            .filter(|(member, _)| member == user_name)
+            .map(|(_, group_name)| Arc::new(group_name.clone()))
+            .collect())
+    }
+
+    fn history(&self, group_name: &str) -> ChatResult<History> {
+        self.with_history(group_name, |history| history.clone())
+    }
+
+    fn insert_message(&self, group_name: &str, id: MessageId, message: &StoredMessage)
+        -> ChatResult<()> {
+        self.with_history(group_name, |history| {
+            history.messages.insert(id, message.clone());
+            history.next_id = history.next_id.max(id + 1);
+        })
+    }
+
+    fn update_message(&self, group_name: &str, id: MessageId, message: &str) -> ChatResult<()> {
+        self.with_history(group_name, |history| {
+            if let Some(stored) = history.messages.get_mut(&id) {
+                stored.message = Arc::new(message.to_string());
+            }
+        })
+    }
+
+    fn delete_message(&self, group_name: &str, id: MessageId) -> ChatResult<()> {
+        self.with_history(group_name, |history| {
+            history.messages.remove(&id);
+        })
+    }
+
+    fn add_reaction(&self, group_name: &str, id: MessageId, user_name: &str, emoji: &str)
+        -> ChatResult<()> {
+        self.with_history(group_name, |history| {
+            if let Some(stored) = history.messages.get_mut(&id) {
+                stored.reactions.push((Arc::new(user_name.to_string()),
+                                       Arc::new(emoji.to_string())));
+            }
+        })
+    }
+
+    fn enqueue_offline(&self, user_name: &str, packet: &FromServer) -> ChatResult<()> {
+        let mut data = self.0.lock().unwrap();
+        data.offline.entry(user_name.to_string()).or_default().push(packet.clone());
+        Ok(())
+    }
+
+    fn take_offline(&self, user_name: &str) -> ChatResult<Vec<FromServer>> {
+        let mut data = self.0.lock().unwrap();
+        Ok(data.offline.remove(user_name).unwrap_or_default())
+    }
+}
@@ -0,0 +1,131 @@
+//! 端到端加密群组使用的加解密工具
+//!
+//! 群组成员共享一个对称的群组密钥，消息在客户端使用 ChaCha20-Poly1305 加密后
+//! 以 `e2e:` 开头的字符串放进 `FromClient::Post` 的 message 字段，
+//! 服务端只是原样保存和转发，无法读取内容。
+//!
+//! 群组密钥通过 X25519 在成员之间交换：
+//! 持有密钥的成员用自己的私钥和对方公钥协商出共享密钥，再用它加密群组密钥发给对方。
+
+use crate::utils::{self, ChatResult};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// 加密消息的前缀
+pub const SEALED_PREFIX: &str = "e2e:";
+
+/// 群组共享的对称密钥
+pub type GroupKey = [u8; 32];
+
+const NONCE_SIZE: usize = 12;
+
+/// 生成新的 X25519 身份密钥
+pub fn generate_identity() -> StaticSecret {
+    StaticSecret::random_from_rng(OsRng)
+}
+
+/// 生成新的随机群组密钥
+pub fn generate_group_key() -> GroupKey {
+    ChaCha20Poly1305::generate_key(&mut OsRng).into()
+}
+
+/// 消息是否是加密过的
+pub fn is_sealed(message: &str) -> bool {
+    message.starts_with(SEALED_PREFIX)
+}
+
+/// 使用群组密钥加密消息，群组名作为附加数据，防止密文被转发到其他群组
+pub fn seal(key: &GroupKey, group_name: &str, plaintext: &str) -> String {
+    seal_bytes(key, group_name, plaintext.as_bytes())
+}
+
+/// 解密 seal 生成的消息
+pub fn open(key: &GroupKey, group_name: &str, sealed: &str) -> ChatResult<String> {
+    let plaintext = open_bytes(key, group_name, sealed)?;
+    Ok(String::from_utf8(plaintext)?)
+}
+
+/// 使用与对方协商出的共享密钥加密群组密钥
+pub fn wrap_key(identity: &StaticSecret, peer: &PublicKey,
+                group_name: &str, group_key: &GroupKey) -> String {
+    seal_bytes(&shared_key(identity, peer), group_name, group_key)
+}
+
+/// 解密对方用 wrap_key 加密的群组密钥
+pub fn unwrap_key(identity: &StaticSecret, peer: &PublicKey,
+                  group_name: &str, wrapped: &str) -> ChatResult<GroupKey> {
+    let bytes = open_bytes(&shared_key(identity, peer), group_name, wrapped)?;
+    let key = bytes.try_into()
+        .map_err(|_| "wrapped group key has wrong length")?;
+    Ok(key)
+}
+
+/// 公钥的十六进制表示，用于在协议中传输
+pub fn encode_public_key(public: &PublicKey) -> String {
+    hex::encode(public.as_bytes())
+}
+
+pub fn decode_public_key(public: &str) -> ChatResult<PublicKey> {
+    let bytes: [u8; 32] = hex::decode(public)?.try_into()
+        .map_err(|_| "public key has wrong length")?;
+    Ok(PublicKey::from(bytes))
+}
+
+/// 公钥指纹，服务端可以替换转发的公钥，用户可以通过其他渠道比对指纹确认对方身份
+pub fn fingerprint(public: &PublicKey) -> String {
+    hex::encode(&Sha256::digest(public.as_bytes())[..8])
+}
+
+fn shared_key(identity: &StaticSecret, peer: &PublicKey) -> GroupKey {
+    let shared = identity.diffie_hellman(peer);
+    let mut hasher = Sha256::new();
+    hasher.update(b"async_chat e2e key wrap");
+    hasher.update(shared.as_bytes());
+    hasher.finalize().into()
+}
+
+fn seal_bytes(key: &GroupKey, group_name: &str, plaintext: &[u8]) -> String {
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let payload = Payload { msg: plaintext, aad: group_name.as_bytes() };
+    let ciphertext = cipher.encrypt(&nonce, payload)
+        .expect("encrypting in memory does not fail");
+
+    let mut sealed = nonce.to_vec();
+    sealed.extend_from_slice(&ciphertext);
+    format!("{}{}", SEALED_PREFIX, utils::encode_chunk(&sealed))
+}
+
+fn open_bytes(key: &GroupKey, group_name: &str, sealed: &str) -> ChatResult<Vec<u8>> {
+    let encoded = sealed.strip_prefix(SEALED_PREFIX)
+        .ok_or("message is not encrypted")?;
+    let bytes = utils::decode_chunk(encoded)?;
+    if bytes.len() < NONCE_SIZE {
+        return Err("encrypted message is too short".into());
+    }
+
+    let (nonce, ciphertext) = bytes.split_at(NONCE_SIZE);
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+    let payload = Payload { msg: ciphertext, aad: group_name.as_bytes() };
+    cipher.decrypt(Nonce::from_slice(nonce), payload)
+        .map_err(|_| "failed to decrypt message".into())
+}
+
+#[test]
+fn test_group_key_exchange() {
+    let alice = generate_identity();
+    let bob = generate_identity();
+    let group_key = generate_group_key();
+
+    // alice 把群组密钥加密后发给 bob，bob 用自己的私钥和 alice 的公钥解密
+    let wrapped = wrap_key(&alice, &PublicKey::from(&bob), "students", &group_key);
+    let received = unwrap_key(&bob, &PublicKey::from(&alice), "students", &wrapped).unwrap();
+    assert_eq!(received, group_key);
+
+    let sealed = seal(&received, "students", "good good study");
+    assert!(is_sealed(&sealed));
+    assert_eq!(open(&group_key, "students", &sealed).unwrap(), "good good study");
+    assert!(open(&group_key, "teachers", &sealed).is_err());
+}
@@ -1,26 +1,225 @@
+pub mod e2e;
 pub mod utils;
 
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 
+/// 消息在所属群组内的唯一编号，由服务端在消息发布时分配
+pub type MessageId = u64;
+
+/// 附件的编号，即附件内容 SHA-256 摘要的十六进制字符串
+pub type AttachmentId = String;
+
+/// 上传的编号，由客户端为每次上传分配，在同一个连接内唯一
+pub type UploadId = u64;
+
+/// 上传和下载附件时每个分块的最大字节数
+pub const ATTACHMENT_CHUNK_SIZE: usize = 32 * 1024;
+
+/// 当前的协议版本，协议出现不兼容的修改时递增
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// 当前版本支持的可选功能，在 Hello 中告知对方
+pub const FEATURES: &[&str] = &["edit", "attachments", "e2e", "search"];
+
+/// 双方都支持的协议版本，对方版本比自己新时使用自己的版本
+pub fn negotiate_version(peer_version: u32) -> Result<u32, String> {
+    if peer_version == 0 {
+        return Err(format!("Unsupported protocol version {}", peer_version));
+    }
+    Ok(peer_version.min(PROTOCOL_VERSION))
+}
+
+/// 双方都支持的功能
+pub fn common_features(peer_features: &[String]) -> Vec<String> {
+    peer_features.iter()
+        .filter(|feature| FEATURES.contains(&feature.as_str()))
+        .cloned()
+        .collect()
+}
+
 #[derive(Debug, Deserialize, Serialize, PartialEq)]
 pub enum FromClient {
+    /// 连接建立后客户端发送的第一个请求，告知服务端自己的协议版本和支持的功能
+    Hello {
+        protocol_version: u32,
+        features: Vec<String>,
+    },
+    /// 使用账号登录，账号不存在时使用这个密码注册，登录后自动加入以前加入过的群组
+    Login {
+        user_name: Arc<String>,
+        password: String,
+    },
     Join {group_name: Arc<String>},
     Post {
         group_name: Arc<String>,
         message: Arc<String>,
-    }
+    },
+    /// 发给某个用户的私信，对方不在线时会在下次登录时收到
+    Direct {
+        recipient: Arc<String>,
+        message: Arc<String>,
+    },
+    /// 修改自己发布过的消息
+    Edit {
+        group_name: Arc<String>,
+        id: MessageId,
+        message: Arc<String>,
+    },
+    /// 删除自己发布过的消息
+    Delete {
+        group_name: Arc<String>,
+        id: MessageId,
+    },
+    /// 对群组中的消息添加表情回应
+    React {
+        group_name: Arc<String>,
+        id: MessageId,
+        emoji: Arc<String>,
+    },
+    /// 搜索群组的历史消息，返回包含 query 中所有词的最近 limit 条消息
+    Search {
+        group_name: Arc<String>,
+        query: Arc<String>,
+        limit: usize,
+    },
+    /// 开始上传附件，size 和 sha256 用于在上传完成时校验文件内容
+    UploadStart {
+        upload_id: UploadId,
+        group_name: Arc<String>,
+        file_name: Arc<String>,
+        size: u64,
+        sha256: String,
+    },
+    /// 附件的一个分块，data 是 base64 编码的文件内容
+    UploadChunk {
+        upload_id: UploadId,
+        offset: u64,
+        data: String,
+    },
+    /// 所有分块发送完毕
+    UploadFinish {
+        upload_id: UploadId,
+    },
+    /// 请求下载附件
+    Download {
+        attachment_id: AttachmentId,
+    },
+    /// 在端到端加密群组中公布自己的 X25519 公钥，请求持有群组密钥的成员发送密钥
+    AnnounceKey {
+        group_name: Arc<String>,
+        public_key: String,
+    },
+    /// 将加密后的群组密钥发给公钥为 recipient_key 的成员
+    ShareKey {
+        group_name: Arc<String>,
+        sender_key: String,
+        recipient_key: String,
+        wrapped_key: String,
+    },
 }
 
-#[derive(Debug, Deserialize, Serialize, PartialEq)]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
 pub enum FromServer {
+    /// 对客户端 Hello 的回复，包含协商后的协议版本和双方都支持的功能
+    Hello {
+        protocol_version: u32,
+        features: Vec<String>,
+    },
+    /// 登录成功
+    LoggedIn {
+        user_name: Arc<String>,
+    },
     Message {
         group_name: Arc<String>,
+        id: MessageId,
+        sender: Arc<String>,
+        message: Arc<String>,
+        /// 用户不在线时收到的消息，在登录后补发，
+        /// 字段为 false 时不序列化，与旧版本的数据帧保持兼容
+        #[serde(default, skip_serializing_if = "is_false")]
+        offline: bool,
+    },
+    /// 其他用户发来的私信
+    Direct {
+        sender: Arc<String>,
+        message: Arc<String>,
+        #[serde(default, skip_serializing_if = "is_false")]
+        offline: bool,
+    },
+    /// 已经发布的消息内容被作者修改
+    Edited {
+        group_name: Arc<String>,
+        id: MessageId,
+        message: Arc<String>,
+    },
+    /// 已经发布的消息被作者删除
+    Deleted {
+        group_name: Arc<String>,
+        id: MessageId,
+    },
+    /// 已经发布的消息收到了表情回应
+    Reacted {
+        group_name: Arc<String>,
+        id: MessageId,
+        sender: Arc<String>,
+        emoji: Arc<String>,
+    },
+    /// 对 Search 请求的回复，消息按照从新到旧的顺序排列
+    SearchResults {
+        group_name: Arc<String>,
+        query: Arc<String>,
+        results: Vec<SearchResult>,
+    },
+    /// 群组中有人上传了附件，客户端可以使用 attachment_id 下载
+    Attachment {
+        group_name: Arc<String>,
+        sender: Arc<String>,
+        attachment_id: AttachmentId,
+        file_name: Arc<String>,
+        size: u64,
+    },
+    /// 下载附件时服务端返回的一个分块，data 是 base64 编码的文件内容
+    AttachmentChunk {
+        attachment_id: AttachmentId,
+        offset: u64,
+        data: String,
+        last: bool,
+    },
+    /// 群组成员公布了公钥
+    KeyAnnounced {
+        group_name: Arc<String>,
+        sender: Arc<String>,
+        public_key: String,
+    },
+    /// 群组成员发送了加密的群组密钥，只有公钥为 recipient_key 的成员能够解密
+    KeyShared {
+        group_name: Arc<String>,
+        sender_key: String,
+        recipient_key: String,
+        wrapped_key: String,
+    },
+    /// 服务端管理员发布的公告
+    Announcement {
         message: Arc<String>,
     },
     Error(String),
 }
 
+/// 搜索结果中的一条历史消息
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct SearchResult {
+    pub id: MessageId,
+    pub sender: Arc<String>,
+    pub message: Arc<String>,
+    /// 发布时间，UNIX 时间戳，单位为秒
+    pub posted_at: u64,
+}
+
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
 #[test]
 fn test_from_client_json() {
     let from_client = FromClient::Post {
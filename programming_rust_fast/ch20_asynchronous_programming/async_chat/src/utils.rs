@@ -1,8 +1,11 @@
 use std::error::Error;
+use std::fmt;
 use serde::de::DeserializeOwned;
 use async_std::prelude::*;
 use serde::Serialize;
 use std::marker::Unpin;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
 
 pub type ChatError = Box<dyn Error + Send + Sync + 'static>;
 pub type ChatResult<T> = Result<T, ChatError>;
@@ -18,7 +21,25 @@ where   S: async_std::io::Write + Unpin,
     Ok(())
 }
 
-/// 对接收到的数据进行反序列化
+/// 收到的一行数据无法解析为协议中的消息，
+/// 通常是对端使用了更新版本的协议，发送了自己不认识的变体。
+/// 这种错误只影响这一行数据，调用方可以跳过它继续读取后续的数据
+#[derive(Debug)]
+pub struct UnknownPacket {
+    pub line: String,
+    pub error: serde_json::Error,
+}
+
+impl fmt::Display for UnknownPacket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown packet {:?}: {}", self.line, self.error)
+    }
+}
+
+impl Error for UnknownPacket {}
+
+/// 对接收到的数据进行反序列化，
+/// 无法解析的行产生 UnknownPacket 错误，读取连接时的 io 错误则原样返回
 pub fn receive_as_json<S, P>(inbound: S) -> impl Stream<Item = ChatResult<P>>
 where   S: async_std::io::BufRead + Unpin,
         P: DeserializeOwned
@@ -26,7 +47,43 @@ where   S: async_std::io::BufRead + Unpin,
     inbound.lines()
         .map(|line_result| -> ChatResult<P> {
             let line = line_result?;
-            let parsed = serde_json::from_str::<P>(&line)?;
-            Ok(parsed)
+            match serde_json::from_str::<P>(&line) {
+                Ok(parsed) => Ok(parsed),
+                Err(error) => Err(Box::new(UnknownPacket { line, error })),
+            }
         })
-}
\ No newline at end of file
+}
+
+/// 错误是否只是一行无法解析的数据，而不是连接本身出了问题
+pub fn as_unknown_packet(error: &ChatError) -> Option<&UnknownPacket> {
+    error.downcast_ref::<UnknownPacket>()
+}
+
+/// 消息中 @ 提到的用户名
+pub fn mentions(message: &str) -> impl Iterator<Item = &str> {
+    message.split(|c: char| !(c.is_alphanumeric() || "@_-.".contains(c)))
+        .filter_map(|word| word.strip_prefix('@'))
+        .map(|name| name.trim_end_matches('.'))
+        .filter(|name| !name.is_empty() && !name.contains('@'))
+}
+
+/// 将附件分块编码成可以放进 json 的字符串
+pub fn encode_chunk(data: &[u8]) -> String {
+    STANDARD.encode(data)
+}
+
+/// 将 json 中的附件分块还原为字节
+pub fn decode_chunk(data: &str) -> ChatResult<Vec<u8>> {
+    Ok(STANDARD.decode(data)?)
+}
+
+/// 附件 id 是 SHA-256 摘要的十六进制字符串，用于检查 id 能否安全地作为文件名
+pub fn is_attachment_id(id: &str) -> bool {
+    id.len() == 64 && id.bytes().all(|b| b.is_ascii_hexdigit() && !b.is_ascii_uppercase())
+}
+
+#[test]
+fn test_mentions() {
+    let names: Vec<&str> = mentions("@alice, ask @bob.smith about it. mail a@b.c @ @carol.").collect();
+    assert_eq!(names, vec!["alice", "bob.smith", "carol"]);
+}
@@ -0,0 +1,45 @@
+//! 协议兼容性测试：tests/frames 下保存了各个协议版本录制的 json 数据帧，
+//! 修改 FromClient / FromServer 之后这些数据帧必须仍然能够被解析，
+//! 并且重新序列化之后与录制的内容完全一致。
+//! 新增变体时在对应版本的文件中追加数据帧。
+
+use async_chat::utils::{self, ChatResult};
+use async_chat::{FromClient, FromServer};
+use async_std::prelude::*;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+fn assert_round_trip<P>(frames: &str)
+where P: DeserializeOwned + Serialize
+{
+    for frame in frames.lines() {
+        let packet: P = serde_json::from_str(frame)
+            .unwrap_or_else(|error| panic!("failed to parse {}: {}", frame, error));
+        assert_eq!(serde_json::to_string(&packet).unwrap(), frame);
+    }
+}
+
+#[test]
+fn test_v1_from_client_frames() {
+    assert_round_trip::<FromClient>(include_str!("frames/v1/from_client.jsonl"));
+}
+
+#[test]
+fn test_v1_from_server_frames() {
+    assert_round_trip::<FromServer>(include_str!("frames/v1/from_server.jsonl"));
+}
+
+#[test]
+fn test_unknown_frames_do_not_end_the_stream() {
+    let frames = include_str!("frames/unknown.jsonl");
+    let inbound = async_std::io::Cursor::new(frames.as_bytes());
+
+    let packets: Vec<ChatResult<FromClient>> = async_std::task::block_on(
+        utils::receive_as_json(inbound).collect());
+
+    assert_eq!(packets.len(), 4);
+    assert!(matches!(packets[0], Ok(FromClient::Join { .. })));
+    assert!(utils::as_unknown_packet(packets[1].as_ref().unwrap_err()).is_some());
+    assert!(utils::as_unknown_packet(packets[2].as_ref().unwrap_err()).is_some());
+    assert!(matches!(packets[3], Ok(FromClient::Post { .. })));
+}
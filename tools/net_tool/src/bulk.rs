@@ -0,0 +1,295 @@
+//! Resolving many names at once. Queries to plain DNS servers share one UDP
+//! socket per address family, many of them in flight at a time, and
+//! responses are matched to their queries by message id.
+
+use async_std::channel::{self, Sender};
+use async_std::net::UdpSocket;
+use async_std::task;
+use rustdns::types::Type;
+use rustdns::Message;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::resolver::{ResolverConfig, Server};
+use crate::transport;
+
+/// The outcome for one host of the input.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct BulkResult {
+    pub host: String,
+    /// The response code, `None` when no server answered.
+    pub status: Option<String>,
+    /// The data of the answer records of the queried type.
+    pub answers: Vec<String>,
+    /// Why no server answered.
+    pub error: Option<String>,
+}
+
+/// The host names in `path`, one per line, skipping blank lines and `#`
+/// comments. `-` reads standard input.
+pub fn read_hosts(path: &Path) -> io::Result<Vec<String>> {
+    let content = if path == Path::new("-") {
+        let mut content = String::new();
+        io::stdin().read_to_string(&mut content)?;
+        content
+    } else {
+        fs::read_to_string(path).map_err(|error| {
+            io::Error::new(error.kind(), format!("{}: {}", path.display(), error))
+        })?
+    };
+    Ok(content.lines()
+        .map(|line| line.split('#').next().unwrap_or("").trim())
+        .filter(|host| !host.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+/// Resolve every host with at most `concurrency` queries in flight, results
+/// are in the order of `hosts`. Each query waits `config.timeout` for an
+/// answer and the server list is walked `config.attempts` times.
+pub fn resolve_all(hosts: &[String], query_type: Type, config: &ResolverConfig,
+                   concurrency: usize) -> io::Result<Vec<BulkResult>> {
+    let servers = config.servers.iter()
+        .map(|server| match server {
+            Server::Plain(address) => Ok(*address),
+            other => Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                        format!("{}: bulk queries need plain DNS servers", other))),
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    if servers.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "no DNS servers configured"));
+    }
+
+    task::block_on(async {
+        let client = Arc::new(Client::bind(servers, config.timeout, config.attempts).await?);
+        let hosts = Arc::new(hosts.to_vec());
+        let next = Arc::new(AtomicUsize::new(0));
+        let results = Arc::new(Mutex::new(HashMap::new()));
+
+        let workers: Vec<_> = (0..concurrency.clamp(1, hosts.len().max(1)))
+            .map(|_| {
+                let (client, hosts, next, results) =
+                    (client.clone(), hosts.clone(), next.clone(), results.clone());
+                task::spawn(async move {
+                    loop {
+                        let index = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(host) = hosts.get(index) else { break };
+                        let result = client.resolve(host, query_type).await;
+                        results.lock().unwrap().insert(index, result);
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.await;
+        }
+
+        let mut results = results.lock().unwrap();
+        Ok((0..hosts.len()).filter_map(|index| results.remove(&index)).collect())
+    })
+}
+
+/// A query waiting for its response.
+struct Waiting {
+    server: SocketAddr,
+    name: String,
+    response: Sender<Message>,
+}
+
+type Pending = Arc<Mutex<HashMap<u16, Waiting>>>;
+
+struct Client {
+    servers: Vec<SocketAddr>,
+    timeout: Duration,
+    attempts: u32,
+    ipv4: Option<Arc<UdpSocket>>,
+    ipv6: Option<Arc<UdpSocket>>,
+    pending: Pending,
+}
+
+impl Client {
+    /// Bind a socket for each address family of `servers` and start
+    /// receiving responses on it.
+    async fn bind(servers: Vec<SocketAddr>, timeout: Duration, attempts: u32)
+        -> io::Result<Client> {
+        let pending = Pending::default();
+        let mut client = Client { servers, timeout, attempts, ipv4: None, ipv6: None, pending };
+        if client.servers.iter().any(SocketAddr::is_ipv4) {
+            client.ipv4 = Some(client.listen("0.0.0.0:0").await?);
+        }
+        if client.servers.iter().any(SocketAddr::is_ipv6) {
+            client.ipv6 = Some(client.listen("[::]:0").await?);
+        }
+        Ok(client)
+    }
+
+    async fn listen(&self, address: &str) -> io::Result<Arc<UdpSocket>> {
+        let socket = Arc::new(UdpSocket::bind(address).await?);
+        task::spawn(receive(socket.clone(), self.pending.clone()));
+        Ok(socket)
+    }
+
+    async fn resolve(&self, host: &str, query_type: Type) -> BulkResult {
+        let mut failures = Vec::new();
+        for _attempt in 0..self.attempts.max(1) {
+            for server in &self.servers {
+                match self.exchange(host, query_type, *server).await {
+                    Ok(response) => return BulkResult {
+                        host: host.to_string(),
+                        status: Some(response.rcode.to_string()),
+                        answers: response.answers.iter()
+                            .filter(|record| record.r#type() == query_type)
+                            .map(|record| record.resource.to_string())
+                            .collect(),
+                        error: None,
+                    },
+                    Err(error) => failures.push(format!("{}: {}", server, error)),
+                }
+            }
+        }
+        BulkResult {
+            host: host.to_string(),
+            status: None,
+            answers: Vec::new(),
+            error: Some(failures.join("; ")),
+        }
+    }
+
+    /// Send one query and wait for the response with its id, truncated
+    /// responses are asked again over TCP.
+    async fn exchange(&self, host: &str, query_type: Type, server: SocketAddr)
+        -> io::Result<Message> {
+        let mut query = crate::query(host, query_type);
+        let (sender, receiver) = channel::bounded(1);
+        let waiting = Waiting {
+            server,
+            name: query.questions[0].name.to_ascii_lowercase(),
+            response: sender,
+        };
+        query.id = self.register(waiting);
+        let _registration = Registration { pending: &self.pending, id: query.id };
+
+        let question = query.to_vec()?;
+        let socket = match server {
+            SocketAddr::V4(_) => &self.ipv4,
+            SocketAddr::V6(_) => &self.ipv6,
+        };
+        socket.as_ref().expect("a socket for every server family")
+            .send_to(&question, server).await?;
+        let response = async_std::future::timeout(self.timeout, receiver.recv()).await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "timed out"))?
+            .map_err(io::Error::other)?;
+        if response.tc {
+            let (id, timeout) = (query.id, self.timeout);
+            let response = task::spawn_blocking(move || {
+                transport::tcp_exchange(server, &question, id, timeout)
+            }).await?;
+            return Message::from_slice(&response);
+        }
+        Ok(response)
+    }
+
+    /// Wait for a response under an id no other query in flight uses.
+    fn register(&self, waiting: Waiting) -> u16 {
+        let mut pending = self.pending.lock().unwrap();
+        loop {
+            let id = Message::random_id();
+            if let std::collections::hash_map::Entry::Vacant(entry) = pending.entry(id) {
+                entry.insert(waiting);
+                return id;
+            }
+        }
+    }
+}
+
+/// Stops waiting for a response when the query is done or times out.
+struct Registration<'a> {
+    pending: &'a Pending,
+    id: u16,
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        self.pending.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Hand each response arriving on `socket` to the query it answers. Late
+/// responses and datagrams from other addresses are dropped.
+async fn receive(socket: Arc<UdpSocket>, pending: Pending) {
+    let mut buffer = [0; 4096];
+    while let Ok((len, peer)) = socket.recv_from(&mut buffer).await {
+        let Ok(response) = Message::from_slice(&buffer[..len]) else { continue };
+        let pending = pending.lock().unwrap();
+        if let Some(waiting) = pending.get(&response.id) {
+            let name = response.questions.first()
+                .map(|question| question.name.to_ascii_lowercase());
+            if waiting.server == peer && name.as_ref() == Some(&waiting.name) {
+                let _ = waiting.response.try_send(response);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_resolve_all() {
+    use crate::testing;
+    use std::time::Instant;
+
+    let server = testing::spawn_udp_server(|query| {
+        let name = String::from_utf8_lossy(&query[13..]).to_string();
+        if name.starts_with("silent") {
+            None
+        } else if name.starts_with("missing") {
+            let mut response = testing::response(query, 0, &[]);
+            response[3] |= 3; // NXDOMAIN
+            Some(response)
+        } else {
+            Some(testing::response(query, 0, &[testing::a_record([192, 0, 2, 1], 300)]))
+        }
+    });
+    let config = ResolverConfig {
+        servers: vec![Server::Plain(server)],
+        timeout: Duration::from_millis(200),
+        attempts: 1,
+        ..ResolverConfig::default()
+    };
+
+    let mut hosts = vec!["www.example".to_string(), "missing.example".to_string()];
+    hosts.extend((0..20).map(|n| format!("silent{}.example", n)));
+    let started = Instant::now();
+    let results = resolve_all(&hosts, Type::A, &config, 64).unwrap();
+    // One after the other the silent hosts would take 20 timeouts.
+    assert!(started.elapsed() < Duration::from_secs(2), "{:?}", started.elapsed());
+
+    assert_eq!(results.len(), hosts.len());
+    assert_eq!(results[0], BulkResult {
+        host: "www.example".to_string(),
+        status: Some("NoError".to_string()),
+        answers: vec!["192.0.2.1".to_string()],
+        error: None,
+    });
+    assert_eq!(results[1].status.as_deref(), Some("NXDomain"));
+    assert!(results[1].answers.is_empty());
+    for (result, host) in results[2..].iter().zip(&hosts[2..]) {
+        assert_eq!(&result.host, host);
+        assert_eq!(result.status, None);
+        assert!(result.error.as_ref().unwrap().contains("timed out"));
+    }
+}
+
+#[test]
+fn test_read_hosts() {
+    let path = std::env::temp_dir()
+        .join(format!("net_tool_test_{}_hosts.txt", std::process::id()));
+    fs::write(&path, "# inventory\nweb1.example\n\n  db1.example  # primary\n").unwrap();
+    assert_eq!(read_hosts(&path).unwrap(), ["web1.example", "db1.example"]);
+    assert!(read_hosts(Path::new("/nonexistent/hosts.txt")).is_err());
+}
@@ -0,0 +1,361 @@
+use clap::builder::ArgPredicate;
+use clap::{Args, Parser, Subcommand};
+use rustdns::types::Type;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::myip::{self, Source};
+use crate::output::Format;
+use crate::provider::{GeoDb, IpInfoIo, IpInfoProvider};
+use crate::resolver::{self, ResolverConfig, Server};
+
+/// DNS and IP address lookups.
+#[derive(Debug, Parser)]
+#[command(name = "net_tool", version)]
+pub struct Cli {
+    /// How results are printed
+    #[arg(short, long, global = true, value_enum, default_value_t = Format::Text)]
+    pub format: Format,
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Query DNS records of a domain
+    Dns {
+        domain: String,
+        /// Record type to query
+        #[arg(short = 't', long = "type", default_value = "A", value_parser = parse_type)]
+        query_type: Type,
+        /// Also look up details of the resolved addresses on ipinfo.io, implied
+        /// by --geo-db
+        #[arg(long, default_value_if("geo_dbs", ArgPredicate::IsPresent, "true"))]
+        info: bool,
+        /// Validate the answer with DNSSEC up to a trust anchor
+        #[arg(long)]
+        dnssec: bool,
+        /// DS records of the trusted keys, one per line like
+        /// `. IN DS 20326 8 2 E06D...` [default: the root zone's keys]
+        #[arg(long, value_name = "PATH", requires = "dnssec")]
+        trust_anchor: Option<PathBuf>,
+        #[command(flatten)]
+        resolver: ResolverArgs,
+        #[command(flatten)]
+        provider: ProviderArgs,
+    },
+    /// Look up details of IP addresses on ipinfo.io or in a MaxMind database
+    Ipinfo {
+        #[arg(required = true)]
+        ips: Vec<IpAddr>,
+        #[command(flatten)]
+        provider: ProviderArgs,
+    },
+    /// Print the public IP address of this machine, as found by several
+    /// sources agreeing on it
+    Myip {
+        /// Ask this source, as an http(s) URL, dns:[TYPE:]NAME@SERVER or
+        /// stun:HOST[:PORT], repeat to ask several [default: the myip_sources
+        /// setting or a list of well-known services]
+        #[arg(long = "source", value_parser = myip::parse_source)]
+        sources: Vec<Source>,
+        /// Seconds to wait for each source
+        #[arg(long, default_value_t = 5.0)]
+        timeout: f64,
+        /// Sources that have to find the same address
+        #[arg(long, default_value_t = 2, value_parser = clap::value_parser!(u8).range(1..))]
+        quorum: u8,
+    },
+    /// Look up the PTR names of IP addresses
+    Ptr {
+        #[arg(required = true)]
+        ips: Vec<IpAddr>,
+        /// Check that each name resolves back to the address (FCrDNS)
+        #[arg(long)]
+        fcrdns: bool,
+        #[command(flatten)]
+        resolver: ResolverArgs,
+    },
+    /// Resolve every host name in a file, many at a time
+    Bulk {
+        /// File with one host name per line, - for standard input
+        #[arg(short, long)]
+        input: PathBuf,
+        /// Record type to query
+        #[arg(short = 't', long = "type", default_value = "A", value_parser = parse_type)]
+        query_type: Type,
+        /// Queries in flight at the same time
+        #[arg(short, long, default_value_t = 64,
+              value_parser = clap::value_parser!(u16).range(1..))]
+        concurrency: u16,
+        #[command(flatten)]
+        resolver: ResolverArgs,
+    },
+    /// Resolve a domain from the root servers down, showing each referral
+    Trace {
+        domain: String,
+        /// Record type to query
+        #[arg(short = 't', long = "type", default_value = "A", value_parser = parse_type)]
+        query_type: Type,
+        /// Start at this server instead of the root servers, as ip[:port],
+        /// repeat to give several
+        #[arg(long = "root", value_parser = parse_root)]
+        roots: Vec<SocketAddr>,
+        /// The resolver for name servers without glue, its timeout is used
+        /// for every server on the way
+        #[command(flatten)]
+        resolver: ResolverArgs,
+    },
+    /// Resolve a domain and check which of its addresses accept TCP
+    /// connections
+    Probe {
+        /// Domain name, or an IP address to probe without resolving
+        target: String,
+        /// Port to connect to, repeat to probe several
+        #[arg(short, long = "port", default_values = ["80", "443"],
+              value_parser = clap::value_parser!(u16).range(1..))]
+        ports: Vec<u16>,
+        /// Seconds to wait for each connection
+        #[arg(long = "connect-timeout", default_value_t = 2.0)]
+        connect_timeout: f64,
+        /// Connections opened at the same time
+        #[arg(short, long, default_value_t = 64,
+              value_parser = clap::value_parser!(u16).range(1..))]
+        concurrency: u16,
+        #[command(flatten)]
+        resolver: ResolverArgs,
+    },
+    /// Resolve the names in a file again and again, printing how the answers
+    /// change until interrupted
+    Watch {
+        /// File with one name per line, - for standard input
+        input: PathBuf,
+        /// Record type to query, repeat to watch several
+        #[arg(short = 't', long = "type", default_values = ["A"], value_parser = parse_type)]
+        query_types: Vec<Type>,
+        /// Time between rounds, like 30s, 5m or 1h
+        #[arg(long, default_value = "60s", value_parser = parse_interval)]
+        interval: Duration,
+        /// Stop after this many rounds
+        #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+        rounds: Option<u32>,
+        /// Also append the changes to this file as JSON Lines
+        #[arg(short, long, value_name = "PATH")]
+        output: Option<PathBuf>,
+        #[command(flatten)]
+        resolver: ResolverArgs,
+    },
+    /// Answer DNS queries on UDP and TCP, forwarding them to the resolver
+    /// and caching the answers, until interrupted
+    Serve {
+        /// Address and port to listen on
+        #[arg(short, long, default_value = "127.0.0.1:53")]
+        listen: SocketAddr,
+        /// Answer for the names in this file, in the format of /etc/hosts
+        #[arg(long, value_name = "PATH")]
+        hosts: Option<PathBuf>,
+        #[command(flatten)]
+        resolver: ResolverArgs,
+    },
+}
+
+/// Options overriding the resolver configuration from the environment
+/// and /etc/resolv.conf.
+#[derive(Debug, Args)]
+pub struct ResolverArgs {
+    /// DNS server as ip[:port], tls://ip[:port][#name] or https://host/path[{?dns}],
+    /// repeat to try several servers in order
+    #[arg(short, long = "server", value_parser = resolver::parse_server)]
+    pub servers: Vec<Server>,
+    /// Seconds to wait for each server
+    #[arg(long, value_parser = resolver::parse_timeout)]
+    pub timeout: Option<Duration>,
+    /// Times to walk the server list before giving up
+    #[arg(long)]
+    pub attempts: Option<u32>,
+    /// Query over TCP instead of UDP
+    #[arg(long)]
+    pub tcp: bool,
+    /// PEM file with extra certificates to trust for TLS and HTTPS servers
+    #[arg(long)]
+    pub ca_file: Option<PathBuf>,
+}
+
+impl ResolverArgs {
+    /// The configuration from the environment with these options applied.
+    pub fn config(&self) -> io::Result<ResolverConfig> {
+        let mut config = ResolverConfig::from_env()?;
+        if !self.servers.is_empty() {
+            config.servers = self.servers.clone();
+        }
+        if let Some(timeout) = self.timeout {
+            config.timeout = timeout;
+        }
+        if let Some(attempts) = self.attempts {
+            config.attempts = attempts;
+        }
+        config.tcp |= self.tcp;
+        if let Some(ca_file) = &self.ca_file {
+            config.ca_file = Some(ca_file.clone());
+        }
+        Ok(config)
+    }
+}
+
+/// Options for looking up IP address details.
+#[derive(Debug, Args)]
+pub struct ProviderArgs {
+    /// Query ipinfo.io without the token from NET_TOOL_IPINFO_TOKEN or the
+    /// configuration file
+    #[arg(long)]
+    pub no_token: bool,
+    /// Look up details offline in a MaxMind database (GeoLite2 City or ASN)
+    /// instead of on ipinfo.io, repeat to combine a City and an ASN database
+    #[arg(long = "geo-db", value_name = "PATH")]
+    pub geo_dbs: Vec<PathBuf>,
+}
+
+impl ProviderArgs {
+    pub fn provider(&self) -> io::Result<Box<dyn IpInfoProvider>> {
+        if !self.geo_dbs.is_empty() {
+            return Ok(Box::new(GeoDb::open(&self.geo_dbs)?));
+        }
+        Ok(Box::new(IpInfoIo::from_env(self.no_token)?))
+    }
+}
+
+fn parse_root(root: &str) -> Result<SocketAddr, String> {
+    match resolver::parse_server(root) {
+        Ok(Server::Plain(address)) => Ok(address),
+        Ok(_) => Err("expected ip[:port], the trace uses plain DNS".to_string()),
+        Err(error) => Err(error.to_string()),
+    }
+}
+
+/// A number with the unit `s`, `m` or `h`, seconds without one.
+fn parse_interval(interval: &str) -> Result<Duration, String> {
+    let (number, unit) = match interval.find(|c: char| c.is_ascii_alphabetic()) {
+        Some(index) => interval.split_at(index),
+        None => (interval, "s"),
+    };
+    let scale = match unit {
+        "s" => 1.0,
+        "m" => 60.0,
+        "h" => 3600.0,
+        _ => return Err(format!("unknown unit {:?}, expected s, m or h", unit)),
+    };
+    number.parse::<f64>().ok()
+        .and_then(|number| Duration::try_from_secs_f64(number * scale).ok())
+        .filter(|interval| !interval.is_zero())
+        .ok_or_else(|| format!("invalid interval {:?}", interval))
+}
+
+/// The record types a question can ask for.
+const QUERY_TYPES: &[Type] = &[
+    Type::A, Type::AAAA, Type::MX, Type::TXT, Type::NS, Type::CNAME, Type::SOA,
+    Type::PTR, Type::SRV, Type::ANY,
+];
+
+fn parse_type(name: &str) -> Result<Type, String> {
+    match Type::from_str(&name.to_ascii_uppercase()) {
+        Ok(query_type) if QUERY_TYPES.contains(&query_type) => Ok(query_type),
+        _ => {
+            let names: Vec<String> = QUERY_TYPES.iter().map(Type::to_string).collect();
+            Err(format!("expected one of {}", names.join(", ")))
+        }
+    }
+}
+
+#[test]
+fn test_parse_command_line() {
+    let cli = Cli::try_parse_from([
+        "net_tool", "dns", "example.com", "--type", "mx", "-s", "127.0.0.1:5353",
+        "--format", "json",
+    ]).unwrap();
+    assert_eq!(cli.format, Format::Json);
+    match cli.command {
+        Command::Dns { domain, query_type, resolver, .. } => {
+            assert_eq!(domain, "example.com");
+            assert_eq!(query_type, Type::MX);
+            assert_eq!(resolver.servers, vec![Server::Plain("127.0.0.1:5353".parse().unwrap())]);
+        }
+        command => panic!("unexpected {:?}", command),
+    }
+
+    assert!(Cli::try_parse_from(["net_tool", "dns", "example.com", "--type", "OPT"]).is_err());
+    let cli = Cli::try_parse_from(["net_tool", "dns", "example.com", "--timeout", "0.5"]).unwrap();
+    assert!(matches!(cli.command, Command::Dns { resolver: ResolverArgs { timeout, .. }, .. }
+                     if timeout == Some(Duration::from_millis(500))));
+    assert!(Cli::try_parse_from(["net_tool", "dns", "example.com", "--timeout", "0"]).is_err());
+    assert!(Cli::try_parse_from(["net_tool", "dns", "example.com", "--timeout=-1"]).is_err());
+    let cli = Cli::try_parse_from([
+        "net_tool", "dns", "example.com", "--dnssec", "--trust-anchor", "anchors.txt",
+    ]).unwrap();
+    match cli.command {
+        Command::Dns { dnssec, trust_anchor, .. } => {
+            assert!(dnssec);
+            assert_eq!(trust_anchor, Some(PathBuf::from("anchors.txt")));
+        }
+        command => panic!("unexpected {:?}", command),
+    }
+    assert!(Cli::try_parse_from([
+        "net_tool", "dns", "example.com", "--trust-anchor", "anchors.txt",
+    ]).is_err());
+    assert!(Cli::try_parse_from(["net_tool", "ipinfo"]).is_err());
+    assert!(Cli::try_parse_from(["net_tool", "ipinfo", "not-an-ip"]).is_err());
+    let cli = Cli::try_parse_from(["net_tool", "ipinfo", "8.8.8.8", "--no-token"]).unwrap();
+    assert!(matches!(cli.command,
+                     Command::Ipinfo { provider: ProviderArgs { no_token: true, .. }, .. }));
+    let cli = Cli::try_parse_from([
+        "net_tool", "dns", "example.com", "--info",
+        "--geo-db", "City.mmdb", "--geo-db", "ASN.mmdb",
+    ]).unwrap();
+    match cli.command {
+        Command::Dns { provider, .. } => {
+            assert_eq!(provider.geo_dbs, vec![PathBuf::from("City.mmdb"), PathBuf::from("ASN.mmdb")]);
+        }
+        command => panic!("unexpected {:?}", command),
+    }
+    // A database is only used for the IP details, so giving one looks them up.
+    let cli = Cli::try_parse_from(["net_tool", "dns", "example.com", "--geo-db", "City.mmdb"])
+        .unwrap();
+    assert!(matches!(cli.command, Command::Dns { info: true, .. }));
+    let cli = Cli::try_parse_from(["net_tool", "dns", "example.com"]).unwrap();
+    assert!(matches!(cli.command, Command::Dns { info: false, .. }));
+    assert!(Cli::try_parse_from(["net_tool", "myip", "--format", "xml"]).is_err());
+    let cli = Cli::try_parse_from([
+        "net_tool", "myip", "--source", "stun:stun.example:3478", "--quorum", "1",
+    ]).unwrap();
+    assert!(matches!(cli.command, Command::Myip { quorum: 1, ref sources, .. }
+                     if sources == &[Source::Stun("stun.example:3478".to_string())]));
+    assert!(Cli::try_parse_from(["net_tool", "myip", "--source", "ftp://example.com"]).is_err());
+    assert!(Cli::try_parse_from(["net_tool", "bulk", "-i", "hosts.txt", "-c", "0"]).is_err());
+    assert!(Cli::try_parse_from(["net_tool", "ptr", "192.0.2.1", "2001:db8::1", "--fcrdns"])
+        .is_ok());
+    assert!(Cli::try_parse_from(["net_tool", "trace", "example.com", "--root", "127.0.0.1:5300"])
+        .is_ok());
+    assert!(Cli::try_parse_from(["net_tool", "trace", "example.com", "--root", "tls://1.1.1.1"])
+        .is_err());
+    let cli = Cli::try_parse_from([
+        "net_tool", "watch", "domains.txt", "-t", "A", "-t", "MX", "--interval", "5m",
+    ]).unwrap();
+    match cli.command {
+        Command::Watch { query_types, interval, rounds, .. } => {
+            assert_eq!(query_types, [Type::A, Type::MX]);
+            assert_eq!(interval, Duration::from_secs(300));
+            assert_eq!(rounds, None);
+        }
+        command => panic!("unexpected {:?}", command),
+    }
+    assert_eq!(parse_interval("1.5"), Ok(Duration::from_millis(1500)));
+    assert!(parse_interval("0s").is_err());
+    assert!(parse_interval("10d").is_err());
+    let cli = Cli::try_parse_from(["net_tool", "probe", "example.com", "-p", "22"]).unwrap();
+    assert!(matches!(cli.command, Command::Probe { ref ports, .. } if ports == &[22]));
+    assert!(Cli::try_parse_from(["net_tool", "probe", "example.com", "-p", "0"]).is_err());
+    let cli = Cli::try_parse_from(["net_tool", "serve", "--listen", "127.0.0.1:5353"]).unwrap();
+    assert!(matches!(cli.command, Command::Serve { hosts: None, .. }));
+}
@@ -0,0 +1,83 @@
+//! The user's configuration file, `$XDG_CONFIG_HOME/net_tool/config` or
+//! `~/.config/net_tool/config`, with one `key = value` setting per line:
+//!
+//! ```text
+//! # token for https://ipinfo.io
+//! ipinfo_token = 0123456789abcd
+//! # where `net_tool myip` looks
+//! myip_sources = https://ifconfig.me/ip, stun:stun.l.google.com:19302
+//! ```
+//!
+//! Blank lines and lines starting with `#` are ignored, values may be quoted.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+#[derive(Debug, Default)]
+pub struct ConfigFile {
+    values: HashMap<String, String>,
+}
+
+impl ConfigFile {
+    /// Read the configuration file, a missing file is an empty configuration.
+    pub fn load() -> io::Result<ConfigFile> {
+        let path = match path() {
+            Some(path) => path,
+            None => return Ok(ConfigFile::default()),
+        };
+        match fs::read_to_string(&path) {
+            Ok(content) => ConfigFile::parse(&content).map_err(|message| {
+                io::Error::new(io::ErrorKind::InvalidData,
+                               format!("{}: {}", path.display(), message))
+            }),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(ConfigFile::default()),
+            Err(error) => Err(error),
+        }
+    }
+
+    fn parse(content: &str) -> Result<ConfigFile, String> {
+        let mut values = HashMap::new();
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = line.split_once('=')
+                .ok_or_else(|| format!("line {}: expected key = value", number + 1))?;
+            let value = value.trim();
+            let value = value.strip_prefix('"')
+                .and_then(|value| value.strip_suffix('"'))
+                .unwrap_or(value);
+            values.insert(key.trim().to_string(), value.to_string());
+        }
+        Ok(ConfigFile { values })
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(String::as_str)
+    }
+}
+
+fn path() -> Option<PathBuf> {
+    let config_home = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(config_home.join("net_tool").join("config"))
+}
+
+#[test]
+fn test_parse_config_file() {
+    let config = ConfigFile::parse("\
+        # comment\n\
+        \n\
+        ipinfo_token = abc123\n\
+        ipinfo_url=\"http://127.0.0.1:8080\"\n").unwrap();
+    assert_eq!(config.get("ipinfo_token"), Some("abc123"));
+    assert_eq!(config.get("ipinfo_url"), Some("http://127.0.0.1:8080"));
+    assert_eq!(config.get("missing"), None);
+
+    assert!(ConfigFile::parse("ipinfo_token").is_err());
+}
@@ -0,0 +1,1453 @@
+//! DNSSEC validation (RFC 4033 to 4035) of the answers to a query.
+//!
+//! rustdns does not know the DNSSEC record types, so responses are read with
+//! the small parser here, which keeps names and record data in the canonical
+//! form signatures are computed over. Starting at a trust anchor, the DNSKEY
+//! records of each zone on the way down to an answer are checked against the
+//! DS records in its parent, and the answer against the keys of its zone.
+//!
+//! Simplifications: the NSEC and NSEC3 records denying a DS record or an
+//! answer are checked to match or cover the name, without the full closest
+//! encloser proof, and wildcard answers are accepted on their signature alone.
+
+use ring::digest;
+use ring::signature::{self, RsaPublicKeyComponents, UnparsedPublicKey};
+use serde::Serialize;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const TYPE_NS: u16 = 2;
+const TYPE_CNAME: u16 = 5;
+const TYPE_SOA: u16 = 6;
+const TYPE_PTR: u16 = 12;
+const TYPE_MX: u16 = 15;
+const TYPE_SRV: u16 = 33;
+const TYPE_DNAME: u16 = 39;
+const TYPE_OPT: u16 = 41;
+const TYPE_DS: u16 = 43;
+const TYPE_RRSIG: u16 = 46;
+const TYPE_NSEC: u16 = 47;
+const TYPE_DNSKEY: u16 = 48;
+const TYPE_NSEC3: u16 = 50;
+
+const RCODE_NXDOMAIN: u8 = 3;
+/// The zone key flag of a DNSKEY, keys without it do not sign zone data.
+const ZONE_KEY: u16 = 0x0100;
+const NSEC3_OPT_OUT: u8 = 0x01;
+/// Hashing names more often than this is refused as too costly (RFC 9276).
+const MAX_NSEC3_ITERATIONS: u16 = 500;
+
+/// The DS records of the root zone's KSK-2017 and KSK-2024, from
+/// <https://data.iana.org/root-anchors/root-anchors.xml>.
+pub const ROOT_TRUST_ANCHORS: &str = "\
+. IN DS 20326 8 2 E06D44B80B8F1D39A95C0B0D7C65D08458E880409BBC683457104237C7F8EC8D
+. IN DS 38696 8 2 683D2D0ACB8C9B712A1948B27F741219298D0A450D612C483AF444A4C0FB2B16
+";
+
+/// Whether an answer is authenticated.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Security {
+    /// Signed, and the signatures lead up to a trust anchor.
+    Secure,
+    /// In a zone proven to be unsigned, or without a trust anchor above it.
+    Insecure,
+    /// Signatures are missing, expired or do not match, or the chain of
+    /// trust is broken.
+    Bogus,
+}
+
+impl fmt::Display for Security {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Security::Secure => "secure",
+            Security::Insecure => "insecure",
+            Security::Bogus => "bogus",
+        })
+    }
+}
+
+/// The validation result of one RRset of the answer, or of the denial when
+/// the answer is empty.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct RrsetStatus {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub record_type: String,
+    pub status: Security,
+    /// Why the status is not `secure`.
+    pub reason: Option<String>,
+}
+
+/// A domain name in canonical wire format: uncompressed and in lower case.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Name(Vec<u8>);
+
+impl Name {
+    pub fn parse(name: &str) -> io::Result<Name> {
+        let mut wire = Vec::new();
+        for label in name.split('.').filter(|label| !label.is_empty()) {
+            if label.len() > 63 {
+                return Err(invalid_data(format!("label {:?} longer than 63 bytes", label)));
+            }
+            wire.push(label.len() as u8);
+            wire.extend(label.bytes().map(|byte| byte.to_ascii_lowercase()));
+        }
+        wire.push(0);
+        Ok(Name(wire))
+    }
+
+    fn labels(&self) -> Vec<&[u8]> {
+        let mut labels = Vec::new();
+        let mut rest = &self.0[..];
+        while let Some((&len, tail)) = rest.split_first().filter(|(len, _)| **len > 0) {
+            labels.push(&tail[..len as usize]);
+            rest = &tail[len as usize..];
+        }
+        labels
+    }
+
+    fn label_count(&self) -> usize {
+        self.labels().len()
+    }
+
+    fn parent(&self) -> Option<Name> {
+        let len = *self.0.first().filter(|len| **len > 0)?;
+        Some(Name(self.0[1 + len as usize..].to_vec()))
+    }
+
+    fn is_subdomain_of(&self, zone: &Name) -> bool {
+        self.0.ends_with(&zone.0) && {
+            let labels = self.labels();
+            labels.len() >= zone.label_count()
+                && Name::from_labels(&labels[labels.len() - zone.label_count()..]) == *zone
+        }
+    }
+
+    fn from_labels(labels: &[&[u8]]) -> Name {
+        let mut wire = Vec::new();
+        for label in labels {
+            wire.push(label.len() as u8);
+            wire.extend_from_slice(label);
+        }
+        wire.push(0);
+        Name(wire)
+    }
+
+    /// The names from just below `zone` down to this one, `b.example.` and
+    /// `a.b.example.` for `a.b.example.` under `example.`.
+    fn below(&self, zone: &Name) -> Vec<Name> {
+        let labels = self.labels();
+        (zone.label_count() + 1..=labels.len())
+            .map(|count| Name::from_labels(&labels[labels.len() - count..]))
+            .collect()
+    }
+
+    /// The canonical order of names (RFC 4034 section 6.1), comparing labels
+    /// from the root down.
+    fn canonical_cmp(&self, other: &Name) -> Ordering {
+        self.labels().iter().rev().cmp(other.labels().iter().rev())
+    }
+}
+
+impl fmt::Display for Name {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let labels = self.labels();
+        if labels.is_empty() {
+            return f.write_str(".");
+        }
+        for label in labels {
+            write!(f, "{}.", String::from_utf8_lossy(label))?;
+        }
+        Ok(())
+    }
+}
+
+/// A resource record with its data in canonical form.
+#[derive(Clone, Debug, PartialEq)]
+struct Rr {
+    name: Name,
+    rtype: u16,
+    class: u16,
+    ttl: u32,
+    rdata: Vec<u8>,
+}
+
+#[derive(Debug)]
+struct Response {
+    rcode: u8,
+    question: Option<(Name, u16)>,
+    answers: Vec<Rr>,
+    authorities: Vec<Rr>,
+    additionals: Vec<Rr>,
+}
+
+fn parse(wire: &[u8]) -> io::Result<Response> {
+    let header = wire.get(..12).ok_or_else(|| invalid_data("truncated DNS header".to_string()))?;
+    let count = |index: usize| u16::from_be_bytes([header[index], header[index + 1]]) as usize;
+    let mut reader = Reader { wire, position: 12 };
+    let mut question = None;
+    for _ in 0..count(4) {
+        let name = reader.name()?;
+        let qtype = reader.u16()?;
+        reader.u16()?;
+        question.get_or_insert((name, qtype));
+    }
+    let mut sections = [Vec::new(), Vec::new(), Vec::new()];
+    for (section, index) in sections.iter_mut().zip([6, 8, 10]) {
+        for _ in 0..count(index) {
+            section.push(reader.record()?);
+        }
+    }
+    let [answers, authorities, additionals] = sections;
+    Ok(Response { rcode: header[3] & 0x0f, question, answers, authorities, additionals })
+}
+
+struct Reader<'a> {
+    wire: &'a [u8],
+    position: usize,
+}
+
+impl Reader<'_> {
+    fn bytes(&mut self, len: usize) -> io::Result<&[u8]> {
+        let bytes = self.wire.get(self.position..self.position + len)
+            .ok_or_else(|| invalid_data("truncated DNS message".to_string()))?;
+        self.position += len;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// A name, following compression pointers.
+    fn name(&mut self) -> io::Result<Name> {
+        let mut wire = Vec::new();
+        let mut position = self.position;
+        let mut jumps = 0;
+        loop {
+            let len = *self.wire.get(position)
+                .ok_or_else(|| invalid_data("truncated name".to_string()))?;
+            match len {
+                0 => {
+                    if jumps == 0 {
+                        self.position = position + 1;
+                    }
+                    wire.push(0);
+                    return Ok(Name(wire));
+                }
+                1..=63 => {
+                    let label = self.wire.get(position + 1..position + 1 + len as usize)
+                        .ok_or_else(|| invalid_data("truncated name".to_string()))?;
+                    wire.push(len);
+                    wire.extend(label.iter().map(u8::to_ascii_lowercase));
+                    position += 1 + len as usize;
+                }
+                0xc0..=0xff => {
+                    let low = *self.wire.get(position + 1)
+                        .ok_or_else(|| invalid_data("truncated name".to_string()))?;
+                    if jumps == 0 {
+                        self.position = position + 2;
+                    }
+                    jumps += 1;
+                    if jumps > 64 {
+                        return Err(invalid_data("name compression loop".to_string()));
+                    }
+                    position = (usize::from(len & 0x3f) << 8) | usize::from(low);
+                }
+                _ => return Err(invalid_data(format!("invalid label length {}", len))),
+            }
+        }
+    }
+
+    fn record(&mut self) -> io::Result<Rr> {
+        let name = self.name()?;
+        let rtype = self.u16()?;
+        let class = self.u16()?;
+        let ttl = self.u32()?;
+        let len = self.u16()? as usize;
+        let end = self.position + len;
+        if end > self.wire.len() {
+            return Err(invalid_data("truncated record data".to_string()));
+        }
+
+        // Names in the data of these types may be compressed, and are
+        // compared in lower case (RFC 4034 section 6.2).
+        let (fixed_before, names, fixed_after) = match rtype {
+            TYPE_NS | TYPE_CNAME | TYPE_PTR | TYPE_DNAME => (0, 1, 0),
+            TYPE_MX => (2, 1, 0),
+            TYPE_SRV => (6, 1, 0),
+            TYPE_SOA => (0, 2, 20),
+            _ => (0, 0, len),
+        };
+        let mut rdata = self.bytes(fixed_before)?.to_vec();
+        for _ in 0..names {
+            rdata.extend_from_slice(&self.name()?.0);
+        }
+        rdata.extend_from_slice(self.bytes(fixed_after)?);
+        if self.position != end {
+            return Err(invalid_data(format!("invalid data of a type {} record", rtype)));
+        }
+        Ok(Rr { name, rtype, class, ttl, rdata })
+    }
+}
+
+/// `wire` without the DNSSEC records rustdns cannot parse, re-encoded
+/// without name compression.
+pub fn strip(wire: &[u8]) -> io::Result<Vec<u8>> {
+    let response = parse(wire)?;
+    let mut reader = Reader { wire, position: 12 };
+    if response.question.is_some() {
+        reader.name()?;
+        reader.bytes(4)?;
+    }
+    let mut stripped = wire[..reader.position].to_vec();
+    let sections = [&response.answers, &response.authorities, &response.additionals];
+    for (index, section) in [6, 8, 10].into_iter().zip(sections) {
+        let kept: Vec<&Rr> = section.iter()
+            .filter(|rr| !matches!(rr.rtype, TYPE_DS | TYPE_RRSIG | TYPE_NSEC | TYPE_DNSKEY
+                                             | TYPE_NSEC3))
+            .collect();
+        stripped[index..index + 2].copy_from_slice(&(kept.len() as u16).to_be_bytes());
+        for rr in kept {
+            stripped.extend_from_slice(&rr.name.0);
+            stripped.extend_from_slice(&rr.rtype.to_be_bytes());
+            stripped.extend_from_slice(&rr.class.to_be_bytes());
+            stripped.extend_from_slice(&rr.ttl.to_be_bytes());
+            stripped.extend_from_slice(&(rr.rdata.len() as u16).to_be_bytes());
+            stripped.extend_from_slice(&rr.rdata);
+        }
+    }
+    Ok(stripped)
+}
+
+/// A query for `name` asking for DNSSEC records like `strip`, for the
+/// record types rustdns has no `Type` for.
+pub fn encode_query(name: &str, rtype: u16, id: u16) -> io::Result<Vec<u8>> {
+    let mut query = id.to_be_bytes().to_vec();
+    query.extend_from_slice(&[0b0000_0001, 0b0001_0000]); // RD, CD
+    query.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 1]);
+    query.extend_from_slice(&Name::parse(name)?.0);
+    query.extend_from_slice(&rtype.to_be_bytes());
+    query.extend_from_slice(&1u16.to_be_bytes());
+    // OPT with a 4096 byte payload and the DO bit.
+    query.push(0);
+    query.extend_from_slice(&TYPE_OPT.to_be_bytes());
+    query.extend_from_slice(&4096u16.to_be_bytes());
+    query.extend_from_slice(&[0, 0, 0b1000_0000, 0, 0, 0]);
+    Ok(query)
+}
+
+fn type_name(rtype: u16) -> String {
+    let name = match rtype {
+        1 => "A",
+        TYPE_NS => "NS",
+        TYPE_CNAME => "CNAME",
+        TYPE_SOA => "SOA",
+        TYPE_PTR => "PTR",
+        TYPE_MX => "MX",
+        16 => "TXT",
+        28 => "AAAA",
+        TYPE_SRV => "SRV",
+        TYPE_DNAME => "DNAME",
+        TYPE_DS => "DS",
+        TYPE_RRSIG => "RRSIG",
+        TYPE_NSEC => "NSEC",
+        TYPE_DNSKEY => "DNSKEY",
+        TYPE_NSEC3 => "NSEC3",
+        rtype => return format!("TYPE{}", rtype),
+    };
+    name.to_string()
+}
+
+#[derive(Clone, Debug)]
+struct Rrsig {
+    type_covered: u16,
+    algorithm: u8,
+    labels: u8,
+    original_ttl: u32,
+    expiration: u32,
+    inception: u32,
+    key_tag: u16,
+    signer: Name,
+    signature: Vec<u8>,
+}
+
+impl Rrsig {
+    fn parse(rdata: &[u8]) -> io::Result<Rrsig> {
+        let mut reader = Reader { wire: rdata, position: 0 };
+        let type_covered = reader.u16()?;
+        let algorithm = reader.bytes(1)?[0];
+        let labels = reader.bytes(1)?[0];
+        let original_ttl = reader.u32()?;
+        let expiration = reader.u32()?;
+        let inception = reader.u32()?;
+        let key_tag = reader.u16()?;
+        let signer = reader.name()?;
+        Ok(Rrsig {
+            type_covered, algorithm, labels, original_ttl, expiration, inception, key_tag, signer,
+            signature: rdata[reader.position..].to_vec(),
+        })
+    }
+
+    /// The record data up to the signature.
+    fn fields(&self) -> Vec<u8> {
+        let mut data = self.type_covered.to_be_bytes().to_vec();
+        data.extend_from_slice(&[self.algorithm, self.labels]);
+        for field in [self.original_ttl, self.expiration, self.inception] {
+            data.extend_from_slice(&field.to_be_bytes());
+        }
+        data.extend_from_slice(&self.key_tag.to_be_bytes());
+        data.extend_from_slice(&self.signer.0);
+        data
+    }
+
+    /// What the signature is computed over: its own data without the
+    /// signature, then the records in canonical order (RFC 4034 section 3.1.8.1).
+    fn signed_data(&self, rrset: &[&Rr]) -> Vec<u8> {
+        let mut data = self.fields();
+        let mut rdatas: Vec<&[u8]> = rrset.iter().map(|rr| &rr.rdata[..]).collect();
+        rdatas.sort();
+        rdatas.dedup();
+        let owner = &rrset[0].name;
+        let labels = owner.labels();
+        // A record expanded from a wildcard is signed under the wildcard name.
+        let owner = match labels.len().checked_sub(self.labels as usize) {
+            Some(extra) if extra > 0 => {
+                let mut wildcard: Vec<&[u8]> = vec![b"*"];
+                wildcard.extend_from_slice(&labels[extra..]);
+                Name::from_labels(&wildcard)
+            }
+            _ => owner.clone(),
+        };
+        for rdata in rdatas {
+            data.extend_from_slice(&owner.0);
+            data.extend_from_slice(&rrset[0].rtype.to_be_bytes());
+            data.extend_from_slice(&rrset[0].class.to_be_bytes());
+            data.extend_from_slice(&self.original_ttl.to_be_bytes());
+            data.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+            data.extend_from_slice(rdata);
+        }
+        data
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Dnskey {
+    flags: u16,
+    algorithm: u8,
+    public_key: Vec<u8>,
+    key_tag: u16,
+    rdata: Vec<u8>,
+}
+
+impl Dnskey {
+    fn parse(rdata: &[u8]) -> Option<Dnskey> {
+        let [flags_high, flags_low, _protocol, algorithm, ..] = *rdata else { return None };
+        Some(Dnskey {
+            flags: u16::from_be_bytes([flags_high, flags_low]),
+            algorithm,
+            public_key: rdata[4..].to_vec(),
+            key_tag: key_tag(rdata),
+            rdata: rdata.to_vec(),
+        })
+    }
+
+    fn matches(&self, owner: &Name, ds: &Ds) -> bool {
+        let algorithm = match ds.digest_type {
+            1 => &digest::SHA1_FOR_LEGACY_USE_ONLY,
+            2 => &digest::SHA256,
+            4 => &digest::SHA384,
+            _ => return false,
+        };
+        let mut context = digest::Context::new(algorithm);
+        context.update(&owner.0);
+        context.update(&self.rdata);
+        ds.key_tag == self.key_tag && ds.algorithm == self.algorithm
+            && context.finish().as_ref() == ds.digest
+    }
+}
+
+/// The key tag of a DNSKEY (RFC 4034 appendix B).
+fn key_tag(rdata: &[u8]) -> u16 {
+    let mut sum: u32 = 0;
+    for (index, byte) in rdata.iter().enumerate() {
+        sum += if index % 2 == 0 { u32::from(*byte) << 8 } else { u32::from(*byte) };
+    }
+    sum += (sum >> 16) & 0xffff;
+    (sum & 0xffff) as u16
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Ds {
+    key_tag: u16,
+    algorithm: u8,
+    digest_type: u8,
+    digest: Vec<u8>,
+}
+
+impl Ds {
+    fn parse(rdata: &[u8]) -> Option<Ds> {
+        let [tag_high, tag_low, algorithm, digest_type, ..] = *rdata else { return None };
+        Some(Ds {
+            key_tag: u16::from_be_bytes([tag_high, tag_low]),
+            algorithm,
+            digest_type,
+            digest: rdata[4..].to_vec(),
+        })
+    }
+
+    fn is_supported(&self) -> bool {
+        supports_algorithm(self.algorithm) && matches!(self.digest_type, 1 | 2 | 4)
+    }
+}
+
+/// A zone and a DS record trusted for its key.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TrustAnchor {
+    pub zone: Name,
+    pub ds: Ds,
+}
+
+/// DS records in presentation format, one per line, like
+/// `. IN DS 20326 8 2 E06D44B8...`. Blank lines and `;` comments are skipped.
+pub fn parse_trust_anchors(text: &str) -> Result<Vec<TrustAnchor>, String> {
+    let mut anchors = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.split(';').next().unwrap_or("");
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words.is_empty() {
+            continue;
+        }
+        let error = || format!("line {}: expected NAME [TTL] [IN] DS TAG ALGORITHM TYPE DIGEST",
+                               number + 1);
+        let position = words.iter().position(|word| word.eq_ignore_ascii_case("DS"))
+            .ok_or_else(error)?;
+        let (Some(zone), [key_tag, algorithm, digest_type, digest @ ..]) =
+            (words.first(), &words[position + 1..]) else { return Err(error()) };
+        let digest = digest.concat();
+        let digest = (0..digest.len()).step_by(2)
+            .map(|index| digest.get(index..index + 2).and_then(|hex| {
+                u8::from_str_radix(hex, 16).ok()
+            }))
+            .collect::<Option<Vec<u8>>>()
+            .filter(|digest| !digest.is_empty())
+            .ok_or_else(error)?;
+        anchors.push(TrustAnchor {
+            zone: Name::parse(zone).map_err(|_| error())?,
+            ds: Ds {
+                key_tag: key_tag.parse().map_err(|_| error())?,
+                algorithm: algorithm.parse().map_err(|_| error())?,
+                digest_type: digest_type.parse().map_err(|_| error())?,
+                digest,
+            },
+        });
+    }
+    Ok(anchors)
+}
+
+/// The trust anchors in `path`, or the root zone's without one.
+pub fn load_trust_anchors(path: Option<&Path>) -> io::Result<Vec<TrustAnchor>> {
+    let text = match path {
+        Some(path) => fs::read_to_string(path).map_err(|error| {
+            io::Error::new(error.kind(), format!("{}: {}", path.display(), error))
+        })?,
+        None => ROOT_TRUST_ANCHORS.to_string(),
+    };
+    let anchors = parse_trust_anchors(&text).map_err(|message| invalid_data(match path {
+        Some(path) => format!("{}: {}", path.display(), message),
+        None => message,
+    }))?;
+    if anchors.is_empty() {
+        return Err(invalid_data("no trust anchors".to_string()));
+    }
+    Ok(anchors)
+}
+
+fn supports_algorithm(algorithm: u8) -> bool {
+    matches!(algorithm, 5 | 7 | 8 | 10 | 13 | 14 | 15)
+}
+
+/// Check `signature` of `data` with a DNSKEY public key (RFC 3110, RFC 5702,
+/// RFC 6605, RFC 8080).
+fn verify_signature(algorithm: u8, public_key: &[u8], data: &[u8], signature: &[u8])
+    -> Result<(), String> {
+    let verified = match algorithm {
+        5 | 7 | 8 | 10 => {
+            // The exponent length is one byte, or zero and two more bytes.
+            let (len, rest) = match public_key {
+                [0, high, low, rest @ ..] => (usize::from(u16::from_be_bytes([*high, *low])), rest),
+                [len, rest @ ..] => (usize::from(*len), rest),
+                [] => return Err("empty RSA key".to_string()),
+            };
+            if len == 0 || rest.len() <= len {
+                return Err("invalid RSA key".to_string());
+            }
+            let (e, n) = rest.split_at(len);
+            let parameters = match algorithm {
+                8 => &signature::RSA_PKCS1_1024_8192_SHA256_FOR_LEGACY_USE_ONLY,
+                10 => &signature::RSA_PKCS1_1024_8192_SHA512_FOR_LEGACY_USE_ONLY,
+                _ => &signature::RSA_PKCS1_1024_8192_SHA1_FOR_LEGACY_USE_ONLY,
+            };
+            RsaPublicKeyComponents { n, e }.verify(parameters, data, signature)
+        }
+        13 | 14 => {
+            // The key is the two coordinates, ring wants an uncompressed point.
+            let mut point = vec![0x04];
+            point.extend_from_slice(public_key);
+            let algorithm = match algorithm {
+                13 => &signature::ECDSA_P256_SHA256_FIXED,
+                _ => &signature::ECDSA_P384_SHA384_FIXED,
+            };
+            UnparsedPublicKey::new(algorithm, point).verify(data, signature)
+        }
+        15 => UnparsedPublicKey::new(&signature::ED25519, public_key).verify(data, signature),
+        algorithm => return Err(format!("unsupported algorithm {}", algorithm)),
+    };
+    verified.map_err(|_| "signature does not match".to_string())
+}
+
+/// Records of the same name, type and class, with the signatures covering them.
+struct Rrset<'a> {
+    records: Vec<&'a Rr>,
+    signatures: Vec<Rrsig>,
+}
+
+fn rrsets(records: &[Rr]) -> Vec<Rrset<'_>> {
+    let mut sets: Vec<Rrset> = Vec::new();
+    for rr in records.iter().filter(|rr| rr.rtype != TYPE_RRSIG && rr.rtype != TYPE_OPT) {
+        match sets.iter_mut().find(|set| {
+            let first = set.records[0];
+            first.name == rr.name && first.rtype == rr.rtype && first.class == rr.class
+        }) {
+            Some(set) => set.records.push(rr),
+            None => sets.push(Rrset { records: vec![rr], signatures: Vec::new() }),
+        }
+    }
+    for rr in records.iter().filter(|rr| rr.rtype == TYPE_RRSIG) {
+        let Ok(rrsig) = Rrsig::parse(&rr.rdata) else { continue };
+        if let Some(set) = sets.iter_mut().find(|set| {
+            set.records[0].name == rr.name && set.records[0].rtype == rrsig.type_covered
+        }) {
+            set.signatures.push(rrsig);
+        }
+    }
+    sets
+}
+
+/// What is known about the zone a name is in.
+#[derive(Clone, Debug)]
+enum Trust {
+    Secure { zone: Name, keys: Vec<Dnskey> },
+    Insecure(String),
+    Bogus(String),
+}
+
+/// What a signed NSEC or NSEC3 record says about a name.
+#[derive(Debug, PartialEq)]
+enum Denial {
+    /// The name exists without the type, and is a delegation to another
+    /// zone when `delegation` is set.
+    NoData { delegation: bool },
+    /// There is no such name, or only unsigned delegations cover it (NSEC3
+    /// opt-out) when `opt_out` is set.
+    NoName { opt_out: bool },
+}
+
+/// Validates answers, asking for the DS and DNSKEY records of the zones
+/// on the way with `fetch`.
+pub struct Validator<F> {
+    anchors: Vec<TrustAnchor>,
+    fetch: F,
+    /// The time signatures have to be valid at, in seconds since 1970.
+    now: u32,
+    responses: HashMap<(Name, u16), Response>,
+}
+
+impl<F> Validator<F>
+where F: FnMut(&str, u16) -> io::Result<Vec<u8>>
+{
+    /// `fetch` sends a query with the DO bit for a name and type, and
+    /// returns the encoded response.
+    pub fn new(anchors: Vec<TrustAnchor>, fetch: F) -> Validator<F> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_secs());
+        Validator { anchors, fetch, now: now as u32, responses: HashMap::new() }
+    }
+
+    /// The status of every RRset in the answer section of the encoded
+    /// `response`, or of the denial when it has no answers.
+    pub fn validate(&mut self, response: &[u8]) -> io::Result<Vec<RrsetStatus>> {
+        let response = parse(response)?;
+        let mut statuses = Vec::new();
+        for rrset in rrsets(&response.answers) {
+            let owner = &rrset.records[0].name;
+            let status = match self.trust(owner)? {
+                Trust::Secure { zone, keys } => verify_rrset(&rrset, &zone, &keys, self.now)
+                    .map(|()| None)
+                    .map_err(|reason| format!("{} {}: {}", owner,
+                                              type_name(rrset.records[0].rtype), reason)),
+                Trust::Insecure(reason) => Ok(Some(reason)),
+                Trust::Bogus(reason) => Err(reason),
+            };
+            statuses.push(rrset_status(owner, rrset.records[0].rtype, status));
+        }
+        if !statuses.is_empty() {
+            return Ok(statuses);
+        }
+
+        let Some((name, qtype)) = &response.question else { return Ok(statuses) };
+        let status = match self.trust(name)? {
+            Trust::Secure { zone, keys } => {
+                match denial(&response, name, *qtype, &zone, &keys, self.now) {
+                    Ok(Denial::NoName { .. }) if response.rcode == RCODE_NXDOMAIN => Ok(None),
+                    Ok(Denial::NoData { .. }) if response.rcode != RCODE_NXDOMAIN => Ok(None),
+                    Ok(_) => Err(format!("the denial does not match the response code {}",
+                                         response.rcode)),
+                    Err(reason) => Err(reason),
+                }
+            }
+            Trust::Insecure(reason) => Ok(Some(reason)),
+            Trust::Bogus(reason) => Err(reason),
+        };
+        statuses.push(rrset_status(name, *qtype, status));
+        Ok(statuses)
+    }
+
+    fn fetch(&mut self, name: &Name, rtype: u16) -> io::Result<&Response> {
+        let key = (name.clone(), rtype);
+        if !self.responses.contains_key(&key) {
+            let response = parse(&(self.fetch)(&name.to_string(), rtype)?)?;
+            self.responses.insert(key.clone(), response);
+        }
+        Ok(&self.responses[&key])
+    }
+
+    /// Follow the chain of trust from the closest trust anchor down to the
+    /// zone `name` is in.
+    fn trust(&mut self, name: &Name) -> io::Result<Trust> {
+        let Some(anchor_zone) = self.anchors.iter()
+            .map(|anchor| &anchor.zone)
+            .filter(|zone| name.is_subdomain_of(zone))
+            .max_by_key(|zone| zone.label_count())
+            .cloned() else {
+            return Ok(Trust::Insecure(format!("no trust anchor above {}", name)));
+        };
+        let ds: Vec<Ds> = self.anchors.iter()
+            .filter(|anchor| anchor.zone == anchor_zone)
+            .map(|anchor| anchor.ds.clone())
+            .collect();
+        let mut trust = self.zone_keys(&anchor_zone, &ds)?;
+
+        for child in name.below(&anchor_zone) {
+            let Trust::Secure { zone, keys } = &trust else { break };
+            let (zone, keys) = (zone.clone(), keys.clone());
+            let now = self.now;
+            let response = self.fetch(&child, TYPE_DS)?;
+            let sets = rrsets(&response.answers);
+            let ds_set = sets.iter().find(|set| {
+                set.records[0].rtype == TYPE_DS && set.records[0].name == child
+            });
+            if let Some(ds_set) = ds_set {
+                if let Err(reason) = verify_rrset(ds_set, &zone, &keys, now) {
+                    return Ok(Trust::Bogus(format!("DS of {}: {}", child, reason)));
+                }
+                let ds: Vec<Ds> = ds_set.records.iter().filter_map(|rr| Ds::parse(&rr.rdata))
+                    .collect();
+                trust = self.zone_keys(&child, &ds)?;
+                continue;
+            }
+            if response.answers.iter().any(|rr| rr.rtype == TYPE_CNAME && rr.name == child) {
+                continue; // an alias in the zone, not a zone cut
+            }
+            match denial(response, &child, TYPE_DS, &zone, &keys, now) {
+                Ok(Denial::NoData { delegation: true }) | Ok(Denial::NoName { opt_out: true }) => {
+                    return Ok(Trust::Insecure(format!("{} is an unsigned delegation", child)));
+                }
+                Ok(_) => {}
+                Err(reason) => return Ok(Trust::Bogus(format!("DS of {}: {}", child, reason))),
+            }
+        }
+        Ok(trust)
+    }
+
+    /// The DNSKEY records of `zone` when one of them matches a DS record and
+    /// signs them all.
+    fn zone_keys(&mut self, zone: &Name, ds: &[Ds]) -> io::Result<Trust> {
+        let ds: Vec<&Ds> = ds.iter().filter(|ds| ds.is_supported()).collect();
+        if ds.is_empty() {
+            return Ok(Trust::Insecure(format!("the DS records of {} use no supported algorithm",
+                                              zone)));
+        }
+        let now = self.now;
+        let response = self.fetch(zone, TYPE_DNSKEY)?;
+        let sets = rrsets(&response.answers);
+        let Some(dnskeys) = sets.iter().find(|set| {
+            set.records[0].rtype == TYPE_DNSKEY && set.records[0].name == *zone
+        }) else {
+            return Ok(Trust::Bogus(format!("no DNSKEY records for {}", zone)));
+        };
+        let keys: Vec<Dnskey> = dnskeys.records.iter()
+            .filter_map(|rr| Dnskey::parse(&rr.rdata))
+            .collect();
+        let trusted: Vec<Dnskey> = keys.iter()
+            .filter(|key| ds.iter().any(|ds| key.matches(zone, ds)))
+            .cloned()
+            .collect();
+        if trusted.is_empty() {
+            return Ok(Trust::Bogus(format!("no DNSKEY of {} matches its DS records", zone)));
+        }
+        Ok(match verify_rrset(dnskeys, zone, &trusted, now) {
+            Ok(_) => Trust::Secure { zone: zone.clone(), keys },
+            Err(reason) => Trust::Bogus(format!("DNSKEY of {}: {}", zone, reason)),
+        })
+    }
+}
+
+fn rrset_status(name: &Name, rtype: u16, status: Result<Option<String>, String>)
+    -> RrsetStatus {
+    let (status, reason) = match status {
+        Ok(None) => (Security::Secure, None),
+        Ok(Some(reason)) => (Security::Insecure, Some(reason)),
+        Err(reason) => (Security::Bogus, Some(reason)),
+    };
+    RrsetStatus { name: name.to_string(), record_type: type_name(rtype), status, reason }
+}
+
+/// Check that a signature by one of the `keys` of `zone` covers `rrset`.
+fn verify_rrset(rrset: &Rrset, zone: &Name, keys: &[Dnskey], now: u32) -> Result<(), String> {
+    let owner = &rrset.records[0].name;
+    if !owner.is_subdomain_of(zone) {
+        return Err(format!("{} is not in zone {}", owner, zone));
+    }
+    let mut reason = format!("no signature by a key of {}", zone);
+    for rrsig in rrset.signatures.iter().filter(|rrsig| rrsig.signer == *zone) {
+        // Serial number arithmetic (RFC 1982), the times wrap around in 2106.
+        if (now.wrapping_sub(rrsig.inception) as i32) < 0 {
+            reason = "the signature is not valid yet".to_string();
+            continue;
+        }
+        if (rrsig.expiration.wrapping_sub(now) as i32) < 0 {
+            reason = "the signature expired".to_string();
+            continue;
+        }
+        if usize::from(rrsig.labels) > owner.label_count() {
+            reason = "the signature has more labels than the name".to_string();
+            continue;
+        }
+        let data = rrsig.signed_data(&rrset.records);
+        for key in keys.iter().filter(|key| {
+            key.key_tag == rrsig.key_tag && key.algorithm == rrsig.algorithm
+                && key.flags & ZONE_KEY != 0
+        }) {
+            match verify_signature(key.algorithm, &key.public_key, &data, &rrsig.signature) {
+                Ok(()) => return Ok(()),
+                Err(error) => reason = error,
+            }
+        }
+    }
+    Err(reason)
+}
+
+/// What the signed NSEC or NSEC3 records in the authority section of
+/// `response` say about `rtype` records of `name` in `zone`.
+fn denial(response: &Response, name: &Name, rtype: u16, zone: &Name, keys: &[Dnskey], now: u32)
+    -> Result<Denial, String> {
+    let sets = rrsets(&response.authorities);
+    let mut reason = format!("no NSEC or NSEC3 record proves that {} has no {} records",
+                             name, type_name(rtype));
+    for set in sets.iter().filter(|set| matches!(set.records[0].rtype, TYPE_NSEC | TYPE_NSEC3)) {
+        if let Err(error) = verify_rrset(set, zone, keys, now) {
+            reason = format!("{} {}: {}", set.records[0].name, type_name(set.records[0].rtype),
+                             error);
+            continue;
+        }
+        for rr in &set.records {
+            let found = match rr.rtype {
+                TYPE_NSEC => nsec_denial(rr, name, rtype),
+                _ => nsec3_denial(rr, name, rtype, zone),
+            };
+            match found {
+                Ok(Some(denial)) => return Ok(denial),
+                Ok(None) => {}
+                Err(error) => reason = error,
+            }
+        }
+    }
+    Err(reason)
+}
+
+fn nsec_denial(rr: &Rr, name: &Name, rtype: u16) -> Result<Option<Denial>, String> {
+    let mut reader = Reader { wire: &rr.rdata, position: 0 };
+    let next = reader.name().map_err(|error| error.to_string())?;
+    let types = &rr.rdata[reader.position..];
+    if rr.name == *name {
+        if has_type(types, rtype) || has_type(types, TYPE_CNAME) {
+            return Err(format!("the NSEC record of {} lists {}", name, type_name(rtype)));
+        }
+        let delegation = has_type(types, TYPE_NS) && !has_type(types, TYPE_SOA);
+        return Ok(Some(Denial::NoData { delegation }));
+    }
+    let after_owner = rr.name.canonical_cmp(name) == Ordering::Less;
+    let before_next = name.canonical_cmp(&next) == Ordering::Less;
+    // The last NSEC of a zone points back to its apex.
+    let last = next.canonical_cmp(&rr.name) != Ordering::Greater;
+    if after_owner && (before_next || last) {
+        return Ok(Some(Denial::NoName { opt_out: false }));
+    }
+    Ok(None)
+}
+
+fn nsec3_denial(rr: &Rr, name: &Name, rtype: u16, zone: &Name)
+    -> Result<Option<Denial>, String> {
+    let invalid = || format!("invalid NSEC3 record {}", rr.name);
+    let mut reader = Reader { wire: &rr.rdata, position: 0 };
+    let (hash_algorithm, flags, iterations, salt, next) = (|| -> io::Result<_> {
+        let hash_algorithm = reader.bytes(1)?[0];
+        let flags = reader.bytes(1)?[0];
+        let iterations = reader.u16()?;
+        let salt_len = reader.bytes(1)?[0] as usize;
+        let salt = reader.bytes(salt_len)?.to_vec();
+        let hash_len = reader.bytes(1)?[0] as usize;
+        let next = reader.bytes(hash_len)?.to_vec();
+        Ok((hash_algorithm, flags, iterations, salt, next))
+    })().map_err(|_| invalid())?;
+    let types = &rr.rdata[reader.position..];
+    if hash_algorithm != 1 || rr.name.parent().as_ref() != Some(zone) {
+        return Ok(None);
+    }
+    if iterations > MAX_NSEC3_ITERATIONS {
+        return Err(format!("NSEC3 with {} iterations", iterations));
+    }
+    let owner = rr.name.labels().first().and_then(|label| base32hex_decode(label))
+        .ok_or_else(invalid)?;
+    let hash = nsec3_hash(name, &salt, iterations);
+
+    if hash == owner {
+        if has_type(types, rtype) || has_type(types, TYPE_CNAME) {
+            return Err(format!("the NSEC3 record of {} lists {}", name, type_name(rtype)));
+        }
+        let delegation = has_type(types, TYPE_NS) && !has_type(types, TYPE_SOA);
+        return Ok(Some(Denial::NoData { delegation }));
+    }
+    let covered = if owner < next {
+        owner < hash && hash < next
+    } else {
+        hash > owner || hash < next // the last NSEC3 of the zone wraps around
+    };
+    Ok(covered.then_some(Denial::NoName { opt_out: flags & NSEC3_OPT_OUT != 0 }))
+}
+
+/// The iterated SHA-1 hash of a name (RFC 5155 section 5).
+fn nsec3_hash(name: &Name, salt: &[u8], iterations: u16) -> Vec<u8> {
+    let mut hash = name.0.clone();
+    for _ in 0..=iterations {
+        let mut context = digest::Context::new(&digest::SHA1_FOR_LEGACY_USE_ONLY);
+        context.update(&hash);
+        context.update(salt);
+        hash = context.finish().as_ref().to_vec();
+    }
+    hash
+}
+
+fn base32hex_decode(text: &[u8]) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let (mut buffer, mut bits) = (0u32, 0);
+    for character in text {
+        let value = match character.to_ascii_uppercase() {
+            digit @ b'0'..=b'9' => digit - b'0',
+            letter @ b'A'..=b'V' => letter - b'A' + 10,
+            _ => return None,
+        };
+        buffer = (buffer << 5) | u32::from(value);
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+/// Whether the type bitmap of an NSEC or NSEC3 record lists `rtype`.
+fn has_type(mut bitmap: &[u8], rtype: u16) -> bool {
+    let [window, offset] = rtype.to_be_bytes();
+    while let [block, len, rest @ ..] = bitmap {
+        let len = usize::from(*len);
+        let Some(bits) = rest.get(..len) else { return false };
+        if *block == window {
+            let byte = usize::from(offset / 8);
+            return bits.get(byte).is_some_and(|bits| bits & (0x80 >> (offset % 8)) != 0);
+        }
+        bitmap = &rest[len..];
+    }
+    false
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// A fixture of two signed zones, the root and `example.`. Ed25519 keys are
+/// made from fixed seeds, the RSA and ECDSA keys ring cannot make from a seed
+/// are read from testdata. The NSEC3 records use the salt, iterations and
+/// hashed names of the example zone in RFC 5155 appendix A.
+#[cfg(test)]
+mod fixture {
+    use super::*;
+    use base64::Engine;
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, Ed25519KeyPair, KeyPair, RsaKeyPair};
+
+    pub const NOW: u32 = 1_700_000_000;
+    pub const NSEC3_SALT: [u8; 4] = [0xaa, 0xbb, 0xcc, 0xdd];
+    pub const NSEC3_ITERATIONS: u16 = 12;
+    const TYPE_A: u16 = 1;
+
+    enum Key {
+        Ed25519(Ed25519KeyPair),
+        Rsa(RsaKeyPair),
+        Ecdsa(EcdsaKeyPair),
+    }
+
+    pub struct Zone {
+        pub name: Name,
+        key: Key,
+    }
+
+    impl Zone {
+        /// A zone signed with Ed25519 (algorithm 15).
+        pub fn new(name: &str, seed: u8) -> Zone {
+            Zone {
+                name: Name::parse(name).unwrap(),
+                key: Key::Ed25519(Ed25519KeyPair::from_seed_unchecked(&[seed; 32]).unwrap()),
+            }
+        }
+
+        /// A zone signed with RSA/SHA-256 (algorithm 8), like the root zone.
+        pub fn rsa(name: &str) -> Zone {
+            let pkcs8 = pem(include_str!("testdata/rsa_zone_key.pem"));
+            Zone {
+                name: Name::parse(name).unwrap(),
+                key: Key::Rsa(RsaKeyPair::from_pkcs8(&pkcs8).unwrap()),
+            }
+        }
+
+        /// A zone signed with ECDSA P-256 and SHA-256 (algorithm 13).
+        pub fn ecdsa(name: &str) -> Zone {
+            let pkcs8 = pem(include_str!("testdata/ecdsa_zone_key.pem"));
+            let key = EcdsaKeyPair::from_pkcs8(&signature::ECDSA_P256_SHA256_FIXED_SIGNING,
+                                               &pkcs8, &SystemRandom::new()).unwrap();
+            Zone { name: Name::parse(name).unwrap(), key: Key::Ecdsa(key) }
+        }
+
+        pub fn algorithm(&self) -> u8 {
+            match self.key {
+                Key::Ed25519(_) => 15,
+                Key::Rsa(_) => 8,
+                Key::Ecdsa(_) => 13,
+            }
+        }
+
+        /// The public key as a DNSKEY carries it.
+        pub fn public_key(&self) -> Vec<u8> {
+            match &self.key {
+                Key::Ed25519(key) => key.public_key().as_ref().to_vec(),
+                Key::Rsa(key) => {
+                    // The exponent length in one byte, the exponent, then the modulus.
+                    let RsaPublicKeyComponents { n, e } =
+                        RsaPublicKeyComponents::<Vec<u8>>::from(key.public());
+                    let mut data = vec![e.len() as u8];
+                    data.extend_from_slice(&e);
+                    data.extend_from_slice(&n);
+                    data
+                }
+                // Both coordinates, without the 0x04 of an uncompressed point.
+                Key::Ecdsa(key) => key.public_key().as_ref()[1..].to_vec(),
+            }
+        }
+
+        pub fn sign_data(&self, data: &[u8]) -> Vec<u8> {
+            let rng = SystemRandom::new();
+            match &self.key {
+                Key::Ed25519(key) => key.sign(data).as_ref().to_vec(),
+                Key::Rsa(key) => {
+                    let mut signature = vec![0; key.public().modulus_len()];
+                    key.sign(&signature::RSA_PKCS1_SHA256, &rng, data, &mut signature).unwrap();
+                    signature
+                }
+                Key::Ecdsa(key) => key.sign(&rng, data).unwrap().as_ref().to_vec(),
+            }
+        }
+
+        /// The DNSKEY record, flagged as a zone key and secure entry point.
+        pub fn dnskey(&self) -> Rr {
+            let mut rdata = vec![0x01, 0x01, 3, self.algorithm()];
+            rdata.extend_from_slice(&self.public_key());
+            rr(&self.name.to_string(), TYPE_DNSKEY, rdata)
+        }
+
+        /// The DS record of the key, for the parent zone.
+        pub fn ds(&self) -> Rr {
+            let dnskey = self.dnskey();
+            let mut rdata = key_tag(&dnskey.rdata).to_be_bytes().to_vec();
+            rdata.extend_from_slice(&[self.algorithm(), 2]);
+            let mut context = digest::Context::new(&digest::SHA256);
+            context.update(&self.name.0);
+            context.update(&dnskey.rdata);
+            rdata.extend_from_slice(context.finish().as_ref());
+            rr(&self.name.to_string(), TYPE_DS, rdata)
+        }
+
+        pub fn anchor(&self) -> TrustAnchor {
+            TrustAnchor { zone: self.name.clone(), ds: Ds::parse(&self.ds().rdata).unwrap() }
+        }
+
+        /// `rrset` followed by its RRSIG.
+        pub fn sign(&self, rrset: &[Rr]) -> Vec<Rr> {
+            let first = &rrset[0];
+            let mut rrsig = Rrsig {
+                type_covered: first.rtype,
+                algorithm: self.algorithm(),
+                labels: first.name.label_count() as u8,
+                original_ttl: first.ttl,
+                expiration: NOW + 86400,
+                inception: NOW - 86400,
+                key_tag: key_tag(&self.dnskey().rdata),
+                signer: self.name.clone(),
+                signature: Vec::new(),
+            };
+            let records: Vec<&Rr> = rrset.iter().collect();
+            rrsig.signature = self.sign_data(&rrsig.signed_data(&records));
+            let mut rdata = rrsig.fields();
+            rdata.extend_from_slice(&rrsig.signature);
+            let mut signed = rrset.to_vec();
+            signed.push(Rr { rtype: TYPE_RRSIG, rdata, ..first.clone() });
+            signed
+        }
+
+        /// An NSEC record of `owner` listing `types`.
+        pub fn nsec(&self, owner: &str, next: &str, types: &[u16]) -> Vec<Rr> {
+            let mut rdata = Name::parse(next).unwrap().0;
+            rdata.extend_from_slice(&type_bitmap(types));
+            self.sign(&[rr(owner, TYPE_NSEC, rdata)])
+        }
+
+        /// An NSEC3 record from the name hashed to `owner` to the one hashed
+        /// to `next`, both in base32hex.
+        pub fn nsec3(&self, owner: &str, next: &str, flags: u8, types: &[u16]) -> Vec<Rr> {
+            let next = base32hex_decode(next.as_bytes()).unwrap();
+            let mut rdata = vec![1, flags];
+            rdata.extend_from_slice(&NSEC3_ITERATIONS.to_be_bytes());
+            rdata.push(NSEC3_SALT.len() as u8);
+            rdata.extend_from_slice(&NSEC3_SALT);
+            rdata.push(next.len() as u8);
+            rdata.extend_from_slice(&next);
+            rdata.extend_from_slice(&type_bitmap(types));
+            self.sign(&[rr(&format!("{}.{}", owner, self.name), TYPE_NSEC3, rdata)])
+        }
+    }
+
+    /// The type bitmap of an NSEC or NSEC3 record, for types below 256.
+    fn type_bitmap(types: &[u16]) -> Vec<u8> {
+        let mut bits = [0u8; 32];
+        for rtype in types {
+            bits[usize::from(*rtype / 8)] |= 0x80 >> (rtype % 8);
+        }
+        let len = bits.iter().rposition(|byte| *byte != 0).unwrap() + 1;
+        let mut bitmap = vec![0, len as u8];
+        bitmap.extend_from_slice(&bits[..len]);
+        bitmap
+    }
+
+    /// The DER document in a PEM file.
+    fn pem(text: &str) -> Vec<u8> {
+        let base64: String = text.lines().filter(|line| !line.starts_with("-----")).collect();
+        base64::engine::general_purpose::STANDARD.decode(base64).unwrap()
+    }
+
+    pub fn rr(name: &str, rtype: u16, rdata: Vec<u8>) -> Rr {
+        Rr { name: Name::parse(name).unwrap(), rtype, class: 1, ttl: 3600, rdata }
+    }
+
+    pub fn a(name: &str, ip: [u8; 4]) -> Rr {
+        rr(name, TYPE_A, ip.to_vec())
+    }
+
+    /// An encoded response to a question.
+    pub fn response(name: &str, rtype: u16, rcode: u8, answers: &[Rr], authorities: &[Rr])
+        -> Vec<u8> {
+        let mut wire = vec![0, 0, 0x81, 0x80 | rcode, 0, 1];
+        wire.extend_from_slice(&(answers.len() as u16).to_be_bytes());
+        wire.extend_from_slice(&(authorities.len() as u16).to_be_bytes());
+        wire.extend_from_slice(&[0, 0]);
+        wire.extend_from_slice(&Name::parse(name).unwrap().0);
+        wire.extend_from_slice(&rtype.to_be_bytes());
+        wire.extend_from_slice(&[0, 1]);
+        for rr in answers.iter().chain(authorities) {
+            wire.extend_from_slice(&rr.name.0);
+            wire.extend_from_slice(&rr.rtype.to_be_bytes());
+            wire.extend_from_slice(&rr.class.to_be_bytes());
+            wire.extend_from_slice(&rr.ttl.to_be_bytes());
+            wire.extend_from_slice(&(rr.rdata.len() as u16).to_be_bytes());
+            wire.extend_from_slice(&rr.rdata);
+        }
+        wire
+    }
+
+    /// The responses of the root and `example.` zones to the DS and DNSKEY
+    /// queries of a validator. `unsigned.` is delegated without a DS record,
+    /// and `c.example.` is covered by an NSEC3 record with the opt-out flag.
+    pub fn fetch(root: &Zone, example: &Zone, name: &str, rtype: u16) -> io::Result<Vec<u8>> {
+        const NXDOMAIN: u8 = 3;
+        Ok(match (name, rtype) {
+            (".", TYPE_DNSKEY) => response(name, rtype, 0, &root.sign(&[root.dnskey()]), &[]),
+            ("example.", TYPE_DNSKEY) => {
+                response(name, rtype, 0, &example.sign(&[example.dnskey()]), &[])
+            }
+            ("example.", TYPE_DS) => response(name, rtype, 0, &root.sign(&[example.ds()]), &[]),
+            ("unsigned.", TYPE_DS) => {
+                let nsec = root.nsec("unsigned.", ".", &[TYPE_NS, TYPE_RRSIG, TYPE_NSEC]);
+                response(name, rtype, 0, &[], &nsec)
+            }
+            ("www.example.", TYPE_DS) => {
+                let nsec = example.nsec("www.example.", "example.", &[TYPE_A, TYPE_RRSIG,
+                                                                       TYPE_NSEC]);
+                response(name, rtype, 0, &[], &nsec)
+            }
+            ("c.example.", TYPE_DS) => {
+                // H(a.example) to H(x.w.example), which covers H(c.example).
+                let nsec3 = example.nsec3("35mthgpgcu1qg68fab165klnsnk3dpvl",
+                                          "b4um86eghhds6nea196smvmlo4ors995", NSEC3_OPT_OUT,
+                                          &[TYPE_NS, TYPE_DS, TYPE_RRSIG]);
+                response(name, rtype, 0, &[], &nsec3)
+            }
+            ("nope.example.", _) => {
+                let nsec = example.nsec("example.", "www.example.",
+                                        &[TYPE_NS, TYPE_SOA, TYPE_RRSIG, TYPE_NSEC, TYPE_DNSKEY]);
+                response(name, rtype, NXDOMAIN, &[], &nsec)
+            }
+            _ => return Err(io::Error::other(format!("unexpected query {} {}", name, rtype))),
+        })
+    }
+}
+
+#[test]
+fn test_validate() {
+    use fixture::{a, response, Zone};
+
+    let root = Zone::new(".", 1);
+    let example = Zone::new("example.", 2);
+    let validate = |wire: &[u8]| -> Vec<(String, String, Security)> {
+        let mut validator = Validator::new(vec![root.anchor()], |name: &str, rtype| {
+            fixture::fetch(&root, &example, name, rtype)
+        });
+        validator.now = fixture::NOW;
+        validator.validate(wire).unwrap().into_iter()
+            .map(|status| (status.name, status.record_type, status.status))
+            .collect()
+    };
+    let status = |name: &str, record_type: &str, status| {
+        vec![(name.to_string(), record_type.to_string(), status)]
+    };
+
+    let signed = example.sign(&[a("www.example.", [192, 0, 2, 1])]);
+    assert_eq!(validate(&response("www.example.", 1, 0, &signed, &[])),
+               status("www.example.", "A", Security::Secure));
+
+    let mut tampered = signed.clone();
+    tampered[0].rdata = vec![192, 0, 2, 66];
+    assert_eq!(validate(&response("www.example.", 1, 0, &tampered, &[])),
+               status("www.example.", "A", Security::Bogus));
+    assert_eq!(validate(&response("www.example.", 1, 0, &signed[..1], &[])),
+               status("www.example.", "A", Security::Bogus));
+
+    // Signed by the wrong zone.
+    let forged = root.sign(&[a("www.example.", [192, 0, 2, 1])]);
+    assert_eq!(validate(&response("www.example.", 1, 0, &forged, &[])),
+               status("www.example.", "A", Security::Bogus));
+
+    let unsigned = [a("host.unsigned.", [192, 0, 2, 2])];
+    assert_eq!(validate(&response("host.unsigned.", 1, 0, &unsigned, &[])),
+               status("host.unsigned.", "A", Security::Insecure));
+
+    let nxdomain = fixture::fetch(&root, &example, "nope.example.", 1).unwrap();
+    assert_eq!(validate(&nxdomain), status("nope.example.", "A", Security::Secure));
+    // The same denial with NOERROR claims the name exists.
+    let mut noerror = nxdomain.clone();
+    noerror[3] &= 0xf0;
+    assert_eq!(validate(&noerror), status("nope.example.", "A", Security::Bogus));
+
+    // Without a trust anchor nothing is validated.
+    let mut validator = Validator::new(Vec::new(), |_: &str, _| unreachable!());
+    assert_eq!(validator.validate(&response("www.example.", 1, 0, &signed, &[])).unwrap()[0].status,
+               Security::Insecure);
+}
+
+#[test]
+fn test_signature_expiry() {
+    use fixture::{a, response, Zone};
+
+    let root = Zone::new(".", 1);
+    let example = Zone::new("example.", 2);
+    let signed = example.sign(&[a("www.example.", [192, 0, 2, 1])]);
+    let mut validator = Validator::new(vec![root.anchor()], |name: &str, rtype| {
+        fixture::fetch(&root, &example, name, rtype)
+    });
+    validator.now = fixture::NOW + 2 * 86400;
+    let statuses = validator.validate(&response("www.example.", 1, 0, &signed, &[])).unwrap();
+    assert_eq!(statuses[0].status, Security::Bogus);
+    assert_eq!(statuses[0].reason.as_deref(), Some("DNSKEY of .: the signature expired"));
+}
+
+#[test]
+fn test_validate_rsa_and_ecdsa() {
+    use fixture::{a, response, Zone};
+
+    // An RSA/SHA-256 root like the real one, above an ECDSA P-256 zone.
+    let root = Zone::rsa(".");
+    let example = Zone::ecdsa("example.");
+    assert_eq!(root.anchor().ds.algorithm, 8);
+    let validate = |wire: &[u8]| -> Vec<(Security, Option<String>)> {
+        let mut validator = Validator::new(vec![root.anchor()], |name: &str, rtype| {
+            fixture::fetch(&root, &example, name, rtype)
+        });
+        validator.now = fixture::NOW;
+        validator.validate(wire).unwrap().into_iter()
+            .map(|status| (status.status, status.reason))
+            .collect()
+    };
+
+    let signed = example.sign(&[a("www.example.", [192, 0, 2, 1])]);
+    assert_eq!(validate(&response("www.example.", 1, 0, &signed, &[])),
+               [(Security::Secure, None)]);
+    let mut tampered = signed.clone();
+    tampered[0].rdata = vec![192, 0, 2, 66];
+    assert_eq!(validate(&response("www.example.", 1, 0, &tampered, &[])),
+               [(Security::Bogus,
+                 Some("www.example. A: signature does not match".to_string()))]);
+    let nxdomain = fixture::fetch(&root, &example, "nope.example.", 1).unwrap();
+    assert_eq!(validate(&nxdomain), [(Security::Secure, None)]);
+}
+
+#[test]
+fn test_verify_signature_keys() {
+    use fixture::Zone;
+
+    let data = b"example. IN A 192.0.2.1";
+    let rsa = Zone::rsa(".");
+    let (key, signature) = (rsa.public_key(), rsa.sign_data(data));
+    assert_eq!(verify_signature(8, &key, data, &signature), Ok(()));
+    assert!(verify_signature(10, &key, data, &signature).is_err());
+    // The same exponent with its length in the three byte form.
+    let exponent_len = usize::from(key[0]);
+    let mut long_form = vec![0, 0, exponent_len as u8];
+    long_form.extend_from_slice(&key[1..]);
+    assert_eq!(verify_signature(8, &long_form, data, &signature), Ok(()));
+    for invalid in [&[][..], &[0, 0, 0][..], &key[..=exponent_len]] {
+        assert!(verify_signature(8, invalid, data, &signature).is_err());
+    }
+    assert!(verify_signature(8, &key, b"example. IN A 192.0.2.66", &signature).is_err());
+
+    let ecdsa = Zone::ecdsa("example.");
+    let (key, signature) = (ecdsa.public_key(), ecdsa.sign_data(data));
+    assert_eq!((key.len(), signature.len()), (64, 64));
+    assert_eq!(verify_signature(13, &key, data, &signature), Ok(()));
+    assert!(verify_signature(14, &key, data, &signature).is_err());
+    assert!(verify_signature(13, &key[..63], data, &signature).is_err());
+    let mut swapped = key[32..].to_vec();
+    swapped.extend_from_slice(&key[..32]);
+    assert!(verify_signature(13, &swapped, data, &signature).is_err());
+}
+
+#[test]
+fn test_nsec3() {
+    use fixture::{a, response, Zone, NSEC3_ITERATIONS, NSEC3_SALT};
+
+    // Hashed names from RFC 5155 appendix A.
+    let hash = |name: &str| nsec3_hash(&Name::parse(name).unwrap(), &NSEC3_SALT, NSEC3_ITERATIONS);
+    let base32hex = |text: &str| base32hex_decode(text.as_bytes()).unwrap();
+    assert_eq!(hash("example."), base32hex("0p9mhaveqvm6t7vbl5lop2u3t2rp3tom"));
+    assert_eq!(hash("a.example."), base32hex("35mthgpgcu1qg68fab165klnsnk3dpvl"));
+    assert_eq!(hash("c.example."), base32hex("4g6p9u5gvfshp30pqecj98b3maqbn1ck"));
+    assert_eq!(hash("ns1.example."), base32hex("2T7B4G4VSA5SMI47K61MV5BV1A22BOJR"));
+    assert_eq!(base32hex_decode(b"0p9m!"), None);
+
+    // An opt-out NSEC3 record covering the DS of c.example. proves an
+    // unsigned delegation, as in most TLDs.
+    let root = Zone::new(".", 1);
+    let example = Zone::new("example.", 2);
+    let mut validator = Validator::new(vec![root.anchor()], |name: &str, rtype| {
+        fixture::fetch(&root, &example, name, rtype)
+    });
+    validator.now = fixture::NOW;
+    let unsigned = [a("host.c.example.", [192, 0, 2, 3])];
+    let statuses = validator.validate(&response("host.c.example.", 1, 0, &unsigned, &[]))
+        .unwrap();
+    assert_eq!(statuses[0].status, Security::Insecure);
+    assert_eq!(statuses[0].reason.as_deref(), Some("c.example. is an unsigned delegation"));
+
+    // Without the flag the same record says c.example. does not exist.
+    let zone = &example.name;
+    let c = Name::parse("c.example.").unwrap();
+    let covering = |flags| {
+        example.nsec3("35mthgpgcu1qg68fab165klnsnk3dpvl", "b4um86eghhds6nea196smvmlo4ors995",
+                      flags, &[TYPE_NS, TYPE_DS, TYPE_RRSIG]).remove(0)
+    };
+    assert_eq!(nsec3_denial(&covering(NSEC3_OPT_OUT), &c, TYPE_DS, zone),
+               Ok(Some(Denial::NoName { opt_out: true })));
+    assert_eq!(nsec3_denial(&covering(0), &c, TYPE_DS, zone),
+               Ok(Some(Denial::NoName { opt_out: false })));
+    // A record matching the hash of the name lists its types.
+    let ns1 = Name::parse("ns1.example.").unwrap();
+    let matching = example.nsec3("2t7b4g4vsa5smi47k61mv5bv1a22bojr",
+                                 "2vptu5timamqttgl4luu9kg21e0aor3s", 0, &[1, TYPE_RRSIG])
+        .remove(0);
+    assert_eq!(nsec3_denial(&matching, &ns1, TYPE_DS, zone),
+               Ok(Some(Denial::NoData { delegation: false })));
+    assert!(nsec3_denial(&matching, &ns1, 1, zone).is_err());
+    // Records of another zone say nothing about the name.
+    assert_eq!(nsec3_denial(&matching, &ns1, TYPE_DS, &Name::parse("other.").unwrap()),
+               Ok(None));
+}
+
+#[test]
+fn test_parse_trust_anchors() {
+    let anchors = parse_trust_anchors(ROOT_TRUST_ANCHORS).unwrap();
+    assert_eq!(anchors.len(), 2);
+    assert_eq!(anchors[0].zone, Name::parse(".").unwrap());
+    assert_eq!((anchors[0].ds.key_tag, anchors[0].ds.algorithm, anchors[0].ds.digest_type),
+               (20326, 8, 2));
+    assert_eq!(anchors[0].ds.digest[..4], [0xe0, 0x6d, 0x44, 0xb8]);
+
+    let anchors = parse_trust_anchors("; comment\n\nExample. 3600 IN DS 1234 13 2 AB CD\n")
+        .unwrap();
+    assert_eq!(anchors[0].zone.to_string(), "example.");
+    assert_eq!(anchors[0].ds.digest, [0xab, 0xcd]);
+    assert!(parse_trust_anchors("example. IN DS 1234 13 2").is_err());
+    assert!(parse_trust_anchors("example. IN DS 1234 13 2 XYZ").is_err());
+}
+
+#[test]
+fn test_strip() {
+    use fixture::{a, response, Zone};
+
+    let example = Zone::new("example.", 2);
+    let wire = response("www.example.", 1, 0, &example.sign(&[a("www.example.", [192, 0, 2, 1])]),
+                        &example.nsec("www.example.", "example.", &[1]));
+    assert!(rustdns::Message::from_slice(&wire).is_err());
+    let message = rustdns::Message::from_slice(&strip(&wire).unwrap()).unwrap();
+    assert_eq!(message.answers.len(), 1);
+    assert_eq!(message.answers[0].resource.to_string(), "192.0.2.1");
+    assert!(message.authoritys.is_empty());
+}
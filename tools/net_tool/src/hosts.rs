@@ -0,0 +1,109 @@
+//! Static overrides from a hosts file, lines of an address followed by the
+//! names it answers for, like `/etc/hosts`:
+//!
+//! ```text
+//! # lab machines
+//! 10.0.0.5    build.lab build
+//! fd00::5     build.lab
+//! ```
+
+use rustdns::types::{Class, Record, Resource, Type};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::resolver::fqdn;
+use crate::reverse;
+
+/// Overrides are answered with a short TTL, so edits show up soon after a
+/// restart of the server.
+const HOSTS_TTL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Default)]
+pub struct Hosts {
+    /// Addresses of each name.
+    addresses: HashMap<String, Vec<IpAddr>>,
+    /// Names of each address, under the reverse lookup name.
+    names: HashMap<String, Vec<String>>,
+}
+
+impl Hosts {
+    pub fn load(path: &Path) -> io::Result<Hosts> {
+        let content = fs::read_to_string(path).map_err(|error| {
+            io::Error::new(error.kind(), format!("{}: {}", path.display(), error))
+        })?;
+        Hosts::parse(&content).map_err(|message| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), message))
+        })
+    }
+
+    fn parse(content: &str) -> Result<Hosts, String> {
+        let mut hosts = Hosts::default();
+        for (number, line) in content.lines().enumerate() {
+            let mut words = line.split('#').next().unwrap_or("").split_whitespace();
+            let Some(address) = words.next() else { continue };
+            let ip: IpAddr = address.parse()
+                .map_err(|_| format!("line {}: invalid address {:?}", number + 1, address))?;
+            for name in words {
+                let name = fqdn(name);
+                hosts.addresses.entry(name.clone()).or_default().push(ip);
+                hosts.names.entry(reverse::reverse_name(ip)).or_default().push(name);
+            }
+        }
+        Ok(hosts)
+    }
+
+    /// The records answering a question about `name`, or `None` when the
+    /// name is not overridden. A name with only IPv4 addresses has no
+    /// AAAA records and the other way around.
+    pub fn lookup(&self, name: &str, query_type: Type) -> Option<Vec<Record>> {
+        let name = fqdn(name);
+        let record = |resource| Record::new(&name, Class::Internet, HOSTS_TTL, resource);
+        if let Some(addresses) = self.addresses.get(&name) {
+            return Some(addresses.iter()
+                .filter_map(|ip| match (ip, query_type) {
+                    (IpAddr::V4(ip), Type::A | Type::ANY) => Some(record(Resource::A(*ip))),
+                    (IpAddr::V6(ip), Type::AAAA | Type::ANY) => Some(record(Resource::AAAA(*ip))),
+                    _ => None,
+                })
+                .collect());
+        }
+        self.names.get(&name).map(|names| match query_type {
+            Type::PTR | Type::ANY => names.iter()
+                .map(|target| record(Resource::PTR(target.clone())))
+                .collect(),
+            _ => Vec::new(),
+        })
+    }
+
+    pub fn name_count(&self) -> usize {
+        self.addresses.len()
+    }
+}
+
+#[test]
+fn test_hosts_lookup() {
+    let hosts = Hosts::parse("\
+        # lab\n\
+        10.0.0.5   build.lab build\n\
+        fd00::5    Build.Lab\n\
+        \n").unwrap();
+    assert_eq!(hosts.name_count(), 2);
+
+    let data = |records: Option<Vec<Record>>| {
+        records.map(|records| {
+            records.iter().map(|record| record.resource.to_string()).collect::<Vec<_>>()
+        })
+    };
+    assert_eq!(data(hosts.lookup("build.lab.", Type::A)), Some(vec!["10.0.0.5".to_string()]));
+    assert_eq!(data(hosts.lookup("BUILD.lab", Type::AAAA)), Some(vec!["fd00::5".to_string()]));
+    assert_eq!(data(hosts.lookup("build", Type::MX)), Some(vec![]));
+    assert_eq!(data(hosts.lookup("5.0.0.10.in-addr.arpa.", Type::PTR)),
+               Some(vec!["build.lab.".to_string(), "build.".to_string()]));
+    assert_eq!(data(hosts.lookup("example.com", Type::A)), None);
+
+    assert!(Hosts::parse("build.lab 10.0.0.5").is_err());
+}
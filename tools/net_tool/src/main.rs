@@ -1,15 +1,51 @@
-use std::collections::HashMap;
 use std::error::Error;
 use rustdns::Message;
 use rustdns::types::*;
-use std::net::UdpSocket;
+use std::fs;
+use std::io::{self, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::process::ExitCode;
+use std::sync::{mpsc, Arc};
+use std::thread;
 use std::time::Duration;
-use ipinfo::{IpDetails, IpError, IpInfo, IpInfoConfig};
+use clap::Parser;
+use rustls::ClientConfig;
+
+mod bulk;
+mod cli;
+mod config;
+mod dnssec;
+mod hosts;
+mod myip;
+mod output;
+mod probe;
+mod provider;
+mod resolver;
+mod reverse;
+mod server;
+#[cfg(test)]
+mod testing;
+mod trace;
+mod transport;
+mod watch;
+mod wire;
+
+use cli::{Cli, Command, ProviderArgs, ResolverArgs};
+use dnssec::{Security, Validator};
+use hosts::Hosts;
+use myip::Source;
+use output::{DnsReport, Format};
+use probe::PortState;
+use resolver::{ResolverConfig, Server};
+use server::Stub;
+use watch::Watcher;
 
 type GenericError = Box<dyn Error + Send + Sync + 'static>;
 type GenericResult<T> = Result<T, GenericError>;
 
-fn udp(domain: &str, query_type: Type) -> std::io::Result<Message> {
+/// Build a recursive query for `domain`.
+fn query(domain: &str, query_type: Type) -> Message {
     // A DNS Message can be easily constructed
     let mut m = Message::default();
     m.add_question(domain, query_type, Class::Internet);
@@ -17,46 +53,98 @@ fn udp(domain: &str, query_type: Type) -> std::io::Result<Message> {
         payload_size: 4096,       // which supports a larger payload size.
         ..Default::default()
     });
+    m
+}
 
-    // Setup a UDP socket for sending to a DNS server.
-    let socket = UdpSocket::bind("0.0.0.0:0")?;
-    socket.set_read_timeout(Some(Duration::new(10, 0)))?;
-    socket.connect("8.8.8.8:53")?; // Google's Public DNS Servers
-
-    // Encode the DNS Message as a Vec<u8>.
-    let question = m.to_vec()?;
-
-    // Send to the server.
-    socket.send(&question)?;
+/// Query the configured servers in order, walking the list again on each
+/// attempt, and return the first response.
+fn udp(domain: &str, query_type: Type, config: &ResolverConfig) -> io::Result<Message> {
+    Message::from_slice(&udp_wire(domain, query_type, false, config)?)
+}
 
-    // Wait for a response from the DNS server.
-    let mut resp = [0; 4096];
-    let len = socket.recv(&mut resp)?;
+/// Like `udp`, but with the RD bit clear, so the server answers from its own
+/// zones or refers to other servers instead of resolving the name itself.
+fn udp_norec(domain: &str, query_type: Type, config: &ResolverConfig) -> io::Result<Message> {
+    let mut m = query(domain, query_type);
+    m.rd = false;
+    Message::from_slice(&send(domain, &m.to_vec()?, m.id, config)?)
+}
 
-    // Take the response bytes and turn it into another DNS Message.
-    let answer = Message::from_slice(&resp[0..len])?;
+/// Like `udp`, but the response is left encoded. With `dnssec` the query
+/// sets the DO bit of its EDNS extension to ask for the DNSSEC records, and
+/// the CD bit so the server answers even when its own validation fails.
+fn udp_wire(domain: &str, query_type: Type, dnssec: bool, config: &ResolverConfig)
+    -> io::Result<Vec<u8>> {
+    let mut m = query(domain, query_type);
+    if dnssec {
+        m.cd = true;
+        if let Some(extension) = &mut m.extension {
+            extension.dnssec_ok = true;
+        }
+    }
 
-    // Now do something with `answer`, in this case print it!
-    println!("DNS Response:\n{}", &answer);
+    // Encode the DNS Message as a Vec<u8>.
+    let question = m.to_vec()?;
+    send(domain, &question, m.id, config)
+}
 
-    Ok(answer)
+/// Send an encoded query for `domain` to the configured servers.
+fn send(domain: &str, question: &[u8], id: u16, config: &ResolverConfig) -> io::Result<Vec<u8>> {
+    let mut tls = None;
+    let mut failures = Vec::new();
+    for _attempt in 0..config.attempts.max(1) {
+        for server in &config.servers {
+            match exchange(server, question, id, config, &mut tls) {
+                Ok(answer) => return Ok(answer),
+                Err(error) => failures.push(format!("{}: {}", server, error)),
+            }
+        }
+    }
+    if failures.is_empty() {
+        failures.push("no DNS servers configured".to_string());
+    }
+    Err(io::Error::other(format!("DNS query for {} failed: {}", domain, failures.join("; "))))
+}
 
+/// Ask one server over its transport. Plain servers are asked over UDP
+/// unless TCP was requested, and again over TCP when the UDP response is
+/// truncated. The TLS configuration is loaded the first time it is needed.
+fn exchange(server: &Server, question: &[u8], id: u16, config: &ResolverConfig,
+            tls: &mut Option<Arc<ClientConfig>>) -> io::Result<Vec<u8>> {
+    let timeout = config.timeout;
+    match server {
+        Server::Plain(address) => {
+            if config.tcp {
+                return transport::tcp_exchange(*address, question, id, timeout);
+            }
+            let answer = transport::udp_exchange(*address, question, id, timeout)?;
+            // The TC flag in the header.
+            if answer.get(2).is_some_and(|flags| flags & 0b0000_0010 != 0) {
+                return transport::tcp_exchange(*address, question, id, timeout);
+            }
+            Ok(answer)
+        }
+        Server::Tls { address, name } => {
+            let tls = tls_config(tls, config)?;
+            transport::tls_exchange(*address, name, question, id, timeout, tls)
+        }
+        Server::Https { url, get } => {
+            let tls = tls_config(tls, config)?;
+            transport::https_exchange(url, *get, question, id, timeout, tls)
+        }
+    }
 }
 
-fn get_ip_info(ips: Vec<&str>) -> Result<HashMap<String, IpDetails>, IpError> {
-    // Setup token and other configurations.
-    let config = IpInfoConfig { token: Some("988a789b27b1f1".to_string()), ..Default::default() };
-    // Setup IpInfo structure and start looking up IP addresses.
-    let mut ipinfo = IpInfo::new(config)?;
-    ipinfo.lookup(ips.as_slice())
+fn tls_config(tls: &mut Option<Arc<ClientConfig>>, config: &ResolverConfig)
+    -> io::Result<Arc<ClientConfig>> {
+    if tls.is_none() {
+        *tls = Some(transport::tls_config(config.ca_file.as_deref())?);
+    }
+    Ok(tls.clone().unwrap())
 }
 
 fn is_ip_record(record: &Record) -> bool {
-    match record.resource.r#type() {
-        Type::A     => true,
-        Type::AAAA  => true,
-        _           => false
-    }
+    matches!(record.resource.r#type(), Type::A | Type::AAAA)
 }
 
 fn dns_message_to_ip_vec(message: Message) -> Vec<String> {
@@ -66,38 +154,359 @@ fn dns_message_to_ip_vec(message: Message) -> Vec<String> {
             .collect()
 }
 
-fn format_ip_details(details: HashMap<String, IpDetails>) -> GenericResult<()> {
-    details.iter().for_each(
-        |(key, details)| {
-            println!("{} info =>", key);
-            println!("{}", serde_json::to_string_pretty(details).unwrap())
+/// Exit status when the DNS server answered with an error such as NXDOMAIN,
+/// other failures exit with 1 and invalid usage with 2.
+const EXIT_DNS_ERROR: u8 = 3;
+/// Exit status when a PTR name does not resolve back to its address.
+const EXIT_NOT_CONFIRMED: u8 = 4;
+/// Exit status when no probed address accepted a connection.
+const EXIT_UNREACHABLE: u8 = 5;
+/// Exit status when DNSSEC validation of the answer failed.
+const EXIT_BOGUS: u8 = 6;
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let format = cli.format;
+    let result = match cli.command {
+        Command::Dns { domain, query_type, info, dnssec, trust_anchor, resolver, provider } => {
+            let dnssec = Some(trust_anchor.as_deref()).filter(|_| dnssec);
+            dns(&domain, query_type, info, dnssec, &resolver, &provider, format)
+        }
+        Command::Ipinfo { ips, provider } => ipinfo(&ips, &provider, format),
+        Command::Myip { sources, timeout, quorum } => {
+            myip(&sources, timeout, quorum.into(), format)
+        }
+        Command::Ptr { ips, fcrdns, resolver } => ptr(&ips, fcrdns, &resolver, format),
+        Command::Bulk { input, query_type, concurrency, resolver } => {
+            bulk(&input, query_type, concurrency.into(), &resolver, format)
+        }
+        Command::Trace { domain, query_type, roots, resolver } => {
+            trace(&domain, query_type, &roots, &resolver, format)
+        }
+        Command::Probe { target, ports, connect_timeout, concurrency, resolver } => {
+            probe(&target, &ports, connect_timeout, concurrency.into(), &resolver, format)
+        }
+        Command::Watch { input, query_types, interval, rounds, output, resolver } => {
+            watch(&input, &query_types, interval, rounds, output.as_deref(), &resolver, format)
+        }
+        Command::Serve { listen, hosts, resolver } => serve(listen, hosts.as_deref(), &resolver),
+    };
+
+    match result {
+        Ok(code) => code,
+        Err(error) => {
+            eprintln!("net_tool: {}", error);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn dns(domain: &str, query_type: Type, info: bool, dnssec: Option<Option<&Path>>,
+       resolver: &ResolverArgs, provider: &ProviderArgs, format: Format)
+    -> GenericResult<ExitCode> {
+    let config = resolver.config()?;
+    let (dns_result, statuses) = match dnssec {
+        Some(trust_anchor) => {
+            let anchors = dnssec::load_trust_anchors(trust_anchor)?;
+            let wire = udp_wire(domain, query_type, true, &config)?;
+            let mut validator = Validator::new(anchors, |name: &str, rtype| {
+                let id = Message::random_id();
+                send(name, &dnssec::encode_query(name, rtype, id)?, id, &config)
+            });
+            let statuses = validator.validate(&wire)?;
+            (Message::from_slice(&dnssec::strip(&wire)?)?, Some(statuses))
+        }
+        None => (udp(domain, query_type, &config)?, None),
+    };
+    let mut report = DnsReport::new(domain, query_type, &dns_result);
+    report.dnssec = statuses;
+    let bogus = report.dnssec.iter().flatten()
+        .find(|status| status.status == Security::Bogus);
+    if let Some(bogus) = bogus {
+        output::print_dns(format, &report, &dns_result)?;
+        eprintln!("net_tool: {} {}: DNSSEC validation failed: {}", domain, query_type,
+                  bogus.reason.as_deref().unwrap_or("bogus"));
+        return Ok(ExitCode::from(EXIT_BOGUS));
+    }
+    if dns_result.rcode != Rcode::NoError {
+        output::print_dns(format, &report, &dns_result)?;
+        eprintln!("net_tool: {} {}: {}", domain, query_type, dns_result.rcode);
+        return Ok(ExitCode::from(EXIT_DNS_ERROR));
+    }
+
+    let ips = dns_message_to_ip_vec(dns_result.clone());
+    if info && !ips.is_empty() {
+        report.ip_details = Some(provider.provider()?.lookup(&ips)?);
+    }
+    output::print_dns(format, &report, &dns_result)?;
+    Ok(ExitCode::SUCCESS)
+}
+
+fn ipinfo(ips: &[IpAddr], provider: &ProviderArgs, format: Format) -> GenericResult<ExitCode> {
+    let ips: Vec<String> = ips.iter().map(IpAddr::to_string).collect();
+    output::print_ips(format, &provider.provider()?.lookup(&ips)?)?;
+    Ok(ExitCode::SUCCESS)
+}
+
+fn myip(sources: &[Source], timeout: f64, quorum: usize, format: Format)
+    -> GenericResult<ExitCode> {
+    let sources = match sources {
+        [] => myip::configured_sources()?,
+        sources => sources.to_vec(),
+    };
+    let results = myip::ask_all(&sources, Duration::try_from_secs_f64(timeout)?);
+    let public_ip = myip::consensus(&results, quorum)?;
+    for result in &results {
+        match result.ip {
+            Some(ip) if ip != public_ip && ip.is_ipv4() == public_ip.is_ipv4() => {
+                eprintln!("net_tool: {} found {} instead", result.source, ip);
+            }
+            _ => {}
         }
-    );
-    Ok(())
+    }
+    output::print_public_ip(format, public_ip, &results)?;
+    Ok(ExitCode::SUCCESS)
 }
 
-async fn get_public_ip() -> GenericResult<String> {
-    let url = "https://ifconfig.me";
-    let client = surf::Client::new();
-    let request= client.get(url).recv_string().await;
+fn ptr(ips: &[IpAddr], fcrdns: bool, resolver: &ResolverArgs, format: Format)
+    -> GenericResult<ExitCode> {
+    let config = resolver.config()?;
+    let results = ips.iter()
+        .map(|ip| reverse::lookup_ptr(*ip, fcrdns, &config))
+        .collect::<io::Result<Vec<_>>>()?;
+    output::print_ptr(format, &results)?;
 
-    Ok(request.unwrap())
+    for result in &results {
+        for name in result.mismatches() {
+            eprintln!("net_tool: {} points to {}, which does not resolve back to it",
+                      result.ip, name.name);
+        }
+    }
+    if results.iter().any(|result| result.is_error()) {
+        return Ok(ExitCode::from(EXIT_DNS_ERROR));
+    }
+    if results.iter().any(|result| result.mismatches().next().is_some()) {
+        return Ok(ExitCode::from(EXIT_NOT_CONFIRMED));
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Exits with `EXIT_DNS_ERROR` when any host did not resolve without error.
+fn bulk(input: &Path, query_type: Type, concurrency: usize, resolver: &ResolverArgs,
+        format: Format) -> GenericResult<ExitCode> {
+    let config = resolver.config()?;
+    let hosts = bulk::read_hosts(input)?;
+    let results = bulk::resolve_all(&hosts, query_type, &config, concurrency)?;
+    output::print_bulk(format, &results)?;
+    let no_error = Rcode::NoError.to_string();
+    if results.iter().any(|result| result.status.as_ref() != Some(&no_error)) {
+        return Ok(ExitCode::from(EXIT_DNS_ERROR));
+    }
+    Ok(ExitCode::SUCCESS)
 }
 
-fn main() -> GenericResult<()> {
-    let domain = "mgs.dgcb.com.cn";
-    let query_type = Type::ANY;
-    let dns_result = udp(domain, query_type)?;
-    let mut ips = dns_message_to_ip_vec(dns_result);
+fn trace(domain: &str, query_type: Type, roots: &[SocketAddr], resolver: &ResolverArgs,
+         format: Format) -> GenericResult<ExitCode> {
+    let config = resolver.config()?;
+    let roots = if roots.is_empty() {
+        trace::ROOT_HINTS.iter().map(|(_, ip)| SocketAddr::from((*ip, 53))).collect()
+    } else {
+        roots.to_vec()
+    };
 
-    let local_ip = async_std::task::block_on(get_public_ip())?;
-    ips.push(local_ip);
+    let mut hops = Vec::new();
+    let result = trace::trace(domain, query_type, &roots, &config, |hop| match format {
+        Format::Text => output::print_hop_text(hop),
+        _ => hops.push(hop.clone()),
+    });
+    output::print_trace(format, &hops)?;
+    let rcode = result?;
+    if rcode != Rcode::NoError {
+        eprintln!("net_tool: {} {}: {}", domain, query_type, rcode);
+        return Ok(ExitCode::from(EXIT_DNS_ERROR));
+    }
+    Ok(ExitCode::SUCCESS)
+}
 
-    println!("records => {:?}", &ips);
+/// Exits with `EXIT_UNREACHABLE` when no address accepted a connection.
+fn probe(target: &str, ports: &[u16], connect_timeout: f64, concurrency: usize,
+         resolver: &ResolverArgs, format: Format) -> GenericResult<ExitCode> {
+    let ips: Vec<IpAddr> = match target.parse() {
+        Ok(ip) => vec![ip],
+        Err(_) => {
+            let config = resolver.config()?;
+            let mut ips = Vec::new();
+            for query_type in [Type::A, Type::AAAA] {
+                let response = udp(target, query_type, &config)?;
+                ips.extend(dns_message_to_ip_vec(response).iter()
+                    .filter_map(|ip| ip.parse::<IpAddr>().ok()));
+            }
+            ips
+        }
+    };
+    if ips.is_empty() {
+        eprintln!("net_tool: {} has no addresses", target);
+        return Ok(ExitCode::from(EXIT_DNS_ERROR));
+    }
 
-    let details = get_ip_info(
-        ips.iter().map(String::as_str).collect())?;
+    let timeout = Duration::try_from_secs_f64(connect_timeout)?;
+    let results = probe::probe_all(&ips, ports, timeout, concurrency);
+    output::print_probe(format, &results)?;
+    if !results.iter().any(|result| result.state == PortState::Open) {
+        return Ok(ExitCode::from(EXIT_UNREACHABLE));
+    }
+    Ok(ExitCode::SUCCESS)
+}
 
-    format_ip_details(details)
+/// Watch until interrupted or for `rounds` rounds. A failed query is
+/// reported and the name compared with its last answer in the next round.
+fn watch(input: &Path, query_types: &[Type], interval: Duration, rounds: Option<u32>,
+         output: Option<&Path>, resolver: &ResolverArgs, format: Format)
+    -> GenericResult<ExitCode> {
+    let config = resolver.config()?;
+    let names = bulk::read_hosts(input)?;
+    let mut log = match output {
+        Some(path) => Some(fs::OpenOptions::new().create(true).append(true).open(path)
+            .map_err(|error| format!("{}: {}", path.display(), error))?),
+        None => None,
+    };
+    let mut watcher = Watcher::new(config);
+    eprintln!("net_tool: watching {} names every {:?}", names.len(), interval);
 
-}
\ No newline at end of file
+    let mut header = true;
+    for round in 1.. {
+        let mut changes = Vec::new();
+        for name in &names {
+            for query_type in query_types {
+                match watcher.check(name, *query_type) {
+                    Ok(change) => changes.extend(change),
+                    Err(error) => eprintln!("net_tool: {} {}: {}", name, query_type, error),
+                }
+            }
+        }
+        if !changes.is_empty() {
+            output::print_changes(format, &changes, header)?;
+            header = false;
+            if let Some(log) = &mut log {
+                for change in &changes {
+                    writeln!(log, "{}", serde_json::to_string(change)?)?;
+                }
+            }
+        }
+        if rounds.is_some_and(|rounds| round >= rounds) {
+            break;
+        }
+        thread::sleep(interval);
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Serve until interrupted, then print the cache statistics.
+fn serve(listen: SocketAddr, hosts: Option<&Path>, resolver: &ResolverArgs)
+    -> GenericResult<ExitCode> {
+    let config = resolver.config()?;
+    let hosts = match hosts {
+        Some(path) => Hosts::load(path)?,
+        None => Hosts::default(),
+    };
+    let (stop, stopped) = mpsc::channel();
+    ctrlc::set_handler(move || {
+        let _ = stop.send(());
+    })?;
+
+    let overrides = hosts.name_count();
+    let stub = Arc::new(Stub::new(config, hosts));
+    let address = server::spawn(stub.clone(), listen)?;
+    eprintln!("net_tool: serving DNS on {} (UDP and TCP), {} names from the hosts file",
+              address, overrides);
+    let _ = stopped.recv();
+    eprintln!("net_tool: {}", stub.stats());
+    Ok(ExitCode::SUCCESS)
+}
+
+#[cfg(test)]
+use std::net::UdpSocket;
+
+#[test]
+fn test_udp_tries_the_next_server() {
+    // Nothing answers on the first server, so the query times out there.
+    let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let answering = testing::spawn_udp_server(|query| Some(testing::empty_response(query)));
+    let config = ResolverConfig {
+        servers: vec![Server::Plain(silent.local_addr().unwrap()), Server::Plain(answering)],
+        timeout: Duration::from_millis(200),
+        attempts: 1,
+        ..ResolverConfig::default()
+    };
+
+    let answer = udp("example.com", Type::A, &config).unwrap();
+    assert_eq!(answer.questions[0].name, "example.com.");
+    assert!(answer.answers.is_empty());
+}
+
+#[test]
+fn test_udp_reports_every_failure() {
+    let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let config = ResolverConfig {
+        servers: vec![Server::Plain(silent.local_addr().unwrap())],
+        timeout: Duration::from_millis(50),
+        attempts: 2,
+        ..ResolverConfig::default()
+    };
+
+    let error = udp("example.com", Type::A, &config).unwrap_err().to_string();
+    assert_eq!(error.matches(&silent.local_addr().unwrap().to_string()).count(), 2);
+}
+
+#[test]
+fn test_truncated_response_is_retried_over_tcp() {
+    let server = testing::spawn_dns_server(
+        |query| Some(testing::response(query, testing::TC, &[])),
+        |query| Some(testing::response(query, 0, &[testing::a_record([192, 0, 2, 1], 300)])));
+    let mut config = ResolverConfig {
+        servers: vec![Server::Plain(server)],
+        timeout: Duration::from_secs(1),
+        attempts: 1,
+        ..ResolverConfig::default()
+    };
+
+    let answer = udp("example.com", Type::A, &config).unwrap();
+    assert!(!answer.tc);
+    assert_eq!(dns_message_to_ip_vec(answer), vec!["192.0.2.1"]);
+
+    config.tcp = true;
+    let answer = udp("example.com", Type::A, &config).unwrap();
+    assert_eq!(dns_message_to_ip_vec(answer), vec!["192.0.2.1"]);
+}
+
+#[test]
+fn test_dns_over_tls_and_https() {
+    let answer = |query: &[u8]| {
+        Some(testing::response(query, 0, &[testing::a_record([192, 0, 2, 1], 300)]))
+    };
+    let (tls_server, tls_ca_file) = testing::spawn_tls_server(answer);
+    let (https_server, https_ca_file) = testing::spawn_https_server(answer);
+    let https = |template: String| resolver::parse_server(&template).unwrap();
+
+    for (server, ca_file) in [
+        (Server::Tls { address: tls_server, name: "localhost".to_string() }, tls_ca_file),
+        (https(format!("https://localhost:{}/dns-query", https_server.port())),
+         https_ca_file.clone()),
+        (https(format!("https://localhost:{}/dns-query{{?dns}}", https_server.port())),
+         https_ca_file),
+    ] {
+        let mut config = ResolverConfig {
+            servers: vec![server.clone()],
+            timeout: Duration::from_secs(2),
+            attempts: 1,
+            ..ResolverConfig::default()
+        };
+        // The self-signed stand-in certificate is not trusted without the CA file.
+        assert!(udp("example.com", Type::A, &config).is_err(), "{} without CA file", server);
+
+        config.ca_file = Some(ca_file);
+        let answer = udp("example.com", Type::A, &config)
+            .unwrap_or_else(|error| panic!("{}: {}", server, error));
+        assert_eq!(dns_message_to_ip_vec(answer), vec!["192.0.2.1"]);
+    }
+}
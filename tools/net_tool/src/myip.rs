@@ -0,0 +1,411 @@
+//! Finding the public IP address of this machine by asking several
+//! independent sources, and only trusting an address enough of them agree on.
+//!
+//! A source is given as
+//!
+//! * `https://host/path` (or `http://`), a web service answering with the
+//!   address as the whole body;
+//! * `dns:[TYPE:]NAME@SERVER[:PORT]`, a DNS server answering a query for NAME
+//!   with the address of the client, in an A or AAAA record, or with TYPE `TXT`
+//!   in a TXT record;
+//! * `stun:HOST[:PORT]`, a STUN server answering a binding request (RFC 5389).
+
+use async_std::future;
+use rustdns::types::{Resource, Type};
+use serde::Serialize;
+use std::cmp::Reverse;
+use std::collections::hash_map::RandomState;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::thread;
+use std::time::Duration;
+use url::Url;
+
+use crate::config::ConfigFile;
+use crate::resolver::{ResolverConfig, Server};
+
+/// The sources asked when neither `--source` nor the `myip_sources` setting
+/// names any.
+pub const DEFAULT_SOURCES: &[&str] = &[
+    "https://ifconfig.me/ip",
+    "https://api.ipify.org",
+    "https://icanhazip.com",
+    "dns:myip.opendns.com@208.67.222.222",
+    "dns:TXT:o-o.myaddr.l.google.com@216.239.32.10",
+    "stun:stun.l.google.com:19302",
+    "stun:stun.cloudflare.com:3478",
+];
+
+const DNS_PORT: u16 = 53;
+const STUN_PORT: u16 = 3478;
+const STUN_BINDING_REQUEST: u16 = 0x0001;
+const STUN_BINDING_RESPONSE: u16 = 0x0101;
+const STUN_MAGIC_COOKIE: u32 = 0x2112_a442;
+const STUN_MAPPED_ADDRESS: u16 = 0x0001;
+const STUN_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+
+/// Somewhere to ask for the public IP address.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Source {
+    Http(Url),
+    Dns { query_type: Type, name: String, server: String },
+    Stun(String),
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Http(url) => write!(f, "{}", url),
+            Source::Dns { query_type: Type::A, name, server } => {
+                write!(f, "dns:{}@{}", name, server)
+            }
+            Source::Dns { query_type, name, server } => {
+                write!(f, "dns:{}:{}@{}", query_type, name, server)
+            }
+            Source::Stun(server) => write!(f, "stun:{}", server),
+        }
+    }
+}
+
+pub fn parse_source(source: &str) -> Result<Source, String> {
+    if let Some(query) = source.strip_prefix("dns:") {
+        let (query, server) = query.rsplit_once('@')
+            .ok_or_else(|| format!("{}: expected dns:[TYPE:]NAME@SERVER", source))?;
+        let (query_type, name) = match query.split_once(':') {
+            Some((query_type, name)) => match query_type.to_ascii_uppercase().as_str() {
+                "A" => (Type::A, name),
+                "AAAA" => (Type::AAAA, name),
+                "TXT" => (Type::TXT, name),
+                _ => return Err(format!("{}: expected type A, AAAA or TXT", source)),
+            },
+            None => (Type::A, query),
+        };
+        if name.is_empty() || server.is_empty() {
+            return Err(format!("{}: expected dns:[TYPE:]NAME@SERVER", source));
+        }
+        return Ok(Source::Dns { query_type, name: name.to_string(), server: server.to_string() });
+    }
+    if let Some(server) = source.strip_prefix("stun:") {
+        if server.is_empty() {
+            return Err(format!("{}: expected stun:HOST[:PORT]", source));
+        }
+        return Ok(Source::Stun(server.to_string()));
+    }
+    match Url::parse(source) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") => Ok(Source::Http(url)),
+        _ => Err(format!("{}: expected an http(s) URL, dns:[TYPE:]NAME@SERVER or stun:HOST[:PORT]",
+                         source)),
+    }
+}
+
+/// The sources named by the `myip_sources` setting of the configuration
+/// file, separated by commas or spaces, or else `DEFAULT_SOURCES`.
+pub fn configured_sources() -> io::Result<Vec<Source>> {
+    let config = ConfigFile::load()?;
+    let sources: Vec<&str> = match config.get("myip_sources") {
+        Some(sources) => sources.split([',', ' ']).filter(|source| !source.is_empty()).collect(),
+        None => DEFAULT_SOURCES.to_vec(),
+    };
+    sources.into_iter()
+        .map(|source| {
+            parse_source(source).map_err(|message| {
+                io::Error::new(io::ErrorKind::InvalidInput, format!("myip_sources: {}", message))
+            })
+        })
+        .collect()
+}
+
+/// What one source answered.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct SourceResult {
+    pub source: String,
+    pub ip: Option<IpAddr>,
+    pub error: Option<String>,
+}
+
+/// Ask every source at once, each within `timeout`. Results are in the
+/// order of `sources`.
+pub fn ask_all(sources: &[Source], timeout: Duration) -> Vec<SourceResult> {
+    thread::scope(|scope| {
+        let asking: Vec<_> = sources.iter()
+            .map(|source| scope.spawn(move || ask(source, timeout)))
+            .collect();
+        sources.iter()
+            .zip(asking)
+            .map(|(source, asking)| {
+                let answer = asking.join()
+                    .unwrap_or_else(|_| Err(io::Error::other("the lookup panicked")));
+                SourceResult {
+                    source: source.to_string(),
+                    ip: answer.as_ref().ok().copied(),
+                    error: answer.err().map(|error| error.to_string()),
+                }
+            })
+            .collect()
+    })
+}
+
+/// The address at least `quorum` sources found, and more sources than any
+/// other address. IPv6 addresses only count when no source found an IPv4
+/// address, as services reached over IPv6 see another address than those
+/// reached over IPv4.
+pub fn consensus(results: &[SourceResult], quorum: usize) -> Result<IpAddr, String> {
+    let found: Vec<IpAddr> = results.iter().filter_map(|result| result.ip).collect();
+    if found.is_empty() {
+        let errors: Vec<String> = results.iter()
+            .filter_map(|result| Some(format!("{}: {}", result.source, result.error.as_ref()?)))
+            .collect();
+        return Err(format!("no source found the public IP address: {}", errors.join("; ")));
+    }
+    let ipv4 = found.iter().any(IpAddr::is_ipv4);
+    let mut votes: BTreeMap<IpAddr, usize> = BTreeMap::new();
+    for ip in found.into_iter().filter(|ip| ip.is_ipv4() == ipv4) {
+        *votes.entry(ip).or_default() += 1;
+    }
+    let mut ranked: Vec<(IpAddr, usize)> = votes.into_iter().collect();
+    ranked.sort_by_key(|(_, votes)| Reverse(*votes));
+    let tally = || {
+        ranked.iter().map(|(ip, votes)| format!("{} ({})", ip, votes)).collect::<Vec<_>>()
+            .join(", ")
+    };
+
+    let (ip, votes) = ranked[0];
+    if ranked.get(1).is_some_and(|(_, runner_up)| *runner_up == votes) {
+        return Err(format!("the sources disagree: {}", tally()));
+    }
+    let quorum = quorum.min(results.len()).max(1);
+    if votes < quorum {
+        return Err(format!("only {} sources agree on {}, {} needed: {}", votes, ip, quorum,
+                           tally()));
+    }
+    Ok(ip)
+}
+
+fn ask(source: &Source, timeout: Duration) -> io::Result<IpAddr> {
+    match source {
+        Source::Http(url) => ask_http(url, timeout),
+        Source::Dns { query_type, name, server } => ask_dns(*query_type, name, server, timeout),
+        Source::Stun(server) => ask_stun(server, timeout),
+    }
+}
+
+fn ask_http(url: &Url, timeout: Duration) -> io::Result<IpAddr> {
+    let body = async_std::task::block_on(future::timeout(timeout, async {
+        let mut response = surf::get(url.as_str()).await.map_err(io::Error::other)?;
+        let body = response.body_string().await.map_err(io::Error::other)?;
+        match response.status().as_u16() {
+            200 => Ok(body),
+            status => Err(io::Error::other(format!("HTTP {}", status))),
+        }
+    }))
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "timed out"))??;
+    parse_ip(&body)
+}
+
+fn ask_dns(query_type: Type, name: &str, server: &str, timeout: Duration) -> io::Result<IpAddr> {
+    let config = ResolverConfig {
+        servers: vec![Server::Plain(socket_address(server, DNS_PORT)?)],
+        timeout,
+        attempts: 1,
+        ..ResolverConfig::default()
+    };
+    let response = crate::udp(name, query_type, &config)?;
+    response.answers.iter()
+        .find_map(|record| match &record.resource {
+            Resource::A(ip) => Some(Ok(IpAddr::V4(*ip))),
+            Resource::AAAA(ip) => Some(Ok(IpAddr::V6(*ip))),
+            Resource::TXT(txt) => {
+                let text: Vec<u8> = txt.0.concat();
+                Some(parse_ip(&String::from_utf8_lossy(&text)))
+            }
+            _ => None,
+        })
+        .unwrap_or_else(|| {
+            Err(io::Error::other(format!("no {} record in the answer ({})", query_type,
+                                         response.rcode)))
+        })
+}
+
+fn ask_stun(server: &str, timeout: Duration) -> io::Result<IpAddr> {
+    let server = socket_address(server, STUN_PORT)?;
+    let socket = UdpSocket::bind(if server.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" })?;
+    socket.set_read_timeout(Some(timeout))?;
+    socket.connect(server)?;
+
+    let mut transaction = [0; 12];
+    let random = RandomState::new().build_hasher().finish().to_be_bytes();
+    transaction[..8].copy_from_slice(&random);
+    transaction[8..].copy_from_slice(&std::process::id().to_be_bytes());
+    let mut request = Vec::with_capacity(20);
+    request.extend_from_slice(&STUN_BINDING_REQUEST.to_be_bytes());
+    request.extend_from_slice(&0u16.to_be_bytes());
+    request.extend_from_slice(&STUN_MAGIC_COOKIE.to_be_bytes());
+    request.extend_from_slice(&transaction);
+    socket.send(&request)?;
+
+    let mut buffer = [0; 1024];
+    loop {
+        let len = socket.recv(&mut buffer).map_err(|error| match error.kind() {
+            io::ErrorKind::WouldBlock => io::Error::new(io::ErrorKind::TimedOut, "timed out"),
+            _ => error,
+        })?;
+        // Stray datagrams of other transactions are skipped.
+        if len >= 20 && buffer[8..20] == transaction {
+            return parse_stun_response(&buffer[..len]);
+        }
+    }
+}
+
+/// The mapped address in a binding response, preferring the XOR-MAPPED-ADDRESS
+/// attribute over the MAPPED-ADDRESS of older servers.
+fn parse_stun_response(response: &[u8]) -> io::Result<IpAddr> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+    if u16::from_be_bytes([response[0], response[1]]) != STUN_BINDING_RESPONSE {
+        return Err(invalid("not a STUN binding success response"));
+    }
+    let length = u16::from_be_bytes([response[2], response[3]]) as usize;
+    let mut attributes = response.get(20..20 + length)
+        .ok_or_else(|| invalid("truncated STUN response"))?;
+
+    let mut mapped = None;
+    while attributes.len() >= 4 {
+        let kind = u16::from_be_bytes([attributes[0], attributes[1]]);
+        let len = u16::from_be_bytes([attributes[2], attributes[3]]) as usize;
+        let value = attributes.get(4..4 + len).ok_or_else(|| invalid("truncated STUN attribute"))?;
+        match kind {
+            STUN_XOR_MAPPED_ADDRESS => {
+                // The address is XORed with the cookie and the transaction id.
+                let key = &response[4..20];
+                let ip = stun_address(value, key).ok_or_else(|| invalid("invalid STUN address"))?;
+                return Ok(ip);
+            }
+            STUN_MAPPED_ADDRESS => mapped = stun_address(value, &[0; 16]),
+            _ => {}
+        }
+        attributes = attributes.get(4 + len.next_multiple_of(4)..).unwrap_or(&[]);
+    }
+    mapped.ok_or_else(|| invalid("no mapped address in the STUN response"))
+}
+
+/// The address of an address attribute, each byte XORed with `key`.
+fn stun_address(value: &[u8], key: &[u8]) -> Option<IpAddr> {
+    let address: Vec<u8> = value.get(4..)?.iter().zip(key).map(|(byte, key)| byte ^ key).collect();
+    match (value.get(1)?, address.len()) {
+        (0x01, 4..) => Some(IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(&address[..4]).ok()?))),
+        (0x02, 16) => Some(IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(&address[..]).ok()?))),
+        _ => None,
+    }
+}
+
+fn parse_ip(text: &str) -> io::Result<IpAddr> {
+    text.trim().parse().map_err(|_| {
+        let text: String = text.trim().chars().take(40).collect();
+        io::Error::new(io::ErrorKind::InvalidData, format!("not an IP address: {:?}", text))
+    })
+}
+
+/// `server` as host[:port], resolved with the system resolver when it is a
+/// name.
+fn socket_address(server: &str, default_port: u16) -> io::Result<SocketAddr> {
+    if let Ok(ip) = server.parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, default_port));
+    }
+    if let Ok(address) = server.parse::<SocketAddr>() {
+        return Ok(address);
+    }
+    let address = match server.contains(':') {
+        true => server.to_string(),
+        false => format!("{}:{}", server, default_port),
+    };
+    address.to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{}: no address", server)))
+}
+
+#[test]
+fn test_parse_source() {
+    assert_eq!(parse_source("https://ifconfig.me/ip"),
+               Ok(Source::Http(Url::parse("https://ifconfig.me/ip").unwrap())));
+    assert_eq!(parse_source("dns:TXT:o-o.myaddr.l.google.com@216.239.32.10"),
+               Ok(Source::Dns {
+                   query_type: Type::TXT,
+                   name: "o-o.myaddr.l.google.com".to_string(),
+                   server: "216.239.32.10".to_string(),
+               }));
+    for source in DEFAULT_SOURCES {
+        assert_eq!(parse_source(source).unwrap().to_string().trim_end_matches('/'), *source);
+    }
+    assert!(parse_source("dns:myip.opendns.com").is_err());
+    assert!(parse_source("dns:MX:example.com@192.0.2.53").is_err());
+    assert!(parse_source("ftp://example.com/ip").is_err());
+    assert!(parse_source("stun:").is_err());
+}
+
+#[test]
+fn test_consensus() {
+    let result = |ip: Option<&str>| SourceResult {
+        source: "test".to_string(),
+        ip: ip.map(|ip| ip.parse().unwrap()),
+        error: ip.is_none().then(|| "timed out".to_string()),
+    };
+    let agreeing = [result(Some("203.0.113.7")), result(Some("203.0.113.7")),
+                    result(Some("198.51.100.1")), result(Some("2001:db8::7")), result(None)];
+    assert_eq!(consensus(&agreeing, 2), Ok("203.0.113.7".parse().unwrap()));
+    assert!(consensus(&agreeing, 3).unwrap_err().starts_with("only 2 sources agree"));
+
+    let split = [result(Some("203.0.113.7")), result(Some("198.51.100.1"))];
+    assert!(consensus(&split, 1).unwrap_err().starts_with("the sources disagree"));
+    // A quorum larger than the number of sources asks for all of them.
+    assert_eq!(consensus(&[result(Some("2001:db8::7"))], 2), Ok("2001:db8::7".parse().unwrap()));
+    assert_eq!(consensus(&[result(None)], 1),
+               Err("no source found the public IP address: test: timed out".to_string()));
+}
+
+#[test]
+fn test_ask_all() {
+    use crate::testing;
+
+    let http = testing::spawn_http_server(|target, _| match target {
+        "/ip" => (200, "203.0.113.7\n".to_string()),
+        _ => (404, "not found".to_string()),
+    });
+    let dns = testing::spawn_udp_server(|query| {
+        Some(testing::response(query, 0, &[testing::a_record([203, 0, 113, 7], 0)]))
+    });
+    // A STUN server mapping every client to 203.0.113.7:54321.
+    let stun = testing::spawn_udp_server(|request| {
+        let mut response = request.get(..20)?.to_vec();
+        response[..2].copy_from_slice(&STUN_BINDING_RESPONSE.to_be_bytes());
+        response[2..4].copy_from_slice(&12u16.to_be_bytes());
+        response.extend_from_slice(&STUN_XOR_MAPPED_ADDRESS.to_be_bytes());
+        response.extend_from_slice(&8u16.to_be_bytes());
+        response.extend_from_slice(&[0, 0x01]);
+        response.extend_from_slice(&(54321 ^ 0x2112u16).to_be_bytes());
+        let cookie = STUN_MAGIC_COOKIE.to_be_bytes();
+        response.extend(Ipv4Addr::new(203, 0, 113, 7).octets().iter().zip(cookie)
+            .map(|(byte, key)| byte ^ key));
+        Some(response)
+    });
+    let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+    let sources = [
+        format!("http://{}/ip", http),
+        format!("http://{}/missing", http),
+        format!("dns:myip.example@{}", dns),
+        format!("stun:{}", stun),
+        format!("stun:{}", silent.local_addr().unwrap()),
+    ];
+    let sources: Vec<Source> = sources.iter().map(|source| parse_source(source).unwrap()).collect();
+    let results = ask_all(&sources, Duration::from_millis(500));
+    let ips: Vec<Option<String>> = results.iter()
+        .map(|result| result.ip.map(|ip| ip.to_string()))
+        .collect();
+    let ip = Some("203.0.113.7".to_string());
+    assert_eq!(ips, [ip.clone(), None, ip.clone(), ip, None]);
+    assert_eq!(results[1].error.as_deref(), Some("HTTP 404"));
+    assert_eq!(results[4].error.as_deref(), Some("timed out"));
+    assert_eq!(consensus(&results, 3), Ok("203.0.113.7".parse().unwrap()));
+}
@@ -0,0 +1,607 @@
+//! Printing results as text for people or as JSON, CSV or a table for scripts.
+//!
+//! The JSON documents have a stable schema, fields may be added but are
+//! never renamed or removed:
+//!
+//! `net_tool dns` prints one object
+//!
+//! ```json
+//! {
+//!   "name": "example.com",
+//!   "type": "A",
+//!   "status": "NoError",
+//!   "records": [
+//!     {"section": "answer", "name": "example.com.", "type": "A", "ttl": 300, "data": "192.0.2.1"}
+//!   ],
+//!   "ip_details": [ ... ],
+//!   "dnssec": [
+//!     {"name": "example.com.", "type": "A", "status": "secure", "reason": null}
+//!   ]
+//! }
+//! ```
+//!
+//! where `status` is the response code (`NoError`, `NXDomain`, `ServFail`, ...),
+//! `section` is one of `answer`, `authority` and `additional`, `ttl` is in
+//! seconds and `ip_details` is only present with `--info`. `dnssec` is only
+//! present with `--dnssec` and has the validation status of each answer
+//! RRset, or of the denial of an empty answer: `secure`, `insecure` or `bogus`.
+//!
+//! `net_tool ipinfo` prints an array of IP details
+//!
+//! ```json
+//! [
+//!   {"ip": "8.8.8.8", "hostname": "dns.google", "city": "Mountain View", "region": "California",
+//!    "country": "US", "location": "37.4056,-122.0775", "asn": "AS15169", "org": "Google LLC"}
+//! ]
+//! ```
+//!
+//! where every field but `ip` may be `null` when the provider does not know it.
+//!
+//! `net_tool myip` prints the address and what each source found
+//!
+//! ```json
+//! {"ip": "203.0.113.7", "sources": [
+//!   {"source": "https://ifconfig.me/ip", "ip": "203.0.113.7", "error": null},
+//!   {"source": "stun:stun.l.google.com:19302", "ip": null, "error": "timed out"}
+//! ]}
+//! ```
+//!
+//! where `ip` is the address enough sources agree on. CSV and table output
+//! only have the `ip` column.
+//!
+//! `net_tool ptr` prints an array with one object per address
+//!
+//! ```json
+//! [
+//!   {"ip": "8.8.8.8", "status": "NoError", "names": [
+//!     {"name": "dns.google.", "addresses": ["8.8.8.8", "8.8.4.4"], "confirmed": true}
+//!   ]}
+//! ]
+//! ```
+//!
+//! where `addresses` and `confirmed` are only present with `--fcrdns`.
+//!
+//! `net_tool bulk` prints an array with one object per input host
+//!
+//! ```json
+//! [
+//!   {"host": "www.example.com", "status": "NoError", "answers": ["192.0.2.1"], "error": null},
+//!   {"host": "db.example.com", "status": null, "answers": [], "error": "8.8.8.8:53: timed out"}
+//! ]
+//! ```
+//!
+//! where `status` is the response code and `null` when no server answered,
+//! `answers` the data of the answer records of the queried type and `error`
+//! why no server answered. The CSV and table `answers` column separates
+//! answers with spaces.
+//!
+//! `net_tool trace` prints an array with the response of each server asked
+//!
+//! ```json
+//! [
+//!   {"server": "198.41.0.4:53", "status": "NoError", "zone": "com.", "records": [ ... ]},
+//!   {"server": "192.0.2.53:53", "status": "NoError", "zone": null, "records": [ ... ]}
+//! ]
+//! ```
+//!
+//! where `zone` is the zone a referral points to and `null` for the final
+//! response, and `records` are DNS records as above, the NS records and glue
+//! of a referral or the answers of the final response.
+//!
+//! `net_tool probe` prints an array with one object per address and port
+//!
+//! ```json
+//! [
+//!   {"ip": "192.0.2.1", "port": 443, "state": "open", "latency_ms": 12.5, "error": null},
+//!   {"ip": "2001:db8::1", "port": 443, "state": "error", "latency_ms": null,
+//!    "error": "Network is unreachable (os error 101)"}
+//! ]
+//! ```
+//!
+//! where `state` is one of `open`, `closed` (refused), `filtered` (timed out)
+//! and `error`, `latency_ms` the time until the connection was accepted or
+//! refused, and `error` why it failed in the `error` state.
+//!
+//! `net_tool watch` prints one object per line (JSON Lines) whenever an
+//! answer changes
+//!
+//! ```json
+//! {"time": "2024-01-31T12:00:00Z", "name": "www.example.com", "type": "A", "status": "NoError",
+//!  "added": [ ... ], "removed": [ ... ],
+//!  "ttl_changes": [{"section": "answer", ..., "ttl": 60, "data": "192.0.2.1",
+//!                   "previous_ttl": 300}]}
+//! ```
+//!
+//! where `added` and `removed` are DNS records as above, `ttl_changes` DNS
+//! records with the TTL of the old answer in `previous_ttl`, and a
+//! `previous_status` field is present when the response code changed. Its
+//! CSV and table output have one row per change, `change` is one of
+//! `status`, `added`, `removed` and `ttl`, and `previous` the old response
+//! code or TTL.
+//!
+//! CSV and table output have one row per record or IP with the same fields
+//! as columns, in the order shown above.
+
+use clap::ValueEnum;
+use rustdns::types::{Record, Type};
+use rustdns::Message;
+use serde::Serialize;
+use std::net::{IpAddr, SocketAddr};
+
+use crate::bulk::BulkResult;
+use crate::dnssec::RrsetStatus;
+use crate::myip::SourceResult;
+use crate::probe::ProbeResult;
+use crate::reverse::PtrResult;
+use crate::trace::Hop;
+use crate::watch::Change;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    /// Human readable text
+    #[default]
+    Text,
+    /// One JSON document
+    Json,
+    /// Comma separated values with a header row
+    Csv,
+    /// Columns aligned with spaces
+    Table,
+}
+
+/// The result of one DNS query.
+#[derive(Debug, Serialize)]
+pub struct DnsReport {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub query_type: String,
+    pub status: String,
+    pub records: Vec<DnsRecord>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ip_details: Option<Vec<IpRecord>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dnssec: Option<Vec<RrsetStatus>>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct DnsRecord {
+    pub section: &'static str,
+    pub name: String,
+    #[serde(rename = "type")]
+    pub record_type: String,
+    pub ttl: u64,
+    pub data: String,
+}
+
+/// What is known about one IP address.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct IpRecord {
+    pub ip: String,
+    pub hostname: Option<String>,
+    pub city: Option<String>,
+    pub region: Option<String>,
+    pub country: Option<String>,
+    /// Latitude and longitude as `lat,long`.
+    pub location: Option<String>,
+    pub asn: Option<String>,
+    pub org: Option<String>,
+}
+
+impl DnsReport {
+    pub fn new(name: &str, query_type: Type, message: &Message) -> DnsReport {
+        let sections = [
+            ("answer", &message.answers),
+            ("authority", &message.authoritys),
+            ("additional", &message.additionals),
+        ];
+        let records = sections.into_iter()
+            .flat_map(|(section, records)| {
+                records.iter().map(move |record| DnsRecord::new(section, record))
+            })
+            .collect();
+        DnsReport {
+            name: name.to_string(),
+            query_type: query_type.to_string(),
+            status: message.rcode.to_string(),
+            records,
+            ip_details: None,
+            dnssec: None,
+        }
+    }
+}
+
+impl DnsRecord {
+    pub fn new(section: &'static str, record: &Record) -> DnsRecord {
+        DnsRecord {
+            section,
+            name: record.name.clone(),
+            record_type: record.r#type().to_string(),
+            ttl: record.ttl.as_secs(),
+            data: record.resource.to_string(),
+        }
+    }
+
+    fn row(&self) -> Vec<String> {
+        vec![self.section.to_string(), self.name.clone(), self.record_type.clone(),
+             self.ttl.to_string(), self.data.clone()]
+    }
+}
+
+const DNS_COLUMNS: &[&str] = &["section", "name", "type", "ttl", "data"];
+const DNSSEC_COLUMNS: &[&str] = &["name", "type", "status", "reason"];
+const IP_COLUMNS: &[&str] = &["ip", "hostname", "city", "region", "country", "location",
+                              "asn", "org"];
+
+impl IpRecord {
+    fn row(&self) -> Vec<String> {
+        let fields = [&self.hostname, &self.city, &self.region, &self.country,
+                      &self.location, &self.asn, &self.org];
+        let mut row = vec![self.ip.clone()];
+        row.extend(fields.into_iter().map(|field| field.clone().unwrap_or_default()));
+        row
+    }
+}
+
+/// Print a DNS response, `message` is only used for the text format which
+/// shows the response the way dig does.
+pub fn print_dns(format: Format, report: &DnsReport, message: &Message) -> serde_json::Result<()> {
+    match format {
+        Format::Text => {
+            println!("DNS Response:\n{}", message);
+            if let Some(statuses) = &report.dnssec {
+                println!(";; DNSSEC:");
+                for status in statuses {
+                    print!("{}\t{}\t{}", status.name, status.record_type, status.status);
+                    match &status.reason {
+                        Some(reason) => println!(" ({})", reason),
+                        None => println!(),
+                    }
+                }
+            }
+            if let Some(details) = &report.ip_details {
+                print_ip_text(details);
+            }
+        }
+        Format::Json => println!("{}", serde_json::to_string_pretty(report)?),
+        Format::Csv | Format::Table => {
+            let rows: Vec<_> = report.records.iter().map(DnsRecord::row).collect();
+            print_rows(format, DNS_COLUMNS, &rows);
+            if let Some(statuses) = &report.dnssec {
+                let rows: Vec<_> = statuses.iter()
+                    .map(|status| vec![status.name.clone(), status.record_type.clone(),
+                                       status.status.to_string(),
+                                       status.reason.clone().unwrap_or_default()])
+                    .collect();
+                println!();
+                print_rows(format, DNSSEC_COLUMNS, &rows);
+            }
+            if let Some(details) = &report.ip_details {
+                println!();
+                print_ips(format, details)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn print_ips(format: Format, records: &[IpRecord]) -> serde_json::Result<()> {
+    match format {
+        Format::Text => print_ip_text(records),
+        Format::Json => println!("{}", serde_json::to_string_pretty(records)?),
+        Format::Csv | Format::Table => {
+            let rows: Vec<_> = records.iter().map(IpRecord::row).collect();
+            print_rows(format, IP_COLUMNS, &rows);
+        }
+    }
+    Ok(())
+}
+
+pub fn print_public_ip(format: Format, ip: IpAddr, sources: &[SourceResult])
+    -> serde_json::Result<()> {
+    match format {
+        Format::Text => println!("{}", ip),
+        Format::Json => {
+            let document = serde_json::json!({ "ip": ip, "sources": sources });
+            println!("{}", serde_json::to_string_pretty(&document)?);
+        }
+        Format::Csv | Format::Table => print_rows(format, &["ip"], &[vec![ip.to_string()]]),
+    }
+    Ok(())
+}
+
+pub fn print_ptr(format: Format, results: &[PtrResult]) -> serde_json::Result<()> {
+    if format == Format::Json {
+        println!("{}", serde_json::to_string_pretty(results)?);
+        return Ok(());
+    }
+    let mut rows = Vec::new();
+    for result in results {
+        if result.names.is_empty() {
+            rows.push(vec![result.ip.clone(), result.status.clone(), String::new(),
+                           String::new(), String::new()]);
+        }
+        for name in &result.names {
+            let confirmed = match name.confirmed {
+                Some(true) => "confirmed",
+                Some(false) => "mismatch",
+                None => "",
+            };
+            rows.push(vec![result.ip.clone(), result.status.clone(), name.name.clone(),
+                           name.addresses.as_deref().unwrap_or_default().join(" "),
+                           confirmed.to_string()]);
+        }
+    }
+    if format == Format::Text {
+        for row in rows {
+            let [ip, status, name, addresses, confirmed] = &row[..] else { unreachable!() };
+            match (name.is_empty(), confirmed.as_str()) {
+                (true, _) => println!("{}\t{}", ip, status),
+                (false, "") => println!("{}\t{}", ip, name),
+                (false, "confirmed") => println!("{}\t{}\tconfirmed", ip, name),
+                (false, _) => println!("{}\t{}\tmismatch, resolves to {}", ip, name,
+                                       if addresses.is_empty() { "nothing" } else { addresses }),
+            }
+        }
+    } else {
+        print_rows(format, &["ip", "status", "name", "addresses", "fcrdns"], &rows);
+    }
+    Ok(())
+}
+
+pub fn print_bulk(format: Format, results: &[BulkResult]) -> serde_json::Result<()> {
+    match format {
+        Format::Json => println!("{}", serde_json::to_string_pretty(results)?),
+        _ => {
+            let rows: Vec<Vec<String>> = results.iter()
+                .map(|result| vec![
+                    result.host.clone(),
+                    result.status.clone().unwrap_or_default(),
+                    result.answers.join(" "),
+                    result.error.clone().unwrap_or_default(),
+                ])
+                .collect();
+            if format == Format::Text {
+                for row in rows {
+                    println!("{}", row.join("\t").trim_end());
+                }
+            } else {
+                print_rows(format, &["host", "status", "answers", "error"], &rows);
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn print_probe(format: Format, results: &[ProbeResult]) -> serde_json::Result<()> {
+    if format == Format::Json {
+        println!("{}", serde_json::to_string_pretty(results)?);
+        return Ok(());
+    }
+    let rows: Vec<Vec<String>> = results.iter()
+        .map(|result| vec![
+            result.ip.to_string(),
+            result.port.to_string(),
+            result.state.name().to_string(),
+            result.latency_ms.map(|latency| format!("{:.1}", latency)).unwrap_or_default(),
+            result.error.clone().unwrap_or_default(),
+        ])
+        .collect();
+    if format == Format::Text {
+        for result in results {
+            let detail = match (&result.error, result.latency_ms) {
+                (Some(error), _) => error.clone(),
+                (None, Some(latency)) => format!("{:.1} ms", latency),
+                (None, None) => String::new(),
+            };
+            let address = SocketAddr::new(result.ip, result.port);
+            println!("{}", format!("{}\t{}\t{}", address, result.state.name(), detail).trim_end());
+        }
+    } else {
+        print_rows(format, &["ip", "port", "state", "latency_ms", "error"], &rows);
+    }
+    Ok(())
+}
+
+/// Print one hop of a trace as text, as soon as it is known.
+pub fn print_hop_text(hop: &Hop) {
+    match &hop.zone {
+        Some(zone) => println!(";; {} ({}): referral to {}", hop.server, hop.status, zone),
+        None => println!(";; {} ({}): final response", hop.server, hop.status),
+    }
+    for record in &hop.records {
+        println!("{}\t{}\t{}\t{}", record.name, record.ttl, record.record_type, record.data);
+    }
+    println!();
+}
+
+/// Print a whole trace in a format for scripts, text is printed hop by hop.
+pub fn print_trace(format: Format, hops: &[Hop]) -> serde_json::Result<()> {
+    match format {
+        Format::Text => hops.iter().for_each(print_hop_text),
+        Format::Json => println!("{}", serde_json::to_string_pretty(hops)?),
+        Format::Csv | Format::Table => {
+            let mut columns = vec!["hop", "server", "status"];
+            columns.extend_from_slice(DNS_COLUMNS);
+            let rows: Vec<Vec<String>> = hops.iter()
+                .enumerate()
+                .flat_map(|(number, hop)| {
+                    hop.records.iter().map(move |record| {
+                        let mut row = vec![(number + 1).to_string(), hop.server.to_string(),
+                                           hop.status.clone()];
+                        row.extend(record.row());
+                        row
+                    })
+                })
+                .collect();
+            print_rows(format, &columns, &rows);
+        }
+    }
+    Ok(())
+}
+
+/// Print the changes found in one round of watching. JSON has one line per
+/// change, and the CSV header only comes before the first round with `header`.
+pub fn print_changes(format: Format, changes: &[Change], header: bool) -> serde_json::Result<()> {
+    if format == Format::Json {
+        for change in changes {
+            println!("{}", serde_json::to_string(change)?);
+        }
+        return Ok(());
+    }
+    if format == Format::Text {
+        for change in changes {
+            match &change.previous_status {
+                Some(previous) => println!("{} {} {}: {} -> {}", change.time, change.name,
+                                           change.query_type, previous, change.status),
+                None => println!("{} {} {}:", change.time, change.name, change.query_type),
+            }
+            let records = change.removed.iter().map(|record| ("-", record))
+                .chain(change.added.iter().map(|record| ("+", record)));
+            for (sign, record) in records {
+                println!("  {} {}\t{}\t{}\t{}", sign, record.name, record.ttl, record.record_type,
+                         record.data);
+            }
+            for ttl in &change.ttl_changes {
+                let record = &ttl.record;
+                println!("  ~ {}\t{} -> {}\t{}\t{}", record.name, ttl.previous_ttl, record.ttl,
+                         record.record_type, record.data);
+            }
+        }
+        return Ok(());
+    }
+
+    let mut rows = Vec::new();
+    for change in changes {
+        let row = |kind: &str, record: Option<&DnsRecord>, previous: String| {
+            let mut row = vec![change.time.clone(), change.name.clone(), change.query_type.clone(),
+                               change.status.clone(), kind.to_string()];
+            match record {
+                Some(record) => row.extend(record.row().into_iter().skip(1)),
+                None => row.extend(vec![String::new(); 4]),
+            }
+            row.push(previous);
+            row
+        };
+        if let Some(previous) = &change.previous_status {
+            rows.push(row("status", None, previous.clone()));
+        }
+        rows.extend(change.removed.iter()
+            .map(|record| row("removed", Some(record), String::new())));
+        rows.extend(change.added.iter()
+            .map(|record| row("added", Some(record), String::new())));
+        rows.extend(change.ttl_changes.iter()
+            .map(|ttl| row("ttl", Some(&ttl.record), ttl.previous_ttl.to_string())));
+    }
+    let columns: Vec<String> = ["time", "name", "type", "status", "change", "record_name",
+                                "record_type", "ttl", "data", "previous"]
+        .iter()
+        .map(|column| column.to_string())
+        .collect();
+    let lines = match format {
+        Format::Csv => csv_lines(&columns, &rows).into_iter().skip(usize::from(!header)).collect(),
+        _ => table_lines(&columns, &rows),
+    };
+    for line in lines {
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+fn print_ip_text(records: &[IpRecord]) {
+    for record in records {
+        println!("{} info =>", record.ip);
+        for (column, value) in IP_COLUMNS.iter().zip(record.row()).skip(1) {
+            if !value.is_empty() {
+                println!("  {}: {}", column, value);
+            }
+        }
+    }
+}
+
+fn print_rows(format: Format, columns: &[&str], rows: &[Vec<String>]) {
+    let header: Vec<String> = columns.iter().map(|column| column.to_string()).collect();
+    let lines = match format {
+        Format::Csv => csv_lines(&header, rows),
+        _ => table_lines(&header, rows),
+    };
+    for line in lines {
+        println!("{}", line);
+    }
+}
+
+fn csv_lines(header: &[String], rows: &[Vec<String>]) -> Vec<String> {
+    std::iter::once(header)
+        .chain(rows.iter().map(Vec::as_slice))
+        .map(|row| row.iter().map(|field| csv_field(field)).collect::<Vec<_>>().join(","))
+        .collect()
+}
+
+/// Quote a field when it contains a separator, a quote or a line break (RFC 4180).
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn table_lines(header: &[String], rows: &[Vec<String>]) -> Vec<String> {
+    let mut widths: Vec<usize> = header.iter().map(|column| column.chars().count()).collect();
+    for row in rows {
+        for (width, field) in widths.iter_mut().zip(row) {
+            *width = (*width).max(field.chars().count());
+        }
+    }
+    std::iter::once(header)
+        .chain(rows.iter().map(Vec::as_slice))
+        .map(|row| {
+            let fields: Vec<String> = row.iter()
+                .zip(&widths)
+                .map(|(field, width)| format!("{:width$}", field, width = width))
+                .collect();
+            fields.join("  ").trim_end().to_string()
+        })
+        .collect()
+}
+
+#[test]
+fn test_csv_and_table_lines() {
+    let header = vec!["name".to_string(), "data".to_string()];
+    let rows = vec![
+        vec!["example.com.".to_string(), "\"v=spf1 -all\"".to_string()],
+        vec!["a.example.".to_string(), "1, 2".to_string()],
+    ];
+
+    assert_eq!(csv_lines(&header, &rows), vec![
+        "name,data",
+        r#"example.com.,"""v=spf1 -all""""#,
+        r#"a.example.,"1, 2""#,
+    ]);
+    assert_eq!(table_lines(&header, &rows), vec![
+        "name          data",
+        "example.com.  \"v=spf1 -all\"",
+        "a.example.    1, 2",
+    ]);
+}
+
+#[test]
+fn test_dns_report_json() {
+    let record = DnsRecord {
+        section: "answer",
+        name: "example.com.".to_string(),
+        record_type: "A".to_string(),
+        ttl: 300,
+        data: "192.0.2.1".to_string(),
+    };
+    let report = DnsReport {
+        name: "example.com".to_string(),
+        query_type: "A".to_string(),
+        status: "NoError".to_string(),
+        records: vec![record],
+        ip_details: None,
+        dnssec: None,
+    };
+
+    assert_eq!(serde_json::to_string(&report).unwrap(),
+               r#"{"name":"example.com","type":"A","status":"NoError","records":[{"section":"answer","name":"example.com.","type":"A","ttl":300,"data":"192.0.2.1"}]}"#);
+}
@@ -0,0 +1,125 @@
+//! Reachability of addresses, by opening TCP connections to their ports.
+//! Unlike ping this needs no privileges and goes through the same firewalls
+//! as the service itself.
+
+use serde::Serialize;
+use std::io;
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PortState {
+    /// The connection was accepted.
+    Open,
+    /// The host refused the connection, it is reachable but nothing listens.
+    Closed,
+    /// Nothing answered within the timeout, the packets were likely dropped.
+    Filtered,
+    /// The connection failed otherwise, like without a route to the host.
+    Error,
+}
+
+impl PortState {
+    pub fn name(self) -> &'static str {
+        match self {
+            PortState::Open => "open",
+            PortState::Closed => "closed",
+            PortState::Filtered => "filtered",
+            PortState::Error => "error",
+        }
+    }
+
+    fn of(error: &io::Error) -> PortState {
+        match error.kind() {
+            io::ErrorKind::ConnectionRefused => PortState::Closed,
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => PortState::Filtered,
+            _ => PortState::Error,
+        }
+    }
+}
+
+/// How one port of one address answered.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct ProbeResult {
+    pub ip: IpAddr,
+    pub port: u16,
+    pub state: PortState,
+    /// Milliseconds until the connection was accepted or refused.
+    pub latency_ms: Option<f64>,
+    /// Why the connection failed, for the `error` state.
+    pub error: Option<String>,
+}
+
+/// Connect to every port of every address with at most `concurrency`
+/// connections being opened at a time, each waiting up to `timeout`.
+/// Results are in the order of `ips`, then `ports`.
+pub fn probe_all(ips: &[IpAddr], ports: &[u16], timeout: Duration, concurrency: usize)
+    -> Vec<ProbeResult> {
+    let targets: Vec<SocketAddr> = ips.iter()
+        .flat_map(|ip| ports.iter().map(|port| SocketAddr::new(*ip, *port)))
+        .collect();
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(Vec::with_capacity(targets.len()));
+    thread::scope(|scope| {
+        for _ in 0..concurrency.clamp(1, targets.len().max(1)) {
+            scope.spawn(|| {
+                loop {
+                    let index = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(target) = targets.get(index) else { break };
+                    let result = probe(*target, timeout);
+                    results.lock().unwrap().push((index, result));
+                }
+            });
+        }
+    });
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|(index, _)| *index);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+fn probe(target: SocketAddr, timeout: Duration) -> ProbeResult {
+    let started = Instant::now();
+    let connected = TcpStream::connect_timeout(&target, timeout);
+    let latency_ms = started.elapsed().as_secs_f64() * 1000.0;
+    let (state, error) = match &connected {
+        Ok(_) => (PortState::Open, None),
+        Err(error) => (PortState::of(error), Some(error.to_string())),
+    };
+    ProbeResult {
+        ip: target.ip(),
+        port: target.port(),
+        state,
+        latency_ms: matches!(state, PortState::Open | PortState::Closed).then_some(latency_ms),
+        error: error.filter(|_| state == PortState::Error),
+    }
+}
+
+#[test]
+fn test_probe_all() {
+    use std::net::TcpListener;
+
+    let open = TcpListener::bind("127.0.0.1:0").unwrap();
+    let open_port = open.local_addr().unwrap().port();
+    // Nothing listens on a port once its listener is dropped.
+    let closed_port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+
+    let ips = ["127.0.0.1".parse().unwrap(), "127.0.0.2".parse().unwrap()];
+    let results = probe_all(&ips, &[open_port, closed_port], Duration::from_secs(2), 8);
+    let states: Vec<(String, u16, PortState)> = results.iter()
+        .map(|result| (result.ip.to_string(), result.port, result.state))
+        .collect();
+    assert_eq!(states, [
+        ("127.0.0.1".to_string(), open_port, PortState::Open),
+        ("127.0.0.1".to_string(), closed_port, PortState::Closed),
+        ("127.0.0.2".to_string(), open_port, PortState::Closed),
+        ("127.0.0.2".to_string(), closed_port, PortState::Closed),
+    ]);
+    assert!(results.iter().all(|result| result.latency_ms.is_some() && result.error.is_none()));
+
+    assert_eq!(PortState::of(&io::Error::from(io::ErrorKind::TimedOut)), PortState::Filtered);
+    assert_eq!(PortState::of(&io::Error::from(io::ErrorKind::HostUnreachable)), PortState::Error);
+}
@@ -0,0 +1,321 @@
+//! Where details of IP addresses come from.
+
+use maxminddb::Reader;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use url::Url;
+
+use crate::config::ConfigFile;
+use crate::output::IpRecord;
+use crate::GenericResult;
+
+const IPINFO_URL: &str = "https://ipinfo.io";
+
+/// A source of IP address metadata.
+pub trait IpInfoProvider {
+    /// Details of every address in `ips`, in the same order.
+    fn lookup(&self, ips: &[String]) -> GenericResult<Vec<IpRecord>>;
+}
+
+/// The ipinfo.io API, or a server speaking the same protocol at `base_url`.
+/// Without a token the API answers a limited number of requests a day.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IpInfoIo {
+    pub base_url: Url,
+    pub token: Option<String>,
+}
+
+impl IpInfoIo {
+    /// The provider configured by the `NET_TOOL_IPINFO_TOKEN` and
+    /// `NET_TOOL_IPINFO_URL` environment variables, or else the `ipinfo_token`
+    /// and `ipinfo_url` settings of the configuration file. With `no_token`
+    /// no token is sent even when one is configured.
+    pub fn from_env(no_token: bool) -> io::Result<IpInfoIo> {
+        let config = ConfigFile::load()?;
+        let setting = |variable: &str, key: &str| {
+            std::env::var(variable).ok()
+                .or_else(|| config.get(key).map(str::to_string))
+                .filter(|value| !value.is_empty())
+        };
+
+        let url = setting("NET_TOOL_IPINFO_URL", "ipinfo_url")
+            .unwrap_or_else(|| IPINFO_URL.to_string());
+        let base_url = Url::parse(&url).map_err(|error| {
+            io::Error::new(io::ErrorKind::InvalidInput,
+                           format!("invalid ipinfo URL {:?}: {}", url, error))
+        })?;
+        let token = setting("NET_TOOL_IPINFO_TOKEN", "ipinfo_token").filter(|_| !no_token);
+        Ok(IpInfoIo { base_url, token })
+    }
+
+    async fn lookup_one(&self, ip: &str) -> GenericResult<IpRecord> {
+        let url = self.base_url.join(&format!("{}/json", ip))?;
+        let mut request = surf::get(url.as_str()).set_header("Accept", "application/json");
+        if let Some(token) = &self.token {
+            request = request.set_header("Authorization", format!("Bearer {}", token));
+        }
+        let mut response = request.await?;
+        let body = response.body_string().await?;
+        match response.status().as_u16() {
+            200 => {}
+            429 => return Err(format!("{}: ipinfo rate limit exceeded", ip).into()),
+            401 | 403 => return Err(format!("{}: ipinfo rejected the token", ip).into()),
+            status => return Err(format!("{}: ipinfo answered HTTP {}", ip, status).into()),
+        }
+        let details: IpInfoDetails = serde_json::from_str(&body)?;
+        Ok(details.into_record(ip))
+    }
+}
+
+impl IpInfoProvider for IpInfoIo {
+    fn lookup(&self, ips: &[String]) -> GenericResult<Vec<IpRecord>> {
+        async_std::task::block_on(async {
+            let mut records = Vec::with_capacity(ips.len());
+            for ip in ips {
+                records.push(self.lookup_one(ip).await?);
+            }
+            Ok(records)
+        })
+    }
+}
+
+/// The fields of an ipinfo.io response the tool uses. Private and reserved
+/// addresses only have `ip` and `bogon`.
+#[derive(Debug, Deserialize)]
+struct IpInfoDetails {
+    hostname: Option<String>,
+    city: Option<String>,
+    region: Option<String>,
+    country: Option<String>,
+    loc: Option<String>,
+    org: Option<String>,
+    asn: Option<IpInfoAsn>,
+}
+
+#[derive(Debug, Deserialize)]
+struct IpInfoAsn {
+    asn: String,
+}
+
+impl IpInfoDetails {
+    fn into_record(self, ip: &str) -> IpRecord {
+        let non_empty = |value: Option<String>| value.filter(|value| !value.is_empty());
+        // Paid plans have the AS details, otherwise ipinfo puts the AS number in
+        // front of the organization, "AS15169 Google LLC".
+        let prefixed = self.org.as_deref().and_then(|org| org.split_once(' '));
+        let (asn, org) = match (self.asn, prefixed) {
+            (Some(asn), _) => (Some(asn.asn), self.org.clone()),
+            (None, Some((asn, org))) if asn.starts_with("AS") => {
+                (Some(asn.to_string()), Some(org.to_string()))
+            }
+            _ => (None, self.org.clone()),
+        };
+        IpRecord {
+            ip: ip.to_string(),
+            hostname: non_empty(self.hostname),
+            city: non_empty(self.city),
+            region: non_empty(self.region),
+            country: non_empty(self.country),
+            location: non_empty(self.loc),
+            asn,
+            org: non_empty(org),
+        }
+    }
+}
+
+/// MaxMind databases read offline, a GeoLite2 City database for the location
+/// and a GeoLite2 ASN database for the network, or one database with both.
+/// Fields are taken from the first database that has them.
+pub struct GeoDb {
+    databases: Vec<(PathBuf, Reader<Vec<u8>>)>,
+}
+
+impl GeoDb {
+    pub fn open<P: AsRef<Path>>(paths: &[P]) -> io::Result<GeoDb> {
+        let databases = paths.iter()
+            .map(|path| {
+                let path = path.as_ref();
+                let reader = Reader::open_readfile(path).map_err(|error| {
+                    io::Error::new(io::ErrorKind::InvalidData,
+                                   format!("{}: {}", path.display(), error))
+                })?;
+                Ok((path.to_path_buf(), reader))
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(GeoDb { databases })
+    }
+
+    fn lookup_one(&self, ip: &str) -> GenericResult<IpRecord> {
+        let address: IpAddr = ip.parse()?;
+        let mut record = IpRecord { ip: ip.to_string(), ..IpRecord::default() };
+        for (path, reader) in &self.databases {
+            // IPv4 only databases have no IPv6 networks.
+            if address.is_ipv6() && reader.metadata().ip_version == 4 {
+                continue;
+            }
+            let found = reader.lookup(address)
+                .and_then(|result| result.decode::<GeoRecord>())
+                .map_err(|error| format!("{}: {}: {}", path.display(), ip, error))?;
+            if let Some(found) = found {
+                found.merge_into(&mut record);
+            }
+        }
+        Ok(record)
+    }
+}
+
+impl IpInfoProvider for GeoDb {
+    fn lookup(&self, ips: &[String]) -> GenericResult<Vec<IpRecord>> {
+        ips.iter().map(|ip| self.lookup_one(ip)).collect()
+    }
+}
+
+/// The fields of GeoLite2 City and ASN records the tool uses.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct GeoRecord {
+    city: Option<GeoNames>,
+    country: Option<GeoCountry>,
+    subdivisions: Vec<GeoNames>,
+    location: Option<GeoLocation>,
+    autonomous_system_number: Option<u32>,
+    autonomous_system_organization: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct GeoNames {
+    names: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct GeoCountry {
+    iso_code: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct GeoLocation {
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+}
+
+impl GeoNames {
+    fn english(mut self) -> Option<String> {
+        self.names.remove("en")
+    }
+}
+
+impl GeoRecord {
+    fn merge_into(self, record: &mut IpRecord) {
+        fn fill(field: &mut Option<String>, value: Option<String>) {
+            if field.is_none() {
+                *field = value;
+            }
+        }
+        fill(&mut record.city, self.city.and_then(GeoNames::english));
+        fill(&mut record.region, self.subdivisions.into_iter().next().and_then(GeoNames::english));
+        fill(&mut record.country, self.country.and_then(|country| country.iso_code));
+        if let Some(GeoLocation { latitude: Some(latitude), longitude: Some(longitude) }) =
+            self.location {
+            fill(&mut record.location, Some(format!("{:.4},{:.4}", latitude, longitude)));
+        }
+        fill(&mut record.asn, self.autonomous_system_number.map(|asn| format!("AS{}", asn)));
+        fill(&mut record.org, self.autonomous_system_organization);
+    }
+}
+
+#[test]
+fn test_ipinfo_lookup() {
+    use crate::testing;
+
+    let server = testing::spawn_http_server(|target, authorization| {
+        match (target, authorization) {
+            (_, Some(authorization)) if authorization != "Bearer secret" => {
+                (403, r#"{"error": "invalid token"}"#.to_string())
+            }
+            ("/8.8.8.8/json", _) => (200, r#"{"ip": "8.8.8.8", "hostname": "dns.google",
+                "city": "Mountain View", "region": "California", "country": "US",
+                "loc": "37.4056,-122.0775", "org": "AS15169 Google LLC"}"#.to_string()),
+            ("/10.0.0.1/json", _) => (200, r#"{"ip": "10.0.0.1", "bogon": true}"#.to_string()),
+            _ => (404, r#"{"error": "not found"}"#.to_string()),
+        }
+    });
+    let mut provider = IpInfoIo {
+        base_url: Url::parse(&format!("http://{}/", server)).unwrap(),
+        token: Some("secret".to_string()),
+    };
+
+    let records = provider.lookup(&["8.8.8.8".to_string(), "10.0.0.1".to_string()]).unwrap();
+    assert_eq!(records, vec![
+        IpRecord {
+            ip: "8.8.8.8".to_string(),
+            hostname: Some("dns.google".to_string()),
+            city: Some("Mountain View".to_string()),
+            region: Some("California".to_string()),
+            country: Some("US".to_string()),
+            location: Some("37.4056,-122.0775".to_string()),
+            asn: Some("AS15169".to_string()),
+            org: Some("Google LLC".to_string()),
+        },
+        IpRecord { ip: "10.0.0.1".to_string(), ..IpRecord::default() },
+    ]);
+
+    provider.token = None;
+    assert_eq!(provider.lookup(&["8.8.8.8".to_string()]).unwrap()[0].asn.as_deref(),
+               Some("AS15169"));
+
+    provider.token = Some("wrong".to_string());
+    let error = provider.lookup(&["8.8.8.8".to_string()]).unwrap_err().to_string();
+    assert!(error.contains("rejected the token"), "{}", error);
+}
+
+#[test]
+fn test_geo_db_lookup() {
+    use crate::testing;
+    use serde_json::json;
+
+    let city = testing::write_mmdb("GeoLite2-City", &[
+        ([8, 8, 8, 0].into(), 24, json!({
+            "city": { "names": { "en": "Mountain View", "de": "Mountain View" } },
+            "country": { "iso_code": "US", "names": { "en": "United States" } },
+            "subdivisions": [{ "iso_code": "CA", "names": { "en": "California" } }],
+            "location": { "latitude": 37.4056, "longitude": -122.0775 },
+        })),
+        ([192, 0, 2, 0].into(), 24, json!({ "country": { "iso_code": "DE" } })),
+    ]);
+    let asn = testing::write_mmdb("GeoLite2-ASN", &[
+        ([8, 8, 8, 0].into(), 24, json!({
+            "autonomous_system_number": 15169,
+            "autonomous_system_organization": "Google LLC",
+        })),
+    ]);
+    let geo_db = GeoDb::open(&[city, asn]).unwrap();
+
+    let ips = ["8.8.8.8", "192.0.2.1", "198.51.100.1", "2001:db8::1"].map(str::to_string);
+    assert_eq!(geo_db.lookup(&ips).unwrap(), vec![
+        IpRecord {
+            ip: "8.8.8.8".to_string(),
+            hostname: None,
+            city: Some("Mountain View".to_string()),
+            region: Some("California".to_string()),
+            country: Some("US".to_string()),
+            location: Some("37.4056,-122.0775".to_string()),
+            asn: Some("AS15169".to_string()),
+            org: Some("Google LLC".to_string()),
+        },
+        IpRecord {
+            ip: "192.0.2.1".to_string(),
+            country: Some("DE".to_string()),
+            ..IpRecord::default()
+        },
+        IpRecord { ip: "198.51.100.1".to_string(), ..IpRecord::default() },
+        IpRecord { ip: "2001:db8::1".to_string(), ..IpRecord::default() },
+    ]);
+
+    assert!(GeoDb::open(&["/nonexistent/GeoLite2-City.mmdb"]).is_err());
+}
@@ -0,0 +1,264 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
+use url::Url;
+
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+const DNS_PORT: u16 = 53;
+const DNS_OVER_TLS_PORT: u16 = 853;
+
+/// A DNS server and the transport used to reach it.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Server {
+    /// Plain DNS over UDP, falling back to TCP for truncated responses.
+    Plain(SocketAddr),
+    /// DNS over TLS (RFC 7858), `name` is checked against the server certificate.
+    Tls { address: SocketAddr, name: String },
+    /// DNS over HTTPS (RFC 8484) with the wireformat in a POST body, or in
+    /// the `dns` query parameter of a GET request when `get` is set.
+    Https { url: Url, get: bool },
+}
+
+impl fmt::Display for Server {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Server::Plain(address) => write!(f, "{}", address),
+            Server::Tls { address, name } => write!(f, "tls://{}#{}", address, name),
+            Server::Https { url, get: false } => write!(f, "{}", url),
+            Server::Https { url, get: true } => write!(f, "{}{{?dns}}", url),
+        }
+    }
+}
+
+/// Where and how DNS queries are sent.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ResolverConfig {
+    /// Servers are tried in order, the whole list is walked once per attempt.
+    pub servers: Vec<Server>,
+    /// How long to wait for a response from a single server.
+    pub timeout: Duration,
+    /// How many times the server list is tried before giving up.
+    pub attempts: u32,
+    /// Always query plain servers over TCP instead of only after a
+    /// truncated UDP response.
+    pub tcp: bool,
+    /// PEM certificates trusted for TLS and HTTPS servers in addition to
+    /// the public roots, for servers with a self-signed certificate.
+    pub ca_file: Option<PathBuf>,
+}
+
+impl Default for ResolverConfig {
+    fn default() -> Self {
+        ResolverConfig {
+            servers: vec![Server::Plain(SocketAddr::from(([8, 8, 8, 8], DNS_PORT)))], // Google's Public DNS Servers
+            timeout: Duration::from_secs(5),
+            attempts: 2,
+            tcp: false,
+            ca_file: None,
+        }
+    }
+}
+
+impl ResolverConfig {
+    /// The system configuration from `/etc/resolv.conf`, anything missing
+    /// there keeps its default value.
+    pub fn system() -> ResolverConfig {
+        let mut config = ResolverConfig::default();
+        if let Ok(content) = fs::read_to_string(RESOLV_CONF) {
+            config.apply_resolv_conf(&content);
+        }
+        config
+    }
+
+    /// The system configuration overridden by the `NET_TOOL_DNS_SERVERS`
+    /// (comma separated), `NET_TOOL_DNS_TIMEOUT` (seconds),
+    /// `NET_TOOL_DNS_ATTEMPTS` and `NET_TOOL_DNS_CA_FILE` environment variables.
+    pub fn from_env() -> io::Result<ResolverConfig> {
+        let mut config = ResolverConfig::system();
+        if let Ok(servers) = std::env::var("NET_TOOL_DNS_SERVERS") {
+            config.servers = servers.split(',')
+                .map(parse_server)
+                .collect::<io::Result<_>>()?;
+        }
+        if let Ok(timeout) = std::env::var("NET_TOOL_DNS_TIMEOUT") {
+            config.timeout = parse_timeout(&timeout)?;
+        }
+        if let Ok(attempts) = std::env::var("NET_TOOL_DNS_ATTEMPTS") {
+            config.attempts = parse_number(&attempts)?;
+        }
+        if let Some(ca_file) = std::env::var_os("NET_TOOL_DNS_CA_FILE") {
+            config.ca_file = Some(ca_file.into());
+        }
+        Ok(config)
+    }
+
+    /// Read `nameserver` lines and the `timeout:` and `attempts:` options,
+    /// a timeout of 0 is ignored.
+    fn apply_resolv_conf(&mut self, content: &str) {
+        let mut servers = Vec::new();
+        for line in content.lines() {
+            let mut words = line.split_whitespace();
+            match words.next() {
+                Some("nameserver") => {
+                    // Link-local IPv6 servers carry a zone like fe80::1%eth0,
+                    // which std does not parse, so they are skipped.
+                    if let Some(Ok(ip)) = words.next().map(str::parse::<IpAddr>) {
+                        servers.push(Server::Plain(SocketAddr::new(ip, DNS_PORT)));
+                    }
+                }
+                Some("options") => {
+                    for option in words {
+                        if let Some(Ok(seconds)) = option.strip_prefix("timeout:").map(str::parse) {
+                            if seconds > 0 {
+                                self.timeout = Duration::from_secs(seconds);
+                            }
+                        }
+                        if let Some(Ok(attempts)) = option.strip_prefix("attempts:").map(str::parse) {
+                            self.attempts = attempts;
+                        }
+                        if option == "use-vc" {
+                            self.tcp = true;
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        if !servers.is_empty() {
+            self.servers = servers;
+        }
+    }
+}
+
+/// Parse a server in one of these forms:
+///
+/// * `ip` or `ip:port` for plain DNS, IPv6 addresses with a port use `[ip]:port`
+/// * `tls://ip[:port]#name` for DNS over TLS, `name` is the name in the
+///   server certificate and may be left out when the certificate is for the IP
+/// * `https://host[:port]/path` for DNS over HTTPS with POST requests, the
+///   URI template form `https://host/path{?dns}` selects GET requests
+pub fn parse_server(server: &str) -> io::Result<Server> {
+    let server = server.trim();
+    let invalid = || invalid_input(format!("invalid DNS server address {:?}", server));
+
+    if let Some(rest) = server.strip_prefix("tls://") {
+        let (address, name) = match rest.split_once('#') {
+            Some((address, name)) => (address, Some(name)),
+            None => (rest, None),
+        };
+        let address = parse_address(address, DNS_OVER_TLS_PORT).ok_or_else(invalid)?;
+        let name = name.map(str::to_string).unwrap_or_else(|| address.ip().to_string());
+        return Ok(Server::Tls { address, name });
+    }
+
+    if server.starts_with("https://") {
+        let (template, get) = match server.strip_suffix("{?dns}") {
+            Some(template) => (template, true),
+            None => (server, false),
+        };
+        let url = Url::parse(template).map_err(|_| invalid())?;
+        if url.host_str().is_none() {
+            return Err(invalid());
+        }
+        return Ok(Server::Https { url, get });
+    }
+
+    parse_address(server, DNS_PORT).map(Server::Plain).ok_or_else(invalid)
+}
+
+/// Parse `ip` or `ip:port`, using `default_port` when the port is left out.
+fn parse_address(address: &str, default_port: u16) -> Option<SocketAddr> {
+    match address.parse::<IpAddr>() {
+        Ok(ip) => Some(SocketAddr::new(ip, default_port)),
+        Err(_) => address.parse().ok(),
+    }
+}
+
+/// `name` in lower case with the trailing dot.
+pub fn fqdn(name: &str) -> String {
+    let name = name.to_ascii_lowercase();
+    if name.ends_with('.') { name } else { format!("{}.", name) }
+}
+
+/// A timeout in seconds, which must be more than 0: a connect with a timeout
+/// of 0 always fails.
+pub fn parse_timeout(seconds: &str) -> io::Result<Duration> {
+    parse_number::<f64>(seconds).ok()
+        .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+        .filter(|timeout| !timeout.is_zero())
+        .ok_or_else(|| invalid_input(format!("invalid timeout {:?}, expected a positive \
+                                              number of seconds", seconds)))
+}
+
+fn parse_number<T: std::str::FromStr>(value: &str) -> io::Result<T> {
+    value.trim().parse().map_err(|_| invalid_input(format!("invalid number {:?}", value)))
+}
+
+fn invalid_input(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+#[test]
+fn test_resolv_conf() {
+    let mut config = ResolverConfig::default();
+    config.apply_resolv_conf("\
+        # generated by NetworkManager\n\
+        search lab.local\n\
+        nameserver 10.0.0.53\n\
+        nameserver fe80::1%eth0\n\
+        nameserver 2001:db8::53\n\
+        options rotate timeout:2 attempts:3 use-vc\n");
+
+    assert_eq!(config.servers, vec![
+        Server::Plain("10.0.0.53:53".parse().unwrap()),
+        Server::Plain("[2001:db8::53]:53".parse().unwrap()),
+    ]);
+    assert_eq!(config.timeout, Duration::from_secs(2));
+    assert_eq!(config.attempts, 3);
+    assert!(config.tcp);
+
+    config.apply_resolv_conf("options timeout:0\n");
+    assert_eq!(config.timeout, Duration::from_secs(2));
+}
+
+#[test]
+fn test_parse_timeout() {
+    assert_eq!(parse_timeout("1.5").unwrap(), Duration::from_millis(1500));
+    assert_eq!(parse_timeout(" 2 ").unwrap(), Duration::from_secs(2));
+    for invalid in ["0", "0.0", "-1", "NaN", "inf", "soon"] {
+        let error = parse_timeout(invalid).unwrap_err();
+        assert_eq!(error.to_string(), format!("invalid timeout {:?}, expected a positive \
+                                               number of seconds", invalid));
+    }
+}
+
+#[test]
+fn test_parse_server() {
+    let plain = |address: &str| Server::Plain(address.parse().unwrap());
+    assert_eq!(parse_server("127.0.0.1").unwrap(), plain("127.0.0.1:53"));
+    assert_eq!(parse_server(" 127.0.0.1:5353").unwrap(), plain("127.0.0.1:5353"));
+    assert_eq!(parse_server("::1").unwrap(), plain("[::1]:53"));
+    assert!(parse_server("localhost").is_err());
+
+    assert_eq!(parse_server("tls://1.1.1.1").unwrap(), Server::Tls {
+        address: "1.1.1.1:853".parse().unwrap(),
+        name: "1.1.1.1".to_string(),
+    });
+    assert_eq!(parse_server("tls://[::1]:8853#dns.lab").unwrap(), Server::Tls {
+        address: "[::1]:8853".parse().unwrap(),
+        name: "dns.lab".to_string(),
+    });
+    assert!(parse_server("tls://dns.lab").is_err());
+
+    let post = parse_server("https://dns.google/dns-query").unwrap();
+    assert_eq!(post, Server::Https {
+        url: Url::parse("https://dns.google/dns-query").unwrap(),
+        get: false,
+    });
+    let get = parse_server("https://dns.google/dns-query{?dns}").unwrap();
+    assert_eq!(get.to_string(), "https://dns.google/dns-query{?dns}");
+    assert!(matches!(get, Server::Https { get: true, .. }));
+}
@@ -0,0 +1,139 @@
+//! Reverse lookups of IP addresses, and forward-confirmed reverse DNS
+//! (FCrDNS): a PTR name only counts when it resolves back to the address.
+
+use rustdns::types::{Rcode, Resource, Type};
+use serde::Serialize;
+use std::io;
+use std::net::IpAddr;
+
+use crate::resolver::ResolverConfig;
+
+/// The PTR names of one address.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct PtrResult {
+    pub ip: String,
+    /// The response code of the PTR query.
+    pub status: String,
+    pub names: Vec<PtrName>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct PtrName {
+    pub name: String,
+    /// The addresses the name resolves to, only with the FCrDNS check.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub addresses: Option<Vec<String>>,
+    /// Whether `addresses` contain the address, only with the FCrDNS check.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub confirmed: Option<bool>,
+}
+
+impl PtrResult {
+    /// Whether the PTR query failed, like NXDOMAIN for addresses without names.
+    pub fn is_error(&self) -> bool {
+        self.status != Rcode::NoError.to_string()
+    }
+
+    /// The names that were checked and do not resolve back to the address.
+    pub fn mismatches(&self) -> impl Iterator<Item = &PtrName> {
+        self.names.iter().filter(|name| name.confirmed == Some(false))
+    }
+}
+
+/// The name to query PTR records of `ip` under, `4.3.2.1.in-addr.arpa.`
+/// for 1.2.3.4 and the reversed nibbles under `ip6.arpa.` for IPv6.
+pub fn reverse_name(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, d] = ip.octets();
+            format!("{}.{}.{}.{}.in-addr.arpa.", d, c, b, a)
+        }
+        IpAddr::V6(ip) => {
+            let mut name = String::with_capacity(73);
+            for byte in ip.octets().iter().rev() {
+                name.push_str(&format!("{:x}.{:x}.", byte & 0xf, byte >> 4));
+            }
+            name.push_str("ip6.arpa.");
+            name
+        }
+    }
+}
+
+/// Look up the PTR names of `ip`, and with `confirm` resolve each name
+/// with A or AAAA queries to see whether it points back to `ip`.
+pub fn lookup_ptr(ip: IpAddr, confirm: bool, config: &ResolverConfig) -> io::Result<PtrResult> {
+    let response = crate::udp(&reverse_name(ip), Type::PTR, config)?;
+    let mut names = Vec::new();
+    for record in &response.answers {
+        let Resource::PTR(name) = &record.resource else { continue };
+        let mut ptr = PtrName { name: name.clone(), addresses: None, confirmed: None };
+        if confirm {
+            let forward_type = if ip.is_ipv4() { Type::A } else { Type::AAAA };
+            let forward = crate::udp(name, forward_type, config)?;
+            let addresses: Vec<IpAddr> = crate::dns_message_to_ip_vec(forward).iter()
+                .filter_map(|address| address.parse().ok())
+                .collect();
+            ptr.confirmed = Some(addresses.contains(&ip));
+            ptr.addresses = Some(addresses.iter().map(IpAddr::to_string).collect());
+        }
+        names.push(ptr);
+    }
+    Ok(PtrResult { ip: ip.to_string(), status: response.rcode.to_string(), names })
+}
+
+#[test]
+fn test_reverse_name() {
+    assert_eq!(reverse_name("192.0.2.1".parse().unwrap()), "1.2.0.192.in-addr.arpa.");
+    assert_eq!(reverse_name("2001:db8::567:89ab".parse().unwrap()),
+               "b.a.9.8.7.6.5.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa.");
+}
+
+#[test]
+fn test_forward_confirmed_reverse_dns() {
+    use crate::resolver::Server;
+    use crate::testing;
+    use rustdns::Message;
+    use std::time::Duration;
+
+    // Both addresses point to host.example., which only resolves to the first.
+    let server = testing::spawn_udp_server(|query| {
+        let question = &Message::from_slice(query).ok()?.questions[0];
+        let answer = match (question.name.as_str(), question.r#type) {
+            ("1.2.0.192.in-addr.arpa." | "2.2.0.192.in-addr.arpa.", Type::PTR) => {
+                testing::ptr_record("host.example.", 300)
+            }
+            ("host.example.", Type::A) => testing::a_record([192, 0, 2, 1], 300),
+            _ => return Some(testing::response(query, 0, &[])),
+        };
+        Some(testing::response(query, 0, &[answer]))
+    });
+    let config = ResolverConfig {
+        servers: vec![Server::Plain(server)],
+        timeout: Duration::from_secs(1),
+        attempts: 1,
+        ..ResolverConfig::default()
+    };
+
+    let confirmed = lookup_ptr("192.0.2.1".parse().unwrap(), true, &config).unwrap();
+    assert_eq!(confirmed, PtrResult {
+        ip: "192.0.2.1".to_string(),
+        status: "NoError".to_string(),
+        names: vec![PtrName {
+            name: "host.example.".to_string(),
+            addresses: Some(vec!["192.0.2.1".to_string()]),
+            confirmed: Some(true),
+        }],
+    });
+    assert_eq!(confirmed.mismatches().count(), 0);
+
+    let mismatch = lookup_ptr("192.0.2.2".parse().unwrap(), true, &config).unwrap();
+    assert_eq!(mismatch.mismatches().map(|name| name.name.as_str()).collect::<Vec<_>>(),
+               ["host.example."]);
+
+    let unchecked = lookup_ptr("192.0.2.2".parse().unwrap(), false, &config).unwrap();
+    assert_eq!(unchecked.names[0].confirmed, None);
+    assert_eq!(unchecked.mismatches().count(), 0);
+
+    let missing = lookup_ptr("198.51.100.1".parse().unwrap(), true, &config).unwrap();
+    assert!(missing.names.is_empty());
+}
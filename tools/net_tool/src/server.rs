@@ -0,0 +1,409 @@
+//! A small caching DNS forwarder for a lab network. Queries on UDP and TCP
+//! are answered from a hosts file, from the cache, or by asking the
+//! configured servers, and answers are cached for as long as their TTLs
+//! allow.
+
+use rustdns::types::{Extension, Opcode, Rcode, Record, Resource, Type, QR};
+use rustdns::Message;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::hosts::Hosts;
+use crate::resolver::{self, ResolverConfig};
+use crate::wire;
+
+/// Upper bound for how long anything is cached, whatever its TTL.
+const MAX_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// When the cache is this full only expired entries make room.
+const MAX_CACHE_ENTRIES: usize = 10_000;
+/// The UDP response size every client handles, larger responses need EDNS.
+const MIN_UDP_PAYLOAD: usize = 512;
+/// The UDP response size this server offers in its EDNS record.
+const UDP_PAYLOAD: u16 = 1232;
+/// How long an idle TCP connection stays open.
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How many UDP queries and TCP connections are served at once, more are
+/// dropped rather than starting a thread each.
+const MAX_WORKERS: usize = 64;
+
+pub struct Stub {
+    config: ResolverConfig,
+    hosts: Hosts,
+    cache: Mutex<HashMap<(String, Type), CacheEntry>>,
+    stats: Stats,
+}
+
+struct CacheEntry {
+    response: Message,
+    stored: Instant,
+    expires: Instant,
+}
+
+/// Counters shown when the server stops.
+#[derive(Debug, Default)]
+pub struct Stats {
+    queries: AtomicU64,
+    overrides: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    failures: AtomicU64,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let count = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let (hits, misses) = (count(&self.hits), count(&self.misses));
+        let lookups = (hits + misses).max(1);
+        let hit_rate = hits as f64 * 100.0 / lookups as f64;
+        write!(f, "{} queries, {} from the hosts file, {} cache hits, {} cache misses \
+                   ({:.1}% hit rate), {} upstream failures",
+               count(&self.queries), count(&self.overrides), hits, misses, hit_rate,
+               count(&self.failures))
+    }
+}
+
+impl Stub {
+    pub fn new(config: ResolverConfig, hosts: Hosts) -> Stub {
+        Stub { config, hosts, cache: Mutex::new(HashMap::new()), stats: Stats::default() }
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// The response to `query`.
+    pub fn answer(&self, query: &Message) -> Message {
+        self.stats.queries.fetch_add(1, Ordering::Relaxed);
+        let mut response = Message {
+            id: query.id,
+            qr: QR::Response,
+            opcode: query.opcode,
+            rd: query.rd,
+            ra: true,
+            ad: false,
+            questions: query.questions.clone(),
+            extension: query.extension.as_ref().map(|_| Extension {
+                payload_size: UDP_PAYLOAD,
+                ..Extension::default()
+            }),
+            ..Message::default()
+        };
+        if query.opcode != Opcode::Query {
+            response.rcode = Rcode::NotImp;
+            return response;
+        }
+        let question = match &query.questions[..] {
+            [question] => question,
+            _ => {
+                response.rcode = Rcode::FormErr;
+                return response;
+            }
+        };
+
+        if let Some(records) = self.hosts.lookup(&question.name, question.r#type) {
+            self.stats.overrides.fetch_add(1, Ordering::Relaxed);
+            response.aa = true;
+            response.answers = records;
+            return response;
+        }
+        match self.resolve(&question.name, question.r#type) {
+            Ok(upstream) => {
+                response.rcode = upstream.rcode;
+                response.answers = upstream.answers;
+                response.authoritys = upstream.authoritys;
+                response.additionals = upstream.additionals;
+            }
+            Err(_) => {
+                self.stats.failures.fetch_add(1, Ordering::Relaxed);
+                response.rcode = Rcode::ServFail;
+            }
+        }
+        response
+    }
+
+    /// The answer from the cache with the TTLs counted down, or from the
+    /// configured servers.
+    fn resolve(&self, name: &str, query_type: Type) -> io::Result<Message> {
+        let key = (resolver::fqdn(name), query_type);
+        let now = Instant::now();
+        let cache = self.cache.lock().unwrap();
+        if let Some(entry) = cache.get(&key).filter(|entry| entry.expires > now) {
+            self.stats.hits.fetch_add(1, Ordering::Relaxed);
+            let age = Duration::from_secs(now.duration_since(entry.stored).as_secs());
+            let mut response = entry.response.clone();
+            for record in response.answers.iter_mut()
+                .chain(&mut response.authoritys)
+                .chain(&mut response.additionals) {
+                record.ttl = record.ttl.saturating_sub(age);
+            }
+            return Ok(response);
+        }
+        drop(cache);
+
+        self.stats.misses.fetch_add(1, Ordering::Relaxed);
+        let response = crate::udp(name, query_type, &self.config)?;
+        if let Some(ttl) = cache_ttl(&response) {
+            let mut cache = self.cache.lock().unwrap();
+            if cache.len() >= MAX_CACHE_ENTRIES {
+                cache.retain(|_, entry| entry.expires > now);
+            }
+            if cache.len() < MAX_CACHE_ENTRIES {
+                let expires = now + ttl;
+                cache.insert(key, CacheEntry { response: response.clone(), stored: now, expires });
+            }
+        }
+        Ok(response)
+    }
+}
+
+/// How long a response may be cached: the smallest TTL of its records, and
+/// for a negative answer the SOA minimum (RFC 2308). `None` for responses
+/// that are not cached, like server failures.
+fn cache_ttl(response: &Message) -> Option<Duration> {
+    let ttl = match response.rcode {
+        Rcode::NoError if !response.answers.is_empty() => response.answers.iter()
+            .chain(&response.authoritys)
+            .chain(&response.additionals)
+            .map(|record| record.ttl)
+            .min(),
+        Rcode::NoError | Rcode::NXDomain => response.authoritys.iter()
+            .filter_map(|record: &Record| match &record.resource {
+                Resource::SOA(soa) => Some(record.ttl.min(soa.minimum)),
+                _ => None,
+            })
+            .min(),
+        _ => None,
+    };
+    ttl.filter(|ttl| !ttl.is_zero()).map(|ttl| ttl.min(MAX_TTL))
+}
+
+/// Counts the threads serving queries, so a flood of queries cannot start
+/// any number of them.
+struct Workers {
+    busy: AtomicUsize,
+    limit: usize,
+}
+
+/// One running worker, counted until dropped.
+struct Worker(Arc<Workers>);
+
+impl Workers {
+    fn new(limit: usize) -> Arc<Workers> {
+        Arc::new(Workers { busy: AtomicUsize::new(0), limit })
+    }
+
+    /// A worker, or `None` when `limit` are already busy.
+    fn try_start(self: &Arc<Self>) -> Option<Worker> {
+        self.busy
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |busy| {
+                (busy < self.limit).then_some(busy + 1)
+            })
+            .ok()?;
+        Some(Worker(self.clone()))
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        self.0.busy.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Serve `stub` on UDP and TCP at `listen`, a port of 0 picks the same free
+/// port for both. Returns the address served on.
+pub fn spawn(stub: Arc<Stub>, listen: SocketAddr) -> io::Result<SocketAddr> {
+    let udp = UdpSocket::bind(listen)?;
+    let address = udp.local_addr()?;
+    let tcp = TcpListener::bind(address)?;
+    let workers = Workers::new(MAX_WORKERS);
+
+    let (udp_stub, udp_workers) = (stub.clone(), workers.clone());
+    thread::spawn(move || {
+        let mut buffer = [0; 4096];
+        while let Ok((len, peer)) = udp.recv_from(&mut buffer) {
+            // When every worker is busy the query is dropped and the client
+            // retries, like a server that lost the datagram.
+            let Some(worker) = udp_workers.try_start() else { continue };
+            let (query, stub) = (buffer[..len].to_vec(), udp_stub.clone());
+            let Ok(udp) = udp.try_clone() else { continue };
+            // Forwarding blocks for up to the timeout of every server.
+            thread::spawn(move || {
+                if let Some(response) = handle(&stub, &query, true) {
+                    let _ = udp.send_to(&response, peer);
+                }
+                drop(worker);
+            });
+        }
+    });
+    thread::spawn(move || {
+        for stream in tcp.incoming().flatten() {
+            // Dropping the stream closes the connection.
+            let Some(worker) = workers.try_start() else { continue };
+            let stub = stub.clone();
+            thread::spawn(move || {
+                serve_tcp(&stub, stream);
+                drop(worker);
+            });
+        }
+    });
+    Ok(address)
+}
+
+fn serve_tcp(stub: &Stub, mut stream: TcpStream) {
+    let _ = stream.set_read_timeout(Some(TCP_IDLE_TIMEOUT));
+    let mut len = [0; 2];
+    while stream.read_exact(&mut len).is_ok() {
+        let mut query = vec![0; u16::from_be_bytes(len) as usize];
+        if stream.read_exact(&mut query).is_err() {
+            return;
+        }
+        let Some(response) = handle(stub, &query, false) else { return };
+        let mut framed = (response.len() as u16).to_be_bytes().to_vec();
+        framed.extend_from_slice(&response);
+        if stream.write_all(&framed).is_err() {
+            return;
+        }
+    }
+}
+
+/// The encoded response to the encoded `query`. Over UDP a response longer
+/// than the client accepts is sent truncated, so the client asks over TCP.
+fn handle(stub: &Stub, query: &[u8], udp: bool) -> Option<Vec<u8>> {
+    let query = match Message::from_slice(query) {
+        Ok(query) if query.qr == QR::Query => query,
+        Ok(_) => return None,
+        Err(_) => return format_error(query),
+    };
+    let response = stub.answer(&query);
+    let encoded = wire::encode(&response).ok()?;
+    let limit = query.extension.as_ref()
+        .map_or(0, |extension| extension.payload_size as usize)
+        .max(MIN_UDP_PAYLOAD);
+    if udp && encoded.len() > limit {
+        return wire::encode_truncated(&response).ok();
+    }
+    Some(encoded)
+}
+
+/// A FORMERR response with only the header, for queries that do not parse.
+fn format_error(query: &[u8]) -> Option<Vec<u8>> {
+    let mut response = query.get(..12)?.to_vec();
+    if response[2] & 0b1000_0000 != 0 {
+        return None; // a response, not a query
+    }
+    response[2] |= 0b1000_0000;
+    response[3] = Rcode::FormErr as u8;
+    response[4..12].fill(0);
+    Some(response)
+}
+
+#[test]
+fn test_stub_server() {
+    use crate::resolver::Server;
+    use crate::testing;
+    use std::sync::atomic::AtomicUsize;
+
+    static UPSTREAM_QUERIES: AtomicUsize = AtomicUsize::new(0);
+    let upstream = testing::spawn_udp_server(|query| {
+        UPSTREAM_QUERIES.fetch_add(1, Ordering::SeqCst);
+        Some(testing::response(query, 0, &[testing::a_record([192, 0, 2, 1], 300)]))
+    });
+    let hosts_file = std::env::temp_dir()
+        .join(format!("net_tool_test_{}_hosts", std::process::id()));
+    std::fs::write(&hosts_file, "10.0.0.5 build.lab\n").unwrap();
+    let stub = Arc::new(Stub::new(
+        ResolverConfig {
+            servers: vec![Server::Plain(upstream)],
+            timeout: Duration::from_secs(1),
+            attempts: 1,
+            ..ResolverConfig::default()
+        },
+        Hosts::load(&hosts_file).unwrap(),
+    ));
+    let address = spawn(stub.clone(), "127.0.0.1:0".parse().unwrap()).unwrap();
+    let mut client = ResolverConfig {
+        servers: vec![Server::Plain(address)],
+        timeout: Duration::from_secs(2),
+        attempts: 1,
+        ..ResolverConfig::default()
+    };
+    let data = |response: &Message| -> Vec<String> {
+        response.answers.iter().map(|record| record.resource.to_string()).collect()
+    };
+
+    let overridden = crate::udp("build.lab", Type::A, &client).unwrap();
+    assert!(overridden.aa);
+    assert_eq!(data(&overridden), ["10.0.0.5"]);
+    let reverse = crate::udp("5.0.0.10.in-addr.arpa", Type::PTR, &client).unwrap();
+    assert_eq!(data(&reverse), ["build.lab."]);
+    let no_ipv6 = crate::udp("build.lab", Type::AAAA, &client).unwrap();
+    assert_eq!((no_ipv6.rcode, no_ipv6.answers.len()), (Rcode::NoError, 0));
+    assert_eq!(UPSTREAM_QUERIES.load(Ordering::SeqCst), 0);
+
+    let first = crate::udp("www.example", Type::A, &client).unwrap();
+    client.tcp = true;
+    let cached = crate::udp("WWW.example", Type::A, &client).unwrap();
+    assert_eq!(data(&first), ["192.0.2.1"]);
+    assert_eq!(data(&cached), ["192.0.2.1"]);
+    assert!(cached.answers[0].ttl <= Duration::from_secs(300));
+    assert_eq!(UPSTREAM_QUERIES.load(Ordering::SeqCst), 1);
+    assert_eq!(stub.stats().to_string(),
+               "5 queries, 3 from the hosts file, 1 cache hits, 1 cache misses (50.0% hit rate), \
+                0 upstream failures");
+}
+
+#[test]
+fn test_cache_ttl() {
+    use rustdns::resource::SOA;
+    use rustdns::types::Class;
+
+    let record = |ttl: u64, resource| {
+        Record::new("example.com.", Class::Internet, Duration::from_secs(ttl), resource)
+    };
+    let soa = |ttl, minimum| record(ttl, Resource::SOA(SOA {
+        mname: "ns.example.com.".into(),
+        rname: "hostmaster@example.com.".into(),
+        serial: 1,
+        refresh: Duration::from_secs(7200),
+        retry: Duration::from_secs(900),
+        expire: Duration::from_secs(1209600),
+        minimum: Duration::from_secs(minimum),
+    }));
+
+    let mut response = Message {
+        answers: vec![record(300, Resource::A([192, 0, 2, 1].into())),
+                      record(60, Resource::A([192, 0, 2, 2].into()))],
+        ..Message::default()
+    };
+    assert_eq!(cache_ttl(&response), Some(Duration::from_secs(60)));
+
+    response.answers = vec![record(0, Resource::A([192, 0, 2, 1].into()))];
+    assert_eq!(cache_ttl(&response), None);
+
+    response.answers.clear();
+    response.rcode = Rcode::NXDomain;
+    response.authoritys = vec![soa(3600, 900)];
+    assert_eq!(cache_ttl(&response), Some(Duration::from_secs(900)));
+
+    response.rcode = Rcode::ServFail;
+    assert_eq!(cache_ttl(&response), None);
+}
+
+#[test]
+fn test_workers_are_limited() {
+    let workers = Workers::new(2);
+    let first = workers.try_start().unwrap();
+    let second = workers.try_start().unwrap();
+    assert!(workers.try_start().is_none());
+    drop(first);
+    let third = workers.try_start().unwrap();
+    assert!(workers.try_start().is_none());
+    drop((second, third));
+    assert_eq!(workers.busy.load(Ordering::Acquire), 0);
+}
@@ -0,0 +1,428 @@
+//! Local stand-ins for the servers the tool talks to, so tests run offline.
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+use std::io::{BufRead, BufReader, Read, Write};
+use serde_json::Value;
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, UdpSocket};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+
+/// The truncation bit in the first flags byte of a DNS header.
+pub const TC: u8 = 0b0000_0010;
+/// The authoritative answer bit in the first flags byte of a DNS header.
+pub const AA: u8 = 0b0000_0100;
+
+/// Answer every datagram sent to a local UDP port with `handler`,
+/// datagrams it returns `None` for are left unanswered.
+pub fn spawn_udp_server<F>(handler: F) -> SocketAddr
+where F: Fn(&[u8]) -> Option<Vec<u8>> + Send + 'static
+{
+    serve_udp(UdpSocket::bind("127.0.0.1:0").unwrap(), handler)
+}
+
+/// A DNS server listening on the same local port for UDP and TCP.
+pub fn spawn_dns_server<U, T>(udp_handler: U, tcp_handler: T) -> SocketAddr
+where U: Fn(&[u8]) -> Option<Vec<u8>> + Send + 'static,
+      T: Fn(&[u8]) -> Option<Vec<u8>> + Send + 'static
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    serve_udp(UdpSocket::bind(address).unwrap(), udp_handler);
+    thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            serve_framed(&mut stream, &tcp_handler);
+        }
+    });
+    address
+}
+
+/// A DNS over TLS server with a self-signed certificate for `localhost`,
+/// returns its address and a PEM file with the certificate to trust.
+pub fn spawn_tls_server<F>(handler: F) -> (SocketAddr, PathBuf)
+where F: Fn(&[u8]) -> Option<Vec<u8>> + Send + 'static
+{
+    let (listener, tls, ca_file) = tls_listener();
+    let address = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for tcp in listener.incoming().flatten() {
+            let connection = ServerConnection::new(tls.clone()).unwrap();
+            serve_framed(&mut StreamOwned::new(connection, tcp), &handler);
+        }
+    });
+    (address, ca_file)
+}
+
+/// A DNS over HTTPS server answering POST and GET requests on any path,
+/// with a self-signed certificate like `spawn_tls_server`.
+pub fn spawn_https_server<F>(handler: F) -> (SocketAddr, PathBuf)
+where F: Fn(&[u8]) -> Option<Vec<u8>> + Send + 'static
+{
+    let (listener, tls, ca_file) = tls_listener();
+    let address = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for tcp in listener.incoming().flatten() {
+            let connection = ServerConnection::new(tls.clone()).unwrap();
+            let mut stream = BufReader::new(StreamOwned::new(connection, tcp));
+            if let Some(query) = read_http_query(&mut stream) {
+                let response = handler(&query).unwrap_or_default();
+                let head = format!("HTTP/1.1 200 OK\r\n\
+                                    Content-Type: application/dns-message\r\n\
+                                    Content-Length: {}\r\n\r\n", response.len());
+                let stream = stream.get_mut();
+                let _ = stream.write_all(head.as_bytes());
+                let _ = stream.write_all(&response);
+                stream.conn.send_close_notify();
+                let _ = stream.flush();
+            }
+        }
+    });
+    (address, ca_file)
+}
+
+/// A plain HTTP server answering every request with `handler`, which gets
+/// the request target and the Authorization header and returns the status
+/// and a JSON body.
+pub fn spawn_http_server<F>(handler: F) -> SocketAddr
+where F: Fn(&str, Option<&str>) -> (u16, String) + Send + 'static
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for tcp in listener.incoming().flatten() {
+            let mut stream = BufReader::new(tcp);
+            let mut request_line = String::new();
+            let mut authorization = None;
+            let _ = stream.read_line(&mut request_line);
+            loop {
+                let mut line = String::new();
+                if stream.read_line(&mut line).unwrap_or(0) == 0 || line.trim_end().is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = line.split_once(':') {
+                    if name.eq_ignore_ascii_case("authorization") {
+                        authorization = Some(value.trim().to_string());
+                    }
+                }
+            }
+            let target = request_line.split_whitespace().nth(1).unwrap_or("");
+            let (status, body) = handler(target, authorization.as_deref());
+            let response = format!("HTTP/1.1 {} Status\r\n\
+                                    Content-Type: application/json\r\n\
+                                    Content-Length: {}\r\n\
+                                    Connection: close\r\n\r\n{}", status, body.len(), body);
+            let _ = stream.get_mut().write_all(response.as_bytes());
+        }
+    });
+    address
+}
+
+/// The DNS message in a POST body or in the `dns` parameter of a GET request.
+fn read_http_query<R: BufRead>(stream: &mut R) -> Option<Vec<u8>> {
+    let mut request_line = String::new();
+    stream.read_line(&mut request_line).ok()?;
+    let mut content_length = 0;
+    loop {
+        let mut line = String::new();
+        stream.read_line(&mut line).ok()?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().ok()?;
+            }
+        }
+    }
+
+    let target = request_line.split_whitespace().nth(1)?;
+    if request_line.starts_with("GET ") {
+        let (_, dns) = target.split_once("dns=")?;
+        return URL_SAFE_NO_PAD.decode(dns.split('&').next()?).ok();
+    }
+    let mut body = vec![0; content_length];
+    stream.read_exact(&mut body).ok()?;
+    Some(body)
+}
+
+fn tls_listener() -> (TcpListener, Arc<ServerConfig>, PathBuf) {
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let ca_file = std::env::temp_dir().join(format!("net_tool_test_{}_{}.pem",
+                                                    std::process::id(),
+                                                    listener.local_addr().unwrap().port()));
+    std::fs::write(&ca_file, certified.cert.pem()).unwrap();
+
+    let key = PrivateKeyDer::Pkcs8(certified.key_pair.serialize_der().into());
+    let certificate = CertificateDer::from(certified.cert.der().to_vec());
+    let tls = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(vec![certificate], key)
+        .unwrap();
+    (listener, Arc::new(tls), ca_file)
+}
+
+/// Answer length-prefixed queries on a TCP or TLS stream until it closes.
+fn serve_framed<S, F>(stream: &mut S, handler: &F)
+where S: Read + Write,
+      F: Fn(&[u8]) -> Option<Vec<u8>>
+{
+    let mut len = [0; 2];
+    let mut query = Vec::new();
+    while stream.read_exact(&mut len).is_ok() {
+        query.resize(u16::from_be_bytes(len) as usize, 0);
+        if stream.read_exact(&mut query).is_err() {
+            break;
+        }
+        match handler(&query) {
+            Some(response) => {
+                let mut framed = (response.len() as u16).to_be_bytes().to_vec();
+                framed.extend_from_slice(&response);
+                if stream.write_all(&framed).and_then(|()| stream.flush()).is_err() {
+                    break;
+                }
+            }
+            None => break,
+        }
+    }
+}
+
+/// Answer datagrams on an already bound socket like `spawn_udp_server`.
+pub fn serve_udp<F>(socket: UdpSocket, handler: F) -> SocketAddr
+where F: Fn(&[u8]) -> Option<Vec<u8>> + Send + 'static
+{
+    let address = socket.local_addr().unwrap();
+    thread::spawn(move || {
+        let mut buffer = [0; 4096];
+        while let Ok((len, peer)) = socket.recv_from(&mut buffer) {
+            if let Some(response) = handler(&buffer[..len]) {
+                let _ = socket.send_to(&response, peer);
+            }
+        }
+    });
+    address
+}
+
+/// A NOERROR response without any records, made by echoing the query with
+/// the QR bit set.
+pub fn empty_response(query: &[u8]) -> Vec<u8> {
+    let mut response = query.to_vec();
+    response[2] |= 0b1000_0000;
+    response
+}
+
+/// A response to the first question of `query` with the given answer
+/// records, `flags` is or-ed into the first flags byte.
+pub fn response(query: &[u8], flags: u8, answers: &[Vec<u8>]) -> Vec<u8> {
+    let question_end = 12 + name_len(&query[12..]) + 4;
+    let mut response = query[..question_end].to_vec();
+    response[2] |= 0b1000_0000 | flags;
+    response[3] = 0b1000_0000; // recursion available, NOERROR
+    response[4..6].copy_from_slice(&1u16.to_be_bytes());
+    response[6..8].copy_from_slice(&(answers.len() as u16).to_be_bytes());
+    response[8..12].fill(0);
+    for answer in answers {
+        response.extend_from_slice(answer);
+    }
+    response
+}
+
+/// A referral to `zone` with an NS record for each name server, and an A
+/// record as glue for those that have an address.
+pub fn referral(query: &[u8], zone: &str, nameservers: &[(&str, Option<[u8; 4]>)]) -> Vec<u8> {
+    let mut response = self::response(query, 0, &[]);
+    let glue: Vec<_> = nameservers.iter()
+        .filter_map(|(name, ip)| ip.map(|ip| (name, ip)))
+        .collect();
+    response[8..10].copy_from_slice(&(nameservers.len() as u16).to_be_bytes());
+    response[10..12].copy_from_slice(&(glue.len() as u16).to_be_bytes());
+    for (name, _) in nameservers {
+        let rdata = encode_name(name);
+        response.extend_from_slice(&encode_name(zone));
+        response.extend_from_slice(&2u16.to_be_bytes()); // NS
+        response.extend_from_slice(&1u16.to_be_bytes()); // IN
+        response.extend_from_slice(&172800u32.to_be_bytes());
+        response.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        response.extend_from_slice(&rdata);
+    }
+    for (name, ip) in glue {
+        response.extend_from_slice(&encode_name(name));
+        response.extend_from_slice(&a_record(ip, 172800)[2..]);
+    }
+    response
+}
+
+/// `name` in uncompressed wire format.
+pub fn encode_name(name: &str) -> Vec<u8> {
+    let mut encoded = Vec::new();
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        encoded.push(label.len() as u8);
+        encoded.extend_from_slice(label.as_bytes());
+    }
+    encoded.push(0);
+    encoded
+}
+
+/// An A record owned by the name of the first question.
+pub fn a_record(ip: [u8; 4], ttl: u32) -> Vec<u8> {
+    let mut record = vec![0xc0, 12]; // pointer to the question name
+    record.extend_from_slice(&1u16.to_be_bytes()); // A
+    record.extend_from_slice(&1u16.to_be_bytes()); // IN
+    record.extend_from_slice(&ttl.to_be_bytes());
+    record.extend_from_slice(&4u16.to_be_bytes());
+    record.extend_from_slice(&ip);
+    record
+}
+
+/// Write an IPv4 MaxMind DB (MMDB) with `data` for each network given as
+/// address and prefix length, and return its path. Networks must not overlap.
+pub fn write_mmdb(database_type: &str, networks: &[(Ipv4Addr, u8, Value)]) -> PathBuf {
+    #[derive(Clone, Copy)]
+    enum Child { Empty, Node(usize), Data(usize) }
+
+    let mut nodes = vec![[Child::Empty; 2]];
+    let mut data = Vec::new();
+    for (address, prefix_len, value) in networks {
+        let bits = u32::from(*address);
+        let mut node = 0;
+        for depth in 0..*prefix_len {
+            let bit = (bits >> (31 - depth) & 1) as usize;
+            if depth + 1 == *prefix_len {
+                nodes[node][bit] = Child::Data(data.len());
+                encode_mmdb(&mut data, value);
+            } else {
+                node = match nodes[node][bit] {
+                    Child::Node(next) => next,
+                    _ => {
+                        nodes.push([Child::Empty; 2]);
+                        nodes[node][bit] = Child::Node(nodes.len() - 1);
+                        nodes.len() - 1
+                    }
+                };
+            }
+        }
+    }
+
+    // 24 bit records, pointers past the tree point into the data section
+    // which starts after 16 zero bytes.
+    let node_count = nodes.len();
+    let mut database = Vec::new();
+    for children in &nodes {
+        for child in children {
+            let record = match *child {
+                Child::Empty => node_count,
+                Child::Node(node) => node,
+                Child::Data(offset) => node_count + 16 + offset,
+            };
+            database.extend_from_slice(&(record as u32).to_be_bytes()[1..]);
+        }
+    }
+    database.extend_from_slice(&[0; 16]);
+    database.extend_from_slice(&data);
+    database.extend_from_slice(b"\xab\xcd\xefMaxMind.com");
+    // The metadata map, its integers must have the exact types of the format.
+    mmdb_control(&mut database, 7, 9);
+    let mut entry = |key: &str, field_type: u8, value: u64| {
+        encode_mmdb(&mut database, &Value::from(key));
+        mmdb_uint(&mut database, field_type, value);
+    };
+    entry("binary_format_major_version", 5, 2);
+    entry("binary_format_minor_version", 5, 0);
+    entry("build_epoch", 9, 1_700_000_000);
+    entry("ip_version", 5, 4);
+    entry("node_count", 6, node_count as u64);
+    entry("record_size", 5, 24);
+    for (key, value) in [
+        ("database_type", Value::from(database_type)),
+        ("description", serde_json::json!({ "en": "net_tool test database" })),
+        ("languages", serde_json::json!(["en"])),
+    ] {
+        encode_mmdb(&mut database, &Value::from(key));
+        encode_mmdb(&mut database, &value);
+    }
+
+    let path = std::env::temp_dir().join(format!("net_tool_test_{}_{}.mmdb",
+                                                 std::process::id(), database_type));
+    std::fs::write(&path, database).unwrap();
+    path
+}
+
+/// Append `value` in the MMDB data section format.
+fn encode_mmdb(out: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::String(string) => {
+            mmdb_control(out, 2, string.len());
+            out.extend_from_slice(string.as_bytes());
+        }
+        Value::Number(number) => match number.as_u64() {
+            Some(number) => mmdb_uint(out, 6, number),
+            None => {
+                mmdb_control(out, 3, 8);
+                out.extend_from_slice(&number.as_f64().unwrap().to_be_bytes());
+            }
+        },
+        Value::Object(map) => {
+            mmdb_control(out, 7, map.len());
+            for (key, value) in map {
+                encode_mmdb(out, &Value::String(key.clone()));
+                encode_mmdb(out, value);
+            }
+        }
+        Value::Array(values) => {
+            mmdb_control(out, 11, values.len());
+            for value in values {
+                encode_mmdb(out, value);
+            }
+        }
+        Value::Bool(value) => mmdb_control(out, 14, *value as usize),
+        Value::Null => panic!("MMDB has no null"),
+    }
+}
+
+/// An unsigned integer of `field_type` 5 (uint16), 6 (uint32) or 9 (uint64)
+/// without leading zero bytes.
+fn mmdb_uint(out: &mut Vec<u8>, field_type: u8, value: u64) {
+    let bytes = value.to_be_bytes();
+    let skip = bytes.iter().take_while(|byte| **byte == 0).count();
+    mmdb_control(out, field_type, 8 - skip);
+    out.extend_from_slice(&bytes[skip..]);
+}
+
+/// The control byte of a field, types above 7 are extended types which
+/// follow in the next byte. Sizes up to 284 bytes are enough for tests.
+fn mmdb_control(out: &mut Vec<u8>, field_type: u8, size: usize) {
+    let first = if field_type <= 7 { field_type << 5 } else { 0 };
+    let size_bits = if size < 29 { size as u8 } else { 29 };
+    out.push(first | size_bits);
+    if field_type > 7 {
+        out.push(field_type - 7);
+    }
+    if size >= 29 {
+        out.push(u8::try_from(size - 29).unwrap());
+    }
+}
+
+/// A PTR record pointing to `name`, owned by the name of the first question.
+pub fn ptr_record(name: &str, ttl: u32) -> Vec<u8> {
+    let rdata = encode_name(name);
+    let mut record = vec![0xc0, 12];
+    record.extend_from_slice(&12u16.to_be_bytes()); // PTR
+    record.extend_from_slice(&1u16.to_be_bytes()); // IN
+    record.extend_from_slice(&ttl.to_be_bytes());
+    record.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+    record.extend_from_slice(&rdata);
+    record
+}
+
+/// Length of the uncompressed name at the start of `bytes`.
+fn name_len(bytes: &[u8]) -> usize {
+    let mut len = 0;
+    while bytes[len] != 0 {
+        len += bytes[len] as usize + 1;
+    }
+    len + 1
+}
@@ -0,0 +1,258 @@
+//! Iterative resolution from the root servers following referrals, to see
+//! where a delegation goes wrong, like `dig +trace`.
+
+use rustdns::types::{Rcode, Resource, Type};
+use rustdns::Message;
+use serde::Serialize;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+
+use crate::output::DnsRecord;
+use crate::resolver::{fqdn, ResolverConfig, Server};
+
+/// The IPv4 addresses of the root servers, from the IANA root hints.
+pub const ROOT_HINTS: &[(&str, [u8; 4])] = &[
+    ("a.root-servers.net.", [198, 41, 0, 4]),
+    ("b.root-servers.net.", [170, 247, 170, 2]),
+    ("c.root-servers.net.", [192, 33, 4, 12]),
+    ("d.root-servers.net.", [199, 7, 91, 13]),
+    ("e.root-servers.net.", [192, 203, 230, 10]),
+    ("f.root-servers.net.", [192, 5, 5, 241]),
+    ("g.root-servers.net.", [192, 112, 36, 4]),
+    ("h.root-servers.net.", [198, 97, 190, 53]),
+    ("i.root-servers.net.", [192, 36, 148, 17]),
+    ("j.root-servers.net.", [192, 58, 128, 30]),
+    ("k.root-servers.net.", [193, 0, 14, 129]),
+    ("l.root-servers.net.", [199, 7, 83, 42]),
+    ("m.root-servers.net.", [202, 12, 27, 33]),
+];
+
+/// Referral chains are rarely longer than a handful of zones, more hops
+/// than this means the servers refer in a circle.
+const MAX_HOPS: usize = 16;
+
+/// The response of one server on the way down.
+#[derive(Clone, Debug, Serialize)]
+pub struct Hop {
+    pub server: SocketAddr,
+    pub status: String,
+    /// The zone the server referred to, `None` for the final response.
+    pub zone: Option<String>,
+    /// The answers of the final response, or the NS records of a referral
+    /// followed by the glue addresses of the name servers.
+    pub records: Vec<DnsRecord>,
+}
+
+/// Resolve `domain` starting at `roots`, calling `on_hop` with each response
+/// as it arrives. Referred servers are queried on the port of the server
+/// that referred to them. Name servers without glue are looked up with the
+/// resolver in `config`, whose timeout also applies to each hop.
+pub fn trace<F>(domain: &str, query_type: Type, roots: &[SocketAddr], config: &ResolverConfig,
+                mut on_hop: F) -> io::Result<Rcode>
+where F: FnMut(&Hop)
+{
+    let name = fqdn(domain);
+    let mut zone = ".".to_string();
+    let mut servers = roots.to_vec();
+    for _hop in 0..MAX_HOPS {
+        let (server, response) = ask(domain, query_type, &servers, &zone, config)?;
+        let mut hop = Hop {
+            server,
+            status: response.rcode.to_string(),
+            zone: None,
+            records: response.answers.iter()
+                .map(|record| DnsRecord::new("answer", record))
+                .collect(),
+        };
+
+        let delegation = match referral(&response, &name, &zone) {
+            Some(delegation) => delegation,
+            None if is_referral(&response) => {
+                // Following a referral to a zone that is not below the current
+                // one would go round in circles, so it ends the trace.
+                hop.zone = response.authoritys.iter()
+                    .find(|record| record.r#type() == Type::NS)
+                    .map(|record| fqdn(&record.name));
+                hop.records = response.authoritys.iter()
+                    .filter(|record| record.r#type() == Type::NS)
+                    .map(|record| DnsRecord::new("authority", record))
+                    .collect();
+                on_hop(&hop);
+                return Err(io::Error::other(format!("lame/upward referral from {}", server)));
+            }
+            None => {
+                on_hop(&hop);
+                return Ok(response.rcode);
+            }
+        };
+        let nameservers: Vec<&String> = response.authoritys.iter()
+            .filter(|record| fqdn(&record.name) == delegation)
+            .filter_map(|record| match &record.resource {
+                Resource::NS(nameserver) => Some(nameserver),
+                _ => None,
+            })
+            .collect();
+        let glue: Vec<_> = response.additionals.iter()
+            .filter(|record| nameservers.iter().any(|ns| fqdn(ns) == fqdn(&record.name)))
+            .filter(|record| matches!(record.resource, Resource::A(_) | Resource::AAAA(_)))
+            .collect();
+        hop.zone = Some(delegation.clone());
+        hop.records = response.authoritys.iter()
+            .filter(|record| record.r#type() == Type::NS && fqdn(&record.name) == delegation)
+            .map(|record| DnsRecord::new("authority", record))
+            .chain(glue.iter().map(|record| DnsRecord::new("additional", record)))
+            .collect();
+        on_hop(&hop);
+
+        // IPv4 first, IPv6 is often not routed.
+        let mut next: Vec<IpAddr> = glue.iter()
+            .filter_map(|record| match record.resource {
+                Resource::A(ip) => Some(IpAddr::V4(ip)),
+                Resource::AAAA(ip) => Some(IpAddr::V6(ip)),
+                _ => None,
+            })
+            .collect();
+        if next.is_empty() {
+            for nameserver in &nameservers {
+                if let Ok(answer) = crate::udp(nameserver, Type::A, config) {
+                    next.extend(crate::dns_message_to_ip_vec(answer).iter()
+                        .filter_map(|ip| ip.parse::<IpAddr>().ok()));
+                }
+            }
+        }
+        next.sort_by_key(IpAddr::is_ipv6);
+        if next.is_empty() {
+            return Err(io::Error::other(format!("no address for the name servers of {}",
+                                                delegation)));
+        }
+        servers = next.into_iter().map(|ip| SocketAddr::new(ip, server.port())).collect();
+        zone = delegation;
+    }
+    Err(io::Error::other(format!("more than {} referrals for {}", MAX_HOPS, domain)))
+}
+
+/// Ask the servers of `zone` in order until one of them answers. The queries
+/// are not recursive, like `dig +trace`, so that a server which also resolves
+/// names refers like any other instead of hiding a broken delegation.
+fn ask(domain: &str, query_type: Type, servers: &[SocketAddr], zone: &str,
+       config: &ResolverConfig) -> io::Result<(SocketAddr, Message)> {
+    let mut failures = Vec::new();
+    for server in servers {
+        let config = ResolverConfig {
+            servers: vec![Server::Plain(*server)],
+            attempts: 1,
+            ..config.clone()
+        };
+        match crate::udp_norec(domain, query_type, &config) {
+            Ok(response) => return Ok((*server, response)),
+            Err(error) => failures.push(error.to_string()),
+        }
+    }
+    Err(io::Error::other(format!("no server of {} answered: {}", zone, failures.join("; "))))
+}
+
+/// The zone a response delegates to, when it is a referral to a zone below
+/// `zone` that contains `name`.
+fn referral(response: &Message, name: &str, zone: &str) -> Option<String> {
+    if response.rcode != Rcode::NoError || !response.answers.is_empty() {
+        return None;
+    }
+    response.authoritys.iter()
+        .filter(|record| record.r#type() == Type::NS)
+        .map(|record| fqdn(&record.name))
+        .find(|delegation| {
+            delegation != zone && in_zone(delegation, zone) && in_zone(name, delegation)
+        })
+}
+
+/// Whether a response without answers hands the query on to other name
+/// servers instead of answering it.
+fn is_referral(response: &Message) -> bool {
+    response.rcode == Rcode::NoError && !response.aa && response.answers.is_empty()
+        && response.authoritys.iter().any(|record| record.r#type() == Type::NS)
+}
+
+fn in_zone(name: &str, zone: &str) -> bool {
+    zone == "." || name == zone || name.ends_with(&format!(".{}", zone))
+}
+
+#[test]
+fn test_trace_follows_referrals() {
+    use crate::testing;
+    use std::net::UdpSocket;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    /// Queries with the RD bit set.
+    static RECURSIVE: AtomicUsize = AtomicUsize::new(0);
+    fn count_recursive(query: &[u8]) {
+        if query[2] & 0b0000_0001 != 0 {
+            RECURSIVE.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    // The root, com. and example.com. servers on the same port of different
+    // loopback addresses.
+    let root = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let port = root.local_addr().unwrap().port();
+    let com = UdpSocket::bind(("127.0.0.2", port)).unwrap();
+    let example = UdpSocket::bind(("127.0.0.3", port)).unwrap();
+    testing::serve_udp(root, |query| {
+        count_recursive(query);
+        Some(testing::referral(query, "com.", &[("a.gtld.test.", Some([127, 0, 0, 2]))]))
+    });
+    testing::serve_udp(com, |query| {
+        count_recursive(query);
+        Some(testing::referral(query, "example.com.", &[
+            ("ns1.example.com.", Some([127, 0, 0, 3])),
+            ("ns2.example.com.", None),
+        ]))
+    });
+    testing::serve_udp(example, |query| {
+        count_recursive(query);
+        Some(testing::response(query, testing::AA, &[testing::a_record([192, 0, 2, 1], 300)]))
+    });
+
+    let config = ResolverConfig {
+        timeout: Duration::from_secs(1),
+        ..ResolverConfig::default()
+    };
+    let mut hops = Vec::new();
+    let rcode = trace("www.example.com", Type::A, &[SocketAddr::from(([127, 0, 0, 1], port))],
+                      &config, |hop| hops.push(hop.clone())).unwrap();
+
+    assert_eq!(rcode, Rcode::NoError);
+    assert_eq!(RECURSIVE.load(Ordering::SeqCst), 0);
+    let servers: Vec<String> = hops.iter().map(|hop| hop.server.ip().to_string()).collect();
+    assert_eq!(servers, ["127.0.0.1", "127.0.0.2", "127.0.0.3"]);
+    let zones: Vec<_> = hops.iter().map(|hop| hop.zone.as_deref()).collect();
+    assert_eq!(zones, [Some("com."), Some("example.com."), None]);
+    let data: Vec<Vec<&str>> = hops.iter()
+        .map(|hop| hop.records.iter().map(|record| record.data.as_str()).collect())
+        .collect();
+    assert_eq!(data, [
+        vec!["a.gtld.test.", "127.0.0.2"],
+        vec!["ns1.example.com.", "ns2.example.com.", "127.0.0.3"],
+        vec!["192.0.2.1"],
+    ]);
+}
+
+#[test]
+fn test_trace_stops_at_referral_loops() {
+    use crate::testing;
+    use std::time::Duration;
+
+    // A server that refers back to a zone above the one it was asked for.
+    let server = testing::spawn_udp_server(|query| {
+        Some(testing::referral(query, "com.", &[("ns.loop.test.", Some([127, 0, 0, 1]))]))
+    });
+    let config = ResolverConfig { timeout: Duration::from_secs(1), ..ResolverConfig::default() };
+
+    let mut zones = Vec::new();
+    let error = trace("example.com", Type::A, &[server], &config, |hop| {
+        zones.push(hop.zone.clone())
+    }).unwrap_err();
+    // The second referral to com. is not followed but ends the trace.
+    assert_eq!(zones, [Some("com.".to_string()), Some("com.".to_string())]);
+    assert_eq!(error.to_string(), format!("lame/upward referral from {}", server));
+}
@@ -0,0 +1,277 @@
+//! Sending an encoded query to a single server and reading back the encoded
+//! response, which is left to the caller to parse.
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, ServerName};
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream, UdpSocket};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use url::{Host, Position, Url};
+
+/// Media type of DNS messages in HTTP requests and responses (RFC 8484).
+const DNS_MESSAGE: &str = "application/dns-message";
+
+/// Send one query to one server and wait for the response carrying the same id.
+pub fn udp_exchange(server: SocketAddr, question: &[u8], id: u16, timeout: Duration)
+    -> io::Result<Vec<u8>> {
+    // Setup a UDP socket for sending to a DNS server.
+    let local: SocketAddr = if server.is_ipv6() { "[::]:0" } else { "0.0.0.0:0" }.parse().unwrap();
+    let socket = UdpSocket::bind(local)?;
+    socket.connect(server)?;
+
+    // Send to the server.
+    socket.send(question)?;
+
+    // Wait for a response from the DNS server, datagrams answering an
+    // earlier query are dropped.
+    let deadline = Instant::now() + timeout;
+    let mut resp = [0; 4096];
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        socket.set_read_timeout(Some(remaining))?;
+        let len = socket.recv(&mut resp)?;
+        if response_id(&resp[..len]) == Some(id) {
+            return Ok(resp[..len].to_vec());
+        }
+    }
+}
+
+/// Send one query over TCP, where each message is preceded by its length
+/// as a two byte big-endian number (RFC 1035 section 4.2.2).
+pub fn tcp_exchange(server: SocketAddr, question: &[u8], id: u16, timeout: Duration)
+    -> io::Result<Vec<u8>> {
+    let mut stream = TcpStream::connect_timeout(&server, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    exchange_framed(&mut stream, question, id)
+}
+
+/// Send one query over TLS (RFC 7858), framed the same way as over TCP.
+pub fn tls_exchange(server: SocketAddr, name: &str, question: &[u8], id: u16,
+                    timeout: Duration, tls: Arc<ClientConfig>) -> io::Result<Vec<u8>> {
+    let mut stream = tls_connect(&[server], name, timeout, tls)?;
+    exchange_framed(&mut stream, question, id)
+}
+
+/// Send one query over HTTPS (RFC 8484), in the body of a POST request or
+/// base64url encoded in the `dns` parameter of a GET request.
+pub fn https_exchange(url: &Url, get: bool, question: &[u8], id: u16, timeout: Duration,
+                      tls: Arc<ClientConfig>) -> io::Result<Vec<u8>> {
+    let (name, host) = match url.host() {
+        Some(Host::Domain(domain)) => (domain.to_string(), domain.to_string()),
+        Some(Host::Ipv4(ip)) => (ip.to_string(), ip.to_string()),
+        Some(Host::Ipv6(ip)) => (ip.to_string(), format!("[{}]", ip)),
+        None => return Err(invalid_data(format!("{} has no host", url))),
+    };
+    let host = match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host,
+    };
+    let mut stream = tls_connect(&url.socket_addrs(|| Some(443))?, &name, timeout, tls)?;
+
+    let request = if get {
+        let mut url = url.clone();
+        url.query_pairs_mut().append_pair("dns", &URL_SAFE_NO_PAD.encode(question));
+        http_request("GET", &url[Position::BeforePath..], &host, None)
+    } else {
+        http_request("POST", &url[Position::BeforePath..], &host, Some(question))
+    };
+    stream.write_all(&request)?;
+    stream.flush()?;
+
+    let mut response = Vec::new();
+    match stream.read_to_end(&mut response) {
+        // Servers often close the connection without a TLS close_notify,
+        // a short body is still caught by the Content-Length check.
+        Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => {}
+        result => { result?; }
+    }
+    check_id(http_response_body(&response)?, id)
+}
+
+/// Certificates of the public web roots, plus the PEM certificates in
+/// `ca_file` for servers using a private or self-signed certificate.
+pub fn tls_config(ca_file: Option<&Path>) -> io::Result<Arc<ClientConfig>> {
+    let mut roots = RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    if let Some(ca_file) = ca_file {
+        let certificates = CertificateDer::pem_file_iter(ca_file)
+            .map_err(|error| invalid_data(format!("{}: {}", ca_file.display(), error)))?;
+        for certificate in certificates {
+            let certificate = certificate
+                .map_err(|error| invalid_data(format!("{}: {}", ca_file.display(), error)))?;
+            roots.add(certificate).map_err(|error| invalid_data(error.to_string()))?;
+        }
+    }
+    let mut config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(Arc::new(config))
+}
+
+/// Connect to the first reachable address and start a TLS session checking
+/// the certificate against `name`.
+fn tls_connect(addresses: &[SocketAddr], name: &str, timeout: Duration, tls: Arc<ClientConfig>)
+    -> io::Result<StreamOwned<ClientConnection, TcpStream>> {
+    let server_name = ServerName::try_from(name.to_string())
+        .map_err(|error| invalid_data(format!("invalid TLS name {:?}: {}", name, error)))?;
+
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, "no addresses to connect to");
+    for address in addresses {
+        match TcpStream::connect_timeout(address, timeout) {
+            Ok(tcp) => {
+                tcp.set_read_timeout(Some(timeout))?;
+                tcp.set_write_timeout(Some(timeout))?;
+                let connection = ClientConnection::new(tls, server_name)
+                    .map_err(io::Error::other)?;
+                return Ok(StreamOwned::new(connection, tcp));
+            }
+            Err(error) => last_error = error,
+        }
+    }
+    Err(last_error)
+}
+
+fn http_request(method: &str, target: &str, host: &str, body: Option<&[u8]>) -> Vec<u8> {
+    let mut request = format!("{} {} HTTP/1.1\r\n\
+                               Host: {}\r\n\
+                               Accept: {}\r\n\
+                               User-Agent: net_tool\r\n\
+                               Connection: close\r\n",
+                              method, target, host, DNS_MESSAGE);
+    if let Some(body) = body {
+        request.push_str(&format!("Content-Type: {}\r\nContent-Length: {}\r\n",
+                                  DNS_MESSAGE, body.len()));
+    }
+    request.push_str("\r\n");
+
+    let mut request = request.into_bytes();
+    request.extend_from_slice(body.unwrap_or_default());
+    request
+}
+
+/// The body of a successful HTTP/1.1 response carrying a DNS message.
+fn http_response_body(response: &[u8]) -> io::Result<Vec<u8>> {
+    let header_end = response.windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .ok_or_else(|| invalid_data("incomplete HTTP response".to_string()))?;
+    let head = String::from_utf8_lossy(&response[..header_end]);
+    let body = &response[header_end + 4..];
+
+    let mut lines = head.split("\r\n");
+    let status = lines.next().unwrap_or_default();
+    if status.split_whitespace().nth(1) != Some("200") {
+        return Err(invalid_data(format!("HTTP server answered {:?}", status)));
+    }
+
+    let mut content_length = None;
+    let mut chunked = false;
+    for line in lines {
+        let (name, value) = line.split_once(':').unwrap_or((line, ""));
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("content-type")
+            && !value.to_ascii_lowercase().starts_with(DNS_MESSAGE) {
+            return Err(invalid_data(format!("unexpected content type {:?}", value)));
+        } else if name.eq_ignore_ascii_case("content-length") {
+            content_length = Some(value.parse::<usize>()
+                .map_err(|_| invalid_data(format!("invalid content length {:?}", value)))?);
+        } else if name.eq_ignore_ascii_case("transfer-encoding") {
+            chunked = value.eq_ignore_ascii_case("chunked");
+        }
+    }
+
+    if chunked {
+        return decode_chunked(body);
+    }
+    match content_length {
+        Some(len) if body.len() < len => Err(invalid_data("truncated HTTP response".to_string())),
+        Some(len) => Ok(body[..len].to_vec()),
+        None => Ok(body.to_vec()),
+    }
+}
+
+fn decode_chunked(mut body: &[u8]) -> io::Result<Vec<u8>> {
+    let truncated = || invalid_data("truncated chunked HTTP response".to_string());
+    let mut decoded = Vec::new();
+    loop {
+        let line_end = body.windows(2).position(|window| window == b"\r\n").ok_or_else(truncated)?;
+        let size_line = String::from_utf8_lossy(&body[..line_end]);
+        // Chunk extensions after ';' are ignored.
+        let size = size_line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16)
+            .map_err(|_| invalid_data(format!("invalid chunk size {:?}", size)))?;
+        body = &body[line_end + 2..];
+        if size == 0 {
+            return Ok(decoded);
+        }
+        if body.len() < size + 2 {
+            return Err(truncated());
+        }
+        decoded.extend_from_slice(&body[..size]);
+        body = &body[size + 2..];
+    }
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Write a length-prefixed query to a stream and read the length-prefixed
+/// response.
+fn exchange_framed<S: Read + Write>(stream: &mut S, question: &[u8], id: u16)
+    -> io::Result<Vec<u8>> {
+    let len = u16::try_from(question.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "DNS query is too long"))?;
+    let mut framed = Vec::with_capacity(question.len() + 2);
+    framed.extend_from_slice(&len.to_be_bytes());
+    framed.extend_from_slice(question);
+    stream.write_all(&framed)?;
+    stream.flush()?;
+
+    let mut len = [0; 2];
+    stream.read_exact(&mut len)?;
+    let mut resp = vec![0; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut resp)?;
+    check_id(resp, id)
+}
+
+/// The id in the header of an encoded message.
+fn response_id(response: &[u8]) -> Option<u16> {
+    Some(u16::from_be_bytes([*response.first()?, *response.get(1)?]))
+}
+
+fn check_id(response: Vec<u8>, id: u16) -> io::Result<Vec<u8>> {
+    match response_id(&response) {
+        Some(response_id) if response_id == id => Ok(response),
+        Some(response_id) => Err(invalid_data(format!(
+            "response id {} does not match query id {}", response_id, id))),
+        None => Err(invalid_data("empty DNS response".to_string())),
+    }
+}
+
+#[test]
+fn test_http_response_body() {
+    let response = b"HTTP/1.1 200 OK\r\n\
+                     Content-Type: application/dns-message\r\n\
+                     Content-Length: 3\r\n\r\nabcdef";
+    assert_eq!(http_response_body(response).unwrap(), b"abc");
+
+    let response = b"HTTP/1.1 200 OK\r\n\
+                     Transfer-Encoding: chunked\r\n\r\n\
+                     2\r\nab\r\n4;name=value\r\ncdef\r\n0\r\n\r\n";
+    assert_eq!(http_response_body(response).unwrap(), b"abcdef");
+
+    assert!(http_response_body(b"HTTP/1.1 415 Unsupported Media Type\r\n\r\n").is_err());
+    assert!(http_response_body(b"HTTP/1.1 200 OK\r\nContent-Type: text/html\r\n\r\n").is_err());
+    assert!(http_response_body(b"HTTP/1.1 200 OK\r\nContent-Length: 9\r\n\r\nabc").is_err());
+}
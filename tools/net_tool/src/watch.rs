@@ -0,0 +1,212 @@
+//! Watching DNS answers for changes, like during a migration. Each name and
+//! type is resolved again and again, and every answer is compared with the
+//! one before it.
+
+use rustdns::types::{Rcode, Type};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::output::DnsRecord;
+use crate::resolver::ResolverConfig;
+
+/// How the answer to one question changed since it was last asked.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Change {
+    /// When the new answer arrived, in UTC as `2024-01-31T12:00:00Z`.
+    pub time: String,
+    pub name: String,
+    #[serde(rename = "type")]
+    pub query_type: String,
+    /// The response code of the new answer.
+    pub status: String,
+    /// The response code of the old answer, only when it changed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub previous_status: Option<String>,
+    pub added: Vec<DnsRecord>,
+    pub removed: Vec<DnsRecord>,
+    pub ttl_changes: Vec<TtlChange>,
+}
+
+/// A record that is still there with another TTL.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct TtlChange {
+    #[serde(flatten)]
+    pub record: DnsRecord,
+    pub previous_ttl: u64,
+}
+
+impl Change {
+    pub fn is_empty(&self) -> bool {
+        self.previous_status.is_none() && self.added.is_empty() && self.removed.is_empty()
+            && self.ttl_changes.is_empty()
+    }
+}
+
+struct Answer {
+    status: Rcode,
+    authoritative: bool,
+    records: Vec<DnsRecord>,
+}
+
+/// The last answer to every question asked.
+pub struct Watcher {
+    config: ResolverConfig,
+    answers: HashMap<(String, Type), Answer>,
+}
+
+impl Watcher {
+    pub fn new(config: ResolverConfig) -> Watcher {
+        Watcher { config, answers: HashMap::new() }
+    }
+
+    /// Ask for the `query_type` records of `name` again. The first answer
+    /// to a question is only remembered, later ones give the differences to
+    /// the one before when there are any.
+    ///
+    /// Recursive resolvers count TTLs down while they cache a record, so TTL
+    /// changes are only compared between authoritative answers.
+    pub fn check(&mut self, name: &str, query_type: Type) -> io::Result<Option<Change>> {
+        let response = crate::udp(name, query_type, &self.config)?;
+        let answer = Answer {
+            status: response.rcode,
+            authoritative: response.aa,
+            records: response.answers.iter()
+                .map(|record| DnsRecord::new("answer", record))
+                .collect(),
+        };
+        let Some(previous) = self.answers.insert((name.to_string(), query_type), answer) else {
+            return Ok(None);
+        };
+        let answer = &self.answers[&(name.to_string(), query_type)];
+
+        let same = |a: &DnsRecord, b: &DnsRecord| {
+            a.name.eq_ignore_ascii_case(&b.name) && a.record_type == b.record_type
+                && a.data == b.data
+        };
+        let find = |records: &'_ [DnsRecord], record: &DnsRecord| {
+            records.iter().find(|other| same(other, record)).cloned()
+        };
+        let change = Change {
+            time: timestamp(SystemTime::now()),
+            name: name.to_string(),
+            query_type: query_type.to_string(),
+            status: answer.status.to_string(),
+            previous_status: Some(previous.status.to_string())
+                .filter(|_| previous.status != answer.status),
+            added: answer.records.iter()
+                .filter(|record| find(&previous.records, record).is_none())
+                .cloned()
+                .collect(),
+            removed: previous.records.iter()
+                .filter(|record| find(&answer.records, record).is_none())
+                .cloned()
+                .collect(),
+            ttl_changes: answer.records.iter()
+                .filter(|_| answer.authoritative && previous.authoritative)
+                .filter_map(|record| {
+                    let old = find(&previous.records, record)?;
+                    (old.ttl != record.ttl)
+                        .then(|| TtlChange { record: record.clone(), previous_ttl: old.ttl })
+                })
+                .collect(),
+        };
+        Ok(Some(change).filter(|change| !change.is_empty()))
+    }
+}
+
+/// `time` in UTC as `2024-01-31T12:00:00Z` (RFC 3339).
+pub fn timestamp(time: SystemTime) -> String {
+    let seconds = time.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs());
+    let (days, seconds) = (seconds / 86400, seconds % 86400);
+    // The civil date of a day count, after Howard Hinnant's days_from_civil.
+    let days = days + 719468;
+    let era = days / 146097;
+    let day_of_era = days % 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524
+                       - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            year, month, day, seconds / 3600, seconds / 60 % 60, seconds % 60)
+}
+
+#[test]
+fn test_timestamp() {
+    use std::time::Duration;
+
+    assert_eq!(timestamp(UNIX_EPOCH), "1970-01-01T00:00:00Z");
+    assert_eq!(timestamp(UNIX_EPOCH + Duration::from_secs(951782400)), "2000-02-29T00:00:00Z");
+    assert_eq!(timestamp(UNIX_EPOCH + Duration::from_secs(1706702400 + 3723)),
+               "2024-01-31T13:02:03Z");
+}
+
+#[test]
+fn test_watch_reports_changes() {
+    use crate::resolver::Server;
+    use crate::testing;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    // The answer moves to a new address, then its TTL drops, then it is gone.
+    static ROUND: AtomicUsize = AtomicUsize::new(0);
+    let server = testing::spawn_udp_server(|query| {
+        let answers = match ROUND.load(Ordering::SeqCst) {
+            0 | 1 => vec![testing::a_record([192, 0, 2, 1], 300)],
+            2 => vec![testing::a_record([192, 0, 2, 2], 300)],
+            3 => vec![testing::a_record([192, 0, 2, 2], 60)],
+            _ => {
+                let mut response = testing::response(query, testing::AA, &[]);
+                response[3] |= 3; // NXDOMAIN
+                return Some(response);
+            }
+        };
+        Some(testing::response(query, testing::AA, &answers))
+    });
+    let mut watcher = Watcher::new(ResolverConfig {
+        servers: vec![Server::Plain(server)],
+        timeout: Duration::from_secs(1),
+        attempts: 1,
+        ..ResolverConfig::default()
+    });
+    let mut check = || {
+        let change = watcher.check("www.example", Type::A).unwrap();
+        ROUND.fetch_add(1, Ordering::SeqCst);
+        change
+    };
+    let data = |records: &[DnsRecord]| -> Vec<String> {
+        records.iter().map(|record| record.data.clone()).collect()
+    };
+
+    assert_eq!(check(), None);
+    assert_eq!(check(), None);
+
+    let moved = check().unwrap();
+    assert_eq!((moved.name.as_str(), moved.query_type.as_str()), ("www.example", "A"));
+    assert_eq!(moved.previous_status, None);
+    assert_eq!(data(&moved.added), ["192.0.2.2"]);
+    assert_eq!(data(&moved.removed), ["192.0.2.1"]);
+    assert!(moved.ttl_changes.is_empty());
+
+    let ttl = check().unwrap();
+    assert!(ttl.added.is_empty() && ttl.removed.is_empty());
+    assert_eq!(ttl.ttl_changes, [TtlChange {
+        record: DnsRecord {
+            section: "answer",
+            name: "www.example.".to_string(),
+            record_type: "A".to_string(),
+            ttl: 60,
+            data: "192.0.2.2".to_string(),
+        },
+        previous_ttl: 300,
+    }]);
+
+    let gone = check().unwrap();
+    assert_eq!(gone.status, "NXDomain");
+    assert_eq!(gone.previous_status.as_deref(), Some("NoError"));
+    assert_eq!(data(&gone.removed), ["192.0.2.2"]);
+}
@@ -0,0 +1,160 @@
+//! Encoding DNS responses. rustdns parses every section of a message but
+//! only encodes the header and questions, so the records are added here.
+
+use rustdns::types::{Record, Resource};
+use rustdns::Message;
+use std::io;
+
+/// The wire format of `message` with all its records. Names are written
+/// without compression.
+pub fn encode(message: &Message) -> io::Result<Vec<u8>> {
+    let header = Message {
+        answers: Vec::new(),
+        authoritys: Vec::new(),
+        additionals: Vec::new(),
+        extension: None,
+        ..message.clone()
+    };
+    let mut bytes = header.to_vec()?;
+
+    let sections = [&message.answers, &message.authoritys, &message.additionals];
+    for (count, section) in bytes[6..12].chunks_mut(2).zip(sections) {
+        count.copy_from_slice(&(section.len() as u16).to_be_bytes());
+    }
+    for record in sections.into_iter().flatten() {
+        encode_record(&mut bytes, record)?;
+    }
+    if let Some(extension) = &message.extension {
+        extension.write(&mut bytes)?;
+        let additionals = message.additionals.len() as u16 + 1;
+        bytes[10..12].copy_from_slice(&additionals.to_be_bytes());
+    }
+    Ok(bytes)
+}
+
+/// `message` without its records and with the truncation bit set, for a
+/// response too long for the client's UDP buffer.
+pub fn encode_truncated(message: &Message) -> io::Result<Vec<u8>> {
+    encode(&Message {
+        tc: true,
+        answers: Vec::new(),
+        authoritys: Vec::new(),
+        additionals: Vec::new(),
+        ..message.clone()
+    })
+}
+
+fn encode_record(out: &mut Vec<u8>, record: &Record) -> io::Result<()> {
+    encode_name(out, &record.name)?;
+    out.extend_from_slice(&(record.r#type() as u16).to_be_bytes());
+    out.extend_from_slice(&(record.class as u16).to_be_bytes());
+    out.extend_from_slice(&seconds(record.ttl).to_be_bytes());
+
+    let mut data = Vec::new();
+    match &record.resource {
+        Resource::A(ip) => data.extend_from_slice(&ip.octets()),
+        Resource::AAAA(ip) => data.extend_from_slice(&ip.octets()),
+        Resource::NS(name) | Resource::CNAME(name) | Resource::PTR(name) => {
+            encode_name(&mut data, name)?;
+        }
+        Resource::MX(mx) => {
+            data.extend_from_slice(&mx.preference.to_be_bytes());
+            encode_name(&mut data, &mx.exchange)?;
+        }
+        Resource::TXT(txt) | Resource::SPF(txt) => {
+            for string in &txt.0 {
+                let len = u8::try_from(string.len())
+                    .map_err(|_| invalid_data("TXT string longer than 255 bytes".to_string()))?;
+                data.push(len);
+                data.extend_from_slice(string);
+            }
+        }
+        Resource::SOA(soa) => {
+            encode_name(&mut data, &soa.mname)?;
+            // rustdns reads the mailbox name as an address, hostmaster@example.com.
+            encode_name(&mut data, &soa.rname.replacen('@', ".", 1))?;
+            data.extend_from_slice(&soa.serial.to_be_bytes());
+            for duration in [soa.refresh, soa.retry, soa.expire, soa.minimum] {
+                data.extend_from_slice(&seconds(duration).to_be_bytes());
+            }
+        }
+        Resource::SRV(srv) => {
+            data.extend_from_slice(&srv.priority.to_be_bytes());
+            data.extend_from_slice(&srv.weight.to_be_bytes());
+            data.extend_from_slice(&srv.port.to_be_bytes());
+            encode_name(&mut data, &srv.name)?;
+        }
+        Resource::OPT | Resource::ANY => {
+            return Err(invalid_data(format!("cannot encode a {} record", record.r#type())));
+        }
+    }
+    out.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    out.extend_from_slice(&data);
+    Ok(())
+}
+
+fn encode_name(out: &mut Vec<u8>, name: &str) -> io::Result<()> {
+    for label in name.split_terminator('.').filter(|label| !label.is_empty()) {
+        let len = u8::try_from(label.len()).ok().filter(|len| *len <= 63)
+            .ok_or_else(|| invalid_data(format!("label {:?} longer than 63 bytes", label)))?;
+        out.push(len);
+        out.extend_from_slice(label.as_bytes());
+    }
+    out.push(0);
+    Ok(())
+}
+
+fn seconds(duration: std::time::Duration) -> u32 {
+    u32::try_from(duration.as_secs()).unwrap_or(u32::MAX)
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[test]
+fn test_encode_round_trip() {
+    use rustdns::resource::{MX, SOA, TXT};
+    use rustdns::types::{Class, Extension, Rcode, Type, QR};
+    use std::time::Duration;
+
+    let mut message = Message {
+        qr: QR::Response,
+        aa: true,
+        rcode: Rcode::NoError,
+        ..Message::default()
+    };
+    message.add_question("example.com", Type::ANY, Class::Internet);
+    let record = |name: &str, resource| {
+        Record::new(name, Class::Internet, Duration::from_secs(300), resource)
+    };
+    message.answers = vec![
+        record("example.com.", Resource::A([192, 0, 2, 1].into())),
+        record("example.com.", Resource::AAAA("2001:db8::1".parse().unwrap())),
+        record("example.com.", Resource::MX(MX {
+            preference: 10,
+            exchange: "mx.example.com.".into(),
+        })),
+        record("example.com.", Resource::TXT(TXT(vec![b"v=spf1 -all".to_vec(), b"x".to_vec()]))),
+        record("www.example.com.", Resource::CNAME("example.com.".into())),
+    ];
+    message.authoritys = vec![record("example.com.", Resource::SOA(SOA {
+        mname: "ns.example.com.".into(),
+        rname: "hostmaster@example.com.".into(),
+        serial: 2024010101,
+        refresh: Duration::from_secs(7200),
+        retry: Duration::from_secs(900),
+        expire: Duration::from_secs(1209600),
+        minimum: Duration::from_secs(300),
+    }))];
+    message.additionals = vec![record("ns.example.com.", Resource::A([192, 0, 2, 53].into()))];
+    message.extension = Some(Extension { payload_size: 1232, ..Default::default() });
+
+    let decoded = Message::from_slice(&encode(&message).unwrap()).unwrap();
+    assert_eq!(decoded, message);
+
+    let truncated = Message::from_slice(&encode_truncated(&message).unwrap()).unwrap();
+    assert!(truncated.tc);
+    assert!(truncated.answers.is_empty());
+    assert_eq!(truncated.questions, message.questions);
+}